@@ -0,0 +1,34 @@
+#![no_main]
+#![allow(dead_code)]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/http.rs"] mod http;
+
+type SBResult<T> = Result<T, failure::Error>;
+
+// The parsers for header values clients send, which Request::parse only hands on as strings
+fuzz_target!(|data: &[u8]| {
+	let header = match std::str::from_utf8(data) {
+		Ok(header) => header,
+		Err(_) => return,
+	};
+
+	if let Some(time) = http::parse_http_date(header) {
+		assert_eq!(http::parse_http_date(&http::format_http_date(time)), Some(time));
+	}
+
+	let _ = http::parse_iso_date(header);
+	let _ = http::etag_matches(header, "\"abc\"", true);
+	let _ = http::etag_matches(header, "\"abc\"", false);
+
+	for &total in [0, 1, 1<<20, u64::MAX].iter() {
+		if let Some(Ok(ranges)) = http::parse_ranges(header, total) {
+			assert!(ranges.windows(2).all(|pair| pair[0].1 < pair[1].0));
+			assert!(ranges.iter().all(|&(start, end)| start <= end && end < total));
+		}
+	}
+
+	let quality = http::accept_quality(header, "text/html");
+	assert!((0.0..=1.0).contains(&quality));
+});
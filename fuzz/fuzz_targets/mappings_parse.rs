@@ -0,0 +1,46 @@
+#![no_main]
+#![feature(generators, generator_trait)]
+#![allow(dead_code, unused_imports)]
+
+use libfuzzer_sys::fuzz_target;
+use std::path::Path;
+
+// Mappings reaches most of the server through autoindex and admin, so all of it is built in
+#[macro_use] #[path = "../../src/log.rs"] mod log;
+#[macro_use] #[path = "../../src/coro_util.rs"] mod coro_util;
+#[path = "../../src/fileserver.rs"] mod fileserver;
+#[path = "../../src/tcp_util.rs"] mod tcp_util;
+#[path = "../../src/http.rs"] mod http;
+#[path = "../../src/ip_filter.rs"] mod ip_filter;
+#[path = "../../src/cert.rs"] mod cert;
+#[path = "../../src/cert_store.rs"] mod cert_store;
+#[path = "../../src/open_file.rs"] mod open_file;
+#[path = "../../src/mime.rs"] mod mime;
+#[path = "../../src/autoindex.rs"] mod autoindex;
+#[path = "../../src/admin.rs"] mod admin;
+#[path = "../../src/preview.rs"] mod preview;
+#[path = "../../src/publish.rs"] mod publish;
+#[path = "../../src/ready.rs"] mod ready;
+#[path = "../../src/clock.rs"] mod clock;
+#[path = "../../src/transform.rs"] mod transform;
+#[path = "../../src/shutdown.rs"] mod shutdown;
+#[path = "../../src/systemd.rs"] mod systemd;
+#[path = "../../src/broadcast.rs"] mod broadcast;
+#[path = "../../src/watch.rs"] mod watch;
+#[path = "../../src/config.rs"] mod config;
+#[path = "../../src/privileges.rs"] mod privileges;
+#[path = "../../src/artifacts.rs"] mod artifacts;
+#[path = "../../src/mappings.rs"] mod mappings;
+
+type SBResult<T> = Result<T, failure::Error>;
+
+fuzz_target!(|data: &[u8]| {
+	let contents = match std::str::from_utf8(data) {
+		Ok(contents) => contents,
+		Err(_) => return,
+	};
+
+	// Mounts, wildcards and imports all point into a directory that doesn't exist, so they
+	// fail as soon as they touch the filesystem instead of walking whatever's around
+	let _ = mappings::Mappings::parse(contents, Path::new("fuzz-no-such-dir"), mappings::CacheMode::Disabled);
+});
@@ -0,0 +1,30 @@
+#![no_main]
+#![allow(dead_code)]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/http.rs"] mod http;
+
+type SBResult<T> = Result<T, failure::Error>;
+
+fuzz_target!(|data: &[u8]| {
+	let _ = http::check_head(data, 8<<10, 8<<10);
+
+	let request = match http::Request::parse(data) {
+		Ok(request) => request,
+		Err(_) => return,
+	};
+
+	// Whatever parsing lets through gets routed, so it has to normalize to something under the root
+	if let Some(path) = http::normalize_path(request.uri()) {
+		assert!(path.starts_with('/'));
+		assert!(!path.split('/').any(|segment| segment == ".." || segment == "."));
+	}
+
+	if let Some(range) = request.get("Range") {
+		if let Some(Ok(ranges)) = http::parse_ranges(range, 1<<20) {
+			assert!(ranges.windows(2).all(|pair| pair[0].1 < pair[1].0));
+			assert!(ranges.iter().all(|&(start, end)| start <= end && end < 1<<20));
+		}
+	}
+});
@@ -0,0 +1,335 @@
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::Receiver;
+use std::io::{Read, Write};
+use std::time::Duration;
+use std::fmt::Write as FmtWrite;
+use std::str;
+use std::sync::atomic::Ordering;
+
+use acme_client::openssl;
+use self::openssl::hash::MessageDigest;
+use self::openssl::nid::Nid;
+use self::openssl::x509::X509NameRef;
+
+use crate::SBResult;
+use crate::mappings::{Mappings, MappingsDelta, CacheMode};
+use crate::http;
+use crate::cert::Certificate;
+use crate::clock::SystemClock;
+use crate::shutdown;
+use crate::fileserver::{Fileserver, FileserverCommand, RedirectPolicy, CONNECTION_STATS, OPEN_CONNECTIONS, TRANSFER_STATS};
+
+const REQUEST_READ_TIMEOUT_SECS: u64 = 5;
+
+const METHODS: &[&str] = &["GET", "HEAD", "OPTIONS", "POST"];
+
+/// Endpoints that change something. Only POSTs reach these, so a link or an image tag can't
+const ACTIONS: &[&str] = &["/reload", "/zombie/on", "/zombie/off"];
+
+/// Hostnames the admin server answers to, with its port. Anything else could be a page on the
+/// web pointing a name it controls at 127.0.0.1
+const LOCAL_HOSTS: &[&str] = &["127.0.0.1", "localhost", "[::1]"];
+
+pub enum AdminCommand {
+	NewMappings(Mappings),
+	UpdateMappings(MappingsDelta),
+	SetCert(Certificate),
+	/// The plain HTTP servers and how they redirect, so zombie mode can be switched off and on again
+	SetZombies(Vec<Fileserver>, RedirectPolicy),
+}
+
+struct AdminState {
+	mappings: Mappings,
+	secure: bool,
+	cert: Option<Certificate>,
+	zombies: Option<Zombies>,
+}
+
+struct Zombies {
+	servers: Vec<Fileserver>,
+	policy: RedirectPolicy,
+	enabled: bool,
+}
+
+/// Serves machine readable server state, and a few controls. Should only ever be bound to a local address
+pub fn start(listener: TcpListener, command_rx: Receiver<AdminCommand>, secure: bool) {
+	let mut state = AdminState {
+		mappings: Mappings::new(CacheMode::Disabled),
+		secure,
+		cert: None,
+		zombies: None,
+	};
+
+	for stream in listener.incoming() {
+		for command in command_rx.try_iter() {
+			match command {
+				AdminCommand::NewMappings(mappings) => state.mappings = mappings,
+				AdminCommand::UpdateMappings(delta) => state.mappings.apply_delta(delta),
+				AdminCommand::SetCert(cert) => state.cert = Some(cert),
+				AdminCommand::SetZombies(servers, policy) => state.zombies = Some(Zombies { servers, policy, enabled: true }),
+			}
+		}
+
+		let stream = match stream {
+			Ok(s) => s,
+			Err(_) => continue
+		};
+
+		if let Err(e) = handle_connection(stream, &mut state) {
+			info!("Connection aborted with error: {}", e);
+		}
+	}
+}
+
+fn handle_connection(mut stream: TcpStream, state: &mut AdminState) -> SBResult<()> {
+	stream.set_read_timeout(Some(Duration::from_secs(REQUEST_READ_TIMEOUT_SECS)))?;
+
+	let mut buf = [0u8; 8<<10];
+	let size = stream.read(&mut buf)?;
+	let request = http::Request::parse_with_methods(&buf[..size], METHODS)?;
+
+	let port = stream.local_addr()?.port();
+	if !is_local_host(request.get("Host"), port) {
+		stream.write_all(&http::Response::new("HTTP/1.1 403 Forbidden").into_bytes())?;
+		failure::bail!("Refused admin request for host {:?}", request.get("Host"));
+	}
+
+	let allowed = if ACTIONS.contains(&request.uri()) { "POST, OPTIONS" } else { "GET, HEAD, OPTIONS" };
+
+	// Nothing here should happen just because something asked what it could do, like /reload would
+	if request.method() == "OPTIONS" {
+		let mut res = http::Response::new("HTTP/1.1 204 No Content");
+		res.set("Allow", allowed);
+		stream.write_all(&res.into_bytes())?;
+		return Ok(())
+	}
+
+	if !allowed.split(", ").any(|method| method == request.method()) {
+		let mut res = http::Response::new("HTTP/1.1 405 Method Not Allowed");
+		res.set("Allow", allowed);
+		stream.write_all(&res.into_bytes())?;
+		return Ok(())
+	}
+
+	let body = match request.uri() {
+		"/routes" => routes_json(&state.mappings),
+		"/cache" => cache_json(&state.mappings),
+		"/cert" => cert_json(state),
+		"/transfers" => transfers_json(),
+		"/connections" => connections_json(),
+
+		// Same as a SIGHUP. The reload happens in the background, so check /routes to see it land
+		"/reload" => {
+			info!("Reload requested by admin");
+			shutdown::request_reload();
+			r#"{"reload":"requested"}"#.to_owned()
+		}
+
+		"/zombie" => zombie_json(state),
+		"/zombie/on" => set_zombie(state, true),
+		"/zombie/off" => set_zombie(state, false),
+
+		_ => {
+			stream.write_all(&http::Response::new("HTTP/1.1 404 Not Found").into_bytes())?;
+			return Ok(())
+		}
+	};
+
+	let content_length = body.len().to_string();
+
+	let mut res = http::Response::new("HTTP/1.1 200 OK");
+	res.set("Content-Type", "application/json");
+	res.set("Content-Length", &content_length);
+
+	stream.write_all(&res.into_bytes())?;
+	stream.write_all(body.as_bytes())?;
+
+	Ok(())
+}
+
+/// Whether a Host field names this server, as `127.0.0.1:PORT`, `localhost:PORT` or `[::1]:PORT`
+fn is_local_host(host: Option<&str>, port: u16) -> bool {
+	let host = match host {
+		Some(host) => host,
+		None => return false,
+	};
+
+	let suffix = format!(":{}", port);
+	if !host.ends_with(&suffix) {
+		return false
+	}
+
+	let name = &host[..host.len() - suffix.len()];
+	LOCAL_HOSTS.iter().any(|local| local.eq_ignore_ascii_case(name))
+}
+
+fn routes_json(mappings: &Mappings) -> String {
+	let mut routes: Vec<_> = mappings.routes().collect();
+	routes.sort_by_key(|(uri, _)| *uri);
+
+	let entries: Vec<_> = routes.into_iter()
+		.map(|(uri, mapping)| format!(r#"{{"uri":{},"path":{},"content_type":{}}}"#,
+			json_string(uri),
+			json_string(&mapping.path.to_string_lossy()),
+			mapping.content_type.as_ref().map_or("null".into(), |ct| json_string(ct))))
+		.collect();
+
+	format!(r#"{{"routes":[{}]}}"#, entries.join(","))
+}
+
+fn cache_json(mappings: &Mappings) -> String {
+	let mut cache_entries = mappings.cache_entries();
+	cache_entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+	let entries: Vec<_> = cache_entries.into_iter()
+		.map(|e| format!(r#"{{"path":{},"sha256":"{}","sizes":{{"identity":{},"gzip":{},"deflate":{},"br":{}}}}}"#,
+			json_string(&e.path.to_string_lossy()),
+			e.content_hash,
+			e.uncompressed_size,
+			e.gzipped_size.map_or("null".into(), |s| s.to_string()),
+			e.deflated_size.map_or("null".into(), |s| s.to_string()),
+			e.brotli_size.map_or("null".into(), |s| s.to_string())))
+		.collect();
+
+	let cache_mode = match mappings.cache_mode() {
+		CacheMode::Eager => "eager",
+		CacheMode::Lazy => "lazy",
+		CacheMode::Disabled => "disabled",
+	};
+
+	format!(r#"{{"cache_mode":"{}","entries":[{}]}}"#, cache_mode, entries.join(","))
+}
+
+fn cert_json(state: &AdminState) -> String {
+	let cert = match state.cert.as_ref() {
+		Some(cert) => cert,
+		None => return format!(r#"{{"secure":{},"days_till_expiry":null}}"#, state.secure),
+	};
+
+	let x509 = cert.certificate();
+
+	let names: Vec<_> = cert.dns_names().iter()
+		.map(|name| json_string(name))
+		.collect();
+
+	let fingerprint = x509.digest(MessageDigest::sha256())
+		.map(|digest| digest.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":"))
+		.ok();
+
+	format!(r#"{{"secure":{},"days_till_expiry":{},"subject":{},"issuer":{},"names":[{}],"not_before":{},"not_after":{},"sha256":{}}}"#,
+		state.secure,
+		cert.days_till_expiry(&SystemClock).ok().map_or("null".into(), |d| d.to_string()),
+		common_name(x509.subject_name()).map_or("null".into(), |n| json_string(&n)),
+		common_name(x509.issuer_name()).map_or("null".into(), |n| json_string(&n)),
+		names.join(","),
+		json_string(&x509.not_before().to_string()),
+		json_string(&x509.not_after().to_string()),
+		fingerprint.map_or("null".into(), |f| json_string(&f)))
+}
+
+fn common_name(name: &X509NameRef) -> Option<String> {
+	let entry = name.entries_by_nid(Nid::COMMONNAME).next()?;
+	Some(String::from_utf8_lossy(entry.data().as_slice()).into_owned())
+}
+
+fn zombie_json(state: &AdminState) -> String {
+	match state.zombies.as_ref() {
+		Some(zombies) => format!(r#"{{"zombie":{}}}"#, zombies.enabled),
+		None => r#"{"zombie":null}"#.to_owned(),
+	}
+}
+
+/// Switches the plain HTTP servers between redirecting to HTTPS and serving content. Only possible with TLS
+fn set_zombie(state: &mut AdminState, enabled: bool) -> String {
+	if let Some(zombies) = state.zombies.as_mut() {
+		for server in zombies.servers.iter() {
+			let command = if enabled {
+				FileserverCommand::Zombify(zombies.policy.clone())
+			} else {
+				FileserverCommand::Revive
+			};
+
+			if let Err(e) = server.apply(command) {
+				error!("Failed to update plain HTTP server: {}", e);
+			}
+		}
+
+		if zombies.enabled != enabled {
+			info!("Zombie mode turned {} by admin", if enabled { "on" } else { "off" });
+		}
+
+		zombies.enabled = enabled;
+	}
+
+	zombie_json(state)
+}
+
+fn transfers_json() -> String {
+	let stats = &TRANSFER_STATS;
+
+	format!(r#"{{"completed":{},"aborted":{},"bytes_sent":{},"bytes_expected":{}}}"#,
+		stats.completed.load(Ordering::Relaxed),
+		stats.aborted.load(Ordering::Relaxed),
+		stats.bytes_sent.load(Ordering::Relaxed),
+		stats.bytes_expected.load(Ordering::Relaxed))
+}
+
+fn connections_json() -> String {
+	let stats = &CONNECTION_STATS;
+
+	format!(r#"{{"open":{},"completed":{},"client_errors":{},"server_errors":{},"aborted":{},"deadline_exceeded":{}}}"#,
+		OPEN_CONNECTIONS.load(Ordering::Relaxed),
+		stats.completed.load(Ordering::Relaxed),
+		stats.client_errors.load(Ordering::Relaxed),
+		stats.server_errors.load(Ordering::Relaxed),
+		stats.aborted.load(Ordering::Relaxed),
+		stats.deadline_exceeded.load(Ordering::Relaxed))
+}
+
+pub fn json_string(s: &str) -> String {
+	let mut out = String::with_capacity(s.len() + 2);
+	out.push('"');
+
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); }
+			c => out.push(c),
+		}
+	}
+
+	out.push('"');
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn only_local_names_with_our_port_are_accepted() {
+		assert!(is_local_host(Some("127.0.0.1:9000"), 9000));
+		assert!(is_local_host(Some("LocalHost:9000"), 9000));
+		assert!(is_local_host(Some("[::1]:9000"), 9000));
+
+		assert!(!is_local_host(None, 9000));
+		assert!(!is_local_host(Some("127.0.0.1"), 9000));
+		assert!(!is_local_host(Some("127.0.0.1:90000"), 9000));
+		assert!(!is_local_host(Some("localhost:9001"), 9000));
+		assert!(!is_local_host(Some("attacker.example:9000"), 9000));
+		assert!(!is_local_host(Some("localhost.attacker.example:9000"), 9000));
+	}
+
+	#[test]
+	fn admin_requests_can_post() {
+		let request = http::Request::parse_with_methods(b"POST /reload HTTP/1.1\r\nHost: localhost:9000\r\n\r\n", METHODS).unwrap();
+		assert_eq!(request.method(), "POST");
+
+		let err = http::Request::parse(b"POST /reload HTTP/1.1\r\nHost: localhost:9000\r\n\r\n").unwrap_err();
+		assert_eq!(err.downcast_ref::<http::RequestError>().unwrap().status, 501);
+	}
+}
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use acme_client::openssl;
+use self::openssl::hash::MessageDigest;
+use self::openssl::pkey::{PKey, Private};
+use self::openssl::sha::{sha256, Sha256};
+use self::openssl::sign::Signer;
+
+use crate::SBResult;
+use crate::mappings::hex;
+
+/// Every artifact under a route with its hash, in the format `sha256sum -c` checks
+pub const SUMS_NAME: &str = "SHA256SUMS";
+
+/// Key `.sig` sidecars are made with, from `artifacts /route => dir sign key.pem`
+pub struct SigningKey {
+	pkey: PKey<Private>,
+	/// Hash of the public half, so signatures made with a previous key are never reused
+	id: String,
+}
+
+impl SigningKey {
+	pub fn load(path: &Path) -> SBResult<SigningKey> {
+		let pem = fs::read(path)
+			.map_err(|e| failure::format_err!("Failed to read signing key {:?}: {}", path, e))?;
+
+		let pkey = PKey::private_key_from_pem(&pem)
+			.map_err(|_| failure::format_err!("{:?} isn't a PEM private key", path))?;
+
+		Signer::new(MessageDigest::sha256(), &pkey)
+			.map_err(|_| failure::format_err!("{:?} can't make SHA-256 signatures, expected an RSA or EC key", path))?;
+
+		let id = hex(&sha256(&pkey.public_key_to_der()?));
+		Ok(SigningKey { pkey, id })
+	}
+
+	/// Detached signature, the same as `openssl dgst -sha256 -sign key.pem` makes
+	pub fn sign(&self, data: &[u8]) -> SBResult<Vec<u8>> {
+		let mut signer = Signer::new(MessageDigest::sha256(), &self.pkey)?;
+		signer.update(data)?;
+		Ok(signer.sign_to_vec()?)
+	}
+}
+
+/// A directory of release artifacts, mounted at `prefix` with sidecars generated alongside
+#[derive(Clone)]
+pub struct ArtifactSet {
+	pub prefix: String,
+	pub dir: PathBuf,
+	pub key: Option<Arc<SigningKey>>,
+}
+
+/// What a uri under an artifact route is asking for
+#[derive(Debug)]
+pub enum Sidecar<'a> {
+	Sums,
+	SumsSignature,
+	Checksum(&'a str),
+	Signature(&'a str),
+}
+
+impl<'a> Sidecar<'a> {
+	/// Takes the part of the uri after the artifact route, e.g. `v1.2/app.tar.gz.sha256`
+	pub fn parse(name: &'a str) -> Option<Sidecar<'a>> {
+		let strip = |suffix: &str| if name.ends_with(suffix) && name.len() > suffix.len() {
+			Some(&name[..name.len() - suffix.len()])
+		} else {
+			None
+		};
+
+		if name == SUMS_NAME {
+			Some(Sidecar::Sums)
+		} else if strip(".sig") == Some(SUMS_NAME) {
+			Some(Sidecar::SumsSignature)
+		} else if let Some(artifact) = strip(".sha256") {
+			Some(Sidecar::Checksum(artifact))
+		} else if let Some(artifact) = strip(".sig") {
+			Some(Sidecar::Signature(artifact))
+		} else {
+			None
+		}
+	}
+
+	pub fn needs_key(&self) -> bool {
+		match self {
+			Sidecar::SumsSignature | Sidecar::Signature(_) => true,
+			Sidecar::Sums | Sidecar::Checksum(_) => false,
+		}
+	}
+
+	pub fn content_type(&self) -> &'static str {
+		if self.needs_key() {
+			"application/octet-stream"
+		} else {
+			"text/plain; charset=utf-8"
+		}
+	}
+}
+
+/// One line of a checksum file. Two spaces means the file is read as binary
+pub fn checksum_line(sha256: &str, name: &str) -> String {
+	format!("{}  {}\n", sha256, name)
+}
+
+#[derive(Clone)]
+pub struct Digest {
+	modified: SystemTime,
+	len: u64,
+	key_id: Option<String>,
+	pub sha256: String,
+	pub signature: Option<Vec<u8>>,
+}
+
+/// Everything generated for an artifact set, made once when the mappings load so requests never touch the disk
+#[derive(Clone)]
+pub struct PreparedSet {
+	pub sums: Vec<u8>,
+	pub sums_signature: Option<Vec<u8>>,
+	pub digests: HashMap<PathBuf, Digest>,
+}
+
+/// Digests of artifacts as they were last seen on disk. Shared across reloads, so only
+/// artifacts that have changed get read again
+#[derive(Default)]
+pub struct DigestCache {
+	digests: Mutex<HashMap<PathBuf, Digest>>,
+}
+
+impl DigestCache {
+	/// Hash of `path`, and its signature if there's a key, rereading it only if it's changed
+	pub fn get(&self, path: &Path, key: Option<&SigningKey>) -> SBResult<Digest> {
+		let metadata = fs::metadata(path)?;
+		let (modified, len) = (metadata.modified()?, metadata.len());
+		let key_id = key.map(|key| key.id.clone());
+
+		let cached = self.digests.lock().unwrap_or_else(|e| e.into_inner())
+			.get(path)
+			.filter(|digest| digest.modified == modified && digest.len == len && digest.key_id == key_id)
+			.cloned();
+
+		if let Some(digest) = cached {
+			return Ok(digest)
+		}
+
+		// Artifacts can be big, so don't hold everyone else up while reading one
+		let (sha256, signature) = digest_file(path, key)?;
+		let digest = Digest { modified, len, key_id, sha256, signature };
+
+		self.digests.lock().unwrap_or_else(|e| e.into_inner())
+			.insert(path.to_owned(), digest.clone());
+
+		Ok(digest)
+	}
+
+	/// Forgets files that aren't artifacts anymore
+	pub fn retain(&self, keep: impl Fn(&Path) -> bool) {
+		self.digests.lock().unwrap_or_else(|e| e.into_inner())
+			.retain(|path, _| keep(path));
+	}
+}
+
+/// Hashes and signs a file in one pass, without holding all of it in memory
+fn digest_file(path: &Path, key: Option<&SigningKey>) -> SBResult<(String, Option<Vec<u8>>)> {
+	let mut file = fs::File::open(path)?;
+	let mut hasher = Sha256::new();
+	let mut signer = match key {
+		Some(key) => Some(Signer::new(MessageDigest::sha256(), &key.pkey)?),
+		None => None,
+	};
+
+	let mut buf = vec![0u8; 64<<10];
+
+	loop {
+		match file.read(&mut buf)? {
+			0 => break,
+			size => {
+				hasher.update(&buf[..size]);
+				if let Some(signer) = signer.as_mut() {
+					signer.update(&buf[..size])?;
+				}
+			}
+		}
+	}
+
+	let signature = match signer {
+		Some(signer) => Some(signer.sign_to_vec()?),
+		None => None,
+	};
+
+	Ok((hex(&hasher.finish()), signature))
+}
@@ -0,0 +1,277 @@
+use std::path::Path;
+use std::fmt::Write;
+use std::fs;
+use std::time::SystemTime;
+
+use crate::SBResult;
+use crate::http;
+use crate::publish;
+use crate::admin::json_string;
+
+/// How a client wants a directory listing, from its query string and Accept headers
+#[derive(Debug, Clone, Copy)]
+pub struct Listing {
+	pub sort: Sort,
+	pub descending: bool,
+	pub json: bool,
+	pub dates: DateStyle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sort {
+	Name,
+	Size,
+	Modified,
+}
+
+/// Day, month and year order, picked from Accept-Language. Times are always UTC
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DateStyle {
+	/// 2024-03-09 14:05, when nothing better is known
+	Iso,
+	/// 03/09/2024 14:05
+	MonthFirst,
+	/// 09/03/2024 14:05
+	DayFirst,
+	/// 09.03.2024 14:05
+	DayFirstDotted,
+}
+
+struct Entry {
+	name: String,
+	is_dir: bool,
+	size: u64,
+	modified: Option<SystemTime>,
+}
+
+impl Listing {
+	/// `?sort=name|size|modified&order=asc|desc`, and JSON if Accept prefers it over html
+	pub fn from_request(query: Option<&str>, accept: Option<&str>, accept_language: Option<&str>) -> Listing {
+		let mut listing = Listing { sort: Sort::Name, descending: false, json: false, dates: DateStyle::Iso };
+
+		for param in query.unwrap_or("").split('&') {
+			let mut parts = param.splitn(2, '=');
+
+			match (parts.next(), parts.next()) {
+				(Some("sort"), Some("name")) => listing.sort = Sort::Name,
+				(Some("sort"), Some("size")) => listing.sort = Sort::Size,
+				(Some("sort"), Some("modified")) => listing.sort = Sort::Modified,
+				(Some("order"), Some("desc")) => listing.descending = true,
+				(Some("order"), Some("asc")) => listing.descending = false,
+				_ => {}
+			}
+		}
+
+		if let Some(accept) = accept {
+			listing.json = http::accept_quality(accept, "application/json") > http::accept_quality(accept, "text/html");
+		}
+
+		if let Some(accept_language) = accept_language {
+			listing.dates = date_style(accept_language);
+		}
+
+		listing
+	}
+
+	pub fn content_type(&self) -> &'static str {
+		if self.json { "application/json" } else { "text/html; charset=utf-8" }
+	}
+}
+
+/// Renders a listing of the files and directories in `dir`, which is served at `uri`
+pub fn render(dir: &Path, uri: &str, listing: &Listing, hide_dotfiles: bool) -> SBResult<String> {
+	let mut entries = Vec::new();
+
+	for entry in fs::read_dir(dir)? {
+		let entry = entry?;
+		let name = entry.file_name().to_string_lossy().into_owned();
+		if name == publish::STATE_DIR { continue }
+		if hide_dotfiles && name.starts_with('.') { continue }
+
+		// Follows symlinks, so linked files get their real size
+		let metadata = fs::metadata(entry.path()).or_else(|_| entry.metadata())?;
+
+		entries.push(Entry {
+			name,
+			is_dir: metadata.is_dir(),
+			size: metadata.len(),
+			modified: metadata.modified().ok(),
+		});
+	}
+
+	// Directories first whichever way it's sorted, then by name to keep ties stable
+	entries.sort_by(|a, b| {
+		let order = match listing.sort {
+			Sort::Name => a.name.cmp(&b.name),
+			Sort::Size => a.size.cmp(&b.size).then_with(|| a.name.cmp(&b.name)),
+			Sort::Modified => a.modified.cmp(&b.modified).then_with(|| a.name.cmp(&b.name)),
+		};
+
+		let order = if listing.descending { order.reverse() } else { order };
+		b.is_dir.cmp(&a.is_dir).then(order)
+	});
+
+	if listing.json {
+		render_json(&entries)
+	} else {
+		render_html(&entries, uri, listing)
+	}
+}
+
+fn render_json(entries: &[Entry]) -> SBResult<String> {
+	let mut json = String::from("[");
+
+	for (index, entry) in entries.iter().enumerate() {
+		if index > 0 { json.push(',') }
+
+		let modified = entry.modified.map_or("null".into(), |modified| {
+			let (year, month, day, hour, minute) = http::civil_time(modified);
+			format!("\"{:04}-{:02}-{:02}T{:02}:{:02}Z\"", year, month, day, hour, minute)
+		});
+
+		write!(json, r#"{{"name":{},"type":"{}","size":{},"modified":{}}}"#,
+			json_string(&entry.name),
+			if entry.is_dir { "directory" } else { "file" },
+			if entry.is_dir { "null".into() } else { entry.size.to_string() },
+			modified)?;
+	}
+
+	json.push_str("]\n");
+	Ok(json)
+}
+
+fn render_html(entries: &[Entry], uri: &str, listing: &Listing) -> SBResult<String> {
+	let title = escape_html(uri);
+	let mut html = String::new();
+
+	write!(html, "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n", title)?;
+	write!(html, "<body><h1>Index of {}</h1><table>\n<tr>", title)?;
+
+	// Clicking the current column flips its order, clicking another sorts by it ascending
+	for &(sort, label, param) in &[(Sort::Name, "Name", "name"), (Sort::Size, "Size", "size"), (Sort::Modified, "Modified", "modified")] {
+		let order = if sort == listing.sort && !listing.descending { "desc" } else { "asc" };
+		write!(html, "<th><a href=\"?sort={}&amp;order={}\">{}</a></th>", param, order, label)?;
+	}
+
+	html.push_str("</tr>\n");
+
+	if uri != "/" {
+		html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+	}
+
+	for entry in entries {
+		let suffix = if entry.is_dir { "/" } else { "" };
+		let size = if entry.is_dir { String::new() } else { format_size(entry.size) };
+		let modified = entry.modified.map_or(String::new(), |modified| format_date(modified, listing.dates));
+
+		write!(html, "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+			encode_uri_component(&entry.name), suffix, escape_html(&entry.name), suffix, size, modified)?;
+	}
+
+	html.push_str("</table></body></html>\n");
+	Ok(html)
+}
+
+/// Picks a date order from the most preferred language that has a known one
+fn date_style(accept_language: &str) -> DateStyle {
+	let mut languages: Vec<(f32, String)> = accept_language.split(',')
+		.filter_map(|entry| {
+			let mut params = entry.split(';').map(str::trim);
+			let tag = params.next()?.to_ascii_lowercase();
+			let quality = params
+				.filter_map(|param| param.strip_prefix("q="))
+				.filter_map(|q| q.parse::<f32>().ok())
+				.next()
+				.unwrap_or(1.0);
+
+			Some((quality, tag))
+		})
+		.filter(|(quality, tag)| *quality > 0.0 && !tag.is_empty())
+		.collect();
+
+	// Stable, so equal preferences stay in the order given
+	languages.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+
+	for (_, tag) in languages {
+		let primary = tag.split('-').next().unwrap_or("");
+
+		let style = match (primary, tag.as_str()) {
+			(_, "en-us") | (_, "en-ph") | (_, "es-us") => DateStyle::MonthFirst,
+			("en", _) | ("fr", _) | ("es", _) | ("it", _) | ("pt", _) | ("nl", _) | ("el", _) | ("ga", _) => DateStyle::DayFirst,
+			("de", _) | ("ru", _) | ("pl", _) | ("cs", _) | ("sk", _) | ("fi", _) | ("nb", _) | ("no", _)
+				| ("da", _) | ("tr", _) | ("uk", _) | ("ro", _) => DateStyle::DayFirstDotted,
+			("ja", _) | ("zh", _) | ("ko", _) | ("sv", _) | ("hu", _) | ("lt", _) => DateStyle::Iso,
+			_ => continue,
+		};
+
+		return style
+	}
+
+	DateStyle::Iso
+}
+
+fn format_date(time: SystemTime, style: DateStyle) -> String {
+	let (year, month, day, hour, minute) = http::civil_time(time);
+
+	match style {
+		DateStyle::Iso => format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, hour, minute),
+		DateStyle::MonthFirst => format!("{:02}/{:02}/{:04} {:02}:{:02}", month, day, year, hour, minute),
+		DateStyle::DayFirst => format!("{:02}/{:02}/{:04} {:02}:{:02}", day, month, year, hour, minute),
+		DateStyle::DayFirstDotted => format!("{:02}.{:02}.{:04} {:02}:{:02}", day, month, year, hour, minute),
+	}
+}
+
+/// 1.5K, 20M etc, in powers of 1024
+fn format_size(size: u64) -> String {
+	const UNITS: [&str; 5] = ["", "K", "M", "G", "T"];
+
+	let mut value = size as f64;
+	let mut unit = 0;
+
+	while value >= 1024.0 && unit < UNITS.len() - 1 {
+		value /= 1024.0;
+		unit += 1;
+	}
+
+	match unit {
+		0 => size.to_string(),
+		_ if value < 10.0 => format!("{:.1}{}", value, UNITS[unit]),
+		_ => format!("{:.0}{}", value, UNITS[unit]),
+	}
+}
+
+/// Renders a landing page linking to each of `hosts`, for requests that didn't say which one they wanted
+pub fn render_sites(hosts: &[&str]) -> SBResult<String> {
+	let mut html = String::new();
+
+	html.push_str("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Sites</title></head>\n");
+	html.push_str("<body><h1>Sites</h1><ul>\n");
+
+	for host in hosts {
+		// Scheme relative, so it stays on https if that's how we were reached
+		write!(html, "<li><a href=\"//{0}/\">{0}</a></li>\n", escape_html(host))?;
+	}
+
+	html.push_str("</ul></body></html>\n");
+	Ok(html)
+}
+
+fn escape_html(s: &str) -> String {
+	s.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+}
+
+fn encode_uri_component(s: &str) -> String {
+	let mut encoded = String::with_capacity(s.len());
+
+	for byte in s.bytes() {
+		match byte {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+			_ => { let _ = write!(encoded, "%{:02X}", byte); }
+		}
+	}
+
+	encoded
+}
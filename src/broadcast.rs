@@ -0,0 +1,133 @@
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+use crate::SBResult;
+use crate::admin::AdminCommand;
+use crate::cert::Certificate;
+use crate::fileserver::{Fileserver, FileserverCommand};
+use crate::mappings::{Mappings, MappingsDelta};
+
+/// Fans mappings and certificate updates out to every server that wants them, however many there are.
+/// Remembers the latest of each, so servers that subscribe later start out up to date
+#[derive(Clone, Default)]
+pub struct Broadcast {
+	subscribers: Arc<Mutex<Subscribers>>,
+}
+
+#[derive(Default)]
+struct Subscribers {
+	/// Servers that serve mappings
+	content: Vec<Fileserver>,
+	/// Servers that need a certificate
+	secure: Vec<Fileserver>,
+	/// Servers ACME challenges are answered from
+	challenge: Vec<Fileserver>,
+	admin: Vec<Sender<AdminCommand>>,
+
+	mappings: Option<Mappings>,
+	cert: Option<Certificate>,
+}
+
+impl Broadcast {
+	fn lock(&self) -> std::sync::MutexGuard<'_, Subscribers> {
+		self.subscribers.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	pub fn subscribe_content(&self, server: Fileserver) {
+		let mut subscribers = self.lock();
+
+		if let Some(mappings) = subscribers.mappings.clone() {
+			apply(&server, FileserverCommand::NewMappings(mappings));
+		}
+
+		subscribers.content.push(server);
+	}
+
+	pub fn subscribe_certificates(&self, server: Fileserver) {
+		let mut subscribers = self.lock();
+
+		if let Some(cert) = subscribers.cert.clone() {
+			apply(&server, FileserverCommand::SetCert(cert));
+		}
+
+		subscribers.secure.push(server);
+	}
+
+	pub fn subscribe_challenges(&self, server: Fileserver) {
+		self.lock().challenge.push(server);
+	}
+
+	pub fn subscribe_admin(&self, admin: Sender<AdminCommand>) {
+		let mut subscribers = self.lock();
+
+		if let Some(mappings) = subscribers.mappings.clone() {
+			let _ = admin.send(AdminCommand::NewMappings(mappings));
+		}
+
+		if let Some(cert) = subscribers.cert.clone() {
+			let _ = admin.send(AdminCommand::SetCert(cert));
+		}
+
+		subscribers.admin.push(admin);
+	}
+
+	pub fn new_mappings(&self, mappings: Mappings) {
+		let mut subscribers = self.lock();
+
+		for server in subscribers.content.iter() {
+			apply(server, FileserverCommand::NewMappings(mappings.clone()));
+		}
+
+		// The admin server just gets dropped if it's gone away
+		subscribers.admin.retain(|admin| admin.send(AdminCommand::NewMappings(mappings.clone())).is_ok());
+		subscribers.mappings = Some(mappings);
+	}
+
+	pub fn update_mappings(&self, delta: MappingsDelta) {
+		let mut subscribers = self.lock();
+
+		for server in subscribers.content.iter() {
+			apply(server, FileserverCommand::UpdateMappings(delta.clone()));
+		}
+
+		subscribers.admin.retain(|admin| admin.send(AdminCommand::UpdateMappings(delta.clone())).is_ok());
+
+		if let Some(mappings) = subscribers.mappings.as_mut() {
+			mappings.apply_delta(delta);
+		}
+	}
+
+	/// Hands `cert` to every secure server, failing if any of them can't use it
+	pub fn set_cert(&self, cert: Certificate) -> SBResult<()> {
+		let servers = {
+			let mut subscribers = self.lock();
+
+			subscribers.admin.retain(|admin| admin.send(AdminCommand::SetCert(cert.clone())).is_ok());
+			subscribers.cert = Some(cert.clone());
+			subscribers.secure.clone()
+		};
+
+		// Without the lock held, so mappings updates don't wait on every server building an acceptor
+		for server in servers.iter() {
+			server.apply(FileserverCommand::SetCert(cert.clone()))?;
+		}
+
+		Ok(())
+	}
+
+	/// Starts answering ACME challenges on every server that answers them. They're being answered once this returns
+	pub fn set_challenges(&self, challenges: Mappings) -> SBResult<()> {
+		for server in self.lock().challenge.iter() {
+			server.apply(FileserverCommand::SetChallenges(challenges.clone()))?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Logs failures rather than passing them on, for changes nobody is waiting on
+fn apply(server: &Fileserver, command: FileserverCommand) {
+	if let Err(e) = server.apply(command) {
+		error!("{}", e);
+	}
+}
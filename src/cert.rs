@@ -1,58 +1,105 @@
-use std::sync::mpsc;
-use std::thread;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::fs;
 use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use acme_client::SignedCertificate;
 use acme_client::openssl;
 use self::openssl::pkey::{PKey, Private};
 use self::openssl::x509::X509;
+use self::openssl::asn1::{Asn1Time, Asn1TimeRef};
 
 use crate::SBResult;
-use crate::mappings::Mappings;
-use crate::fileserver::FileserverCommand;
+use crate::clock::{self, Clock, SystemClock};
+use crate::mappings::{Mappings, CacheMode};
+use crate::broadcast::Broadcast;
+use crate::cert_store::CertStore;
 
 pub type PrivateKey = PKey<Private>;
 
-const CERT_FILENAME: &'static str = ".spiderbutter/certificate_chain.pem";
-const STAGING_CERT_FILENAME: &'static str = ".spiderbutter/staging_certificate_chain.pem";
+const CERT_NAME: &'static str = "certificate_chain.pem";
+const STAGING_CERT_NAME: &'static str = "staging_certificate_chain.pem";
 
-const INTERMEDIATE_CERT_FILENAME: &'static str = ".spiderbutter/intermediate_cert.pem";
-const STAGING_INTERMEDIATE_CERT_FILENAME: &'static str = ".spiderbutter/staging_intermediate_cert.pem";
+const INTERMEDIATE_CERT_NAME: &'static str = "intermediate_cert.pem";
+const STAGING_INTERMEDIATE_CERT_NAME: &'static str = "staging_intermediate_cert.pem";
 
-const PRIV_CERT_FILENAME: &'static str = ".spiderbutter/private_key.pem";
-const STAGING_PRIV_CERT_FILENAME: &'static str = ".spiderbutter/staging_private_key.pem";
+const PRIV_CERT_NAME: &'static str = "private_key.pem";
+const STAGING_PRIV_CERT_NAME: &'static str = "staging_private_key.pem";
 
-pub const RENEWAL_PERIOD_DAYS: i32 = 7;
+/// Certificate files being written, so shutdown can wait for them instead of leaving them half written
+pub static PENDING_CERT_WRITES: AtomicUsize = AtomicUsize::new(0);
 
-pub fn certificate_filename(staging: bool) -> &'static str {
+pub const DEFAULT_RENEW_BEFORE_HOURS: u64 = 7 * 24;
+pub const DEFAULT_RENEW_RETRY_MINUTES: u64 = 60;
+
+/// When certificates get renewed, and what happens when that fails
+#[derive(Debug, Clone, Copy)]
+pub struct RenewalPolicy {
+	/// How long before expiry to start renewing
+	pub renew_before: Duration,
+	/// How long to wait after a failed renewal before trying again
+	pub retry_interval: Duration,
+	/// Certificates on disk with less than this left are replaced at startup instead of reused
+	pub min_validity: Duration,
+}
+
+impl RenewalPolicy {
+	/// How long to wait before renewing `cert`. Certificates with a total lifetime shorter than a few
+	/// renewal windows (e.g. 6 day certs) renew once two thirds of their lifetime has passed instead
+	pub fn time_till_renewal(&self, cert: &Certificate, clock: &dyn Clock) -> SBResult<Duration> {
+		let lifetime = cert.lifetime()?;
+		let lead_time = self.renew_before.min(lifetime / 3);
+
+		let till_expiry = Duration::from_secs(cert.secs_till_expiry(clock)?.max(0) as u64);
+		Ok(till_expiry.checked_sub(lead_time).unwrap_or(Duration::from_secs(0)))
+	}
+}
+
+impl Default for RenewalPolicy {
+	fn default() -> RenewalPolicy {
+		RenewalPolicy {
+			renew_before: Duration::from_secs(DEFAULT_RENEW_BEFORE_HOURS * 60 * 60),
+			retry_interval: Duration::from_secs(DEFAULT_RENEW_RETRY_MINUTES * 60),
+			min_validity: Duration::from_secs(DEFAULT_RENEW_BEFORE_HOURS * 60 * 60),
+		}
+	}
+}
+
+fn certificate_name(staging: bool) -> &'static str {
 	if staging {
-		STAGING_CERT_FILENAME.into()
+		STAGING_CERT_NAME
 	} else {
-		CERT_FILENAME.into()
+		CERT_NAME
 	}
 }
 
-pub fn intermediate_cert_filename(staging: bool) -> &'static str {
+fn intermediate_cert_name(staging: bool) -> &'static str {
 	if staging {
-		STAGING_INTERMEDIATE_CERT_FILENAME.into()
+		STAGING_INTERMEDIATE_CERT_NAME
 	} else {
-		INTERMEDIATE_CERT_FILENAME.into()
+		INTERMEDIATE_CERT_NAME
 	}
 }
 
-pub fn private_key_filename(staging: bool) -> &'static str {
+fn private_key_name(staging: bool) -> &'static str {
 	if staging {
-		STAGING_PRIV_CERT_FILENAME.into()
+		STAGING_PRIV_CERT_NAME
 	} else {
-		PRIV_CERT_FILENAME.into()
+		PRIV_CERT_NAME
 	}
 }
 
+/// Files to watch for certificates renewed by something else, if `store` keeps them on local disk
+pub fn watch_paths(store: &dyn CertStore, staging: bool) -> Vec<PathBuf> {
+	[certificate_name(staging), intermediate_cert_name(staging), private_key_name(staging)].iter()
+		.filter_map(|name| store.path(name))
+		.collect()
+}
+
+#[derive(Clone)]
 pub struct Certificate {
 	public_cert: X509,
-	intermediate_cert: X509,
+	intermediate_certs: Vec<X509>,
 	private_key: PrivateKey,
 }
 
@@ -62,108 +109,231 @@ impl Certificate {
 
 		Ok(Certificate {
 			public_cert: cert,
-			intermediate_cert,
+			intermediate_certs: vec![intermediate_cert],
 			private_key: pkey,
 		})
 	}
 
 	pub fn from_pem(cert_raw: &[u8], intermediate_raw: &[u8], priv_raw: &[u8]) -> SBResult<Certificate> {
 		let public_cert = X509::from_pem(&cert_raw)?;
-		let intermediate_cert = X509::from_pem(&intermediate_raw)?;
+		let intermediate_certs = X509::stack_from_pem(&intermediate_raw)?;
+		let private_key = PrivateKey::private_key_from_pem(&priv_raw)?;
+
+		Ok(Certificate {
+			public_cert,
+			intermediate_certs,
+			private_key
+		})
+	}
+
+	/// From a full chain, leaf first, like certbot's fullchain.pem
+	pub fn from_chain_pem(chain_raw: &[u8], priv_raw: &[u8]) -> SBResult<Certificate> {
+		let mut chain = X509::stack_from_pem(&chain_raw)?.into_iter();
+		let public_cert = chain.next()
+			.ok_or_else(|| failure::format_err!("Certificate chain is empty"))?;
+
 		let private_key = PrivateKey::private_key_from_pem(&priv_raw)?;
 
 		Ok(Certificate {
 			public_cert,
-			intermediate_cert,
+			intermediate_certs: chain.collect(),
 			private_key
 		})
 	}
 
-	pub fn days_till_expiry(&self) -> SBResult<i32> {
-		enum Asn1TimeOpaque {}
-		extern "C" { fn ASN1_TIME_diff(pday: *mut libc::c_int, psec: *mut libc::c_int, from: *const Asn1TimeOpaque, to: *const Asn1TimeOpaque) -> libc::c_int; }
+	pub fn days_till_expiry(&self, clock: &dyn Clock) -> SBResult<i32> {
+		Ok((self.secs_till_expiry(clock)? / (24 * 60 * 60)) as i32)
+	}
+
+	/// Negative once the certificate has expired
+	pub fn secs_till_expiry(&self, clock: &dyn Clock) -> SBResult<i64> {
+		let now = Asn1Time::from_unix(clock::unix_secs(clock.now()) as libc::time_t)?;
+		asn1_time_diff(&now, self.public_cert.not_after())
+	}
 
-		let mut day_offset = 0;
-		let mut second_offset = 0;
+	/// How long the certificate was issued for
+	pub fn lifetime(&self) -> SBResult<Duration> {
+		let secs = asn1_time_diff(self.public_cert.not_before(), self.public_cert.not_after())?;
+		Ok(Duration::from_secs(secs.max(0) as u64))
+	}
 
-		let success = unsafe {
-			ASN1_TIME_diff(
-				&mut day_offset,
-				&mut second_offset,
-				std::ptr::null(),
-				std::mem::transmute(self.public_cert.not_after())
-				// a dirty hack but openssl doesn't give me what I need
-				// this is safe so long as not_after() implements ForeignTypeRef
-			)
-		};
+	/// A throwaway certificate, for clients asking for names we don't have a real certificate for
+	pub fn self_signed(common_name: &str) -> SBResult<Certificate> {
+		use self::openssl::ec::{EcGroup, EcKey};
+		use self::openssl::nid::Nid;
+		use self::openssl::bn::BigNum;
+		use self::openssl::asn1::Asn1Integer;
+		use self::openssl::hash::MessageDigest;
+		use self::openssl::x509::X509NameBuilder;
+
+		// P-256 is much quicker to generate than RSA, and every client that does SNI supports it
+		let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+		let private_key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+		let mut name = X509NameBuilder::new()?;
+		name.append_entry_by_text("CN", common_name)?;
+		let name = name.build();
+
+		let serial = BigNum::from_u32(1)?;
+		let serial = Asn1Integer::from_bn(&serial)?;
+		let not_before = Asn1Time::days_from_now(0)?;
+		let not_after = Asn1Time::days_from_now(365)?;
+
+		let mut builder = X509::builder()?;
+		builder.set_version(2)?;
+		builder.set_serial_number(&serial)?;
+		builder.set_subject_name(&name)?;
+		builder.set_issuer_name(&name)?;
+		builder.set_pubkey(&private_key)?;
+		builder.set_not_before(&not_before)?;
+		builder.set_not_after(&not_after)?;
+		builder.sign(&private_key, MessageDigest::sha256())?;
+
+		let public_cert = builder.build();
 
-		if success != 1 {
-			failure::bail!("Failed to determine time to expiry")
-		}
+		Ok(Certificate {
+			public_cert,
+			intermediate_certs: Vec::new(),
+			private_key,
+		})
+	}
 
-		Ok(day_offset)
+	/// DNS names the certificate is valid for, possibly including wildcards
+	pub fn dns_names(&self) -> Vec<String> {
+		self.public_cert.subject_alt_names()
+			.map(|names| names.iter()
+				.filter_map(|name| name.dnsname().map(str::to_owned))
+				.collect())
+			.unwrap_or_else(Vec::new)
 	}
 
 	pub fn certificate(&self) -> &X509 { &self.public_cert }
-	pub fn intermediate(&self) -> &X509 { &self.intermediate_cert }
+	pub fn intermediates(&self) -> &[X509] { &self.intermediate_certs }
 	pub fn private_key(&self) -> &PrivateKey { &self.private_key }
 }
 
+/// Seconds from `from` to `to`
+fn asn1_time_diff(from: &Asn1TimeRef, to: &Asn1TimeRef) -> SBResult<i64> {
+	enum Asn1TimeOpaque {}
+	extern "C" { fn ASN1_TIME_diff(pday: *mut libc::c_int, psec: *mut libc::c_int, from: *const Asn1TimeOpaque, to: *const Asn1TimeOpaque) -> libc::c_int; }
+
+	let mut day_offset = 0;
+	let mut second_offset = 0;
+
+	let success = unsafe {
+		ASN1_TIME_diff(
+			&mut day_offset,
+			&mut second_offset,
+			std::mem::transmute(from),
+			std::mem::transmute(to)
+			// a dirty hack but openssl doesn't give me what I need
+			// this is safe so long as Asn1TimeRef implements ForeignTypeRef
+		)
+	};
+
+	if success != 1 {
+		failure::bail!("Failed to determine time to expiry")
+	}
+
+	Ok(day_offset as i64 * 24 * 60 * 60 + second_offset as i64)
+}
+
 
-pub fn acquire_certificate(domains: &[String], fs_command_tx: &mpsc::Sender<FileserverCommand>, staging: bool) -> SBResult<Certificate> {
-	let cert_path = Path::new(certificate_filename(staging));
-	let intermediate_cert_path = Path::new(intermediate_cert_filename(staging));
-	let priv_key_path = Path::new(private_key_filename(staging));
+/// Reuses the stored certificate if `reuse_existing` and it has at least `policy.min_validity` left, otherwise requests a new one
+pub fn acquire_certificate(store: &dyn CertStore, domains: &[String], broadcast: &Broadcast, staging: bool,
+	policy: &RenewalPolicy, reuse_existing: bool, clock: &dyn Clock) -> SBResult<Certificate> {
 
-	if let Ok(cert) = load_certificate_from(cert_path, intermediate_cert_path, priv_key_path) {
-		return Ok(cert)
+	if reuse_existing {
+		if let Ok(cert) = load_stored_certificate(store, staging, policy.min_validity, clock) {
+			return Ok(cert)
+		}
 	}
 
 	let domains = domains.iter()
 		.map(String::as_ref)
 		.collect::<Vec<_>>();
 
-	let cert = request_new_certificate(&domains, fs_command_tx, staging)?;
+	let cert = request_new_certificate(&domains, broadcast, staging)?;
 
-	if let Some(dir) = cert_path.parent() { fs::create_dir_all(dir)?; }
-	if let Some(dir) = intermediate_cert_path.parent() { fs::create_dir_all(dir)?; }
-	if let Some(dir) = priv_key_path.parent() { fs::create_dir_all(dir)?; }
-
-	std::fs::write(cert_path, cert.cert.to_pem()?)?;
-	std::fs::write(intermediate_cert_path, cert.intermediate_cert.to_pem()?)?;
-	std::fs::write(priv_key_path, cert.pkey.private_key_to_pem_pkcs8()?)?;
+	PENDING_CERT_WRITES.fetch_add(1, Ordering::SeqCst);
+	let saved = save_certificate(store, &cert, staging);
+	PENDING_CERT_WRITES.fetch_sub(1, Ordering::SeqCst);
+	saved?;
 
 	Certificate::from_signed(cert)
 }
 
+fn save_certificate(store: &dyn CertStore, cert: &SignedCertificate, staging: bool) -> SBResult<()> {
+	store.write(certificate_name(staging), &cert.cert.to_pem()?)?;
+	store.write(intermediate_cert_name(staging), &cert.intermediate_cert.to_pem()?)?;
+	store.write(private_key_name(staging), &cert.pkey.private_key_to_pem_pkcs8()?)?;
+
+	Ok(())
+}
+
+
+
+/// Loads a certificate managed by something else, e.g. certbot or a corporate CA
+pub fn load_external_certificate(chain_path: &Path, priv_key_path: &Path) -> SBResult<Certificate> {
+	let chain_raw = fs::read(chain_path)
+		.map_err(|e| failure::format_err!("Failed to read {:?}: {}", chain_path, e))?;
+	let priv_key_raw = fs::read(priv_key_path)
+		.map_err(|e| failure::format_err!("Failed to read {:?}: {}", priv_key_path, e))?;
+
+	let cert = Certificate::from_chain_pem(&chain_raw, &priv_key_raw)?;
+
+	match cert.days_till_expiry(&SystemClock)? {
+		days if days <= 0 => warn!("Certificate {:?} has expired", chain_path),
+		days => info!("Using certificate {:?}, expiry in {} days", chain_path, days),
+	}
+
+	Ok(cert)
+}
+
+/// Makes sure a client CA bundle is usable before the TLS listener depends on it, returning how many CAs it has
+pub fn check_ca_bundle(path: &Path) -> SBResult<usize> {
+	let raw = fs::read(path)
+		.map_err(|e| failure::format_err!("Failed to read {:?}: {}", path, e))?;
+
+	let cas = X509::stack_from_pem(&raw)?;
+	if cas.is_empty() {
+		failure::bail!("No certificates found in {:?}", path);
+	}
+
+	Ok(cas.len())
+}
 
+/// Reads back the certificate `acquire_certificate` stored, regardless of expiry
+pub fn reload_acme_certificate(store: &dyn CertStore, staging: bool) -> SBResult<Certificate> {
+	let cert_raw = store.read(certificate_name(staging))?;
+	let intermediate_raw = store.read(intermediate_cert_name(staging))?;
+	let priv_key_raw = store.read(private_key_name(staging))?;
 
-fn load_certificate_from(cert_path: &Path, intermediate_path: &Path, priv_key_path: &Path) -> SBResult<Certificate> {
-	let cert_raw = fs::read(cert_path)?;
-	let intermediate_raw = fs::read(intermediate_path)?;
-	let priv_key_raw = fs::read(priv_key_path)?;
+	Certificate::from_pem(&cert_raw, &intermediate_raw, &priv_key_raw)
+}
 
-	let cert = Certificate::from_pem(&cert_raw, &intermediate_raw, &priv_key_raw)?;
+fn load_stored_certificate(store: &dyn CertStore, staging: bool, min_validity: Duration, clock: &dyn Clock) -> SBResult<Certificate> {
+	let cert = reload_acme_certificate(store, staging)?;
 
-	let days_till_expiry = cert.days_till_expiry()?;
+	let secs_till_expiry = cert.secs_till_expiry(clock)?;
 
-	if days_till_expiry <= RENEWAL_PERIOD_DAYS {
-		println!("Certificate exists but has expired or is near expiry - ignoring");
+	if secs_till_expiry <= min_validity.as_secs() as i64 {
+		warn!("Certificate exists but has expired or is near expiry - ignoring");
 		failure::bail!("Certificate expired")
 	}
 
-	println!("Using existing certificate, expiry in {} days", days_till_expiry);
+	info!("Using existing certificate, expiry in {} hours", secs_till_expiry / (60 * 60));
 
 	Ok(cert)
 }
 
 
-fn request_new_certificate(domains: &[&str], fs_command_tx: &mpsc::Sender<FileserverCommand>, staging: bool) -> SBResult<SignedCertificate> {
+fn request_new_certificate(domains: &[&str], broadcast: &Broadcast, staging: bool) -> SBResult<SignedCertificate> {
 	use acme_client::{AcmeClient, AcmeStatus, AccountRegistration, Authorization};
 
 	assert!(domains.len() > 0);
 
-	println!("Requesting certificate for {:?}", domains);
+	info!("Requesting certificate for {:?}", domains);
 
 	let client = if staging {
 		AcmeClient::lets_encrypt_staging(AccountRegistration::new())?
@@ -174,7 +344,7 @@ fn request_new_certificate(domains: &[&str], fs_command_tx: &mpsc::Sender<Filese
 	let (mut order, order_location) = client.submit_order(domains)?;
 
 	let mut challenges = Vec::new();
-	let mut mapping = Mappings::new(true);
+	let mut mapping = Mappings::new(CacheMode::Eager);
 
 	for auth_uri in order.authorizations.iter() {
 		let auth = client.fetch_authorization(auth_uri)?;
@@ -197,8 +367,8 @@ fn request_new_certificate(domains: &[&str], fs_command_tx: &mpsc::Sender<Filese
 		challenges.push(challenge);
 	}
 
-	fs_command_tx.send(FileserverCommand::NewMappings(mapping))?;
-	thread::sleep(Duration::from_millis(200));
+	// The challenges have to be servable before the CA comes looking for them
+	broadcast.set_challenges(mapping)?;
 
 	for challenge in challenges.iter() {
 		client.signal_challenge_ready(challenge)?;
@@ -229,6 +399,34 @@ fn request_new_certificate(domains: &[&str], fs_command_tx: &mpsc::Sender<Filese
 	}
 
 	let (cert, _) = client.finalize_order(&order)?;
-	println!("Validation successful");
+	info!("Validation successful");
 	Ok(cert)
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::SystemTime;
+	use crate::test_util::FakeClock;
+
+	const DAY: u64 = 24 * 60 * 60;
+
+	#[test]
+	fn renewal_and_expiry_follow_the_clock() {
+		let cert = Certificate::self_signed("example.com").unwrap();
+		let clock = FakeClock::at(SystemTime::now());
+		let policy = RenewalPolicy::default();
+
+		// Issued for a year, so renewal starts a week before the end
+		let till_renewal = policy.time_till_renewal(&cert, &clock).unwrap();
+		assert!((357 * DAY..=358 * DAY).contains(&till_renewal.as_secs()), "{:?}", till_renewal);
+
+		clock.advance(Duration::from_secs(360 * DAY));
+		assert_eq!(policy.time_till_renewal(&cert, &clock).unwrap(), Duration::from_secs(0));
+		assert!((4..=5).contains(&cert.days_till_expiry(&clock).unwrap()));
+
+		clock.advance(Duration::from_secs(10 * DAY));
+		assert!(cert.secs_till_expiry(&clock).unwrap() < 0);
+		assert_eq!(policy.time_till_renewal(&cert, &clock).unwrap(), Duration::from_secs(0));
+	}
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::fs;
+
+use crate::SBResult;
+
+pub const DEFAULT_DIR: &'static str = ".spiderbutter";
+
+/// Where ACME certificates and their private keys are kept between runs.
+/// Items are named like files, e.g. `private_key.pem`
+pub trait CertStore: Send + Sync {
+	fn read(&self, name: &str) -> SBResult<Vec<u8>>;
+	fn write(&self, name: &str, data: &[u8]) -> SBResult<()>;
+
+	/// The file an item lives in, if it's on local disk, so changes made by something else can be watched for
+	fn path(&self, _name: &str) -> Option<PathBuf> {
+		None
+	}
+}
+
+/// Picks a store from `--cert_store`:
+/// - `<dir>` or `dir:<dir>`, files in a directory. The default is `.spiderbutter`
+/// - `systemd-creds`, the service's credentials directory, e.g. from LoadCredentialEncrypted=
+/// - `exec:<program>`, run as `<program> get <name>` and `<program> put <name>`, to hand storage
+///   off to a secret manager's command line tools
+pub fn from_spec(spec: Option<&str>) -> SBResult<Arc<dyn CertStore>> {
+	let spec = spec.unwrap_or(DEFAULT_DIR);
+
+	if spec == "systemd-creds" {
+		let dir = std::env::var_os("CREDENTIALS_DIRECTORY")
+			.ok_or_else(|| failure::format_err!("--cert_store systemd-creds needs $CREDENTIALS_DIRECTORY, set by LoadCredential= and friends"))?;
+
+		return Ok(Arc::new(SystemdCredentials { dir: dir.into(), renewed: Mutex::new(HashMap::new()) }))
+	}
+
+	if let Some(program) = spec.strip_prefix("exec:") {
+		if program.is_empty() {
+			failure::bail!("--cert_store exec: needs a program to run");
+		}
+
+		return Ok(Arc::new(ExecStore { program: program.into() }))
+	}
+
+	let dir = spec.strip_prefix("dir:").unwrap_or(spec);
+	Ok(Arc::new(DirStore { dir: dir.into() }))
+}
+
+
+struct DirStore {
+	dir: PathBuf,
+}
+
+impl CertStore for DirStore {
+	fn read(&self, name: &str) -> SBResult<Vec<u8>> {
+		Ok(fs::read(self.dir.join(name))?)
+	}
+
+	/// Written aside, synced and renamed, so a crash part way through never leaves half a key behind
+	fn write(&self, name: &str, data: &[u8]) -> SBResult<()> {
+		fs::create_dir_all(&self.dir)?;
+
+		let temp_path = self.dir.join(format!("{}.tmp", name));
+		let mut file = fs::File::create(&temp_path)?;
+		file.write_all(data)?;
+		file.sync_all()?;
+		drop(file);
+
+		fs::rename(&temp_path, self.dir.join(name))?;
+
+		// The rename itself only sticks once the directory is synced
+		fs::File::open(&self.dir)?.sync_all()?;
+		Ok(())
+	}
+
+	fn path(&self, name: &str) -> Option<PathBuf> {
+		Some(self.dir.join(name))
+	}
+}
+
+
+/// Credentials are read-only once the service has started, so renewed certificates only live in memory
+/// until the next restart. They're best used with certificates that are provisioned along with the service
+struct SystemdCredentials {
+	dir: PathBuf,
+	renewed: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl CertStore for SystemdCredentials {
+	fn read(&self, name: &str) -> SBResult<Vec<u8>> {
+		let renewed = self.renewed.lock().unwrap_or_else(|e| e.into_inner());
+
+		match renewed.get(name) {
+			Some(data) => Ok(data.clone()),
+			None => Ok(fs::read(self.dir.join(name))?),
+		}
+	}
+
+	fn write(&self, name: &str, data: &[u8]) -> SBResult<()> {
+		warn!("Systemd credentials are read-only, keeping the new {} in memory only", name);
+
+		let mut renewed = self.renewed.lock().unwrap_or_else(|e| e.into_inner());
+		renewed.insert(name.to_owned(), data.to_owned());
+		Ok(())
+	}
+}
+
+
+struct ExecStore {
+	program: PathBuf,
+}
+
+impl CertStore for ExecStore {
+	fn read(&self, name: &str) -> SBResult<Vec<u8>> {
+		let output = Command::new(&self.program)
+			.args(&["get", name])
+			.stdin(Stdio::null())
+			.stderr(Stdio::inherit())
+			.output()
+			.map_err(|e| failure::format_err!("Failed to run {:?}: {}", self.program, e))?;
+
+		if !output.status.success() {
+			failure::bail!("{:?} get {} failed with {}", self.program, name, output.status);
+		}
+
+		Ok(output.stdout)
+	}
+
+	fn write(&self, name: &str, data: &[u8]) -> SBResult<()> {
+		let mut child = Command::new(&self.program)
+			.args(&["put", name])
+			.stdin(Stdio::piped())
+			.stdout(Stdio::null())
+			.stderr(Stdio::inherit())
+			.spawn()
+			.map_err(|e| failure::format_err!("Failed to run {:?}: {}", self.program, e))?;
+
+		// Dropped after writing so the program sees the end of its input
+		let written = child.stdin.take().map_or(Ok(()), |mut stdin| stdin.write_all(data));
+		let status = child.wait()?;
+		written?;
+
+		if !status.success() {
+			failure::bail!("{:?} put {} failed with {}", self.program, name, status);
+		}
+
+		Ok(())
+	}
+}
@@ -0,0 +1,70 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Where expiry, renewal and activation decisions get the time from, so they can be run against
+/// any date in tests
+pub trait Clock: Send + Sync {
+	fn now(&self) -> SystemTime;
+
+	/// Waits for `duration` to pass on this clock
+	fn sleep(&self, duration: Duration);
+}
+
+/// The real time
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now(&self) -> SystemTime {
+		SystemTime::now()
+	}
+
+	fn sleep(&self, duration: Duration) {
+		thread::sleep(duration)
+	}
+}
+
+pub fn system() -> Arc<dyn Clock> {
+	Arc::new(SystemClock)
+}
+
+/// Sleeps until `at`, in steps of at most `step` so that a clock that jumps, like after a suspend, is noticed
+pub fn sleep_until(clock: &dyn Clock, at: SystemTime, step: Duration) {
+	while let Ok(remaining) = at.duration_since(clock.now()) {
+		if remaining == Duration::from_secs(0) { break }
+		clock.sleep(remaining.min(step));
+	}
+}
+
+/// Whole seconds since the unix epoch, negative for dates before it
+pub fn unix_secs(time: SystemTime) -> i64 {
+	match time.duration_since(SystemTime::UNIX_EPOCH) {
+		Ok(since) => since.as_secs() as i64,
+		Err(before) => -(before.duration().as_secs() as i64),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_util::FakeClock;
+
+	#[test]
+	fn sleep_until_arrives_in_steps() {
+		let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+		let clock = FakeClock::at(start);
+
+		sleep_until(&clock, start + Duration::from_secs(10 * 60 * 60 + 5), Duration::from_secs(60 * 60));
+		assert_eq!(clock.now(), start + Duration::from_secs(10 * 60 * 60 + 5));
+
+		// Times already passed return straight away
+		sleep_until(&clock, start, Duration::from_secs(60 * 60));
+		assert_eq!(clock.now(), start + Duration::from_secs(10 * 60 * 60 + 5));
+	}
+
+	#[test]
+	fn unix_secs_handles_dates_before_the_epoch() {
+		assert_eq!(unix_secs(SystemTime::UNIX_EPOCH + Duration::from_secs(90)), 90);
+		assert_eq!(unix_secs(SystemTime::UNIX_EPOCH - Duration::from_secs(90)), -90);
+	}
+}
@@ -0,0 +1,216 @@
+use std::path::Path;
+use std::fs;
+
+use crate::SBResult;
+
+/// A value from a config file. Only what command line options can take
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+	String(String),
+	Integer(i64),
+	Boolean(bool),
+	Array(Vec<Value>),
+}
+
+/// Reads the subset of TOML that command line options need: `key = value` pairs of strings, integers,
+/// booleans and arrays of those, with comments. Keys are option names, with either `-` or `_`
+pub fn load(path: &Path) -> SBResult<Vec<(String, Value)>> {
+	let data = fs::read_to_string(path)
+		.map_err(|e| failure::format_err!("Failed to read config {:?}: {}", path, e))?;
+
+	parse(&data).map_err(|e| failure::format_err!("In config {:?}: {}", path, e))
+}
+
+/// Turns config values into command line arguments, skipping any in `skip`
+pub fn to_args(values: &[(String, Value)], skip: impl Fn(&str) -> bool) -> SBResult<Vec<String>> {
+	let mut args = Vec::new();
+
+	for (key, value) in values {
+		if skip(key) { continue }
+
+		let flag = format!("--{}", key);
+
+		match value {
+			Value::Boolean(true) => args.push(flag),
+			Value::Boolean(false) => {}
+
+			Value::Array(items) => for item in items {
+				args.push(flag.clone());
+				args.push(scalar_arg(key, item)?);
+			}
+
+			value => {
+				args.push(flag);
+				args.push(scalar_arg(key, value)?);
+			}
+		}
+	}
+
+	Ok(args)
+}
+
+fn scalar_arg(key: &str, value: &Value) -> SBResult<String> {
+	match value {
+		Value::String(s) => Ok(s.clone()),
+		Value::Integer(i) => Ok(i.to_string()),
+		_ => failure::bail!("'{}' can only hold strings or numbers", key),
+	}
+}
+
+fn parse(data: &str) -> SBResult<Vec<(String, Value)>> {
+	let mut values: Vec<(String, Value)> = Vec::new();
+	let mut lines = data.lines().enumerate();
+
+	while let Some((number, line)) = lines.next() {
+		let mut line = strip_comment(line).trim().to_owned();
+		if line.is_empty() { continue }
+
+		if line.starts_with('[') {
+			failure::bail!("line {}: tables aren't supported, every option goes at the top level", number + 1);
+		}
+
+		// Arrays can be split over lines
+		while bracket_depth(&line) > 0 {
+			match lines.next() {
+				Some((_, next)) => {
+					line.push(' ');
+					line.push_str(strip_comment(next).trim());
+				}
+
+				None => failure::bail!("line {}: array is never closed", number + 1),
+			}
+		}
+
+		let partition = line.find('=')
+			.ok_or_else(|| failure::format_err!("line {}: expected 'key = value'", number + 1))?;
+
+		let key = line[..partition].trim().replace('-', "_");
+		if key.is_empty() || !key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+			failure::bail!("line {}: invalid key '{}'", number + 1, key);
+		}
+
+		if values.iter().any(|(existing, _)| *existing == key) {
+			failure::bail!("line {}: '{}' is set more than once", number + 1, key);
+		}
+
+		let (value, rest) = parse_value(line[partition+1..].trim())
+			.map_err(|e| failure::format_err!("line {}: {}", number + 1, e))?;
+
+		if !rest.trim().is_empty() {
+			failure::bail!("line {}: unexpected '{}' after value", number + 1, rest.trim());
+		}
+
+		values.push((key, value));
+	}
+
+	Ok(values)
+}
+
+/// Parses one value off the front of `s`, returning it and whatever follows
+fn parse_value(s: &str) -> SBResult<(Value, &str)> {
+	if s.starts_with('"') {
+		let mut value = String::new();
+		let mut chars = s[1..].char_indices();
+
+		while let Some((i, c)) = chars.next() {
+			match c {
+				'"' => return Ok((Value::String(value), &s[i+2..])),
+				'\\' => match chars.next().map(|(_, c)| c) {
+					Some('"') => value.push('"'),
+					Some('\\') => value.push('\\'),
+					Some('n') => value.push('\n'),
+					Some('t') => value.push('\t'),
+					other => failure::bail!("unsupported escape '\\{}'", other.map_or(String::new(), String::from)),
+				},
+				c => value.push(c),
+			}
+		}
+
+		failure::bail!("string is never closed")
+	}
+
+	if s.starts_with('\'') {
+		let end = s[1..].find('\'').ok_or_else(|| failure::format_err!("string is never closed"))?;
+		return Ok((Value::String(s[1..end+1].to_owned()), &s[end+2..]))
+	}
+
+	if s.starts_with('[') {
+		let mut items = Vec::new();
+		let mut rest = s[1..].trim_start();
+
+		loop {
+			if rest.starts_with(']') {
+				return Ok((Value::Array(items), &rest[1..]))
+			}
+
+			let (item, after) = parse_value(rest)?;
+			if let Value::Array(_) = item {
+				failure::bail!("nested arrays aren't supported");
+			}
+
+			items.push(item);
+			rest = after.trim_start();
+
+			// Trailing commas are fine
+			if rest.starts_with(',') {
+				rest = rest[1..].trim_start();
+			} else if !rest.starts_with(']') {
+				failure::bail!("expected ',' or ']' in array");
+			}
+		}
+	}
+
+	let end = s.find(|c: char| c == ',' || c == ']' || c.is_whitespace()).unwrap_or(s.len());
+	let (word, rest) = s.split_at(end);
+
+	let value = match word {
+		"true" => Value::Boolean(true),
+		"false" => Value::Boolean(false),
+		_ => word.replace('_', "").parse::<i64>().map(Value::Integer)
+			.map_err(|_| failure::format_err!("invalid value '{}', strings need quotes", word))?,
+	};
+
+	Ok((value, rest))
+}
+
+/// Everything before a `#` that isn't inside a string
+fn strip_comment(line: &str) -> &str {
+	let mut quote = None;
+	let mut escaped = false;
+
+	for (i, c) in line.char_indices() {
+		match (quote, c) {
+			(Some('"'), '\\') if !escaped => { escaped = true; continue }
+			(Some(q), c) if c == q && !escaped => quote = None,
+			(None, '"') | (None, '\'') => quote = Some(c),
+			(None, '#') => return &line[..i],
+			_ => {}
+		}
+
+		escaped = false;
+	}
+
+	line
+}
+
+/// How many more `[` than `]` there are outside of strings
+fn bracket_depth(line: &str) -> i32 {
+	let mut depth = 0;
+	let mut quote = None;
+	let mut escaped = false;
+
+	for c in line.chars() {
+		match (quote, c) {
+			(Some('"'), '\\') if !escaped => { escaped = true; continue }
+			(Some(q), c) if c == q && !escaped => quote = None,
+			(None, '"') | (None, '\'') => quote = Some(c),
+			(None, '[') => depth += 1,
+			(None, ']') => depth -= 1,
+			_ => {}
+		}
+
+		escaped = false;
+	}
+
+	depth
+}
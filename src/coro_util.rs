@@ -14,6 +14,9 @@ unsafe impl<R> Send for Task<R> where R: Send {}
 impl<R> Task<R> {
 	pub fn is_valid(&self) -> bool { self.valid }
 
+	/// Stops the task from being resumed again, e.g. after it has panicked
+	pub fn abandon(&mut self) { self.valid = false }
+
 	pub fn resume(&mut self) -> Option<R> {
 		if !self.valid { return None }
 
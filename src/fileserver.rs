@@ -1,14 +1,19 @@
-use std::net::TcpListener;
-use std::sync::mpsc::{self, Receiver};
-use std::io::{Write, Read};
+use std::net::{TcpListener, TcpStream, IpAddr};
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TrySendError};
+use std::io::{self, Write, Read};
 use std::ops::Generator;
-use std::rc::Rc;
 use std::thread;
-use std::time;
+use std::time::{self, Duration, Instant, SystemTime};
 use std::str;
+use std::panic::{self, AssertUnwindSafe};
 
-use std::sync::Arc;
-use acme_client::openssl::ssl::{SslAcceptor, SslMethod, HandshakeError};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use acme_client::openssl::ssl::{SslAcceptor, SslMethod, HandshakeError, NameType, SniError, SslAlert, SslAcceptorBuilder, SslVerifyMode};
+use acme_client::openssl::ssl::{ErrorCode, Ssl, SslOptions, SslStream};
+use acme_client::openssl::x509::X509Name;
 
 use failure::bail;
 
@@ -19,128 +24,841 @@ use crate::coro_util::*;
 use crate::tcp_util::*;
 use crate::mappings::*;
 use crate::http;
+use crate::autoindex;
+use crate::publish;
+use crate::shutdown;
+use crate::watch::Watch;
+use crate::ready::ReadyNotifier;
+use crate::log::{self, RequestId};
+use crate::ip_filter::IpFilter;
+use crate::heartbeat::{Heartbeat, Heartbeats};
 
 const MAX_CONCURRENT_CONNECTIONS_PER_THREAD: usize = 128;
 const MAX_PENDING_CONNECTIONS_PER_THREAD: usize = 128;
-const NUM_WORKER_THREADS: usize = 4;
 
-const SSL_UPGRADE_TIMEOUT_SECS: u64 = 5;
-const REQUEST_READ_TIMEOUT_SECS: u64 = 5;
+/// How often idle workers show they're still alive
+const IDLE_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
 
+/// Longest request target and header block accepted, before answering 414 or 431
+static MAX_URI_BYTES: AtomicUsize = AtomicUsize::new(8<<10);
+static MAX_HEADER_BYTES: AtomicUsize = AtomicUsize::new(8<<10);
+
+/// How long a client gets to finish a TLS handshake, and then to send its request
+static TLS_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(5);
+static READ_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(5);
+
+/// How long a request gets from its first byte being read to its last byte being written, 0 for no limit.
+/// Routes can have their own with `deadline`
+static REQUEST_DEADLINE_SECS: AtomicU64 = AtomicU64::new(0);
+
+/// Whether TLS 1.3 clients resuming a session can send their request before the handshake's done.
+/// Early data can be replayed by anyone who saw it, so only GET and HEAD requests are answered from it
+static EARLY_DATA: AtomicBool = AtomicBool::new(false);
+/// Enough for any GET request this server would accept
+const MAX_EARLY_DATA: u32 = 16<<10;
+
+/// How long a request waits on a `limit ... queue` route before giving up with a 503
+const LIMIT_QUEUE_TIMEOUT: Duration = Duration::from_secs(30);
+const LIMIT_RETRY_AFTER_SECS: &str = "5";
+
+const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Short enough that files appearing under wildcard routes aren't hidden for long
+const MISS_CACHE_TTL: time::Duration = time::Duration::from_secs(5);
+const MISS_CACHE_MAX_ENTRIES: usize = 4096;
+
+/// Connections currently being handled, across all worker threads and servers
+pub static OPEN_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Cap on OPEN_CONNECTIONS, 0 for none
+static MAX_OPEN_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+/// Whether to turn connections past the cap away, rather than leave them in the listen backlog until there's room
+static REJECT_WHEN_FULL: AtomicBool = AtomicBool::new(false);
+/// Connections one address can have open at once, 0 for no limit. Browsers open at most 6 per host,
+/// so anything past the default is a crawler or worse
+static MAX_CONNECTIONS_PER_CLIENT: AtomicUsize = AtomicUsize::new(8);
+
+const FULL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Limits how many connections every server put together handles at once, so a flood can't use up all our memory,
+/// and how many of them one client can have
+pub fn set_connection_limit(max: usize, per_client: usize, reject_when_full: bool) {
+	MAX_OPEN_CONNECTIONS.store(max, Ordering::SeqCst);
+	MAX_CONNECTIONS_PER_CLIENT.store(per_client, Ordering::SeqCst);
+	REJECT_WHEN_FULL.store(reject_when_full, Ordering::SeqCst);
+}
+
+/// Sets how long slow clients are waited on during the TLS handshake, while sending their request,
+/// and while being sent a response, before they're dropped
+pub fn set_timeouts(tls: Duration, read: Duration, write: Duration) {
+	TLS_TIMEOUT_SECS.store(tls.as_secs(), Ordering::Relaxed);
+	READ_TIMEOUT_SECS.store(read.as_secs(), Ordering::Relaxed);
+	set_write_timeout(write);
+}
+
+/// Caps how long any one request can keep a worker busy, however slow the disk or the client
+pub fn set_request_deadline(deadline: Option<Duration>) {
+	REQUEST_DEADLINE_SECS.store(deadline.map_or(0, |d| d.as_secs()), Ordering::Relaxed);
+}
+
+/// Lets returning clients send their request without waiting on the TLS handshake. Takes effect
+/// for certificates set after this
+pub fn set_early_data(enabled: bool) {
+	EARLY_DATA.store(enabled, Ordering::Relaxed);
+}
+
+/// Requests with longer targets or header blocks than these are refused instead of being read any further
+pub fn set_request_limits(max_uri: usize, max_header: usize) {
+	MAX_URI_BYTES.store(max_uri, Ordering::Relaxed);
+	MAX_HEADER_BYTES.store(max_header, Ordering::Relaxed);
+}
+
+fn at_connection_limit() -> bool {
+	let max = MAX_OPEN_CONNECTIONS.load(Ordering::SeqCst);
+	max > 0 && OPEN_CONNECTIONS.load(Ordering::SeqCst) >= max
+}
+
+/// Body bytes sent vs. promised, across all worker threads
+pub struct TransferStats {
+	pub completed: AtomicU64,
+	pub aborted: AtomicU64,
+	pub bytes_sent: AtomicU64,
+	pub bytes_expected: AtomicU64,
+}
+
+pub static TRANSFER_STATS: TransferStats = TransferStats {
+	completed: AtomicU64::new(0),
+	aborted: AtomicU64::new(0),
+	bytes_sent: AtomicU64::new(0),
+	bytes_expected: AtomicU64::new(0),
+};
+
+impl TransferStats {
+	fn record(&self, sent: u64, expected: u64, aborted: bool) {
+		if aborted {
+			self.aborted.fetch_add(1, Ordering::Relaxed);
+		} else {
+			self.completed.fetch_add(1, Ordering::Relaxed);
+		}
+
+		self.bytes_sent.fetch_add(sent, Ordering::Relaxed);
+		self.bytes_expected.fetch_add(expected, Ordering::Relaxed);
+	}
+}
+
+/// How connections ended, across all worker threads
+pub struct ConnectionStats {
+	pub completed: AtomicU64,
+	/// Bad requests, failed handshakes and clients too slow to send anything
+	pub client_errors: AtomicU64,
+	/// Failures on our end, including panics
+	pub server_errors: AtomicU64,
+	/// Clients that went away before we were done
+	pub aborted: AtomicU64,
+	/// Requests cut off at their deadline
+	pub deadline_exceeded: AtomicU64,
+}
+
+pub static CONNECTION_STATS: ConnectionStats = ConnectionStats {
+	completed: AtomicU64::new(0),
+	client_errors: AtomicU64::new(0),
+	server_errors: AtomicU64::new(0),
+	aborted: AtomicU64::new(0),
+	deadline_exceeded: AtomicU64::new(0),
+};
+
+impl ConnectionStats {
+	/// Counts and logs how a connection ended
+	fn record(&self, result: &SBResult<()>) {
+		let e = match result {
+			Ok(()) => {
+				self.completed.fetch_add(1, Ordering::Relaxed);
+				return
+			}
+
+			Err(e) => e,
+		};
+
+		if e.downcast_ref::<ClientError>().is_some() {
+			self.client_errors.fetch_add(1, Ordering::Relaxed);
+			info!("Client error: {}", e);
+		} else if is_deadline_exceeded(e) {
+			self.deadline_exceeded.fetch_add(1, Ordering::Relaxed);
+			warn!("Request cut off: {}", e);
+		} else if is_disconnect(e) {
+			self.aborted.fetch_add(1, Ordering::Relaxed);
+			info!("Connection aborted by client: {}", e);
+		} else {
+			self.server_errors.fetch_add(1, Ordering::Relaxed);
+			error!("Server error: {}", e);
+		}
+	}
+}
+
+/// Something the client did wrong, as opposed to something going wrong on our end
+#[derive(Debug)]
+struct ClientError(String);
+
+impl std::fmt::Display for ClientError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(&self.0)
+	}
+}
+
+impl std::error::Error for ClientError {}
+
+fn client_error(message: impl Into<String>) -> failure::Error {
+	ClientError(message.into()).into()
+}
+
+/// Counts open connections per client address, so one client can't monopolise the worker threads
+#[derive(Clone, Default)]
+struct ClientTracker(Arc<Mutex<HashMap<IpAddr, usize>>>);
+
+/// Held for the lifetime of a connection, releasing its slot on drop
+struct ClientSlot {
+	tracker: ClientTracker,
+	addr: IpAddr,
+}
+
+/// Why a connection was turned away
+enum Refusal {
+	/// The client already has too many connections open
+	Client,
+	/// Every server together is at the connection limit
+	Server,
+}
+
+impl ClientTracker {
+	fn acquire(&self, addr: IpAddr) -> Result<ClientSlot, Refusal> {
+		let mut counts = self.0.lock().unwrap_or_else(|e| e.into_inner());
+		let count = counts.entry(addr).or_insert(0);
+		let per_client = MAX_CONNECTIONS_PER_CLIENT.load(Ordering::SeqCst);
+		if per_client != 0 && *count >= per_client { return Err(Refusal::Client) }
+
+		// Other listeners' threads can get in between checking and accepting, so this has the final say
+		let max = MAX_OPEN_CONNECTIONS.load(Ordering::SeqCst);
+		let reserved = OPEN_CONNECTIONS.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+			if max == 0 || open < max { Some(open + 1) } else { None }
+		});
+
+		if reserved.is_err() {
+			if *count == 0 { counts.remove(&addr); }
+			return Err(Refusal::Server)
+		}
+
+		*count += 1;
+		Ok(ClientSlot { tracker: self.clone(), addr })
+	}
+}
+
+impl Drop for ClientSlot {
+	fn drop(&mut self) {
+		OPEN_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+
+		let mut counts = self.tracker.0.lock().unwrap_or_else(|e| e.into_inner());
+
+		if let Some(count) = counts.get_mut(&self.addr) {
+			*count -= 1;
+			if *count == 0 {
+				counts.remove(&self.addr);
+			}
+		}
+	}
+}
+
+/// Requests that recently ended up at the 404 page, keyed by host and raw uri, so bursts of scans
+/// for things like /wp-login.php skip normalization and routing entirely
+#[derive(Clone, Default)]
+struct MissCache(Arc<Mutex<HashMap<String, SystemTime>>>);
+
+impl MissCache {
+	fn key(host: Option<&str>, uri: &str) -> String {
+		format!("{} {}", host.unwrap_or(""), uri)
+	}
+
+	fn live(at: SystemTime, now: SystemTime) -> bool {
+		now.duration_since(at).map_or(false, |age| age < MISS_CACHE_TTL)
+	}
+
+	fn contains(&self, key: &str, now: SystemTime) -> bool {
+		let misses = self.0.lock().unwrap_or_else(|e| e.into_inner());
+		misses.get(key).map_or(false, |&at| Self::live(at, now))
+	}
+
+	fn insert(&self, key: String, now: SystemTime) {
+		let mut misses = self.0.lock().unwrap_or_else(|e| e.into_inner());
+
+		if misses.len() >= MISS_CACHE_MAX_ENTRIES {
+			misses.retain(|_, &mut at| Self::live(at, now));
+
+			// Still full of live entries means we're being scanned hard, start over rather than track it all
+			if misses.len() >= MISS_CACHE_MAX_ENTRIES {
+				misses.clear();
+			}
+		}
+
+		misses.insert(key, now);
+	}
+}
+
+/// What to do with TLS clients asking for a name the certificate doesn't cover
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SniPolicy {
+	/// Serve the configured certificate anyway
+	Default,
+	/// Serve a generated self-signed certificate
+	SelfSigned,
+	/// Fail the handshake with an unrecognized_name alert
+	Reject,
+}
+
+impl std::str::FromStr for SniPolicy {
+	type Err = failure::Error;
+
+	fn from_str(s: &str) -> SBResult<SniPolicy> {
+		match s {
+			"default" => Ok(SniPolicy::Default),
+			"self-signed" => Ok(SniPolicy::SelfSigned),
+			"reject" => Ok(SniPolicy::Reject),
+			_ => bail!("Unknown SNI policy '{}', expected default, self-signed or reject", s),
+		}
+	}
+}
+
+/// Changes to how a fileserver handles new connections, see `Fileserver::apply`
 pub enum FileserverCommand {
 	NewMappings(Mappings),
+	/// Replaces just the cache entries that changed since the last `NewMappings`
+	UpdateMappings(MappingsDelta),
 	SetCert(Certificate),
-	Zombify,
+	/// Takes effect the next time a certificate is set
+	SetSniPolicy(SniPolicy),
+	/// Require client certificates signed by a CA in this bundle. Takes effect the next time a certificate is set
+	SetClientCa(PathBuf),
+	/// Send Strict-Transport-Security with this max-age on every response
+	SetHsts(u64),
+	/// Responses for pending ACME challenges, served ahead of everything else
+	SetChallenges(Mappings),
+	/// Redirect everything to the TLS server, apart from ACME challenges and whatever the policy allows
+	Zombify(RedirectPolicy),
+	/// Stop redirecting and serve content over plain HTTP again
+	Revive,
+	/// Answer health checks on a path, ahead of any routing or redirects
+	SetHealthCheck(HealthCheck),
+	/// Close connections from peers the filter doesn't permit, before reading anything from them
+	SetIpFilter(IpFilter),
 	// Close,
 }
 
-pub fn start(listener: TcpListener, command_rx: Receiver<FileserverCommand>) {
-	let mut mappings = Arc::new(Mappings::new(false));
+/// Shared with a fileserver's accept loop, which reads it for every connection. Commands applied through
+/// any clone are seen by the very next connection, while connections already open keep what they started with
+#[derive(Clone)]
+pub struct Fileserver {
+	state: Watch<ServerState>,
+}
+
+impl Fileserver {
+	pub fn new() -> Fileserver {
+		Fileserver {
+			state: Watch::new(ServerState {
+				context: ConnectionContext {
+					mappings: Arc::new(Mappings::new(CacheMode::Disabled)),
+					challenges: None,
+					redirect_policy: None,
+					response_headers: Arc::new(Vec::new()),
+					misses: MissCache::default(),
+					health_check: None,
+				},
+				ssl_acceptor: None,
+				sni_policy: SniPolicy::Default,
+				client_ca: None,
+				ip_filter: IpFilter::default(),
+			}),
+		}
+	}
+
+	/// Only fails for things like a certificate that doesn't match its key, in which case nothing changes
+	pub fn apply(&self, command: FileserverCommand) -> SBResult<()> {
+		self.state.modify(|state| state.apply(command))
+	}
+}
+
+/// How a zombified server sends plain HTTP requests over to HTTPS
+#[derive(Debug, Clone)]
+pub struct RedirectPolicy {
+	/// Always redirect to this host, rather than whatever the client asked for
+	pub canonical_host: Option<String>,
+	/// Hosts the client is allowed to ask for. Anything else is sent to the first one. Empty allows any host
+	pub allowed_hosts: Vec<String>,
+	/// 301, 307 or 308
+	pub status: u16,
+	pub https_port: u16,
+	/// Path patterns that are still served over plain HTTP, where `*` matches anything
+	pub plain_paths: Vec<String>,
+}
+
+impl RedirectPolicy {
+	fn serves_plain(&self, path: &str) -> bool {
+		self.plain_paths.iter().any(|pattern| glob_match(pattern, path))
+	}
+
+	/// Where to send a request for `target`, or None if there's no sensible host to send it to
+	fn location(&self, host: Option<&str>, target: &str) -> Option<String> {
+		let host = match &self.canonical_host {
+			Some(canonical_host) => canonical_host.clone(),
+			None => {
+				let host = strip_port(host?).to_ascii_lowercase();
+				let valid = !host.is_empty() && host.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'-');
+
+				match self.allowed_hosts.first() {
+					Some(fallback) if !valid || !self.allowed_hosts.contains(&host) => fallback.clone(),
+					_ if !valid => return None,
+					_ => host,
+				}
+			}
+		};
+
+		// Never let the client turn the location into something other than a path on our host
+		let target = if target.starts_with('/') && !target.starts_with("//") { target } else { "/" };
+
+		match self.https_port {
+			443 => Some(format!("https://{}{}", host, target)),
+			port => Some(format!("https://{}:{}{}", host, port, target)),
+		}
+	}
+}
+
+/// Tells load balancers and uptime monitors whether we're serving real content yet
+#[derive(Clone)]
+pub struct HealthCheck {
+	pub path: String,
+	pub ready: Arc<ReadyNotifier>,
+}
+
+impl HealthCheck {
+	fn response(&self, head_only: bool) -> Vec<u8> {
+		let (status, mut body) = if self.ready.is_ready() {
+			("HTTP/1.1 200 OK", "ok\n".to_owned())
+		} else {
+			("HTTP/1.1 503 Service Unavailable", "starting\n".to_owned())
+		};
+
+		// So a fleet sharing a --cluster can be checked for having switched over
+		if let Some(generation) = publish::serving_generation() {
+			body.push_str(&format!("generation {}\n", generation));
+		}
+
+		let content_length = body.len().to_string();
+
+		let mut res = http::Response::new(status);
+		res.set("Content-Type", "text/plain; charset=utf-8");
+		res.set("X-Content-Type-Options", "nosniff");
+		res.set("Content-Length", &content_length);
+		res.set("Cache-Control", "no-store");
+
+		let mut response = res.into_bytes();
+		if !head_only {
+			response.extend_from_slice(body.as_bytes());
+		}
+
+		response
+	}
+}
+
+/// How the server was set up at the moment a connection was accepted
+#[derive(Clone)]
+struct ConnectionContext {
+	mappings: Arc<Mappings>,
+	challenges: Option<Arc<Mappings>>,
+	/// Set once zombified
+	redirect_policy: Option<Arc<RedirectPolicy>>,
+	/// Added to every response, error or otherwise
+	response_headers: Arc<Vec<(String, String)>>,
+	misses: MissCache,
+	health_check: Option<Arc<HealthCheck>>,
+}
+
+/// Everything commands can change about how a server handles new connections
+#[derive(Clone)]
+struct ServerState {
+	context: ConnectionContext,
+	ssl_acceptor: Option<Arc<SslAcceptor>>,
+	sni_policy: SniPolicy,
+	client_ca: Option<PathBuf>,
+	/// Peers turned away as soon as they connect
+	ip_filter: IpFilter,
+}
+
+impl ServerState {
+	fn apply(&mut self, command: FileserverCommand) -> SBResult<()> {
+		let context = &mut self.context;
+
+		match command {
+			FileserverCommand::NewMappings(new_mappings) => {
+				context.mappings = Arc::new(new_mappings);
+				context.misses = MissCache::default();
+			}
+
+			FileserverCommand::UpdateMappings(delta) => {
+				// Only copies the mappings if open connections are still using them
+				Arc::make_mut(&mut context.mappings).apply_delta(delta);
+				context.misses = MissCache::default();
+			}
+
+			FileserverCommand::SetCert(cert) => {
+				// A bad certificate shouldn't take the listener down with it
+				let acceptor = build_acceptor(&cert, self.sni_policy, self.client_ca.as_ref().map(PathBuf::as_path))
+					.map_err(|e| failure::format_err!("Failed to use new certificate, keeping the old one: {}", e))?;
+
+				self.ssl_acceptor = Some(Arc::new(acceptor));
+			}
+
+			FileserverCommand::SetSniPolicy(policy) => {
+				self.sni_policy = policy;
+			}
+
+			FileserverCommand::SetClientCa(path) => {
+				self.client_ca = Some(path);
+			}
+
+			FileserverCommand::SetHsts(max_age) => {
+				let mut headers: Vec<(String, String)> = (*context.response_headers).clone();
+				headers.retain(|(key, _)| key != "Strict-Transport-Security");
+				headers.push(("Strict-Transport-Security".to_owned(), format!("max-age={}", max_age)));
+				context.response_headers = Arc::new(headers);
+			}
+
+			FileserverCommand::SetChallenges(challenges) => {
+				context.challenges = Some(Arc::new(challenges));
+			}
+
+			FileserverCommand::Zombify(policy) => {
+				context.redirect_policy = Some(Arc::new(policy));
+			}
+
+			FileserverCommand::Revive => {
+				context.redirect_policy = None;
+			}
+
+			FileserverCommand::SetHealthCheck(health_check) => {
+				context.health_check = Some(Arc::new(health_check));
+			}
+
+			FileserverCommand::SetIpFilter(ip_filter) => {
+				self.ip_filter = ip_filter;
+			}
+		}
+
+		Ok(())
+	}
+}
 
+pub fn start(listener: TcpListener, server: Fileserver, workers: usize, heartbeats: Heartbeats) {
 	let (coro_threads, worker_tx_list) = {
 		let mut txs = Vec::new();
 		let mut ths = Vec::new();
-		for _ in 0..NUM_WORKER_THREADS {
+		for _ in 0..workers {
 			let (tx, rx) = mpsc::sync_channel(MAX_PENDING_CONNECTIONS_PER_THREAD);
-			ths.push(thread::spawn(move || continuation_thread(rx)));
+			let heartbeat = heartbeats.register();
+			ths.push(thread::spawn(move || continuation_thread(rx, heartbeat)));
 			txs.push(tx);
 		}
 		(ths, txs)
 	};
 
-	let mut worker_tx_iter = worker_tx_list.into_iter().cycle();
-	let mut submit_task = move |task| {
-		worker_tx_iter.next().unwrap().send(task).unwrap()
-	};
+	let mut next_worker = 0;
+	let mut submit_task = move |mut task: Connection| {
+		let first = next_worker;
+		next_worker = (next_worker + 1) % worker_tx_list.len();
 
-	let mut ssl_acceptor = None;
-	let mut zombie_mode = false;
+		// A worker backed up with slow handshakes shouldn't hold up accepts while others have room
+		for offset in 0..worker_tx_list.len() {
+			let worker_tx = &worker_tx_list[(first + offset) % worker_tx_list.len()];
 
-	for stream in listener.incoming() {
-		for command in command_rx.try_iter() {
-			match command {
-				FileserverCommand::NewMappings(new_mappings) => {
-					mappings = Arc::new(new_mappings);
+			match worker_tx.try_send(task) {
+				Ok(()) => return,
+				Err(TrySendError::Full(returned)) => task = returned,
+				Err(TrySendError::Disconnected(_)) => {
+					error!("Worker thread has gone away, dropping connection");
+					return
 				}
+			}
+		}
 
-				FileserverCommand::SetCert(cert) => {
-					let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
-					builder.set_certificate(cert.certificate()).unwrap();
-					builder.add_extra_chain_cert(cert.intermediate().clone()).unwrap();
-					builder.set_private_key(cert.private_key()).unwrap();
-					builder.check_private_key().unwrap();
-					ssl_acceptor = Some(Rc::new(builder.build()));
-				}
+		// Everyone's full, so queue up behind whoever's turn it was rather than drop the connection
+		if worker_tx_list[first].send(task).is_err() {
+			error!("Worker thread has gone away, dropping connection");
+		}
+	};
 
-				FileserverCommand::Zombify => {
-					zombie_mode = true;
-				}
-			}
+	let clients = ClientTracker::default();
+
+	for stream in listener.incoming() {
+		// Connections already accepted carry on until they're done
+		if shutdown::requested() { break }
+
+		// Hold on to this one until there's room, leaving everyone after it in the listen backlog
+		while at_connection_limit() && !REJECT_WHEN_FULL.load(Ordering::SeqCst) && !shutdown::requested() {
+			thread::sleep(FULL_POLL_INTERVAL);
 		}
 
-		if stream.is_err() {
+		let mut stream = match stream {
+			Ok(stream) => stream,
+			Err(_) => continue,
+		};
+
+		// Whatever the server looked like as this connection came in
+		let state = server.state.get();
+
+		let addr = match stream.peer_addr() {
+			Ok(addr) => addr,
+			Err(_) => continue,
+		};
+
+		if !state.ip_filter.permits(addr.ip()) {
+			debug!("Closing connection from {}, which isn't allowed", addr.ip());
 			continue
 		}
 
-		let stream = stream.unwrap();
+		let slot = match clients.acquire(addr.ip()) {
+			Ok(slot) => slot,
+			Err(refusal) => {
+				// Not worth a TLS handshake just to turn the client away
+				if state.ssl_acceptor.is_none() {
+					let mut res = match refusal {
+						Refusal::Client => http::Response::new("HTTP/1.1 429 Too Many Requests"),
+						Refusal::Server => http::Response::new("HTTP/1.1 503 Service Unavailable"),
+					};
+
+					res.set("Retry-After", "1");
+					let _ = stream.write_all(&res.into_bytes());
+				}
+
+				continue
+			}
+		};
 
 		if stream.set_nonblocking(true).is_err() {
 			continue
 		}
 
-		let mappings_clone = mappings.clone();
+		let context = state.context.clone();
+		let id = RequestId::next();
 
-		if let Some(acceptor) = ssl_acceptor.clone() {
+		if let Some(acceptor) = state.ssl_acceptor.clone() {
 			let stream_task = static move || {
-				// Start TLS upgrade
-				let mut accept_result = acceptor.accept(stream);
-				let handshake_timer = std::time::Instant::now();
-
-				// Keep resuming handshake until either an error, timeout or success
-				while let Err(HandshakeError::WouldBlock(inprogress_stream)) = accept_result {
-					if handshake_timer.elapsed().as_secs() >= SSL_UPGRADE_TIMEOUT_SECS {
-						bail!("Timeout while trying to upgrade connection")
-					}
-
-					yield;
-					accept_result = inprogress_stream.handshake();
-				}
+				let _slot = slot;
 
-				// Start regular stream process
-				let tls_stream = accept_result?;
-				task_await!(start_stream_process(tls_stream, mappings_clone, zombie_mode))
+				let (tls_stream, early_data) = task_await!(accept_tls(acceptor, stream))?;
+				task_await!(start_stream_process(TlsStream(tls_stream), early_data, context, id, addr.ip()))
 			};
 
-			submit_task(stream_task.into());
+			submit_task(Connection { id, task: stream_task.into() });
 
 		} else {
-			let stream_task = start_stream_process(stream, mappings_clone, zombie_mode);
-			submit_task(stream_task.into());
+			let stream_task = static move || {
+				let _slot = slot;
+				task_await!(start_stream_process(stream, Vec::new(), context, id, addr.ip()))
+			};
+
+			submit_task(Connection { id, task: stream_task.into() });
 		}
 	}
 
+	// Workers finish what they have then exit once there's nothing left to send them more
+	drop(submit_task);
+
 	for th in coro_threads {
 		th.join().unwrap();
 	}
 }
 
-fn continuation_thread(rx: Receiver<Task<SBResult<()>>>) {
+/// Does the TLS handshake, collecting whatever the client sent as early data on the way
+fn accept_tls(acceptor: Arc<SslAcceptor>, stream: TcpStream)
+	-> impl Generator<Yield=(), Return=SBResult<(SslStream<TcpStream>, Vec<u8>)>> {
+
+	static move || {
+		let handshake_timer = Instant::now();
+		let handshake_timeout = TLS_TIMEOUT_SECS.load(Ordering::Relaxed);
+		let timed_out = || handshake_timer.elapsed().as_secs() >= handshake_timeout;
+		let would_block = |code: ErrorCode| code == ErrorCode::WANT_READ || code == ErrorCode::WANT_WRITE;
+
+		if !EARLY_DATA.load(Ordering::Relaxed) {
+			let mut accept_result = acceptor.accept(stream);
+
+			// Keep resuming handshake until either an error, timeout or success
+			while let Err(HandshakeError::WouldBlock(inprogress_stream)) = accept_result {
+				if timed_out() {
+					return Err(client_error("Timeout while trying to upgrade connection"))
+				}
+
+				yield;
+				accept_result = inprogress_stream.handshake();
+			}
+
+			return match accept_result {
+				Ok(tls_stream) => Ok((tls_stream, Vec::new())),
+				Err(HandshakeError::SetupFailure(e)) => Err(e.into()),
+				Err(e) => Err(client_error(format!("TLS handshake failed: {}", e))),
+			}
+		}
+
+		let mut ssl = Ssl::new(acceptor.context())?;
+		ssl.set_accept_state();
+		let mut tls_stream = SslStream::new(ssl, stream)?;
+
+		// Early data has to be read before the handshake can finish. Clients that don't send any
+		// get through here as soon as their hello has been answered
+		let mut early_data = vec![0u8; MAX_EARLY_DATA as usize + 1];
+		let mut early_len = 0;
+
+		loop {
+			match tls_stream.read_early_data(&mut early_data[early_len..]) {
+				Ok(0) => break,
+				Ok(size) => early_len += size,
+				Err(ref e) if would_block(e.code()) => {
+					if timed_out() {
+						return Err(client_error("Timeout while reading early data"))
+					}
+
+					yield
+				}
+
+				Err(e) => return Err(client_error(format!("TLS handshake failed: {}", e))),
+			}
+		}
+
+		early_data.truncate(early_len);
+
+		loop {
+			match tls_stream.accept() {
+				Ok(()) => break,
+				Err(ref e) if would_block(e.code()) => {
+					if timed_out() {
+						return Err(client_error("Timeout while trying to upgrade connection"))
+					}
+
+					yield
+				}
+
+				Err(e) => return Err(client_error(format!("TLS handshake failed: {}", e))),
+			}
+		}
+
+		Ok((tls_stream, early_data))
+	}
+}
+
+fn build_acceptor(cert: &Certificate, sni_policy: SniPolicy, client_ca: Option<&Path>) -> SBResult<SslAcceptor> {
+	let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+	builder.set_certificate(cert.certificate())?;
+	for intermediate in cert.intermediates() {
+		builder.add_extra_chain_cert(intermediate.clone())?;
+	}
+
+	builder.set_private_key(cert.private_key())?;
+	builder.check_private_key()?;
+
+	// Clients without a certificate from one of these CAs fail the handshake
+	if let Some(client_ca) = client_ca {
+		builder.set_ca_file(client_ca)?;
+		builder.set_client_ca_list(X509Name::load_client_ca_file(client_ca)?);
+		builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+	}
+
+	// Early data only exists in TLS 1.3, which mozilla_intermediate turns off
+	if EARLY_DATA.load(Ordering::Relaxed) {
+		builder.clear_options(SslOptions::NO_TLSV1_3);
+		builder.set_max_early_data(MAX_EARLY_DATA)?;
+	}
+
+	set_sni_callback(&mut builder, cert, sni_policy)?;
+	Ok(builder.build())
+}
+
+fn set_sni_callback(builder: &mut SslAcceptorBuilder, cert: &Certificate, policy: SniPolicy) -> SBResult<()> {
+	let names = cert.dns_names();
+
+	let fallback_context = if policy == SniPolicy::SelfSigned {
+		let fallback = Certificate::self_signed("spiderbutter.invalid")?;
+
+		let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+		builder.set_certificate(fallback.certificate())?;
+		builder.set_private_key(fallback.private_key())?;
+		Some(builder.build().into_context())
+	} else {
+		None
+	};
+
+	builder.set_servername_callback(move |ssl, alert| {
+		// Clients that don't send SNI at all just get the real certificate
+		let server_name = match ssl.servername(NameType::HOST_NAME) {
+			Some(name) => name.to_ascii_lowercase(),
+			None => return Ok(()),
+		};
+
+		if names.iter().any(|name| sni_matches(name, &server_name)) {
+			return Ok(())
+		}
+
+		info!("Unknown SNI \"{}\"", http::escape_for_log(server_name.as_bytes()));
+
+		match (policy, fallback_context.as_ref()) {
+			(SniPolicy::SelfSigned, Some(context)) => ssl.set_ssl_context(context).map_err(|_| SniError::ALERT_FATAL),
+			(SniPolicy::Reject, _) => {
+				*alert = SslAlert::UNRECOGNIZED_NAME;
+				Err(SniError::ALERT_FATAL)
+			}
+
+			_ => Ok(()),
+		}
+	});
+
+	Ok(())
+}
+
+/// Matches a certificate name against a requested server name. Wildcards only cover a single label
+fn sni_matches(cert_name: &str, server_name: &str) -> bool {
+	let cert_name = cert_name.to_ascii_lowercase();
+
+	if cert_name.starts_with("*.") {
+		match server_name.find('.') {
+			Some(dot) => server_name[dot..] == cert_name[1..],
+			None => false,
+		}
+	} else {
+		cert_name == server_name
+	}
+}
+
+/// A connection's task, and the id everything it logs is tagged with
+struct Connection {
+	id: RequestId,
+	task: Task<SBResult<()>>,
+}
+
+fn continuation_thread(rx: Receiver<Connection>, heartbeat: Heartbeat) {
 	let mut coros = Vec::new();
 
 	loop {
-		// Block until we receive a new connection
-		match rx.recv() {
+		// Block until we receive a new connection, waking now and then to show we're not stuck
+		match rx.recv_timeout(IDLE_HEARTBEAT_INTERVAL) {
 			Ok(c) => coros.push(c),
+			Err(RecvTimeoutError::Timeout) => {
+				heartbeat.beat();
+				continue
+			}
 			Err(e) => {
-				println!("[fsrv] Rx error: {:?}", e);
+				debug!("Rx error: {:?}", e);
 				break;
 			}
 		}
 
-		// println!("[cont {:?}] connection made, transitioning to processing loop", thread::current().id());
-
 		// Process all connections until completion
 		loop {
+			heartbeat.beat();
+
 			if coros.len() < MAX_CONCURRENT_CONNECTIONS_PER_THREAD {
 				for c in rx.try_iter() {
 					coros.push(c);
@@ -148,76 +866,252 @@ fn continuation_thread(rx: Receiver<Task<SBResult<()>>>) {
 			}
 
 			for c in coros.iter_mut() {
-				if let Some(Err(e)) = c.resume() {
-					println!("[fsrv] Connection aborted with error: {}", e);
+				let _span = log::enter(c.id);
+				let task = &mut c.task;
+
+				// A panicking connection shouldn't take every other connection on this thread with it
+				match panic::catch_unwind(AssertUnwindSafe(|| task.resume())) {
+					Ok(Some(result)) => CONNECTION_STATS.record(&result),
+					Ok(None) => {}
+					Err(_) => {
+						error!("Connection task panicked, dropping it");
+						CONNECTION_STATS.server_errors.fetch_add(1, Ordering::Relaxed);
+						task.abandon();
+					}
 				}
 			}
 
-			coros.retain(Task::is_valid);
+			coros.retain(|c| c.task.is_valid());
 			if coros.is_empty() { break }
 
 			thread::sleep(time::Duration::from_millis(1));
 		}
-
-		// println!("[cont {:?}] connections processed, waiting...", thread::current().id());
 	}
 }
 
 
-fn start_stream_process<S>(mut stream: S, mappings: Arc<Mappings>, zombie_mode: bool)
+/// `early_data` is the start of the request if it came as TLS early data, and empty otherwise
+fn start_stream_process<S>(stream: S, early_data: Vec<u8>, context: ConnectionContext, id: RequestId, peer: IpAddr)
 	-> impl Generator<Yield=(), Return=SBResult<()>>
 	where S: Read + Write + TcpStreamExt + 'static {
 
 	static move || {
-		// println!("[stream {:?}] new stream", thread::current().id());
+		let ConnectionContext {mappings, challenges, redirect_policy, response_headers, misses, health_check} = context;
+
+		// Headers from the mappings go first, so anything the server sets itself wins over them
+		let mut response_headers: Vec<(String, String)> = mappings.global_headers().iter()
+			.chain(response_headers.iter())
+			.cloned()
+			.collect();
+
+		// Every response carries the id, so a client's report can be matched up with the logs
+		response_headers.push(("X-Request-Id".to_owned(), id.to_string()));
+
+		let mut buf = ReadBuffer::new();
+		buf.extend_from_slice(&early_data);
 
-		let mut buf = [0u8; 8<<10];
 		let read_start = std::time::Instant::now();
 
-		// Try to read request
-		let size = loop {
+		// Everything from here on is covered by the deadline, until a route says otherwise
+		let deadline = Some(REQUEST_DEADLINE_SECS.load(Ordering::Relaxed))
+			.filter(|&secs| secs > 0)
+			.map(|secs| read_start + Duration::from_secs(secs));
+
+		let mut stream = DeadlineStream::new(stream, deadline);
+		let read_timeout = READ_TIMEOUT_SECS.load(Ordering::Relaxed);
+		let (max_uri, max_fields) = (MAX_URI_BYTES.load(Ordering::Relaxed), MAX_HEADER_BYTES.load(Ordering::Relaxed));
+
+		// Early data can hold the whole head already, so it's checked before waiting on more
+		let mut check_head = !buf.is_empty();
+
+		// Read until the whole head is in, or it's clear it's too big
+		let head = loop {
 			use std::io::ErrorKind as EK;
 
-			match stream.read(&mut buf) {
+			if check_head {
+				match http::check_head(&buf, max_uri, max_fields) {
+					Ok(Some(size)) => break Ok(size),
+					Ok(None) => {}
+					Err(e) => break Err(e),
+				}
+			}
+
+			match buf.read_from(&mut stream) {
 				Err(e) => match e.kind() {
-					EK::WouldBlock => {},
-					_ => bail!("Error while reading request: {:?}", e)
+					EK::WouldBlock => check_head = false,
+					// Left as an io::Error so disconnects are counted as such
+					_ => return Err(e.into()),
 				}
 
-				Ok(0) => bail!("Zero size request"),
-				Ok(s) => break s,
+				Ok(0) if buf.is_empty() => return Err(client_error("Zero size request")),
+				// The client's done sending, so whatever it sent will have to do
+				Ok(0) => break Ok(buf.len()),
+
+				Ok(_) => {
+					check_head = true;
+					continue
+				}
 			}
 
-			if read_start.elapsed().as_secs() > REQUEST_READ_TIMEOUT_SECS {
-				bail!("Timeout during request read");
+			if read_start.elapsed().as_secs() >= read_timeout {
+				return Err(client_error("Timeout during request read"));
 			}
 
 			yield
 		};
 
-		let request = str::from_utf8(&buf[0..size])
-			.map_err(Into::into)
-			.and_then(http::Request::parse);
-
-		let request = match request {
+		let request = match head.and_then(|size| http::Request::parse(&buf[0..size])) {
 			Ok(r) => r,
 			Err(e) => {
-				let _ = stream.write_all(&http::Response::new("HTTP/1.1 400 Bad Request").into_bytes());
-				return Err(e);
+				let status = e.downcast_ref::<http::RequestError>().map_or(400, |e| e.status);
+				let _ = stream.write_all(&new_response(&http::status_line(status), &response_headers).into_bytes());
+				return Err(client_error(format!("Bad request: {}", e)));
+			}
+		};
+
+		// Anyone who saw the early data can send it again, so only requests that are safe to repeat get answered.
+		// 425 tells the client to send it again once the handshake's done
+		if !early_data.is_empty() && request.method() != "GET" && request.method() != "HEAD" {
+			let _ = stream.write_all(&new_response(&http::status_line(425), &response_headers).into_bytes());
+			return Err(client_error(format!("{} request sent as early data", request.method())));
+		}
+
+		// Health checks come in on every port, zombie or not, and shouldn't depend on the content
+		if let Some(health_check) = health_check.as_ref() {
+			let path = request.uri().split('?').next().unwrap_or("");
+
+			if path == health_check.path {
+				let response = health_check.response(request.method() == "HEAD");
+				return task_await!(write_async(&mut stream, &response))
 			}
+		}
+
+		let normalized = http::normalize_path(request.uri());
+
+		// Challenges come from the certificate code rather than the content, and win over it
+		let is_challenge = normalized.as_ref().map_or(false, |path| path.starts_with(ACME_CHALLENGE_PREFIX));
+		let mappings = match (&challenges, is_challenge) {
+			(Some(challenges), true) => challenges.clone(),
+			_ => mappings,
 		};
 
+		// Scanners are turned away before doing anything else for them
+		let block_path = normalized.as_deref().unwrap_or(request.uri());
+		match mappings.get_block(block_path, request.get("User-Agent")) {
+			Some(BlockAction::Drop) => return Ok(()),
+			Some(BlockAction::Forbid) => {
+				let response = new_response("HTTP/1.1 403 Forbidden", &response_headers).into_bytes();
+				return task_await!(write_async(&mut stream, &response))
+			}
+
+			None => {}
+		}
+
 		// If we're on a zombie thread, and the request isn't part of an acme challenge,
 		// tell the client to upgrade to https
-		if zombie_mode && !request.uri().contains("/.well-known/acme-challenge") {
-			// TODO: this needs to be made way more robust - way too much trust here
-			let mut res = http::Response::new("HTTP/1.1 301 Moved Permanently");
-			let new_location = format!("https://{}{}", request.get("Host").unwrap_or(""), request.uri());
-			res.set("Location", &new_location);
-			let _ = stream.write_all(&res.into_bytes());
-			return Ok(());
+		if let Some(policy) = redirect_policy.as_ref().filter(|p| !is_challenge && !p.serves_plain(block_path)) {
+			let response = match policy.location(request.get("Host"), request.uri()) {
+				Some(location) => {
+					let status = http::status_line(policy.status);
+					let mut res = new_response(&status, &response_headers);
+					res.set("Location", &location);
+					res.into_bytes()
+				}
+
+				None => new_response("HTTP/1.1 400 Bad Request", &response_headers).into_bytes(),
+			};
+
+			return task_await!(write_async(&mut stream, &response))
+		}
+
+		if !mappings.permits_ip(block_path, peer) {
+			let response = new_response("HTTP/1.1 403 Forbidden", &response_headers).into_bytes();
+			return task_await!(write_async(&mut stream, &response))
+		}
+
+		// Every route takes the same methods, so there's nothing to look up. Answered before auth,
+		// since CORS preflights never carry credentials
+		if request.method() == "OPTIONS" {
+			let mut res = new_response("HTTP/1.1 204 No Content", &response_headers);
+			res.set("Allow", http::ALLOWED_METHODS);
+
+			let response = res.into_bytes();
+			return task_await!(write_async(&mut stream, &response))
 		}
 
+		// Only checked once the client's on https, if it's going to be, so passwords aren't sent in the clear
+		if let Err(realm) = mappings.check_auth(block_path, request.get("Authorization")) {
+			let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm);
+			let mut res = new_response("HTTP/1.1 401 Unauthorized", &response_headers);
+			res.set("WWW-Authenticate", &challenge);
+			res.set("Cache-Control", "no-store");
+
+			let response = res.into_bytes();
+			return task_await!(write_async(&mut stream, &response))
+		}
+
+		// Requests we've recently sent to the 404 page go straight there, without a path to route
+		let miss_key = MissCache::key(request.get("Host"), request.uri());
+		let known_missing = misses.contains(&miss_key, mappings.clock().now());
+
+		// Routing only ever sees the canonical path, so prefix and wildcard routes can't be walked out of
+		let path = match normalized.filter(|_| !known_missing) {
+			Some(path) => Some(path),
+			None if known_missing => None,
+			None => {
+				let response = new_response("HTTP/1.1 400 Bad Request", &response_headers).into_bytes();
+				return task_await!(write_async(&mut stream, &response))
+			}
+		};
+
+		if let Some(deadline) = path.as_ref().and_then(|path| mappings.get_deadline(path)) {
+			stream.set_deadline(Some(read_start + deadline));
+		}
+
+		if let Some(redirect) = path.as_ref().and_then(|path| mappings.get_redirect(path)) {
+			let status = http::status_line(redirect.status);
+			let mut res = new_response(&status, &response_headers);
+			res.set("Location", &redirect.location);
+
+			let response = res.into_bytes();
+			return task_await!(write_async(&mut stream, &response))
+		}
+
+		// Expired routes stop existing, or redirect somewhere else if they say so
+		let expiry = path.as_ref().and_then(|path| mappings.get_expiry(path));
+		let expired = expiry.map_or(false, |e| mappings.has_expired(e));
+
+		if let (true, Some(location)) = (expired, expiry.and_then(|e| e.redirect.as_ref())) {
+			let mut res = new_response("HTTP/1.1 302 Found", &response_headers);
+			res.set("Location", location);
+
+			let response = res.into_bytes();
+			return task_await!(write_async(&mut stream, &response))
+		}
+
+		// Expensive routes only get so many requests at once. Held until the response is sent
+		let limit_start = Instant::now();
+		let _limit_slot = loop {
+			let admission = match path.as_ref() {
+				Some(path) => mappings.admit(path),
+				None => Admission::Unlimited,
+			};
+
+			match admission {
+				Admission::Unlimited => break None,
+				Admission::Admitted(slot) => break Some(slot),
+				Admission::Full { queue: true } if limit_start.elapsed() < LIMIT_QUEUE_TIMEOUT && !stream.expired() => yield,
+				Admission::Full { .. } if stream.expired() => return deadline_exceeded(&mut stream, &response_headers),
+				Admission::Full { .. } => {
+					let mut res = new_response("HTTP/1.1 503 Service Unavailable", &response_headers);
+					res.set("Retry-After", LIMIT_RETRY_AFTER_SECS);
+
+					let response = res.into_bytes();
+					return task_await!(write_async(&mut stream, &response))
+				}
+			}
+		};
+
 		// Figure out what compression method to use
 		let mut encodings = request.get("Accept-Encoding")
 			.map(|s| s.split_terminator(',')
@@ -225,62 +1119,584 @@ fn start_stream_process<S>(mut stream: S, mappings: Arc<Mappings>, zombie_mode:
 				.filter_map(|enc| match enc {
 					"deflate" => Some(Encoding::Deflate),
 					"gzip" => Some(Encoding::Gzip),
+					"br" => Some(Encoding::Brotli),
 					_ => None
 				})
 				.collect())
 			.unwrap_or(Vec::new());
 
 		encodings.sort_unstable_by_key(|k| match *k {
+			Encoding::Brotli => 0,
 			Encoding::Gzip => 1,
 			Encoding::Deflate => 2,
 			_ => 10,
 		});
 
+		let debug_timer = if path.as_ref().map_or(false, |path| mappings.debug_enabled(path)) {
+			Some(Instant::now())
+		} else {
+			None
+		};
+
+		let save_data = request.get("Save-Data")
+			.map_or(false, |v| v.eq_ignore_ascii_case("on"));
+
+		let mut headers: Vec<(String, String)> = response_headers.to_vec();
+		let mut missing = false;
+
+		if path.as_ref().map_or(false, |path| mappings.is_noindex(path)) {
+			headers.push(("X-Robots-Tag".to_owned(), "noindex".to_owned()));
+		}
+
 		// Try to send the asset with the correct encoding and content type
-		// or bail with a 404 if it's not found in the mappings
-		let asset_and_content_type = mappings
-			.get_route(request.uri())
-			.and_then(|r| Some((mappings.get_asset(&r.path)?, &r.content_type)));
+		// or fall back to the 404 page if it's not found in the mappings
+		let asset_and_content_type = path.as_ref()
+			.filter(|_| !expired)
+			.and_then(|path| mappings.get_route(request.get("Host"), path))
+			.and_then(|r| {
+				let asset_path = match &r.save_data_path {
+					Some(variant) => {
+						add_vary(&mut headers, "Save-Data");
+						if save_data { variant } else { &r.path }
+					}
+
+					None => &r.path,
+				};
+
+				let asset = mappings.get_asset(asset_path)?;
+				headers.extend(r.headers.iter().cloned());
+				Some((r.status.unwrap_or(200), asset, r.content_type.clone()))
+			})
+			.or_else(|| {
+				let index = mappings.get_site_index(request.get("Host"), path.as_ref()?)?;
+				Some((200, index, Some("text/html; charset=utf-8".to_owned())))
+			})
+			.or_else(|| {
+				if expired { return None }
+
+				let (sidecar, content_type) = mappings.get_artifact_sidecar(path.as_ref()?)?;
+				Some((200, sidecar, Some(content_type)))
+			})
+			.or_else(|| {
+				if expired { return None }
+
+				let query = request.uri().splitn(2, '?').nth(1);
+				let listing = autoindex::Listing::from_request(query, request.get("Accept"), request.get("Accept-Language"));
+
+				let asset = mappings.get_directory_listing(path.as_ref()?, &listing)?;
+				add_vary(&mut headers, "Accept");
+				add_vary(&mut headers, "Accept-Language");
+				Some((200, asset, Some(listing.content_type().to_owned())))
+			})
+			.or_else(|| {
+				if expired { return None }
+
+				let r = mappings.get_fallback(path.as_ref()?)?;
+				let asset = mappings.get_asset(&r.path)?;
+				headers.extend(r.headers.iter().cloned());
+
+				// The app works out what to show, but as far as crawlers and monitoring go there's no page here
+				headers.push(("X-SPA-Fallback".to_owned(), "1".to_owned()));
+				headers.push(("X-Robots-Tag".to_owned(), "noindex".to_owned()));
+				Some((r.status.unwrap_or(200), asset, r.content_type.clone()))
+			})
+			.or_else(|| {
+				missing = true;
+
+				let r = mappings.get_status_route(404)?;
+				let asset = mappings.get_asset(&r.path)?;
+				headers.extend(r.headers.iter().cloned());
+				Some((404, asset, r.content_type.clone()))
+			});
+
+		if missing && !known_missing {
+			misses.insert(miss_key, mappings.clock().now());
+		}
+
+		if let Some((status, asset, content_type)) = asset_and_content_type {
+			// Caches mustn't hand a compressed copy to a client that never asked for one
+			if [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate].iter().any(|&e| asset.has_encoding(e)) {
+				add_vary(&mut headers, "Accept-Encoding");
+			}
+
+			let range_header = request.get("Range")
+				.filter(|_| status == 200);
+
+			// Ranges are only served from the identity encoding, since byte offsets into
+			// compressed variants aren't meaningful to anyone
+			let encoding = if range_header.is_some() {
+				Encoding::Uncompressed
+			} else {
+				// Brotli, and anything for files too big to keep compressed copies of, is only available
+				// if a precompressed sidecar exists
+				encodings.iter().cloned()
+					.find(|&e| asset.has_encoding(e))
+					.unwrap_or(Encoding::Uncompressed)
+			};
+
+			// Route headers can say how this response came about, e.g. `{Cache-Tag: $content_hash}`
+			if headers.iter().any(|(_, value)| value.contains('$')) {
+				let variables = [
+					("encoding", encoding.content_coding().to_owned()),
+					("cache_status", asset.cache_status().to_owned()),
+					("content_hash", asset.content_hash().unwrap_or_default()),
+					("site", mappings.site_name(request.get("Host")).unwrap_or_default()),
+				];
+
+				for (_, value) in headers.iter_mut() {
+					*value = expand_variables(value, &variables);
+				}
+			}
+
+			// Conditional requests only make sense for the real resource, not error pages
+			let etag = if status == 200 {
+				entity_tag(&*asset, encoding, path.as_ref().and_then(|path| mappings.etag_kind(path)))
+			} else {
+				None
+			};
+
+			if let Some(etag) = &etag {
+				let precondition_failed = request.get("If-Match")
+					.map_or(false, |if_match| !http::etag_matches(if_match, &etag, true));
+
+				if precondition_failed {
+					let response = new_response("HTTP/1.1 412 Precondition Failed", &response_headers).into_bytes();
+					return task_await!(write_async(&mut stream, &response))
+				}
+
+				let not_modified = request.get("If-None-Match")
+					.map_or(false, |if_none_match| http::etag_matches(if_none_match, &etag, false));
+
+				// Carries the same caching headers the full response would have, so caches can update what they've stored
+				if not_modified {
+					let mut res = http::Response::new("HTTP/1.1 304 Not Modified");
+					for (key, value) in headers.iter() {
+						res.set(key, value);
+					}
+
+					res.set("ETag", &etag);
+
+					let expires = expiry.map(|expiry| http::format_http_date(expiry.at));
+					if let Some(expires) = expires.as_ref() {
+						res.set("Expires", expires);
+					}
+
+					let response = res.into_bytes();
+					return task_await!(write_async(&mut stream, &response))
+				}
+
+				headers.push(("ETag".to_owned(), etag.clone()));
+			}
+
+			let last_modified = asset.last_modified();
+
+			if status == 200 {
+				if let Some(last_modified) = last_modified {
+					headers.push(("Last-Modified".to_owned(), http::format_http_date(last_modified)));
+				}
+
+				if asset.encoded_len(Encoding::Uncompressed).is_some() {
+					headers.push(("Accept-Ranges".to_owned(), "bytes".to_owned()));
+				}
+
+				// So caches don't keep serving it after it's been unpublished
+				if let Some(expiry) = expiry {
+					headers.push(("Expires".to_owned(), http::format_http_date(expiry.at)));
+				}
+			}
+
+			// If the validator doesn't match the current representation, the client's partial
+			// copy is stale and it needs the whole thing again
+			let range_valid = request.get("If-Range").map_or(true, |if_range| {
+				if if_range.starts_with('"') || if_range.starts_with("W/") {
+					etag.as_ref().map_or(false, |etag| http::etag_matches(if_range, etag, true))
+				} else {
+					match (http::parse_http_date(if_range), last_modified) {
+						(Some(date), Some(modified)) => same_second(date, modified),
+						_ => false,
+					}
+				}
+			});
 
-		if let Some((asset, content_type)) = asset_and_content_type {
-			let encoding = encodings.first().cloned()
-				.unwrap_or(Encoding::Uncompressed);
+			let total = asset.encoded_len(Encoding::Uncompressed).map(|len| len as u64);
 
-			let content_type = content_type.as_ref().map(String::clone);
+			let range = match (range_header.filter(|_| range_valid), total) {
+				(Some(header), Some(total)) => match http::parse_ranges(header, total) {
+					Some(Ok(ranges)) => Some((ranges, total)),
+					Some(Err(())) => {
+						let content_range = format!("bytes */{}", total);
+						let mut res = new_response("HTTP/1.1 416 Range Not Satisfiable", &response_headers);
+						res.set("Content-Range", &content_range);
 
-			task_await!(send_data_async(stream, asset, encoding, content_type))
+						let response = res.into_bytes();
+						return task_await!(write_async(&mut stream, &response))
+					}
+					None => None,
+				},
+
+				_ => None,
+			};
+
+			// Loading and compressing on demand can take a while, so check before starting on the response
+			if stream.expired() {
+				return deadline_exceeded(&mut stream, &response_headers)
+			}
+
+			let response = AssetResponse {
+				request_line: format!("{} {}", request.method(), http::escape_for_log(request.uri().as_bytes())),
+				status,
+				asset,
+				encoding,
+				content_type,
+				headers,
+				debug_timer,
+				head_only: request.method() == "HEAD",
+				range,
+			};
+
+			task_await!(send_data_async(stream, response))
 		} else {
-			let response = http::Response::new("HTTP/1.1 404 File not found").into_bytes();
+			let response = new_response("HTTP/1.1 404 File not found", &response_headers).into_bytes();
 			task_await!(write_async(&mut stream, &response))
 		}
+	}
+}
+
+/// Answers 503 if nothing's been sent yet. Only tries once, since the client isn't owed any more of our time
+fn deadline_exceeded<S: Write>(stream: &mut DeadlineStream<S>, response_headers: &[(String, String)]) -> SBResult<()> {
+	let response = new_response("HTTP/1.1 503 Service Unavailable", response_headers).into_bytes();
+	let _ = stream.get_mut().write(&response);
+	Err(io::Error::new(io::ErrorKind::TimedOut, DeadlineExceeded).into())
+}
 
-		// println!("[stream {:?}] stream close", thread::current().id());
+/// Replaces `$name` with the variable of that name. A `$` followed by anything else is left as it is
+fn expand_variables(value: &str, variables: &[(&str, String)]) -> String {
+	let mut expanded = String::with_capacity(value.len());
+	let mut rest = value;
+
+	while let Some(dollar) = rest.find('$') {
+		expanded.push_str(&rest[..dollar]);
+		rest = &rest[dollar + 1..];
+
+		let name_len = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+
+		match variables.iter().find(|(name, _)| *name == &rest[..name_len]) {
+			Some((_, variable)) => {
+				expanded.push_str(variable);
+				rest = &rest[name_len..];
+			}
+
+			None => expanded.push('$'),
+		}
 	}
+
+	expanded.push_str(rest);
+	expanded
 }
 
-fn send_data_async<S>(mut stream: S, data: Arc<dyn MappedAsset>, encoding: Encoding, content_type: Option<String>)
-	-> impl Generator<Yield=(), Return=SBResult<()>>
+/// Drops the port from a Host header, including bracketed ipv6 addresses
+fn strip_port(host: &str) -> &str {
+	if host.starts_with('[') {
+		return host.split(']').next().map_or(host, |h| &h[1..])
+	}
+
+	host.split(':').next().unwrap_or(host)
+}
+
+/// Adds to any Vary already in `headers`, since responses can only have one
+fn add_vary(headers: &mut Vec<(String, String)>, field: &str) {
+	match headers.iter_mut().find(|(key, _)| key.eq_ignore_ascii_case("Vary")) {
+		Some((_, value)) => {
+			value.push_str(", ");
+			value.push_str(field);
+		}
+
+		None => headers.push(("Vary".to_owned(), field.to_owned())),
+	}
+}
+
+/// A response with the headers every response from this server gets
+fn new_response<'a>(status: &'a str, response_headers: &'a [(String, String)]) -> http::Response<'a> {
+	let mut res = http::Response::new(status);
+	for (key, value) in response_headers {
+		res.set(key, value);
+	}
+
+	res
+}
+
+fn entity_tag(asset: &dyn MappedAsset, encoding: Encoding, kind: Option<ETagKind>) -> Option<String> {
+	use std::time::UNIX_EPOCH;
+
+	// Different encodings are different representations, so need different strong tags
+	let strong = || {
+		let hash = asset.content_hash()?;
+		match encoding {
+			Encoding::Uncompressed => Some(format!("\"{}\"", &hash[..32])),
+			_ => Some(format!("\"{}-{}\"", &hash[..32], encoding.content_coding())),
+		}
+	};
+
+	let weak = || {
+		let modified = asset.last_modified()?.duration_since(UNIX_EPOCH).ok()?;
+		let len = asset.encoded_len(Encoding::Uncompressed)?;
+		Some(format!("W/\"{:x}-{:x}\"", modified.as_secs(), len))
+	};
+
+	match kind {
+		Some(ETagKind::Strong) => strong(),
+		Some(ETagKind::Weak) => weak(),
+		None => strong().or_else(weak),
+	}
+}
+
+fn same_second(a: SystemTime, b: SystemTime) -> bool {
+	use std::time::UNIX_EPOCH;
+
+	let secs = |t: SystemTime| t.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
+	secs(a).is_some() && secs(a) == secs(b)
+}
+
+struct AssetResponse {
+	/// Method and uri, for logging
+	request_line: String,
+	status: u16,
+	asset: Arc<dyn MappedAsset>,
+	encoding: Encoding,
+	content_type: Option<String>,
+	headers: Vec<(String, String)>,
+	debug_timer: Option<Instant>,
+	/// Send only the head, with the length the body would've had
+	head_only: bool,
+	/// Inclusive byte ranges of the uncompressed data, and its total length
+	range: Option<(Vec<(u64, u64)>, u64)>,
+}
+
+/// A multipart/byteranges body, for requests asking for more than one range
+struct Multipart {
+	boundary: String,
+	/// Each part's headers, and the inclusive range it holds
+	parts: Vec<(String, u64, u64)>,
+}
+
+impl Multipart {
+	fn new(ranges: &[(u64, u64)], total: u64, content_type: Option<&str>) -> Multipart {
+		// Only has to be unlikely to turn up in the file
+		let now = SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap_or_default();
+		let boundary = format!("spiderbutter-{:x}{:08x}", now.as_secs(), now.subsec_nanos());
+
+		let parts = ranges.iter().map(|&(start, end)| {
+			let mut head = format!("\r\n--{}\r\n", boundary);
+			if let Some(content_type) = content_type {
+				head.push_str(&format!("Content-Type: {}\r\n", content_type));
+			}
+
+			head.push_str(&format!("Content-Range: bytes {}-{}/{}\r\n\r\n", start, end, total));
+			(head, start, end)
+		}).collect();
+
+		Multipart { boundary, parts }
+	}
+
+	fn content_type(&self) -> String {
+		format!("multipart/byteranges; boundary={}", self.boundary)
+	}
+
+	fn tail(&self) -> String {
+		format!("\r\n--{}--\r\n", self.boundary)
+	}
+
+	fn len(&self) -> u64 {
+		let parts: u64 = self.parts.iter().map(|(head, start, end)| head.len() as u64 + end - start + 1).sum();
+		parts + self.tail().len() as u64
+	}
+
+	/// Reads each range straight out of the asset, between the part headers
+	fn open(&self, asset: &dyn MappedAsset) -> SBResult<Box<dyn Read>> {
+		let mut reader: Box<dyn Read> = Box::new(std::io::empty());
+
+		for (head, start, end) in self.parts.iter() {
+			let part = asset.open_range(*start, end - start + 1)?;
+			reader = Box::new(reader.chain(std::io::Cursor::new(head.clone().into_bytes())).chain(part));
+		}
+
+		Ok(Box::new(reader.chain(std::io::Cursor::new(self.tail().into_bytes()))))
+	}
+}
+
+fn send_data_async<S>(mut stream: S, response: AssetResponse) -> impl Generator<Yield=(), Return=SBResult<()>>
 	where S: Read + Write + TcpStreamExt + 'static {
 
 	static move || {
-		let body = data.get_encoding(encoding)?;
-		let mut res = http::Response::new("HTTP/1.1 200 OK");
+		let AssetResponse {request_line, mut status, asset, encoding, mut content_type, mut headers, debug_timer, head_only, range} = response;
 
-		match encoding {
-			Encoding::Uncompressed => {},
-			Encoding::Gzip => res.set("Content-Encoding", "gzip"),
-			Encoding::Deflate => res.set("Content-Encoding", "deflate"),
+		// Has to be checked before the asset is touched
+		let cache_status = asset.cache_status();
+
+		// Better to fail loudly than hand out a file that's been corrupted or tampered with
+		if verify_integrity() {
+			if let Err(e) = asset.verify() {
+				let response = http::Response::new("HTTP/1.1 500 Internal Server Error").into_bytes();
+				let _ = task_await!(write_async(&mut stream, &response));
+				bail!("Integrity check failed for {}: {}", request_line, e)
+			}
 		}
 
+		// One range is sent as is, more go in a multipart body
+		let (range, multipart) = match range {
+			Some((ranges, total)) if ranges.len() == 1 => (Some((ranges[0].0, ranges[0].1, total)), None),
+			Some((ranges, total)) => (None, Some(Multipart::new(&ranges, total, content_type.as_ref().map(String::as_str)))),
+			None => (None, None),
+		};
+
+		// Open the stream or encode the body up front so errors don't produce a partial response
+		let opened = if head_only {
+			Ok((Vec::new(), None))
+		} else if let Some((start, end, _)) = range {
+			asset.open_range(start, end - start + 1).map(|reader| (Vec::new(), Some(reader)))
+		} else if let Some(multipart) = multipart.as_ref() {
+			multipart.open(&*asset).map(|reader| (Vec::new(), Some(reader)))
+		} else {
+			match asset.get_stream(encoding) {
+				Some(reader) => reader.map(|reader| (Vec::new(), Some(reader))),
+				None => asset.get_encoding(encoding).map(|body| (body, None)),
+			}
+		};
+
+		let (body, reader) = match opened {
+			Ok(opened) => opened,
+			Err(e) => {
+				let response = http::Response::new("HTTP/1.1 500 Internal Server Error").into_bytes();
+				let _ = task_await!(write_async(&mut stream, &response));
+				return Err(e)
+			}
+		};
+
+		let content_length = if let Some((start, end, total)) = range {
+			status = 206;
+			headers.push(("Content-Range".to_owned(), format!("bytes {}-{}/{}", start, end, total)));
+			Some((end - start + 1) as usize)
+		} else if let Some(multipart) = multipart.as_ref() {
+			status = 206;
+			content_type = Some(multipart.content_type());
+			Some(multipart.len() as usize)
+		} else if head_only || reader.is_some() {
+			asset.encoded_len(encoding)
+		} else {
+			Some(body.len())
+		};
+
+		// Tiny streamed assets are cheaper to read up front, so they can go out in one write
+		let (body, reader) = match reader {
+			Some(mut reader) if content_length.map_or(false, |len| len <= COALESCE_MAX_SIZE) => {
+				let mut body = Vec::with_capacity(content_length.unwrap_or(0));
+				reader.read_to_end(&mut body)?;
+				(body, None)
+			}
+
+			reader => (body, reader),
+		};
+
+		if let Some(timer) = debug_timer {
+			let elapsed_ms = timer.elapsed().as_secs_f64() * 1000.0;
+
+			headers.push(("X-SB-Cache".to_owned(), cache_status.to_owned()));
+			headers.push(("X-SB-Encoding".to_owned(), encoding.content_coding().to_owned()));
+			headers.push(("X-SB-Time".to_owned(), format!("{:.2}ms", elapsed_ms)));
+
+			if let Some(hash) = asset.content_hash() {
+				headers.push(("X-SB-Hash".to_owned(), hash));
+			}
+		}
+
+		if let Some(content_length) = content_length {
+			headers.push(("Content-Length".to_owned(), content_length.to_string()));
+		}
+
+		let status_line = http::status_line(status);
+		let mut res = http::Response::new(&status_line);
+
+		if encoding != Encoding::Uncompressed {
+			res.set("Content-Encoding", encoding.content_coding());
+		}
+
+		// Types come from the mappings or known extensions, so browsers shouldn't second guess them
 		if let Some(content_type) = content_type.as_ref() {
 			res.set("Content-Type", content_type);
+			res.set("X-Content-Type-Options", "nosniff");
+		}
+
+		for (key, value) in headers.iter() {
+			res.set(key, value);
 		}
 
 		let response_head = res.into_bytes();
+		let head_len = response_head.len() as u64;
 
-		task_await!(write_async(&mut stream, &response_head))?;
-		task_await!(write_async(&mut stream, &body))?;
+		let mut stream = CountingStream::new(stream);
 
-		Ok(())
+		let result = if let Some(reader) = reader {
+			// Dropping the reader on failure stops any further reading or compression for this client
+			match task_await!(write_async(&mut stream, &response_head)) {
+				Ok(()) => task_await!(copy_async(&mut stream, reader)),
+				result => result,
+			}
+		} else if head_only {
+			task_await!(write_async(&mut stream, &response_head))
+		} else if response_head.len() + body.len() <= COALESCE_MAX_SIZE {
+			let mut response = response_head;
+			response.extend_from_slice(&body);
+			task_await!(write_async(&mut stream, &response))
+		} else if body.len() <= VECTORED_MAX_SIZE {
+			task_await!(write_vectored_async(&mut stream, &response_head, &body))
+		} else {
+			match task_await!(write_async(&mut stream, &response_head)) {
+				Ok(()) => task_await!(write_async(&mut stream, &body)),
+				result => result,
+			}
+		};
+
+		let body_sent = stream.sent().saturating_sub(head_len);
+		let body_expected = match content_length {
+			_ if head_only => 0,
+			Some(len) => len as u64,
+			None => body_sent,
+		};
+
+		TRANSFER_STATS.record(body_sent, body_expected, result.is_err());
+
+		match result {
+			Err(ref e) if is_disconnect(e) => {
+				info!("{} aborted by client after {}/{} bytes: {}", request_line, body_sent, body_expected, e);
+				Ok(())
+			}
+
+			Err(e) if is_deadline_exceeded(&e) => {
+				let context = format!("{} after {}/{} bytes", request_line, body_sent, body_expected);
+				Err(e.context(context).into())
+			}
+
+			result => result,
+		}
 	}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn miss_cache_entries_expire() {
+		let misses = MissCache::default();
+		let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+		let key = MissCache::key(Some("example.com"), "/wp-login.php");
+
+		misses.insert(key.clone(), start);
+		assert!(misses.contains(&key, start + MISS_CACHE_TTL - Duration::from_secs(1)));
+		assert!(!misses.contains(&key, start + MISS_CACHE_TTL));
+		assert!(!misses.contains(&MissCache::key(None, "/wp-login.php"), start));
+
+		// A clock that's been set back doesn't keep entries alive
+		assert!(!misses.contains(&key, start - Duration::from_secs(1)));
+	}
+}
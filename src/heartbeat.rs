@@ -0,0 +1,79 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Threads that should always be getting through their work, like fileserver workers, each beat one of these.
+/// The watchdog is only fed while none of them have gone quiet, so systemd restarts a server that's stuck
+/// rather than one that's just busy reloading
+#[derive(Clone)]
+pub struct Heartbeats {
+	start: Instant,
+	beats: Arc<Mutex<Vec<Arc<AtomicU64>>>>,
+}
+
+impl Heartbeats {
+	pub fn new() -> Heartbeats {
+		Heartbeats { start: Instant::now(), beats: Arc::new(Mutex::new(Vec::new())) }
+	}
+
+	/// A new heartbeat for a thread to keep beating for as long as it runs
+	pub fn register(&self) -> Heartbeat {
+		let last = Arc::new(AtomicU64::new(0));
+		self.beats.lock().unwrap_or_else(|e| e.into_inner()).push(last.clone());
+
+		let heartbeat = Heartbeat { start: self.start, last, beats: self.beats.clone() };
+		heartbeat.beat();
+		heartbeat
+	}
+
+	/// Whether any thread has gone longer than `timeout` without a beat
+	pub fn stalled(&self, timeout: Duration) -> bool {
+		let now = self.start.elapsed().as_millis() as u64;
+
+		self.beats.lock().unwrap_or_else(|e| e.into_inner()).iter()
+			.any(|last| now.saturating_sub(last.load(Ordering::Relaxed)) > timeout.as_millis() as u64)
+	}
+}
+
+pub struct Heartbeat {
+	start: Instant,
+	/// Milliseconds since `start`
+	last: Arc<AtomicU64>,
+	beats: Arc<Mutex<Vec<Arc<AtomicU64>>>>,
+}
+
+impl Heartbeat {
+	pub fn beat(&self) {
+		self.last.store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+	}
+}
+
+/// Threads that have finished, like workers during shutdown, aren't stuck
+impl Drop for Heartbeat {
+	fn drop(&mut self) {
+		self.beats.lock().unwrap_or_else(|e| e.into_inner())
+			.retain(|last| !Arc::ptr_eq(last, &self.last));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn quiet_threads_stall() {
+		let heartbeats = Heartbeats::new();
+		assert!(!heartbeats.stalled(Duration::from_millis(0)));
+
+		let busy = heartbeats.register();
+		let quiet = heartbeats.register();
+		std::thread::sleep(Duration::from_millis(20));
+
+		busy.beat();
+		assert!(heartbeats.stalled(Duration::from_millis(10)));
+		assert!(!heartbeats.stalled(Duration::from_secs(10)));
+
+		drop(quiet);
+		assert!(!heartbeats.stalled(Duration::from_millis(10)));
+	}
+}
@@ -1,10 +1,34 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH, Duration};
 use crate::SBResult;
 
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Room for the method, version and spaces around a request target
+const MAX_REQUEST_LINE_OVERHEAD: usize = 32;
+
+/// Enough for any real client. Range sets past these get the whole representation instead
+const MAX_RANGES: usize = 16;
+const MAX_RANGE_OVERLAPS: usize = 2;
+
+/// Ranges closer together than this are sent as one part, since each part costs about this much in headers
+const RANGE_COALESCE_GAP: u64 = 80;
+
+/// Every route answers the same methods, for `Allow` in answers to OPTIONS
+pub const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// What `Request::parse` accepts. Anything else is answered with 501
+const METHODS: &[&str] = &["GET", "HEAD", "OPTIONS"];
+
 #[derive(Debug)]
 pub struct Request<'a> {
-	uri: &'a str,
-	fields: HashMap<&'a str, &'a str>,
+	method: &'a str,
+	/// Always ascii. Raw bytes past ascii that some clients send are percent-encoded
+	uri: Cow<'a, str>,
+	/// Values are left as bytes, since clients can send anything in them. Names are case insensitive
+	fields: Vec<(&'a str, &'a [u8])>,
 }
 
 #[derive(Debug)]
@@ -13,51 +37,255 @@ pub struct Response<'a> {
 	fields: HashMap<&'a str, &'a str>,
 }
 
+/// Why a request couldn't be understood, and the status to answer it with
+#[derive(Debug)]
+pub struct RequestError {
+	pub status: u16,
+	message: String,
+}
+
+impl std::fmt::Display for RequestError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(&self.message)
+	}
+}
+
+impl std::error::Error for RequestError {}
+
+fn request_error(status: u16, message: impl Into<String>) -> failure::Error {
+	RequestError { status, message: message.into() }.into()
+}
+
 impl<'a> Request<'a> {
-	pub fn parse(data: &'a str) -> SBResult<Request<'a>> {
-		let header_end = data.split("\r\n\r\n").next().unwrap();
-		let mut lines = header_end.split_terminator("\r\n");
-		let reqline = lines.next().unwrap_or("");
+	pub fn parse(data: &'a [u8]) -> SBResult<Request<'a>> {
+		Self::parse_with_methods(data, METHODS)
+	}
 
-		let mut reqlineels = reqline.split_whitespace();
+	/// Like `parse`, for servers that understand methods other than the fileserver's
+	pub fn parse_with_methods(data: &'a [u8], methods: &[&'static str]) -> SBResult<Request<'a>> {
+		let header_end = find(data, b"\r\n\r\n").map_or(data, |end| &data[..end]);
+		let mut lines = split_lines(header_end).into_iter();
+		let reqline = lines.next().unwrap_or(b"");
 
-		if reqlineels.next().unwrap_or("") != "GET" {
-			failure::bail!("Non-GET requests not supported");
-		}
+		let mut reqlineels = reqline.split(u8::is_ascii_whitespace)
+			.filter(|el| !el.is_empty());
 
-		let requri = reqlineels.next().unwrap_or("");
-		let version = reqlineels.next().unwrap_or("");
+		// Methods are case sensitive, so `get` is as unknown as `PATCH`
+		let method = match reqlineels.next() {
+			Some(method) => match methods.iter().find(|known| known.as_bytes() == method) {
+				Some(known) => *known,
+				None => return Err(request_error(501, format!("Only {} requests are supported", methods.join(", ")))),
+			},
+			None => return Err(request_error(400, "Empty request line")),
+		};
 
-		if version != "HTTP/1.0" && version != "HTTP/1.1" {
-			failure::bail!("Invalid HTTP version");
+		let requri = reqlineels.next().unwrap_or(b"");
+
+		// Any HTTP/1.x can be answered as 1.1, other major versions can't be answered at all
+		let minor_version = match reqlineels.next() {
+			Some(&[b'H', b'T', b'T', b'P', b'/', b'1', b'.', minor]) if minor.is_ascii_digit() => minor - b'0',
+			Some(&[b'H', b'T', b'T', b'P', b'/', major, b'.', minor]) if major.is_ascii_digit() && minor.is_ascii_digit() =>
+				return Err(request_error(505, "Only HTTP/1.x is supported")),
+			_ => return Err(request_error(400, "Invalid HTTP version")),
+		};
+
+		if requri.is_empty() || reqlineels.next().is_some() {
+			return Err(request_error(400, "Malformed request line"));
 		}
 
-		let mut fields = HashMap::new();
+		// Absolute-form targets are mostly sent to proxies, but have to be accepted anyway.
+		// Their host wins over any Host field
+		let (authority, requri) = split_absolute_form(requri);
+
+		let requri = encode_uri(requri)
+			.ok_or_else(|| request_error(400, format!("Malformed request line {}", escape_for_log(reqline))))?;
+
+		let mut fields = Vec::new();
 
 		for line in lines {
-			let mut line = line.splitn(2, ":").map(|s| s.trim());
-			let key = line.next().unwrap();
-			let value = match line.next() {
-				Some(v) => v,
-				None => continue
+			// Folded lines are obsolete, and can smuggle fields past anything that doesn't unfold them the same way
+			if line.starts_with(b" ") || line.starts_with(b"\t") {
+				return Err(request_error(400, "Folded header fields aren't supported"));
+			}
+
+			let colon = match line.iter().position(|&b| b == b':') {
+				Some(colon) => colon,
+				None => continue,
 			};
 
-			fields.insert(key, value);
+			// Whitespace before the colon has to be rejected, since it's been used to sneak fields past proxies
+			let key = match std::str::from_utf8(&line[..colon]) {
+				Ok(key) if !key.is_empty() && !key.bytes().any(|b| b.is_ascii_whitespace()) => key,
+				Ok(_) => return Err(request_error(400, format!("Invalid header field {}", escape_for_log(line)))),
+				// Field names are tokens, so a name that isn't utf8 isn't one we'd look up
+				Err(_) => continue,
+			};
+
+			if key.eq_ignore_ascii_case("Host") && fields.iter().any(|&(existing, _): &(&str, _)| existing.eq_ignore_ascii_case("Host")) {
+				return Err(request_error(400, "More than one Host field"));
+			}
+
+			fields.push((key, trim_bytes(&line[colon+1..])));
+		}
+
+		if let Some(authority) = authority {
+			fields.retain(|&(key, _)| !key.eq_ignore_ascii_case("Host"));
+			fields.push(("Host", authority));
+		}
+
+		if minor_version >= 1 && !fields.iter().any(|&(key, _)| key.eq_ignore_ascii_case("Host")) {
+			return Err(request_error(400, "HTTP/1.1 requests need a Host field"));
 		}
 
 		Ok(Request {
+			method,
 			uri: requri,
 			fields: fields,
 		})
 	}
 
+	pub fn method(&self) -> &str {
+		self.method
+	}
+
 	pub fn uri(&self) -> &str {
-		self.uri
+		&self.uri
 	}
 
+	/// Fields that aren't valid utf8 are treated as missing
 	pub fn get(&self, key: &str) -> Option<&str> {
-		self.fields.get(&key).cloned()
+		self.fields.iter().rev()
+			.find(|(name, _)| name.eq_ignore_ascii_case(key))
+			.and_then(|(_, value)| std::str::from_utf8(value).ok())
+	}
+}
+
+/// Makes request data safe to print. Anything but printable ascii becomes `\xNN`, and `\` becomes `\\`,
+/// so control characters can't mess with terminals or split log lines, and the original bytes can be recovered
+pub fn escape_for_log(bytes: &[u8]) -> String {
+	let mut escaped = String::with_capacity(bytes.len());
+
+	for &byte in bytes {
+		match byte {
+			b'\\' => escaped.push_str("\\\\"),
+			0x20..=0x7e => escaped.push(byte as char),
+			_ => escaped.push_str(&format!("\\x{:02x}", byte)),
+		}
+	}
+
+	escaped
+}
+
+/// Percent-encodes any bytes past ascii, so they can be decoded into a path like any other escape.
+/// None for control characters, which can't be anywhere in a uri
+fn encode_uri(uri: &[u8]) -> Option<Cow<'_, str>> {
+	if uri.iter().any(|&b| b < 0x20 || b == 0x7f) {
+		return None
+	}
+
+	if uri.is_ascii() {
+		return std::str::from_utf8(uri).ok().map(Cow::Borrowed)
+	}
+
+	let mut encoded = String::with_capacity(uri.len() * 3);
+
+	for &byte in uri {
+		if byte.is_ascii() {
+			encoded.push(byte as char);
+		} else {
+			encoded.push_str(&format!("%{:02X}", byte));
+		}
+	}
+
+	Some(Cow::Owned(encoded))
+}
+
+/// Splits `http://host/path` into the host and `/path`. Targets already in origin-form come back as they are
+fn split_absolute_form(uri: &[u8]) -> (Option<&[u8]>, &[u8]) {
+	let scheme_len = ["http://", "https://"].iter()
+		.find(|scheme| uri.len() >= scheme.len() && uri[..scheme.len()].eq_ignore_ascii_case(scheme.as_bytes()))
+		.map(|scheme| scheme.len());
+
+	let rest = match scheme_len {
+		Some(len) => &uri[len..],
+		None => return (None, uri),
+	};
+
+	let authority_end = rest.iter().position(|&b| b == b'/' || b == b'?' || b == b'#').unwrap_or(rest.len());
+	let (authority, path) = rest.split_at(authority_end);
+
+	// `http://host` and `http://host?query` still mean the root
+	match path.first() {
+		Some(b'/') => (Some(authority), path),
+		_ => (Some(authority), b"/"),
+	}
+}
+
+/// Checks as much of a request head as has arrived against size limits, so oversized requests get
+/// a 414 or 431 as soon as they're over rather than being cut off. Returns the length of the head
+/// once all of it is there
+pub fn check_head(data: &[u8], max_uri: usize, max_fields: usize) -> SBResult<Option<usize>> {
+	let line_end = find(data, b"\r\n");
+	let request_line = &data[..line_end.unwrap_or(data.len())];
+
+	// The method and version are short, so a request line that's still going past this can't be under the limit
+	let uri_len = match line_end {
+		Some(_) => request_line.split(u8::is_ascii_whitespace).filter(|el| !el.is_empty()).nth(1).map_or(0, <[u8]>::len),
+		None => request_line.len().saturating_sub(MAX_REQUEST_LINE_OVERHEAD),
+	};
+
+	if uri_len > max_uri {
+		return Err(request_error(414, format!("Request target is over {} bytes", max_uri)))
+	}
+
+	let line_end = match line_end {
+		Some(end) => end,
+		None => return Ok(None),
+	};
+
+	let head_end = find(data, b"\r\n\r\n").map(|end| end + 4);
+
+	let fields_len = head_end.unwrap_or(data.len()) - line_end;
+	if fields_len > max_fields {
+		return Err(request_error(431, format!("Header fields are over {} bytes", max_fields)))
+	}
+
+	Ok(head_end)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+	haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Splits on `\r\n`, without a trailing empty line
+fn split_lines(data: &[u8]) -> Vec<&[u8]> {
+	let mut lines = Vec::new();
+	let mut rest = data;
+
+	while let Some(end) = find(rest, b"\r\n") {
+		lines.push(&rest[..end]);
+		rest = &rest[end+2..];
+	}
+
+	if !rest.is_empty() {
+		lines.push(rest);
 	}
+
+	lines
+}
+
+fn trim_bytes(mut bytes: &[u8]) -> &[u8] {
+	while let Some((first, rest)) = bytes.split_first() {
+		if !first.is_ascii_whitespace() { break }
+		bytes = rest;
+	}
+
+	while let Some((last, rest)) = bytes.split_last() {
+		if !last.is_ascii_whitespace() { break }
+		bytes = rest;
+	}
+
+	bytes
 }
 
 
@@ -73,8 +301,18 @@ impl<'a> Response<'a> {
 		let _ = self.fields.insert(key, value);
 	}
 
+	/// Adds Date, which every response needs, and Connection: close, since connections only ever get one response
 	pub fn into_bytes(&self) -> Vec<u8> {
-		let fields = self.fields.iter().map(|(k, v)| format!("{}: {}", k, v));
+		let date = format_http_date(SystemTime::now());
+		let standard = [("Date", date.as_str()), ("Connection", "close")];
+
+		let standard = standard.iter().cloned()
+			.filter(|(k, _)| !self.fields.contains_key(k));
+
+		let fields = standard
+			.chain(self.fields.iter().map(|(k, v)| (*k, *v)))
+			.map(|(k, v)| format!("{}: {}", k, v));
+
 		let mut response_str = std::iter::once(self.status_line.to_string())
 			.chain(fields)
 			.fold(String::new(), |mut acc, s| {
@@ -87,3 +325,707 @@ impl<'a> Response<'a> {
 		response_str.into_bytes()
 	}
 }
+
+
+/// Builds a full status line for a status code, e.g. `HTTP/1.1 404 Not Found`
+pub fn status_line(code: u16) -> String {
+	let reason = match code {
+		200 => "OK",
+		204 => "No Content",
+		206 => "Partial Content",
+		301 => "Moved Permanently",
+		302 => "Found",
+		303 => "See Other",
+		304 => "Not Modified",
+		307 => "Temporary Redirect",
+		308 => "Permanent Redirect",
+		400 => "Bad Request",
+		401 => "Unauthorized",
+		403 => "Forbidden",
+		404 => "Not Found",
+		405 => "Method Not Allowed",
+		410 => "Gone",
+		412 => "Precondition Failed",
+		413 => "Payload Too Large",
+		414 => "URI Too Long",
+		415 => "Unsupported Media Type",
+		416 => "Range Not Satisfiable",
+		425 => "Too Early",
+		429 => "Too Many Requests",
+		431 => "Request Header Fields Too Large",
+		451 => "Unavailable For Legal Reasons",
+		500 => "Internal Server Error",
+		501 => "Not Implemented",
+		503 => "Service Unavailable",
+		505 => "HTTP Version Not Supported",
+		_ => "",
+	};
+
+	format!("HTTP/1.1 {} {}", code, reason)
+}
+
+
+/// Resolves the path part of a request target into a decoded, canonical form for routing, collapsing
+/// duplicate slashes and `.`/`..` segments and dropping any query or fragment.
+/// Returns None if the path would escape the root, is badly encoded, or hides separators behind percent-encoding
+pub fn normalize_path(uri: &str) -> Option<String> {
+	let path = uri.split(|c| c == '?' || c == '#').next().unwrap_or("");
+	if !path.starts_with('/') { return None }
+
+	let mut segments: Vec<String> = Vec::new();
+	let mut trailing_slash = false;
+
+	for segment in path[1..].split('/') {
+		let lower = segment.to_ascii_lowercase();
+
+		if lower.contains("%2f") || lower.contains("%5c") || lower.contains("%00") || segment.contains('\\') {
+			return None
+		}
+
+		// Dot segments can be smuggled past naive checks as `%2e`
+		let segment = percent_decode(segment)?;
+
+		match segment.as_str() {
+			"" | "." => trailing_slash = true,
+			".." => {
+				segments.pop()?;
+				trailing_slash = true;
+			}
+
+			_ => {
+				segments.push(segment);
+				trailing_slash = false;
+			}
+		}
+	}
+
+	let mut normalized = String::with_capacity(path.len());
+	for segment in segments.iter() {
+		normalized.push('/');
+		normalized.push_str(segment);
+	}
+
+	if trailing_slash || normalized.is_empty() {
+		normalized.push('/');
+	}
+
+	Some(normalized)
+}
+
+
+/// Decodes `%XX` escapes. Returns None for truncated or invalid escapes, or if the result isn't utf8
+pub fn percent_decode(s: &str) -> Option<String> {
+	let bytes = s.as_bytes();
+	let mut decoded = Vec::with_capacity(bytes.len());
+	let mut i = 0;
+
+	while i < bytes.len() {
+		if bytes[i] == b'%' {
+			let hex = bytes.get(i+1..i+3)?;
+			if !hex.iter().all(u8::is_ascii_hexdigit) { return None }
+
+			let hex = std::str::from_utf8(hex).ok()?;
+			decoded.push(u8::from_str_radix(hex, 16).ok()?);
+			i += 3;
+		} else {
+			decoded.push(bytes[i]);
+			i += 1;
+		}
+	}
+
+	String::from_utf8(decoded).ok()
+}
+
+
+/// Checks an `If-Match`/`If-None-Match` style list against an entity tag.
+/// Strong comparison requires both tags to be strong, weak comparison ignores the `W/` prefix
+pub fn etag_matches(header: &str, etag: &str, strong: bool) -> bool {
+	let opaque = |tag: &str| tag.trim_start_matches("W/").to_owned();
+	let is_weak = |tag: &str| tag.starts_with("W/");
+
+	header.split(',')
+		.map(str::trim)
+		.any(|candidate| {
+			if candidate == "*" { return true }
+
+			if strong {
+				!is_weak(candidate) && !is_weak(etag) && candidate == etag
+			} else {
+				opaque(candidate) == opaque(etag)
+			}
+		})
+}
+
+
+/// How much an `Accept` style header wants `media_type`, from the most specific entry covering it.
+/// 0 if nothing does. A missing header accepts anything, so callers should check for that first
+pub fn accept_quality(header: &str, media_type: &str) -> f32 {
+	let main_type = media_type.split('/').next().unwrap_or("");
+
+	let mut best: Option<(u8, f32)> = None;
+
+	for entry in header.split(',') {
+		let mut params = entry.split(';').map(str::trim);
+		let range = params.next().unwrap_or("").to_ascii_lowercase();
+
+		let specificity = if range == media_type {
+			2
+		} else if range.ends_with("/*") && range[..range.len()-2] == *main_type {
+			1
+		} else if range == "*/*" {
+			0
+		} else {
+			continue
+		};
+
+		let quality = params
+			.filter_map(|param| param.strip_prefix("q=").or_else(|| param.strip_prefix("Q=")))
+			.filter_map(|q| q.parse::<f32>().ok())
+			// Anything else isn't a qvalue, and NaN would make every comparison with it false
+			.filter(|q| (0.0..=1.0).contains(q))
+			.next()
+			.unwrap_or(1.0);
+
+		if best.map_or(true, |(best_specificity, _)| specificity > best_specificity) {
+			best = Some((specificity, quality));
+		}
+	}
+
+	best.map_or(0.0, |(_, quality)| quality)
+}
+
+
+/// Parses a `bytes=` range set against a representation of `total` bytes.
+/// Returns None if the header should be ignored, Some(Err) if no range is satisfiable,
+/// otherwise the inclusive byte ranges, sorted, with overlapping and nearby ranges merged.
+///
+/// Range sets that look like an attempt to get more out of us than the file itself are ignored,
+/// so the client gets the whole thing once instead
+pub fn parse_ranges(header: &str, total: u64) -> Option<Result<Vec<(u64, u64)>, ()>> {
+	let spec = header.trim().strip_prefix("bytes=")?;
+
+	let specs: Vec<&str> = spec.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+	if specs.is_empty() || specs.len() > MAX_RANGES { return None }
+
+	let mut ranges = Vec::with_capacity(specs.len());
+
+	for spec in specs {
+		// Unsatisfiable ranges are dropped, as long as some other range is satisfiable
+		if let Some(range) = parse_range_spec(spec, total)? {
+			ranges.push(range);
+		}
+	}
+
+	if ranges.is_empty() {
+		return Some(Err(()))
+	}
+
+	let requested: u64 = ranges.iter().map(|&(start, end)| end - start + 1).sum();
+	if requested > total { return None }
+
+	ranges.sort();
+
+	let overlaps = ranges.windows(2).filter(|pair| pair[1].0 <= pair[0].1).count();
+	if overlaps > MAX_RANGE_OVERLAPS { return None }
+
+	let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+
+	for (start, end) in ranges {
+		match merged.last_mut() {
+			// Sending the gap is cheaper than the part headers it would take to skip it
+			Some(last) if start <= last.1.saturating_add(RANGE_COALESCE_GAP + 1) => last.1 = last.1.max(end),
+			_ => merged.push((start, end)),
+		}
+	}
+
+	Some(Ok(merged))
+}
+
+/// Parses one `first-last` range. None if it's malformed, Some(None) if it's unsatisfiable
+fn parse_range_spec(spec: &str, total: u64) -> Option<Option<(u64, u64)>> {
+	let (start, end) = spec.split_at(spec.find('-')?);
+	let (start, end) = (start.trim(), end[1..].trim());
+
+	let range = match (start.is_empty(), end.is_empty()) {
+		// -n, the last n bytes
+		(true, false) => {
+			let suffix_len: u64 = end.parse().ok()?;
+			if suffix_len == 0 || total == 0 { return Some(None) }
+			(total.saturating_sub(suffix_len), total - 1)
+		}
+
+		// n-
+		(false, true) => (start.parse().ok()?, total.saturating_sub(1)),
+
+		// n-m
+		(false, false) => {
+			let (start, end): (u64, u64) = (start.parse().ok()?, end.parse().ok()?);
+			if end < start { return None }
+			(start, end.min(total.saturating_sub(1)))
+		}
+
+		(true, true) => return None,
+	};
+
+	if range.0 >= total {
+		return Some(None)
+	}
+
+	Some(Some(range))
+}
+
+/// Formats a time as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn format_http_date(time: SystemTime) -> String {
+	let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+	let (days, secs_of_day) = (secs / 86400, secs % 86400);
+	let (year, month, day) = civil_from_days(days as i64);
+
+	// 1970-01-01 was a Thursday
+	let weekday = WEEKDAYS[((days + 4) % 7) as usize];
+
+	format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+		weekday, day, MONTHS[month as usize - 1], year,
+		secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60)
+}
+
+/// Year, month, day, hour and minute, in UTC
+pub fn civil_time(time: SystemTime) -> (i64, u32, u32, u32, u32) {
+	let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+	let (days, secs_of_day) = (secs / 86400, secs % 86400);
+	let (year, month, day) = civil_from_days(days as i64);
+
+	(year, month, day, (secs_of_day / 3600) as u32, (secs_of_day / 60 % 60) as u32)
+}
+
+/// Parses an IMF-fixdate. Obsolete date formats aren't supported
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+	let mut parts = date.split_whitespace().skip(1);
+
+	let day: u32 = parts.next()?.parse().ok()?;
+	let month_name = parts.next()?;
+	let month = MONTHS.iter().position(|&m| m == month_name)? as u32 + 1;
+	let year: i64 = parts.next()?.parse().ok()?;
+
+	let mut time = parts.next()?.split(':').map(|p| p.parse::<u64>().ok());
+	let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+
+	if parts.next()? != "GMT" { return None }
+
+	time_from_civil(year, month, day, hours, minutes, seconds)
+}
+
+/// Parses a UTC `YYYY-MM-DD`, optionally followed by `THH:MM` or `THH:MM:SS`, for config files
+pub fn parse_iso_date(date: &str) -> Option<SystemTime> {
+	let date = date.trim_end_matches('Z');
+	let mut parts = date.splitn(2, 'T');
+
+	let mut ymd = parts.next()?.split('-');
+	let year: i64 = ymd.next()?.parse().ok()?;
+	let month: u32 = ymd.next()?.parse().ok()?;
+	let day: u32 = ymd.next()?.parse().ok()?;
+
+	if ymd.next().is_some() {
+		return None
+	}
+
+	let (hours, minutes, seconds) = match parts.next() {
+		Some(time) => {
+			let mut time = time.split(':').map(|p| p.parse::<u64>().ok());
+			let (hours, minutes) = (time.next()??, time.next()??);
+			let seconds = time.next().unwrap_or(Some(0))?;
+			(hours, minutes, seconds)
+		}
+
+		None => (0, 0, 0),
+	};
+
+	time_from_civil(year, month, day, hours, minutes, seconds)
+}
+
+/// Checks every field is in range before combining them, since they come from clients and config files.
+/// Seconds go up to 60 for leap seconds, and dates before the epoch aren't needed
+fn time_from_civil(year: i64, month: u32, day: u32, hours: u64, minutes: u64, seconds: u64) -> Option<SystemTime> {
+	if !(1970..=9999).contains(&year) || !(1..=12).contains(&month) || !(1..=31).contains(&day)
+		|| hours > 23 || minutes > 59 || seconds > 60 {
+		return None
+	}
+
+	let days = days_from_civil(year, month, day);
+	if days < 0 { return None }
+
+	let secs = (days as u64).checked_mul(86400)?
+		.checked_add(hours * 3600 + minutes * 60 + seconds)?;
+
+	UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+// http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+	let z = days + 719468;
+	let era = z.div_euclid(146097);
+	let doe = z - era * 146097;
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+	let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+	(year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+	let year = if month <= 2 { year - 1 } else { year };
+	let era = year.div_euclid(400);
+	let yoe = year - era * 400;
+	let mp = (month as i64 + 9) % 12;
+	let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+	let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+	era * 146097 + doe - 719468
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_util::XorShift;
+
+	fn status(data: &[u8]) -> Option<u16> {
+		match Request::parse(data) {
+			Ok(_) => None,
+			Err(e) => Some(e.downcast_ref::<RequestError>().map_or(0, |e| e.status)),
+		}
+	}
+
+	const REQUEST: &[u8] = b"GET /docs/a%20b.html?x=1 HTTP/1.1\r\nHost: example.com\r\nRange: bytes=0-10\r\nAccept: */*\r\n\r\n";
+
+	#[test]
+	fn parse_survives_every_truncation() {
+		for end in 0..=REQUEST.len() {
+			let _ = Request::parse(&REQUEST[..end]);
+		}
+
+		let request = Request::parse(REQUEST).unwrap();
+		assert_eq!(request.uri(), "/docs/a%20b.html?x=1");
+		assert_eq!(request.get("host"), Some("example.com"));
+	}
+
+	#[test]
+	fn parse_survives_random_input() {
+		let tokens: &[&[u8]] = &[
+			b"GET", b"HEAD", b"OPTIONS", b"get", b" ", b"\t", b"/", b"..", b"%2e", b"%2f", b"%", b"?", b"#",
+			b"HTTP/1.1", b"HTTP/1.0", b"HTTP/2.0", b"HTTP/1.", b"\r\n", b"\r", b"\n", b":", b"Host", b"host: a",
+			b"http://", b"https://x", b"\xff", b"\xc3\xa9", b"\xc3", b"\0", b"\x7f", b"a", b"Range: bytes=0-",
+		];
+
+		let mut rng = XorShift::new(0x5eed);
+
+		for _ in 0..20_000 {
+			let mut data = Vec::new();
+			for _ in 0..rng.below(24) {
+				data.extend_from_slice(rng.pick(tokens));
+			}
+
+			if let Ok(request) = Request::parse(&data) {
+				assert!(request.uri().bytes().all(|b| b.is_ascii() && b >= 0x20 && b != 0x7f), "{:?}", data);
+				let _ = normalize_path(request.uri());
+			}
+
+			let _ = check_head(&data, 64, 64);
+		}
+	}
+
+	#[test]
+	fn parse_percent_encodes_non_utf8_targets() {
+		let request = Request::parse(b"GET /caf\xc3\xa9/\xff HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+		assert_eq!(request.uri(), "/caf%C3%A9/%FF");
+
+		// Valid utf8 decodes back, a lone 0xff never can
+		assert_eq!(normalize_path("/caf%C3%A9").as_deref(), Some("/café"));
+		assert_eq!(normalize_path(request.uri()), None);
+	}
+
+	#[test]
+	fn parse_treats_non_utf8_fields_as_missing() {
+		let request = Request::parse(b"GET / HTTP/1.1\r\nHost: a\r\nReferer: \xff\xfe\r\n\xff: x\r\n\r\n").unwrap();
+		assert_eq!(request.get("Referer"), None);
+		assert_eq!(request.get("Host"), Some("a"));
+	}
+
+	#[test]
+	fn parse_rejects_control_characters_in_targets() {
+		assert_eq!(status(b"GET /a\x01b HTTP/1.1\r\nHost: a\r\n\r\n"), Some(400));
+		assert_eq!(status(b"GET /a\x7f HTTP/1.1\r\nHost: a\r\n\r\n"), Some(400));
+		assert_eq!(status(b"GET /a\0 HTTP/1.1\r\nHost: a\r\n\r\n"), Some(400));
+	}
+
+	#[test]
+	fn parse_follows_rfc_7230_framing_rules() {
+		assert_eq!(status(b"GET / HTTP/1.1\r\n\r\n"), Some(400));
+		assert_eq!(status(b"GET / HTTP/1.0\r\n\r\n"), None);
+		assert_eq!(status(b"GET / HTTP/1.1\r\nHost: a\r\nHOST: b\r\n\r\n"), Some(400));
+		assert_eq!(status(b"GET / HTTP/1.1\r\nHost: a\r\nX-A: 1\r\n 2\r\n\r\n"), Some(400));
+		assert_eq!(status(b"GET / HTTP/1.1\r\nHost: a\r\nX-A: 1\r\n\t2\r\n\r\n"), Some(400));
+		assert_eq!(status(b"GET / HTTP/1.1\r\nHost : a\r\n\r\n"), Some(400));
+		assert_eq!(status(b"get / HTTP/1.1\r\nHost: a\r\n\r\n"), Some(501));
+		assert_eq!(status(b"GET / HTTP/2.0\r\nHost: a\r\n\r\n"), Some(505));
+		assert_eq!(status(b"GET / HTTP/1.9\r\nHost: a\r\n\r\n"), None);
+		assert_eq!(status(b"GET / HTTP/x\r\nHost: a\r\n\r\n"), Some(400));
+	}
+
+	#[test]
+	fn parse_takes_the_host_from_absolute_form_targets() {
+		let request = Request::parse(b"GET http://example.com/a?b HTTP/1.1\r\nHost: other.example\r\n\r\n").unwrap();
+		assert_eq!(request.uri(), "/a?b");
+		assert_eq!(request.get("Host"), Some("example.com"));
+
+		let request = Request::parse(b"GET HTTPS://example.com:8443?q HTTP/1.1\r\n\r\n").unwrap();
+		assert_eq!(request.uri(), "/");
+		assert_eq!(request.get("host"), Some("example.com:8443"));
+	}
+
+	#[test]
+	fn responses_always_carry_date_and_connection_close() {
+		let bytes = Response::new("HTTP/1.1 200 OK").into_bytes();
+		let head = String::from_utf8(bytes).unwrap();
+		let fields: Vec<&str> = head.split("\r\n").skip(1).filter(|line| !line.is_empty()).collect();
+
+		assert_eq!(fields.iter().filter(|field| field.starts_with("Date: ")).count(), 1);
+		assert!(fields.contains(&"Connection: close"));
+		assert!(parse_http_date(&fields.iter().find(|field| field.starts_with("Date: ")).unwrap()[6..]).is_some());
+
+		// Set explicitly, they aren't doubled up
+		let mut response = Response::new("HTTP/1.1 200 OK");
+		response.set("Connection", "close");
+		let head = String::from_utf8(response.into_bytes()).unwrap();
+		assert_eq!(head.matches("Connection:").count(), 1);
+	}
+
+	#[test]
+	fn dates_with_out_of_range_fields_are_refused() {
+		assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(UNIX_EPOCH + Duration::from_secs(784111777)));
+		assert_eq!(parse_iso_date("1994-11-06T08:49:37Z"), Some(UNIX_EPOCH + Duration::from_secs(784111777)));
+
+		let bad_http_dates = [
+			"Sun, 06 Nov 1994 18446744073709551615:00:00 GMT",
+			"Sun, 06 Nov 1994 08:18446744073709551615:37 GMT",
+			"Sun, 06 Nov 1994 08:49:18446744073709551615 GMT",
+			"Sun, 06 Nov 9223372036854775807 08:49:37 GMT",
+			"Sun, 06 Nov -9223372036854775808 08:49:37 GMT",
+			"Sun, 4294967295 Nov 1994 08:49:37 GMT",
+			"Sun, 00 Nov 1994 08:49:37 GMT",
+			"Sun, 06 Nov 1994 24:00:00 GMT",
+			"Sun, 06 Nov 10000 08:49:37 GMT",
+		];
+
+		for date in bad_http_dates.iter() {
+			assert_eq!(parse_http_date(date), None, "{}", date);
+		}
+
+		let bad_iso_dates = [
+			"9223372036854775807-01-01",
+			"1994-11-06T18446744073709551615:00",
+			"1994-11-06T08:49:18446744073709551615",
+			"1994-13-06",
+			"1994-11-32",
+		];
+
+		for date in bad_iso_dates.iter() {
+			assert_eq!(parse_iso_date(date), None, "{}", date);
+		}
+	}
+
+	#[test]
+	fn oversized_requests_are_refused_early() {
+		let mut long_uri = b"GET /".to_vec();
+		long_uri.extend(std::iter::repeat(b'a').take(100_000));
+
+		// Still arriving, but already too long
+		let err = check_head(&long_uri, 8 << 10, 8 << 10).unwrap_err();
+		assert_eq!(err.downcast_ref::<RequestError>().unwrap().status, 414);
+
+		let mut many_fields = b"GET / HTTP/1.1\r\n".to_vec();
+		for i in 0..1000 {
+			many_fields.extend(format!("X-Field-{}: value\r\n", i).bytes());
+		}
+
+		let err = check_head(&many_fields, 8 << 10, 8 << 10).unwrap_err();
+		assert_eq!(err.downcast_ref::<RequestError>().unwrap().status, 431);
+
+		// Parsing something that big anyway mustn't fall over either
+		long_uri.extend_from_slice(b" HTTP/1.1\r\nHost: a\r\n\r\n");
+		assert!(Request::parse(&long_uri).is_ok());
+	}
+
+	#[test]
+	fn normalize_path_keeps_requests_under_the_root() {
+		let cases: &[(&str, Option<&str>)] = &[
+			("/", Some("/")),
+			("//a//b/", Some("/a/b/")),
+			("/a/./b", Some("/a/b")),
+			("/a/b/..", Some("/a/")),
+			("/a/%2e%2e/b", Some("/b")),
+			("/a/%2E%2e/%2e/b", Some("/b")),
+			("/a?x=/../../etc", Some("/a")),
+			("/a#/../..", Some("/a")),
+			("/a%20b", Some("/a b")),
+
+			// Past the root, however it's spelled
+			("/..", None),
+			("/../etc/passwd", None),
+			("/a/../../etc/passwd", None),
+			("/%2e%2e/etc/passwd", None),
+			("/a/%2E%2E/%2e%2e/x", None),
+			("/.%2e/x", None),
+
+			// Separators and nul hidden behind escapes
+			("/a%2fb", None),
+			("/a%2F..%2F..%2Fb", None),
+			("/a%5cb", None),
+			("/a\\..\\b", None),
+			("/a%00.html", None),
+
+			// Bad escapes
+			("/%", None),
+			("/%2", None),
+			("/%zz", None),
+			("/%ff", None),
+
+			// Not origin-form
+			("", None),
+			("a/b", None),
+			("*", None),
+		];
+
+		for &(uri, expected) in cases {
+			assert_eq!(normalize_path(uri).as_deref(), expected, "{}", uri);
+		}
+	}
+
+	#[test]
+	fn normalize_path_survives_random_input() {
+		let tokens = ["/", "..", ".", "%2e", "%2E", "%2f", "%5c", "%", "%2", "%c3%a9", "%ff", "a", "?", "#", "\\", "é"];
+		let mut rng = XorShift::new(42);
+
+		for _ in 0..20_000 {
+			let uri: String = (0..rng.below(16)).map(|_| *rng.pick(&tokens)).collect();
+
+			if let Some(path) = normalize_path(&uri) {
+				assert!(path.starts_with('/'), "{} => {}", uri, path);
+				assert!(!path.split('/').any(|segment| segment == ".." || segment == "."), "{} => {}", uri, path);
+				assert!(!path.contains("//"), "{} => {}", uri, path);
+				assert_eq!(normalize_path(&path.replace('%', "%25")).as_deref(), Some(path.as_str()), "{}", uri);
+			}
+		}
+	}
+
+	#[test]
+	fn parse_ranges_handles_malformed_headers() {
+		let ignored = ["", "bytes=", "bytes=-", "bytes=5", "bytes=a-b", "bytes=5-3", "items=0-1", "bytes=--1",
+			"bytes=0-99999999999999999999999", "bytes=99999999999999999999999-", "bytes=-99999999999999999999999"];
+
+		for header in ignored.iter() {
+			assert_eq!(parse_ranges(header, 100), None, "{}", header);
+		}
+
+		assert_eq!(parse_ranges("bytes=100-", 100), Some(Err(())));
+		assert_eq!(parse_ranges("bytes=18446744073709551615-", 100), Some(Err(())));
+		assert_eq!(parse_ranges("bytes=0-0", 0), Some(Err(())));
+		assert_eq!(parse_ranges("bytes=-0", 100), Some(Err(())));
+		assert_eq!(parse_ranges("bytes=0-18446744073709551615", 100), Some(Ok(vec![(0, 99)])));
+		assert_eq!(parse_ranges("bytes=-1000", 100), Some(Ok(vec![(0, 99)])));
+		assert_eq!(parse_ranges("bytes=-10", 100), Some(Ok(vec![(90, 99)])));
+
+		let too_many: Vec<String> = (0..100).map(|i| format!("{}-{}", i * 1000, i * 1000)).collect();
+		assert_eq!(parse_ranges(&format!("bytes={}", too_many.join(",")), 1 << 20), None);
+	}
+
+	#[test]
+	fn parse_ranges_output_is_sorted_disjoint_and_covers_the_request() {
+		let mut rng = XorShift::new(99);
+
+		for _ in 0..50_000 {
+			let total = rng.below(2000);
+			let specs: Vec<String> = (0..1 + rng.below(6))
+				.map(|_| {
+					let (a, b) = (rng.below(total + 50), rng.below(total + 50));
+					match rng.below(3) {
+						0 => format!("{}-{}", a.min(b), a.max(b)),
+						1 => format!("{}-", a),
+						_ => format!("-{}", a),
+					}
+				})
+				.collect();
+
+			let header = format!("bytes={}", specs.join(","));
+
+			let ranges = match parse_ranges(&header, total) {
+				Some(Ok(ranges)) => ranges,
+				Some(Err(())) => {
+					assert!(specs.iter().all(|spec| parse_range_spec(spec, total) == Some(None)), "{}", header);
+					continue
+				}
+
+				None => continue,
+			};
+
+			assert!(!ranges.is_empty(), "{}", header);
+			assert!(ranges.iter().all(|&(start, end)| start <= end && end < total), "{} => {:?}", header, ranges);
+
+			// Sorted, and far enough apart that they weren't worth merging
+			assert!(ranges.windows(2).all(|pair| pair[0].1 + RANGE_COALESCE_GAP + 1 < pair[1].0), "{} => {:?}", header, ranges);
+
+			// Every satisfiable byte asked for is in there
+			for spec in specs.iter() {
+				if let Some(Some((start, end))) = parse_range_spec(spec, total) {
+					assert!(ranges.iter().any(|&(s, e)| s <= start && end <= e), "{} => {:?}", header, ranges);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn client_header_parsers_survive_arbitrary_input() {
+		let pieces = ["bytes=", "-", ",", ";", ":", " ", "q=", "0", "1", "0.5", "23", "59", "60", "1994", "9999", "10000",
+			"18446744073709551615", "9223372036854775807", "-9223372036854775808", "NaN", "inf", "Sun,", "Nov", "GMT",
+			"T", "Z", "text/html", "*/*", "text/*", "W/", "\"x\"", "*", "\t", "é"];
+
+		let mut rng = XorShift::new(1541);
+
+		for _ in 0..50_000 {
+			let header: String = (0..rng.below(12)).map(|_| *rng.pick(&pieces)).collect();
+			let total = *rng.pick(&[0, 1, 100, 1 << 20, u64::MAX]);
+
+			if let Some(time) = parse_http_date(&header) {
+				assert_eq!(parse_http_date(&format_http_date(time)), Some(time), "{}", header);
+			}
+
+			let _ = parse_iso_date(&header);
+			let _ = etag_matches(&header, "\"x\"", rng.below(2) == 0);
+
+			if let Some(Ok(ranges)) = parse_ranges(&header, total) {
+				assert!(ranges.iter().all(|&(start, end)| start <= end && end < total), "{} => {:?}", header, ranges);
+			}
+
+			let quality = accept_quality(&header, "text/html");
+			assert!((0.0..=1.0).contains(&quality), "{} => {}", header, quality);
+		}
+
+		// Dates shaped right, with any number in any field
+		let numbers = ["0", "1", "12", "31", "59", "60", "99", "1969", "1970", "9999", "10000", "4294967296",
+			"18446744073709551615", "-1", "-9223372036854775808"];
+
+		for _ in 0..50_000 {
+			let date = format!("Sun, {} Nov {} {}:{}:{} GMT", rng.pick(&numbers), rng.pick(&numbers),
+				rng.pick(&numbers), rng.pick(&numbers), rng.pick(&numbers));
+
+			if let Some(time) = parse_http_date(&date) {
+				assert_eq!(parse_http_date(&format_http_date(time)), Some(time), "{}", date);
+			}
+
+			let _ = parse_iso_date(&format!("{}-{}-{}T{}:{}:{}Z", rng.pick(&numbers), rng.pick(&numbers),
+				rng.pick(&numbers), rng.pick(&numbers), rng.pick(&numbers), rng.pick(&numbers)));
+		}
+	}
+}
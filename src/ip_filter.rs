@@ -0,0 +1,72 @@
+use std::net::IpAddr;
+
+use crate::SBResult;
+
+/// An address range like `10.0.0.0/8` or `fd00::/8`. A bare address is a range of one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+	addr: IpAddr,
+	prefix_len: u32,
+}
+
+impl Cidr {
+	pub fn parse(s: &str) -> SBResult<Cidr> {
+		let mut parts = s.trim().splitn(2, '/');
+		let addr: IpAddr = parts.next().unwrap_or("").parse()
+			.map_err(|_| failure::format_err!("Invalid address range '{}'", s))?;
+
+		let max_len = if addr.is_ipv4() { 32 } else { 128 };
+		let prefix_len = match parts.next() {
+			Some(len) => len.parse::<u32>().ok().filter(|&len| len <= max_len)
+				.ok_or_else(|| failure::format_err!("Invalid prefix length in '{}'", s))?,
+			None => max_len,
+		};
+
+		Ok(Cidr { addr, prefix_len })
+	}
+
+	pub fn contains(&self, ip: IpAddr) -> bool {
+		// Dual stack listeners see IPv4 clients as ::ffff:a.b.c.d
+		let ip = match ip {
+			IpAddr::V6(v6) => v6.to_ipv4().filter(|_| v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff])
+				.map_or(ip, IpAddr::V4),
+			ip => ip,
+		};
+
+		match (self.addr, ip) {
+			(IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(&net.octets(), &ip.octets(), self.prefix_len),
+			(IpAddr::V6(net), IpAddr::V6(ip)) => prefix_matches(&net.octets(), &ip.octets(), self.prefix_len),
+			_ => false,
+		}
+	}
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix_len: u32) -> bool {
+	net.iter().zip(ip).enumerate().all(|(i, (net, ip))| {
+		let bits = prefix_len.saturating_sub(i as u32 * 8).min(8);
+		let mask = (0xff00u16 >> bits) as u8;
+		net & mask == ip & mask
+	})
+}
+
+/// Which peers can connect, or request a route. Denied ranges always lose, and if there
+/// are any allowed ranges, the peer has to be in one of them
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+	pub allow: Vec<Cidr>,
+	pub deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+	pub fn permits(&self, ip: IpAddr) -> bool {
+		if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+			return false
+		}
+
+		self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.allow.is_empty() && self.deny.is_empty()
+	}
+}
@@ -0,0 +1,126 @@
+use std::cell::Cell;
+use std::fmt;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use crate::admin::json_string;
+use crate::clock;
+
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Request ids count up from the start time in the top half, so they don't repeat across restarts
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+	/// The request whatever's running on this thread is handling, added to everything it logs
+	static CURRENT_REQUEST: Cell<Option<RequestId>> = Cell::new(None);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+	Error,
+	Warn,
+	Info,
+	/// Per-file and per-route detail, too much for a busy or large site
+	Debug,
+}
+
+impl Level {
+	fn name(self) -> &'static str {
+		match self {
+			Level::Error => "error",
+			Level::Warn => "warn",
+			Level::Info => "info",
+			Level::Debug => "debug",
+		}
+	}
+}
+
+/// Only log messages at `max_level` or more important, as one JSON object per line if `json` is set
+pub fn init(max_level: Level, json: bool) {
+	MAX_LEVEL.store(max_level as usize, Ordering::Relaxed);
+	JSON.store(json, Ordering::Relaxed);
+	NEXT_REQUEST_ID.store((clock::unix_secs(SystemTime::now()) as u64) << 32, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+	level as usize <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+pub fn json() -> bool {
+	JSON.load(Ordering::Relaxed)
+}
+
+/// Identifies one connection across every line it logs, and to the client in `X-Request-Id`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RequestId(u64);
+
+impl RequestId {
+	pub fn next() -> RequestId {
+		RequestId(NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed))
+	}
+}
+
+impl fmt::Display for RequestId {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{:016x}", self.0)
+	}
+}
+
+/// Tags everything logged on this thread with `id` until the guard is dropped.
+/// Tasks share threads, so this has to be entered every time one is resumed
+pub fn enter(id: RequestId) -> SpanGuard {
+	let previous = CURRENT_REQUEST.with(|current| current.replace(Some(id)));
+	SpanGuard { previous }
+}
+
+pub struct SpanGuard {
+	previous: Option<RequestId>,
+}
+
+impl Drop for SpanGuard {
+	fn drop(&mut self) {
+		CURRENT_REQUEST.with(|current| current.set(self.previous));
+	}
+}
+
+/// Called by the logging macros. `module` is the module_path!() the message came from
+pub fn write(level: Level, module: &str, args: fmt::Arguments<'_>) {
+	if !enabled(level) { return }
+
+	// spiderbutter::fileserver => fileserver
+	let target = module.rsplit("::").next().unwrap_or(module);
+
+	let request = CURRENT_REQUEST.with(Cell::get);
+
+	let line = match (json(), request) {
+		(true, Some(request)) => format!(r#"{{"level":"{}","target":"{}","request_id":"{}","message":{}}}"#,
+			level.name(), target, request, json_string(&args.to_string())),
+		(true, None) => format!(r#"{{"level":"{}","target":"{}","message":{}}}"#, level.name(), target, json_string(&args.to_string())),
+		(false, Some(request)) => format!("{:<5} [{}] {} {}", level.name(), target, request, args),
+		(false, None) => format!("{:<5} [{}] {}", level.name(), target, args),
+	};
+
+	// One write per line so threads don't interleave, and a closed stdout isn't worth a panic
+	let stdout = std::io::stdout();
+	let mut stdout = stdout.lock();
+	let _ = writeln!(stdout, "{}", line);
+}
+
+macro_rules! error {
+	($($arg:tt)*) => { $crate::log::write($crate::log::Level::Error, module_path!(), format_args!($($arg)*)) };
+}
+
+macro_rules! warn {
+	($($arg:tt)*) => { $crate::log::write($crate::log::Level::Warn, module_path!(), format_args!($($arg)*)) };
+}
+
+macro_rules! info {
+	($($arg:tt)*) => { $crate::log::write($crate::log::Level::Info, module_path!(), format_args!($($arg)*)) };
+}
+
+macro_rules! debug {
+	($($arg:tt)*) => { $crate::log::write($crate::log::Level::Debug, module_path!(), format_args!($($arg)*)) };
+}
@@ -3,159 +3,1068 @@
 #![deny(rust_2018_idioms, future_incompatible)]
 
 use structopt::StructOpt;
-use inotify::{event_mask, watch_mask, Inotify};
+use inotify::{event_mask, watch_mask, Inotify, WatchDescriptor};
 
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::net::TcpListener;
-use std::sync::mpsc;
+use std::os::unix::io::AsRawFd;
+use std::sync::{mpsc, Arc};
 use std::thread;
+use std::time::{Duration, Instant};
 
+#[macro_use] mod log;
 #[macro_use] mod coro_util;
 mod fileserver;
 mod tcp_util;
 mod http;
+mod ip_filter;
 mod cert;
+mod cert_store;
+mod open_file;
+mod mime;
+mod autoindex;
+mod admin;
+mod preview;
+mod publish;
+mod ready;
+mod clock;
+mod transform;
+mod shutdown;
+mod systemd;
+mod heartbeat;
+mod broadcast;
+mod watch;
+mod config;
+mod privileges;
+mod artifacts;
+#[cfg(test)] mod test_util;
 
 mod mappings;
 use crate::mappings::*;
-use crate::fileserver::FileserverCommand;
+use crate::fileserver::{Fileserver, FileserverCommand, HealthCheck, SniPolicy};
+use crate::admin::AdminCommand;
+use crate::broadcast::Broadcast;
+use crate::cert_store::CertStore;
+use crate::ready::ReadyNotifier;
+use crate::clock::Clock;
 
 pub type SBResult<T> = Result<T, failure::Error>;
 
+const DEFAULT_HSTS_MAX_AGE_SECS: u64 = 365 * 24 * 60 * 60;
+
 
 #[derive(Debug, StructOpt)]
 #[structopt( raw(setting="structopt::clap::AppSettings::ColoredHelp") )]
 struct Opts {
+	/// Read options from this TOML file, as `option_name = value`. Options given on the command line win
+	#[structopt(long, parse(from_os_str))]
+	config: Option<PathBuf>,
+
 	/// Load and compress resources as they're requested instead of ahead of time
 	#[structopt(short, long)]
 	nocache: bool,
 
+	/// Compress resources on first request and keep them cached until they change
+	#[structopt(long)]
+	lazy: bool,
+
+	/// Recheck cached files for changes every this many minutes, for when file watching is unreliable
+	#[structopt(long)]
+	revalidate: Option<u64>,
+
 	/// Serve everything in the current working directory
 	#[structopt(short, long)]
 	local: bool,
 
-	/// Port to use for unencrypted connections
+	/// Generate listings for directories without an index.html in --local mode
+	#[structopt(long)]
+	autoindex: bool,
+
+	/// Leave files and directories starting with a dot out of --autoindex listings
+	#[structopt(long)]
+	hide_dotfiles: bool,
+
+	/// Port to use for unencrypted connections, unless systemd passes in a socket
 	#[structopt(short, long, default_value="8000")]
 	port: u16,
 
+	/// Listen for unencrypted connections on this address or port instead of --port. Can be given more than once
+	#[structopt(long)]
+	bind: Vec<String>,
+
 	/// Encrypt connections and attempt to request a certificate
 	#[structopt(short, long)]
 	secure: bool,
 
-	/// Port to use for encrypted connections
+	/// Port to use for encrypted connections, unless systemd passes in a socket
 	#[structopt(short, long, default_value="8001")]
 	tls_port: u16,
 
+	/// Listen for encrypted connections on this address or port instead of --tls_port. Can be given more than once.
+	/// Redirects to https go to the port of the first one
+	#[structopt(long)]
+	bind_tls: Vec<String>,
+
 	/// Use letsencrypt staging API so you don't get rate limited
 	#[structopt(long)]
 	staging: bool,
 
+	/// Where letsencrypt certificates and keys are kept: a directory (the default is .spiderbutter), systemd-creds
+	/// for the service's credentials directory, or exec:<program> to run `<program> get|put <name>` against a secret manager
+	#[structopt(long)]
+	cert_store: Option<String>,
+
 	/// Domains to try and request certificates for
 	#[structopt(short, long)]
 	domains: Vec<String>,
+
+	/// Renew certificates this many hours before they expire.
+	/// Certificates that don't live much longer than this renew two thirds of the way through their lifetime instead
+	#[structopt(long, default_value="168")]
+	renew_before: u64,
+
+	/// Minutes to wait before retrying a failed certificate request
+	#[structopt(long, default_value="60")]
+	renew_retry: u64,
+
+	/// Request a new certificate at startup instead of reusing one with less than this many hours left. Defaults to --renew_before
+	#[structopt(long)]
+	min_cert_validity: Option<u64>,
+
+	/// Use this certificate chain instead of requesting one, leaf certificate first. Implies --secure
+	#[structopt(long, parse(from_os_str), requires="key")]
+	cert: Option<PathBuf>,
+
+	/// Private key for --cert
+	#[structopt(long, parse(from_os_str), requires="cert")]
+	key: Option<PathBuf>,
+
+	/// Send Strict-Transport-Security on every encrypted response, with this max-age in seconds (default a year)
+	#[structopt(long)]
+	hsts: Option<Option<u64>>,
+
+	/// Also send Strict-Transport-Security on the redirects from the unencrypted port
+	#[structopt(long, requires="hsts")]
+	hsts_on_redirect: bool,
+
+	/// Redirect plain HTTP requests to this host, instead of the one they asked for
+	#[structopt(long)]
+	canonical_host: Option<String>,
+
+	/// Status for redirects from plain HTTP to HTTPS: 301, 307 or 308
+	#[structopt(long, default_value="301")]
+	redirect_status: u16,
+
+	/// Paths to keep serving over plain HTTP instead of redirecting, where * matches anything
+	#[structopt(long)]
+	plain_http: Vec<String>,
+
+	/// Only accept TLS clients presenting a certificate signed by a CA in this PEM bundle
+	#[structopt(long, parse(from_os_str))]
+	client_ca: Option<PathBuf>,
+
+	/// What to do when a TLS client asks for a domain the certificate doesn't cover: default, self-signed or reject
+	#[structopt(long, default_value="default")]
+	unknown_sni: SniPolicy,
+
+	/// Register a preview as name=path/to/dir and exit. A running server picks it up immediately
+	#[structopt(long)]
+	preview: Option<String>,
+
+	/// How many hours a preview registered with --preview stays up for
+	#[structopt(long)]
+	preview_ttl: Option<u64>,
+
+	/// Copy a directory containing a mappings.sb into a new generation and start serving it, then exit
+	#[structopt(long, parse(from_os_str))]
+	publish: Option<PathBuf>,
+
+	/// Go back this many published generations and serve that instead, then exit
+	#[structopt(long)]
+	rollback: Option<usize>,
+
+	/// A directory shared by several servers, e.g. over NFS. Each of them polls it and serves whatever
+	/// generation was last published to it. --publish and --rollback act on it instead of the local generations
+	#[structopt(long, parse(from_os_str))]
+	cluster: Option<PathBuf>,
+
+	/// How many seconds apart servers in a --cluster check for new generations
+	#[structopt(long, default_value="10")]
+	cluster_poll: u64,
+
+	/// How many seconds after publishing to a --cluster every server switches over. Should leave them
+	/// enough time to notice and copy the new generation
+	#[structopt(long, default_value="60")]
+	cluster_delay: u64,
+
+	/// Serve route, cache and certificate status as JSON on this port, on localhost only
+	/// /reload, /zombie/on and /zombie/off only take POST requests
+	#[structopt(long)]
+	admin_port: Option<u16>,
+
+	/// Write a line of JSON describing the server to this file descriptor and close it,
+	/// once content and certificates are loaded
+	#[structopt(long)]
+	ready_fd: Option<i32>,
+
+	/// Switch to this user once ports are bound, so ports below 1024 can be used without serving as root.
+	/// --cert and --key are read before switching, but have to stay readable by this user for changes to be picked up.
+	/// The --cert_store directory has to be owned by it, since renewals are written there
+	#[structopt(long)]
+	user: Option<String>,
+
+	/// Switch to this group once ports are bound, along with --user. Defaults to the primary group of --user
+	#[structopt(long, requires="user")]
+	group: Option<String>,
+
+	/// Also log every route, file and cache update, not just what's going on overall
+	#[structopt(short, long)]
+	verbose: bool,
+
+	/// Only log warnings and errors
+	#[structopt(short, long, conflicts_with="verbose")]
+	quiet: bool,
+
+	/// Log one JSON object per line, with level, target and message fields
+	#[structopt(long)]
+	log_json: bool,
+
+	/// Answer load balancer and uptime checks here on every port, with 200 once content and certificates
+	/// are loaded and 503 until then. An empty path turns it off
+	#[structopt(long, default_value="/.spiderbutter/health")]
+	health_path: String,
+
+	/// Only accept connections from this address range, e.g. 192.168.0.0/16. Can be given more than once
+	#[structopt(long)]
+	allow: Vec<String>,
+
+	/// Close connections from this address range straight away, even if --allow covers it. Can be given more than once
+	#[structopt(long)]
+	deny: Vec<String>,
+
+	/// Connections to handle at once across every port, each of which holds buffers and a task. 0 for no limit
+	#[structopt(long, default_value="1024")]
+	max_connections: usize,
+
+	/// Connections one address can have open at once, past which it gets 429. 0 for no limit,
+	/// for when lots of clients share an address behind a proxy or NAT
+	#[structopt(long, default_value="8")]
+	max_connections_per_client: usize,
+
+	/// Answer connections past --max_connections with 503 straight away, instead of leaving them waiting
+	#[structopt(long)]
+	reject_when_full: bool,
+
+	/// Seconds a client gets to finish the TLS handshake
+	#[structopt(long, default_value="5")]
+	tls_timeout: u64,
+
+	/// Let TLS 1.3 clients resuming a session send their request before the handshake finishes.
+	/// Early data can be replayed, so only GET and HEAD requests are answered from it, others get 425 Too Early
+	#[structopt(long)]
+	early_data: bool,
+
+	/// Seconds a client gets to send its request once connected
+	#[structopt(long, default_value="5")]
+	read_timeout: u64,
+
+	/// Seconds a client can go without accepting any of a response before it's dropped
+	#[structopt(long, default_value="30")]
+	write_timeout: u64,
+
+	/// Seconds a request gets from being read to being fully sent, however slow the disk or the client.
+	/// Routes can set their own with `deadline /prefix => seconds`. No limit by default
+	#[structopt(long)]
+	request_deadline: Option<u64>,
+
+	/// Longest request target to accept, in bytes. Longer ones get 414 URI Too Long
+	#[structopt(long, default_value="8192")]
+	max_uri_length: usize,
+
+	/// Most bytes of header fields to accept in a request. More get 431 Request Header Fields Too Large
+	#[structopt(long, default_value="8192")]
+	max_header_size: usize,
+
+	/// Threads handling connections, for each listener. More helps with lots of slow clients
+	#[structopt(long, default_value="4")]
+	workers: usize,
+
+	/// Before each response, check files read from disk (with --nocache, or large memory mapped files) against
+	/// the hash taken when mappings were loaded, answering 500 if they've changed. Costs a full read per request
+	#[structopt(long)]
+	verify_integrity: bool,
+
+	/// Refuse to load mappings where a route's declared [content-type] doesn't match what its file looks like
+	#[structopt(long)]
+	strict_types: bool,
+
+	/// Threads compressing assets when mappings are loaded. Defaults to one per CPU.
+	/// Fewer keeps memory down on small machines, since each holds a whole file and its compressed copies
+	#[structopt(long)]
+	compression_threads: Option<usize>,
+
+	/// Seconds to let open connections finish after SIGINT or SIGTERM before exiting anyway
+	#[structopt(long, default_value="10")]
+	drain_timeout: u64,
 }
 
 fn main() -> SBResult<()> {
-	let opts = Opts::from_args();
+	let opts = parse_opts()?;
+
+	let log_level = match (opts.verbose, opts.quiet) {
+		(true, _) => log::Level::Debug,
+		(_, true) => log::Level::Warn,
+		_ => log::Level::Info,
+	};
+
+	log::init(log_level, opts.log_json);
+
+	if opts.workers == 0 || opts.compression_threads == Some(0) {
+		failure::bail!("--workers and --compression_threads need at least one thread");
+	}
+
+	if let Some(threads) = opts.compression_threads {
+		mappings::set_compression_threads(threads);
+	}
+
+	mappings::set_strict_types(opts.strict_types);
+	mappings::set_verify_integrity(opts.verify_integrity);
+	fileserver::set_connection_limit(opts.max_connections, opts.max_connections_per_client, opts.reject_when_full);
+
+	if opts.tls_timeout == 0 || opts.read_timeout == 0 || opts.write_timeout == 0 || opts.request_deadline == Some(0) {
+		failure::bail!("--tls_timeout, --read_timeout, --write_timeout and --request_deadline need to be at least a second");
+	}
+
+	if opts.renew_retry == 0 {
+		failure::bail!("--renew_retry needs to be at least a minute");
+	}
+
+	if ![301, 307, 308].contains(&opts.redirect_status) {
+		failure::bail!("--redirect_status should be 301, 307 or 308");
+	}
+
+	if !opts.health_path.is_empty() && !opts.health_path.starts_with('/') {
+		failure::bail!("--health_path should start with /");
+	}
+
+	if opts.cluster.is_some() && (opts.local || opts.cluster_poll == 0) {
+		failure::bail!("--cluster serves published generations, so can't be used with --local, and --cluster_poll needs to be at least a second");
+	}
+
+	fileserver::set_request_limits(opts.max_uri_length, opts.max_header_size);
+	fileserver::set_timeouts(Duration::from_secs(opts.tls_timeout), Duration::from_secs(opts.read_timeout),
+		Duration::from_secs(opts.write_timeout));
+	fileserver::set_request_deadline(opts.request_deadline.map(Duration::from_secs));
+	fileserver::set_early_data(opts.early_data);
+
+	let clock = clock::system();
+
+	if let Some(preview) = opts.preview.as_ref() {
+		let mut parts = preview.splitn(2, '=');
+		let (name, dir) = match (parts.next(), parts.next()) {
+			(Some(name), Some(dir)) => (name, dir),
+			_ => failure::bail!("--preview should look like name=path/to/dir"),
+		};
+
+		let ttl = opts.preview_ttl.map(|hours| std::time::Duration::from_secs(hours * 60 * 60));
+		preview::register(name, Path::new(dir), ttl, &*clock)?;
+
+		info!("Registered preview {} => {}", name, dir);
+		return Ok(())
+	}
+
+	let cluster_delay = Duration::from_secs(opts.cluster_delay);
+
+	if let (Some(source), Some(shared)) = (opts.publish.as_ref(), opts.cluster.as_ref()) {
+		let target = publish::publish_to_cluster(source, shared, cluster_delay, &*clock)?;
+		info!("Published {:?} to {:?} as generation {}, servers switch in {} seconds", source, shared, target.generation, opts.cluster_delay);
+		return Ok(())
+	}
+
+	if let (Some(steps), Some(shared)) = (opts.rollback, opts.cluster.as_ref()) {
+		let target = publish::rollback_cluster(shared, steps, cluster_delay, &*clock)?;
+		info!("Rolled {:?} back to generation {}, servers switch in {} seconds", shared, target.generation, opts.cluster_delay);
+		return Ok(())
+	}
+
+	if let Some(source) = opts.publish.as_ref() {
+		let generation = publish::publish(source)?;
+		info!("Published {:?} as generation {}", source, generation);
+		return Ok(())
+	}
+
+	if let Some(steps) = opts.rollback {
+		let generation = publish::rollback(steps)?;
+		info!("Rolled back to generation {}", generation);
+		return Ok(())
+	}
+
+	// Before any threads exist, so none of them get killed by a signal mid-response
+	shutdown::block_signals();
 
 	let current_dir = std::env::current_dir().expect("Failed to determine current directory");
 
-	let fs_listener = TcpListener::bind(("0.0.0.0", opts.port)).unwrap();
-	let (mut fs_command_tx, fs_command_rx) = mpsc::channel();
+	let tls = opts.secure || opts.cert.is_some();
+
+	// Sockets from socket activation take the place of binding our own
+	let activated = systemd::take_listeners()?;
+
+	let fs_listeners = match activated.http {
+		listeners if !listeners.is_empty() => listeners,
+		_ => bind_listeners(&opts.bind, opts.port)?,
+	};
+
+	let sfs_listeners = match activated.https {
+		_ if !tls && !opts.bind_tls.is_empty() => failure::bail!("--bind_tls needs --secure or --cert"),
+		listeners if !tls && !listeners.is_empty() => {
+			warn!("Ignoring activated https sockets without --secure or --cert");
+			Vec::new()
+		}
+
+		_ if !tls => Vec::new(),
+		listeners if !listeners.is_empty() => listeners,
+		_ => bind_listeners(&opts.bind_tls, opts.tls_port)?,
+	};
 
-	println!("Running...");
-	if opts.nocache {
-		println!("Caching disabled!");
+	let admin_listener = match opts.admin_port {
+		Some(port) => Some(TcpListener::bind(("127.0.0.1", port))?),
+		None => None,
+	};
+
+	// Keys are often only readable by root, so read them while we still are
+	let external_cert = match (opts.cert.as_ref(), opts.key.as_ref()) {
+		(Some(cert_path), Some(key_path)) => Some(cert::load_external_certificate(cert_path, key_path)?),
+		_ => None,
+	};
+
+	// Everything's bound, so root isn't needed anymore
+	privileges::drop_to(opts.user.as_ref().map(String::as_str), opts.group.as_ref().map(String::as_str))?;
+
+	let listener_fds = fs_listeners.iter().chain(sfs_listeners.iter())
+		.map(|listener| listener.as_raw_fd())
+		.collect();
+
+	shutdown::start(listener_fds, Duration::from_secs(opts.drain_timeout));
+
+	let cache_mode = if opts.nocache {
+		CacheMode::Disabled
+	} else if opts.lazy {
+		CacheMode::Lazy
+	} else {
+		CacheMode::Eager
+	};
+
+	print_banner(&opts, cache_mode, &fs_listeners, &sfs_listeners);
+
+	if opts.hsts.is_some() && !tls {
+		warn!("--hsts does nothing without --secure or --cert");
+	}
+
+	if opts.client_ca.is_some() && !tls {
+		warn!("--client_ca does nothing without --secure or --cert");
+	}
+
+	let mut waiting_on = vec!["mappings"];
+	if tls { waiting_on.push("certificate") }
+
+	let summary = ready_summary(&opts, cache_mode, &fs_listeners, &sfs_listeners);
+	let ready = Arc::new(ReadyNotifier::new(opts.ready_fd, waiting_on, summary));
+
+	// Redirects can only point at one of them
+	let https_port = sfs_listeners.first()
+		.and_then(|listener| listener.local_addr().ok())
+		.map_or(opts.tls_port, |addr| addr.port());
+
+	let heartbeats = heartbeat::Heartbeats::new();
+	let fs_servers = start_fileservers(fs_listeners, opts.workers, &heartbeats);
+	let sfs_servers = start_fileservers(sfs_listeners, opts.workers, &heartbeats);
+	systemd::start_watchdog(heartbeats);
+
+	if !opts.health_path.is_empty() {
+		let health_check = HealthCheck { path: opts.health_path.clone(), ready: ready.clone() };
+
+		for server in fs_servers.iter().chain(sfs_servers.iter()) {
+			server.apply(FileserverCommand::SetHealthCheck(health_check.clone()))?;
+		}
 	}
 
-	thread::spawn(move || fileserver::start(fs_listener, fs_command_rx));
+	let ip_filter = ip_filter::IpFilter {
+		allow: opts.allow.iter().map(|s| ip_filter::Cidr::parse(s)).collect::<SBResult<_>>()?,
+		deny: opts.deny.iter().map(|s| ip_filter::Cidr::parse(s)).collect::<SBResult<_>>()?,
+	};
+
+	if !ip_filter.is_empty() {
+		for server in fs_servers.iter().chain(sfs_servers.iter()) {
+			server.apply(FileserverCommand::SetIpFilter(ip_filter.clone()))?;
+		}
+	}
+
+	// Everything that needs to hear about new mappings or certificates subscribes to this
+	let broadcast = Broadcast::default();
+
+	let admin_tx = admin_listener.map(|admin_listener| {
+		let (admin_tx, admin_rx) = mpsc::channel();
+		let secure = tls;
+
+		thread::spawn(move || admin::start(admin_listener, admin_rx, secure));
+		broadcast.subscribe_admin(admin_tx.clone());
+		admin_tx
+	});
+
+	if tls {
+		let allowed_hosts: Vec<String> = opts.domains.iter().map(|d| d.to_ascii_lowercase()).collect();
+
+		for sfs_server in sfs_servers.iter() {
+			sfs_server.apply(FileserverCommand::SetSniPolicy(opts.unknown_sni))?;
+		}
+
+		if let Some(client_ca) = opts.client_ca.clone() {
+			let count = cert::check_ca_bundle(&client_ca)?;
+			info!("Requiring client certificates from {} CAs in {:?}", count, client_ca);
+
+			for sfs_server in sfs_servers.iter() {
+				sfs_server.apply(FileserverCommand::SetClientCa(client_ca.clone()))?;
+			}
+		}
+
+		if let Some(max_age) = opts.hsts {
+			let max_age = max_age.unwrap_or(DEFAULT_HSTS_MAX_AGE_SECS);
+			for sfs_server in sfs_servers.iter() {
+				sfs_server.apply(FileserverCommand::SetHsts(max_age))?;
+			}
+
+			if opts.hsts_on_redirect {
+				for fs_server in fs_servers.iter() {
+					fs_server.apply(FileserverCommand::SetHsts(max_age))?;
+				}
+			}
+		}
+
+		for sfs_server in sfs_servers.iter() {
+			broadcast.subscribe_certificates(sfs_server.clone());
+			broadcast.subscribe_content(sfs_server.clone());
+		}
+
+		if let (Some(cert_path), Some(key_path), Some(cert)) = (opts.cert.clone(), opts.key.clone(), external_cert) {
+			broadcast.set_cert(cert)?;
+			ready.done("certificate");
+
+			let paths = vec![cert_path.clone(), key_path.clone()];
+			start_cert_watch_thread(paths, move || cert::load_external_certificate(&cert_path, &key_path), broadcast.clone());
+		} else {
+			let policy = cert::RenewalPolicy {
+				renew_before: Duration::from_secs(opts.renew_before * 60 * 60),
+				retry_interval: Duration::from_secs(opts.renew_retry * 60),
+				min_validity: Duration::from_secs(opts.min_cert_validity.unwrap_or(opts.renew_before) * 60 * 60),
+			};
+
+			for fs_server in fs_servers.iter() {
+				broadcast.subscribe_challenges(fs_server.clone());
+			}
+
+			let store = cert_store::from_spec(opts.cert_store.as_deref())?;
+			let staging = opts.staging;
+			let paths = cert::watch_paths(&*store, staging);
+
+			start_autorenew_thread(store.clone(), opts.domains, broadcast.clone(), ready.clone(), staging, policy, clock.clone());
+
+			// Stores that aren't on disk only change when we change them
+			if !paths.is_empty() {
+				start_cert_watch_thread(paths, move || cert::reload_acme_certificate(&*store, staging), broadcast.clone());
+			}
+		}
+
+		let redirect_policy = fileserver::RedirectPolicy {
+			canonical_host: opts.canonical_host.clone(),
+			allowed_hosts,
+			status: opts.redirect_status,
+			https_port,
+			plain_paths: opts.plain_http.clone(),
+		};
 
-	if opts.secure {
-		let sfs_listener = TcpListener::bind(("0.0.0.0", opts.tls_port)).unwrap();
-		let (sfs_command_tx, sfs_command_rx) = mpsc::channel();
+		for fs_server in fs_servers.iter() {
+			// Plain paths need the real content, as does turning zombie mode off through the admin server.
+			// The rest of the time it only ever serves challenges
+			if !redirect_policy.plain_paths.is_empty() || admin_tx.is_some() {
+				broadcast.subscribe_content(fs_server.clone());
+			}
 
-		thread::spawn(move || fileserver::start(sfs_listener, sfs_command_rx));
-		start_autorenew_thread(opts.domains, fs_command_tx.clone(), sfs_command_tx.clone(), opts.staging);
+			fs_server.apply(FileserverCommand::Zombify(redirect_policy.clone()))?;
+		}
 
-		fs_command_tx.send(FileserverCommand::Zombify).unwrap();
-		fs_command_tx = sfs_command_tx;
+		if let Some(admin_tx) = admin_tx.as_ref() {
+			let _ = admin_tx.send(AdminCommand::SetZombies(fs_servers, redirect_policy));
+		}
+	} else {
+		for fs_server in fs_servers {
+			broadcast.subscribe_content(fs_server);
+		}
 	}
 
 	if opts.local {
-		let mappings = Mappings::from_dir(".".into(), !opts.nocache)?;
-		fs_command_tx.send(FileserverCommand::NewMappings(mappings))?;
-		println!("Done.");
+		let mut mappings = Mappings::from_dir(".".into(), cache_mode)?;
+		if opts.autoindex {
+			mappings.enable_autoindex(".".into(), opts.hide_dotfiles);
+		}
 
+		info!("Done. {} routes", mappings.routes().count());
+		broadcast.new_mappings(mappings);
+		ready.done("mappings");
+
+		// Nothing is watched in local mode, so SIGHUP is the only way to pick up changes
 		loop {
-			thread::park();
+			if !shutdown::wait_for_reload(None, None) { continue }
+
+			match Mappings::from_dir(".".into(), cache_mode) {
+				Ok(mut mappings) => {
+					if opts.autoindex {
+						mappings.enable_autoindex(".".into(), opts.hide_dotfiles);
+					}
+
+					info!("Done. {} routes", mappings.routes().count());
+					broadcast.new_mappings(mappings);
+				}
+
+				Err(err) => {
+					error!("Failed to load mappings: {}", err);
+				}
+			}
+		}
+	}
+
+	let mut mappings = Mappings::new(cache_mode);
+
+	// A new node should start out on the cluster's generation rather than whatever it had before
+	if let Some(shared) = opts.cluster.as_ref() {
+		if let Err(err) = publish::sync_with_cluster(shared, &*clock) {
+			error!("Failed to sync with {:?}: {}", shared, err);
 		}
 	}
 
-	match Mappings::from_file(MAPPINGS_FILENAME, !opts.nocache) {
-		Ok(mappings) => {
-			fs_command_tx.send(FileserverCommand::NewMappings(mappings))?;
-			println!("Done.");
+	let generation = publish::current_generation();
+	if let Some(generation) = generation {
+		info!("Serving published generation {}", generation);
+	}
+
+	match Mappings::from_file(publish::mappings_path(generation), cache_mode) {
+		Ok(new_mappings) => {
+			mappings = new_mappings;
+			broadcast.new_mappings(mappings.clone());
+			publish::set_serving_generation(generation);
+			info!("Done. {} routes", mappings.routes().count());
+			ready.done("mappings");
 		}
 
 		Err(err) => {
-			println!("Error: {:?}", err);
+			error!("Failed to load mappings: {}", err);
 		}
 	}
 
 	let mut inotify = Inotify::init().expect("Inotify init failed");
-	inotify.add_watch(current_dir, watch_mask::MODIFY)
+	inotify.add_watch(current_dir, WATCH_MASK)
+		.expect("Failed to add inotify watch");
+
+	// Publishing swaps the `current` link with a rename
+	std::fs::create_dir_all(publish::STATE_DIR)?;
+	let state_watch = inotify.add_watch(publish::STATE_DIR, watch_mask::MOVED_TO)
 		.expect("Failed to add inotify watch");
 
+	let mut asset_dirs = HashMap::new();
+	watch_mapped_assets(&mut inotify, &mut asset_dirs, &mappings);
+
+	// Switching generations moves the `current` link like publishing does, so the watch above picks it up
+	if let Some(shared) = opts.cluster.clone() {
+		start_cluster_thread(shared, Duration::from_secs(opts.cluster_poll), clock.clone());
+	}
+
+	let revalidate_interval = opts.revalidate.map(|minutes| Duration::from_secs(minutes * 60));
+	let mut last_revalidation = Instant::now();
+
 	let mut buffer = [0u8; 4096];
 	loop {
-		let mapping_file_changed = inotify
-			.read_events_blocking(&mut buffer)
+		let mut changed_assets = Vec::new();
+
+		// Revalidating means we can't block on events that might never come
+		let poll_timeout = revalidate_interval.map(|_| REVALIDATE_POLL_INTERVAL);
+
+		// SIGHUP reloads even if nothing seems to have changed, for when a write wasn't noticed
+		let mut mapping_file_changed = shutdown::wait_for_reload(Some(inotify.as_raw_fd()), poll_timeout);
+
+		let events = inotify.read_events(&mut buffer)
 			.expect("Failed to read inotify events")
-			.filter(|e| !e.mask.contains(event_mask::ISDIR))
-			.map(|e| e.name.to_str().unwrap_or(""))
-			.any(|name| name.ends_with(MAPPINGS_FILENAME));
+			.filter(|e| !e.mask.contains(event_mask::ISDIR));
+
+		for event in events {
+			let name = event.name.to_str().unwrap_or("");
+			if name.ends_with(MAPPINGS_FILENAME) || name == preview::PREVIEWS_FILENAME {
+				mapping_file_changed = true;
+			}
+
+			if event.wd == state_watch && name == publish::CURRENT_LINK_NAME {
+				mapping_file_changed = true;
+			}
+
+			// Only react to completed writes and deletions so we don't compress half written files
+			let write_finished = event.mask.intersects(event_mask::CLOSE_WRITE | event_mask::MOVED_TO | event_mask::DELETE);
+
+			if let (true, Some(dir)) = (write_finished, asset_dirs.get(&event.wd)) {
+				changed_assets.push(dir.join(event.name));
+			}
+		}
+
+		if let Some(interval) = revalidate_interval {
+			if last_revalidation.elapsed() >= interval {
+				last_revalidation = Instant::now();
+				changed_assets.extend(mappings.revalidate());
+			}
+		}
 
 		if mapping_file_changed {
-			println!("Updating mappings...");
+			info!("Updating mappings...");
+
+			let generation = publish::current_generation();
 
-			match Mappings::from_file(MAPPINGS_FILENAME, !opts.nocache) {
-				Ok(mappings) => {
-					fs_command_tx.send(FileserverCommand::NewMappings(mappings)).unwrap();
-					println!("Done.");
+			match Mappings::from_file_reusing(publish::mappings_path(generation), cache_mode, Some(&mappings)) {
+				Ok(new_mappings) => {
+					mappings = new_mappings;
+					broadcast.new_mappings(mappings.clone());
+					publish::set_serving_generation(generation);
+					watch_mapped_assets(&mut inotify, &mut asset_dirs, &mappings);
+					info!("Done. {} routes", mappings.routes().count());
+					ready.done("mappings");
 				}
 
 				Err(err) => {
-					println!("Error: {:?}", err);
+					error!("Failed to load mappings: {}", err);
 				}
 			}
+
+			continue
+		}
+
+		changed_assets.sort();
+		changed_assets.dedup();
+
+		let mut reloaded = Vec::new();
+		for path in changed_assets {
+			match mappings.reload_asset(&path) {
+				Ok(paths) => reloaded.extend(paths),
+				Err(err) => error!("Failed to reload {:?}: {:?}", path, err),
+			}
+		}
+
+		if !reloaded.is_empty() {
+			// Only the entries that changed need sending, everything else about the mappings is the same
+			reloaded.sort();
+			reloaded.dedup();
+			broadcast.update_mappings(mappings.delta(&reloaded));
+
+			// Rebuilt pages might include fragments from somewhere new
+			watch_mapped_assets(&mut inotify, &mut asset_dirs, &mappings);
 		}
 	}
 }
 
 
-fn start_autorenew_thread(domains: Vec<String>, insecure_server: mpsc::Sender<FileserverCommand>, secure_server: mpsc::Sender<FileserverCommand>, staging: bool) {
-	use std::time::Duration;
+/// Parses the command line, filling in anything it leaves out from --config
+fn parse_opts() -> SBResult<Opts> {
+	let matches = Opts::clap().get_matches();
+
+	let config_path = match matches.value_of_os("config") {
+		Some(path) => PathBuf::from(path),
+		None => return Ok(Opts::from_clap(&matches)),
+	};
+
+	let values = config::load(&config_path)?;
+	let file_args = config::to_args(&values, |key| key == "config" || matches.occurrences_of(key) > 0)?;
 
-	println!("Starting certificate autorenewal thread...");
+	let mut args: Vec<std::ffi::OsString> = std::env::args_os().take(1).collect();
+	args.extend(file_args.into_iter().map(Into::into));
+	args.extend(std::env::args_os().skip(1));
+
+	// Usage examples would only be for the command line
+	let matches = Opts::clap().get_matches_from_safe(args)
+		.map_err(|e| {
+			let message = e.message.lines().next().unwrap_or("").trim_start_matches("error: ");
+			failure::format_err!("In config {:?}: {}", config_path, message)
+		})?;
+
+	Ok(Opts::from_clap(&matches))
+}
+
+/// Binds each of `addresses`, which are either `host:port` or just a port on every interface.
+/// With no addresses, binds `default_port` on every interface
+fn bind_listeners(addresses: &[String], default_port: u16) -> SBResult<Vec<TcpListener>> {
+	if addresses.is_empty() {
+		return Ok(vec![TcpListener::bind(("0.0.0.0", default_port))?])
+	}
+
+	addresses.iter()
+		.map(|address| match address.parse::<u16>() {
+			Ok(port) => TcpListener::bind(("0.0.0.0", port)),
+			Err(_) => TcpListener::bind(address.as_str()),
+		}.map_err(|e| failure::format_err!("Failed to bind {}: {}", address, e)))
+		.collect()
+}
+
+/// Starts a fileserver with `workers` threads for each listener, returning handles to change how they serve
+fn start_fileservers(listeners: Vec<TcpListener>, workers: usize, heartbeats: &heartbeat::Heartbeats) -> Vec<Fileserver> {
+	listeners.into_iter()
+		.map(|listener| {
+			let server = Fileserver::new();
+			let accept_server = server.clone();
+			let heartbeats = heartbeats.clone();
+			thread::spawn(move || fileserver::start(listener, accept_server, workers, heartbeats));
+			server
+		})
+		.collect()
+}
+
+fn print_banner(opts: &Opts, cache_mode: CacheMode, fs_listeners: &[TcpListener], sfs_listeners: &[TcpListener]) {
+	let tls = opts.secure || opts.cert.is_some();
+	let address = |listener: &TcpListener| listener.local_addr()
+		.map_or("unknown".to_owned(), |addr| addr.to_string());
+
+	// Log parsers have the ready summary instead
+	if log::json() { return }
+
+	info!("spiderbutter {}", env!("CARGO_PKG_VERSION"));
+
+	if let Some(config) = opts.config.as_ref() {
+		info!("config {:?}", config);
+	}
+
+	for fs_listener in fs_listeners {
+		if tls {
+			info!("http   {} (redirects to https)", address(fs_listener));
+		} else {
+			info!("http   {}", address(fs_listener));
+		}
+	}
+
+	for sfs_listener in sfs_listeners {
+		info!("https  {}", address(sfs_listener));
+	}
+
+	if let Some(port) = opts.admin_port {
+		info!("admin  127.0.0.1:{}", port);
+	}
+
+	let content = if opts.local {
+		"current directory".to_owned()
+	} else if let Some(generation) = publish::current_generation() {
+		format!("published generation {}", generation)
+	} else {
+		MAPPINGS_FILENAME.to_owned()
+	};
+
+	info!("serve  {}", content);
+	info!("cache  {}", cache_mode_name(cache_mode));
+	info!("cpu    {} workers per listener, {} compressing", opts.workers, mappings::compression_threads());
+
+	let cert_store_name = opts.cert_store.as_deref().unwrap_or(cert_store::DEFAULT_DIR);
+
+	match (&opts.cert, opts.secure) {
+		(Some(path), _) => info!("cert   {:?}", path),
+		(None, true) if opts.staging => info!("cert   letsencrypt staging for {:?}, kept in {}", opts.domains, cert_store_name),
+		(None, true) => info!("cert   letsencrypt for {:?}, kept in {}", opts.domains, cert_store_name),
+		(None, false) => info!("cert   none"),
+	}
+
+	if let (true, Some(max_age)) = (tls, opts.hsts) {
+		info!("hsts   max-age={}", max_age.unwrap_or(DEFAULT_HSTS_MAX_AGE_SECS));
+	}
+}
+
+/// Only mentions the first listener of each kind, which is all there was before there could be more
+fn ready_summary(opts: &Opts, cache_mode: CacheMode, fs_listeners: &[TcpListener], sfs_listeners: &[TcpListener]) -> String {
+	// Activated sockets can be on any port
+	let port = |listener: &TcpListener| listener.local_addr().ok().map(|addr| addr.port());
+	let port_json = |port: Option<u16>| port.map_or("null".to_owned(), |p| p.to_string());
+
+	format!(r#"{{"ready":true,"pid":{},"http_port":{},"https_port":{},"admin_port":{},"cache_mode":"{}","local":{}}}"#,
+		std::process::id(),
+		port_json(fs_listeners.first().and_then(port)),
+		port_json(sfs_listeners.first().and_then(port)),
+		port_json(opts.admin_port),
+		cache_mode_name(cache_mode),
+		opts.local)
+}
+
+fn cache_mode_name(cache_mode: CacheMode) -> &'static str {
+	match cache_mode {
+		CacheMode::Eager => "eager",
+		CacheMode::Lazy => "lazy",
+		CacheMode::Disabled => "disabled",
+	}
+}
+
+
+
+const WATCH_MASK: watch_mask::WatchMask = watch_mask::MODIFY;
+const REVALIDATE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Watches are added to containing directories rather than the files themselves,
+// so that editors that replace files on save are still picked up, as are .gz and .br sidecars
+fn watch_mapped_assets(inotify: &mut Inotify, asset_dirs: &mut HashMap<WatchDescriptor, PathBuf>, mappings: &Mappings) {
+	let mask = WATCH_MASK | watch_mask::CLOSE_WRITE | watch_mask::MOVED_TO | watch_mask::DELETE;
+
+	let dependency_paths = mappings.dependency_paths();
+
+	for path in mappings.asset_paths().chain(dependency_paths.iter().map(PathBuf::as_path)) {
+		let dir = path.parent().unwrap_or(Path::new(""));
+		if asset_dirs.values().any(|d| d == dir) { continue }
+
+		let watch_path = if dir == Path::new("") { Path::new(".") } else { dir };
+
+		match inotify.add_watch(watch_path, mask) {
+			Ok(wd) => { asset_dirs.insert(wd, dir.to_owned()); }
+			Err(err) => warn!("Failed to watch {:?}: {:?}", watch_path, err),
+		}
+	}
+}
+
+
+/// Reloads the certificate whenever one of its files is rewritten, so renewals done by something
+/// else take effect without a restart
+fn start_cert_watch_thread<F>(paths: Vec<PathBuf>, load: F, broadcast: Broadcast)
+	where F: Fn() -> SBResult<cert::Certificate> + Send + 'static {
 
 	thread::spawn(move || {
+		let mut inotify = Inotify::init().expect("Inotify init failed");
+
+		// Watch directories rather than files, since renewals usually replace files or swap symlinks
+		let mask = watch_mask::CLOSE_WRITE | watch_mask::MOVED_TO | watch_mask::CREATE;
+		let mut watched_dirs = Vec::new();
+
+		for path in paths.iter() {
+			let dir = match path.parent() {
+				Some(dir) if dir != Path::new("") => dir,
+				_ => Path::new("."),
+			};
+
+			if watched_dirs.iter().any(|d| d == dir) { continue }
+
+			// The ACME cert directory may not exist until the first certificate is saved
+			let _ = std::fs::create_dir_all(dir);
+
+			match inotify.add_watch(dir, mask) {
+				Ok(_) => watched_dirs.push(dir.to_owned()),
+				Err(err) => warn!("Failed to watch {:?} for certificate changes: {:?}", dir, err),
+			}
+		}
+
+		let file_names: Vec<_> = paths.iter().filter_map(|p| p.file_name().map(|n| n.to_owned())).collect();
+
+		let mut buffer = [0u8; 4096];
 		loop {
-			let cert = cert::acquire_certificate(&domains, &insecure_server, staging)
-				.expect("Failed to acquire certificate");
+			let changed = inotify
+				.read_events_blocking(&mut buffer)
+				.expect("Failed to read inotify events")
+				.any(|e| file_names.iter().any(|name| name.as_os_str() == e.name));
 
-			let days_till_expiry = cert.days_till_expiry().unwrap();
+			if !changed { continue }
 
-			assert!(days_till_expiry > 0);
-			println!("Valid certificate acquired");
+			// Give whatever is renewing a moment to finish writing the rest of the files
+			thread::sleep(Duration::from_secs(1));
 
-			secure_server.send(FileserverCommand::SetCert(cert)).unwrap();
+			match load() {
+				Ok(cert) => {
+					info!("Certificate files changed, reloading");
 
-			// I don't know if sleeping for long periods of time is okay, but idk how else to do this
-			let hours_to_wait = days_till_expiry.saturating_sub(cert::RENEWAL_PERIOD_DAYS) as u64 * 24;
-			for _ in 0..hours_to_wait {
-				thread::sleep(Duration::from_secs(60 * 60));
+					if let Err(err) = broadcast.set_cert(cert) {
+						error!("Reloaded certificate wasn't applied: {}", err);
+					}
+				}
+
+				Err(err) => error!("Failed to reload certificate, keeping the old one: {:?}", err),
+			}
+		}
+	});
+}
+
+
+fn start_cluster_thread(shared: PathBuf, poll_interval: Duration, clock: Arc<dyn Clock>) {
+	info!("Following generations published to {:?}", shared);
+
+	thread::spawn(move || {
+		// The same failure is only worth logging once, not every poll
+		let mut last_error = None;
+
+		loop {
+			match publish::sync_with_cluster(&shared, &*clock) {
+				Ok(Some(target)) => info!("Switched to cluster generation {}", target.generation),
+				Ok(None) => {}
+				Err(err) => {
+					let err = err.to_string();
+					if last_error.as_ref() != Some(&err) {
+						error!("Failed to sync with {:?}: {}", shared, err);
+					}
+
+					last_error = Some(err);
+					thread::sleep(poll_interval);
+					continue
+				}
 			}
 
-			println!("Renewing certificate...");
+			last_error = None;
+
+			thread::sleep(poll_interval);
+		}
+	});
+}
+
+fn start_autorenew_thread(store: Arc<dyn CertStore>, domains: Vec<String>, broadcast: Broadcast, ready: Arc<ReadyNotifier>,
+	staging: bool, policy: cert::RenewalPolicy, clock: Arc<dyn Clock>) {
+	info!("Starting certificate autorenewal thread...");
+
+	thread::spawn(move || {
+		let mut renewing = false;
+
+		loop {
+			// A cert on disk is fine at startup, but renewing means asking for a new one
+			let acquired = cert::acquire_certificate(&*store, &domains, &broadcast, staging, &policy, !renewing, &*clock)
+				.and_then(|cert| Ok((cert.secs_till_expiry(&*clock)?, cert)));
+
+			let retry_minutes = policy.retry_interval.as_secs() / 60;
+
+			let cert = match acquired {
+				Ok((secs, cert)) if secs > 0 => cert,
+				Ok(_) => {
+					warn!("Acquired certificate has already expired, retrying in {} minutes", retry_minutes);
+					clock.sleep(policy.retry_interval);
+					continue
+				}
+
+				Err(err) => {
+					error!("Failed to acquire certificate, retrying in {} minutes: {:?}", retry_minutes, err);
+					clock.sleep(policy.retry_interval);
+					continue
+				}
+			};
+
+			info!("Valid certificate acquired");
+
+			let wait = policy.time_till_renewal(&cert, &*clock).unwrap_or(policy.retry_interval);
+
+			// A certificate the server can't use is as good as none, so get another one
+			if let Err(err) = broadcast.set_cert(cert) {
+				error!("Failed to apply certificate, retrying in {} minutes: {}", retry_minutes, err);
+				renewing = true;
+				clock.sleep(policy.retry_interval);
+				continue
+			}
+
+			ready.done("certificate");
+
+			info!("Renewing certificate in {} hours", wait.as_secs() / (60 * 60));
+
+			// I don't know if sleeping for long periods of time is okay, but idk how else to do this
+			clock::sleep_until(&*clock, clock.now() + wait, Duration::from_secs(60 * 60));
+
+			info!("Renewing certificate...");
+			renewing = true;
 		}
 	});
 }
\ No newline at end of file
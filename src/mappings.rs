@@ -1,84 +1,641 @@
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
-use std::io::{Read, Write};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf, Component};
+use std::borrow::Cow;
+use std::io::{Read, Write, Seek, SeekFrom, Cursor};
 use std::fs;
+use std::net::IpAddr;
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+use std::thread;
 
 use crate::SBResult;
+use crate::ip_filter::{Cidr, IpFilter};
+use crate::clock::{self, Clock};
+use crate::open_file::OpenFile;
+use crate::mime;
+use crate::preview::{self, Preview};
+use crate::publish;
+use crate::autoindex;
+use crate::artifacts::{self, ArtifactSet, DigestCache, PreparedSet, Sidecar, SigningKey};
+use crate::transform::{self, Transform, Includes};
+
+use acme_client::openssl::sha::Sha256;
+use acme_client::openssl::{base64, memcmp, pkcs5};
+use acme_client::openssl::hash::MessageDigest;
 
 use flate2::Compression;
 use flate2::write::{GzEncoder, DeflateEncoder};
+use flate2::read;
 
 pub const MAPPINGS_FILENAME: &'static str = "mappings.sb";
 
-#[derive(Clone, Copy)]
+/// Files at least this big are read from disk when served instead of being held in the cache
+const OPEN_FILE_THRESHOLD_BYTES: u64 = 1<<20;
+
+/// Threads compressing assets when mappings are loaded. 0 means one per CPU
+static COMPRESSION_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether routes declaring a content type their file doesn't look like stop mappings from loading
+static STRICT_TYPES: AtomicBool = AtomicBool::new(false);
+
+/// Whether disk-backed assets are checked against the hash taken at load before every response
+static VERIFY_INTEGRITY: AtomicBool = AtomicBool::new(false);
+
+/// Enough to recognise any format `mime::sniff` knows about
+const SNIFF_BYTES: u64 = 512;
+
+/// Deep enough for any sane layout, shallow enough that an import cycle errors instead of overflowing the stack
+const MAX_IMPORT_DEPTH: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CacheMode {
+	/// Load and compress everything when mappings are loaded
+	Eager,
+	/// Load and compress on first request, and keep until the file changes
+	Lazy,
+	/// Load and compress on every request
+	Disabled,
+}
+
+#[derive(Clone, Copy, PartialEq)]
 pub enum Encoding {
 	Uncompressed,
 	Gzip,
 	Deflate,
+	Brotli,
+}
+
+impl Encoding {
+	/// Value for the Content-Encoding header
+	pub fn content_coding(&self) -> &'static str {
+		match self {
+			Encoding::Uncompressed => "identity",
+			Encoding::Gzip => "gzip",
+			Encoding::Deflate => "deflate",
+			Encoding::Brotli => "br",
+		}
+	}
 }
 
 pub trait MappedAsset {
 	fn get_encoding(&self, _: Encoding) -> SBResult<Vec<u8>>;
+
+	/// Returns a reader for assets that should be streamed from disk rather than buffered
+	fn get_stream(&self, _: Encoding) -> Option<SBResult<Box<dyn Read>>> {
+		None
+	}
+
+	fn has_encoding(&self, encoding: Encoding) -> bool {
+		match encoding {
+			Encoding::Brotli => false,
+			_ => true,
+		}
+	}
+
+	/// Reads `len` bytes of the uncompressed data, starting at `start`
+	fn open_range(&self, start: u64, len: u64) -> SBResult<Box<dyn Read>> {
+		let data = self.get_encoding(Encoding::Uncompressed)?;
+		let mut cursor = Cursor::new(data);
+		cursor.set_position(start);
+		Ok(Box::new(cursor.take(len)))
+	}
+
+	/// Size of an encoded variant, if it's known without encoding it
+	fn encoded_len(&self, _: Encoding) -> Option<usize> {
+		None
+	}
+
+	/// Whether the next request will be served from memory, for diagnostics
+	fn cache_status(&self) -> &'static str {
+		"bypass"
+	}
+
+	fn content_hash(&self) -> Option<String> {
+		None
+	}
+
+	fn last_modified(&self) -> Option<SystemTime> {
+		None
+	}
+
+	/// Checks that bytes read from disk are still the ones that were there when mappings were loaded.
+	/// Assets held in memory have nothing to check
+	fn verify(&self) -> SBResult<()> {
+		Ok(())
+	}
+}
+
+enum AssetData {
+	Buffered(Vec<u8>),
+	File(OpenFile),
 }
 
 struct PreprocessedAsset {
-	uncompressed_data: Vec<u8>,
-	deflated_data: Vec<u8>,
-	gzipped_data: Vec<u8>,
+	uncompressed_data: AssetData,
+	/// Files held open don't get compressed copies, so these are only there for them if they had sidecars
+	deflated_data: Option<AssetData>,
+	gzipped_data: Option<AssetData>,
+	brotli_data: Option<AssetData>,
+	content_hash: String,
+	/// Modification time of the source file, if there is one
+	modified: Option<SystemTime>,
 }
 
 struct UnprocessedAsset {
 	file_path: PathBuf,
+	/// Hash of the file when mappings were loaded, if integrity is being verified
+	expected_hash: Option<String>,
 }
 
-#[derive(Debug)]
+/// Content generated per request, compressed on demand
+struct GeneratedAsset {
+	data: Vec<u8>,
+}
+
+struct LazyAsset {
+	file_path: PathBuf,
+	transforms: Vec<Arc<dyn Transform>>,
+	state: Arc<Mutex<LazyState>>,
+}
+
+#[derive(Default)]
+struct LazyState {
+	cached: Option<(SystemTime, Arc<PreprocessedAsset>)>,
+	/// Modification time of the version last sent off to be compressed on another thread, so each is only
+	/// sent once, even if compressing it fails
+	compressing: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Mapping {
 	pub path: PathBuf,
 	pub content_type: Option<String>,
+	/// Smaller variant to serve to clients that send `Save-Data: on`
+	pub save_data_path: Option<PathBuf>,
+	/// Extra response headers, from `{Key: value; Key: value}`
+	pub headers: Vec<(String, String)>,
+	/// Sent instead of 200 along with the content, from `[410]` or `[451 text/html]`
+	pub status: Option<u16>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ETagKind {
+	/// Hash of the content, differs per encoding
+	Strong,
+	/// Modification time and size
+	Weak,
+}
+
+#[derive(Debug, Clone)]
+pub struct Redirect {
+	pub location: String,
+	pub status: u16,
+}
+
+/// From `expire /route => 2030-01-01T00:00 /optional/redirect`. Routes ending in `*` are prefixes
+#[derive(Debug, Clone)]
+pub struct Expiry {
+	pub route: String,
+	pub at: SystemTime,
+	pub redirect: Option<String>,
+}
+
+/// What happens to requests caught by a `block` or `block-agent` rule
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockAction {
+	/// Respond with 403 Forbidden
+	Forbid,
+	/// Close the connection without responding, like nginx's 444
+	Drop,
+}
+
+/// From `block /pattern => 403|drop` or `block-agent pattern => 403|drop`, where `*` matches anything
+#[derive(Debug, Clone)]
+pub struct BlockRule {
+	/// Lowercased for user agents, which are matched case-insensitively
+	pub pattern: String,
+	pub user_agent: bool,
+	pub action: BlockAction,
+}
+
+/// From `limit /pattern => 4` or `limit /pattern => 4 queue`, where `*` matches anything
+#[derive(Debug, Clone)]
+struct ConcurrencyLimit {
+	pattern: String,
+	max: usize,
+	/// Wait for a slot to free up instead of answering 503
+	queue: bool,
+	/// Shared by every copy of the mappings, and carried over on reload so requests still running keep counting
+	in_flight: Arc<AtomicUsize>,
+}
+
+/// From `auth /prefix => user:pbkdf2-sha256:<iterations>:<salt>:<hash>`. Several users can share a prefix,
+/// each on their own line
+#[derive(Debug, Clone)]
+struct AuthRule {
+	prefix: String,
+	users: Vec<(String, PasswordHash)>,
+}
+
+/// Fewer than this and a leaked mappings file is too cheap to brute force
+const MIN_PBKDF2_ITERATIONS: usize = 10_000;
+
+/// A salted PBKDF2-HMAC-SHA256 of a password, as made by
+/// `python3 -c 'import hashlib,os,sys; s=os.urandom(16); print("pbkdf2-sha256:100000:%s:%s" % (s.hex(), hashlib.pbkdf2_hmac("sha256", sys.argv[1].encode(), s, 100000).hex()))' <password>`
+#[derive(Debug, Clone)]
+struct PasswordHash {
+	iterations: usize,
+	salt: Vec<u8>,
+	hash: Vec<u8>,
+}
+
+impl PasswordHash {
+	fn parse(value: &str) -> Option<PasswordHash> {
+		let mut parts = value.split(':');
+		if parts.next()? != "pbkdf2-sha256" {
+			return None
+		}
+
+		let iterations = parts.next()?.parse().ok().filter(|&n| n >= MIN_PBKDF2_ITERATIONS)?;
+		let salt = unhex(parts.next()?).filter(|salt| salt.len() >= 8)?;
+		let hash = unhex(parts.next()?).filter(|hash| hash.len() >= 16)?;
+
+		if parts.next().is_some() {
+			return None
+		}
+
+		Some(PasswordHash { iterations, salt, hash })
+	}
+
+	/// Compared in constant time so response timing doesn't give the hash away
+	fn matches(&self, password: &str) -> bool {
+		let mut derived = vec![0u8; self.hash.len()];
+		pkcs5::pbkdf2_hmac(password.as_bytes(), &self.salt, self.iterations, MessageDigest::sha256(), &mut derived).is_ok()
+			&& memcmp::eq(&self.hash, &derived)
+	}
+}
+
+/// Whether a request can go ahead under its route's concurrency limit
+pub enum Admission {
+	Unlimited,
+	Admitted(LimitSlot),
+	Full { queue: bool },
+}
+
+/// One request's share of a concurrency limit, given back on drop
+pub struct LimitSlot {
+	in_flight: Arc<AtomicUsize>,
+}
+
+impl Drop for LimitSlot {
+	fn drop(&mut self) {
+		self.in_flight.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+/// A route from a `[host example.com]` or `[host *.example.com]` section.
+/// `{subdomain}` in the mapping path is replaced by whatever `*` matched
+#[derive(Debug, Clone)]
+pub struct HostRoute {
+	pub host: String,
+	pub route: String,
+	pub mapping: Mapping,
+}
+
+/// What requests by IP address or without a Host get, from `unknown-host sites` or `unknown-host example.com`
+#[derive(Debug, Clone)]
+enum UnknownHost {
+	/// A generated page linking to every `[host]` section, and nothing else
+	Sites,
+	/// Whatever this host would get
+	Default(String),
+}
+
+/// Cache entries that changed in a `Mappings`, so a one-file change doesn't mean sending every
+/// mapping over again. Applied with `Mappings::apply_delta`
+#[derive(Clone)]
+pub struct MappingsDelta {
+	changed: Vec<(PathBuf, Arc<PreprocessedAsset>)>,
+	/// Files that have been deleted since they were cached
+	removed: Vec<PathBuf>,
+}
+
+/// Summary of a single cache entry, for reporting
+#[derive(Debug)]
+pub struct CacheEntryInfo {
+	pub path: PathBuf,
+	pub content_hash: String,
+	pub uncompressed_size: usize,
+	pub gzipped_size: Option<usize>,
+	pub deflated_size: Option<usize>,
+	pub brotli_size: Option<usize>,
 }
 
+#[derive(Clone)]
 pub struct Mappings {
 	mappings: HashMap<String, Mapping>,
+	/// `/prefix/* => dir/*` routes, where `Mapping::path` is the directory
+	wildcard_mappings: Vec<(String, Mapping)>,
+	/// Files that existed under wildcard directories at load time
+	wildcard_files: Vec<PathBuf>,
+	/// Routes that only apply to matching Host headers, checked before everything else
+	host_routes: Vec<HostRoute>,
+	/// Host pattern that previews are served under, from `previews *.preview.example.com`
+	preview_host: Option<String>,
+	previews: HashMap<String, Preview>,
+	unknown_host: Option<UnknownHost>,
 	imported_mappings: Vec<PathBuf>,
+	/// Canonical paths of this file and everything it imports, which wildcards must never serve
+	mappings_files: Vec<PathBuf>,
+	/// How many imports deep `load_from` currently is
+	import_depth: usize,
+	redirects: HashMap<String, Redirect>,
+	expiries: Vec<Expiry>,
+	block_rules: Vec<BlockRule>,
+	limits: Vec<ConcurrencyLimit>,
+	auth_rules: Vec<AuthRule>,
+	/// Uri prefixes only some peers can request, from `allow /prefix => cidr...` and `deny /prefix => cidr...`
+	ip_rules: Vec<(String, IpFilter)>,
+	/// Added to every response, from lines in a `[headers]` section. Route headers win over these
+	global_headers: Vec<(String, String)>,
+	/// Uri prefixes with their own time limit per request, from `deadline /prefix => seconds`
+	deadlines: Vec<(String, Duration)>,
+	/// Route patterns that serve a page for any unknown uri under them, from `fallback /app/* => app/index.html`.
+	/// The page is a mapping under `@fallback <pattern>`, so it's cached like any other
+	fallbacks: Vec<String>,
+	/// Uri prefixes that get diagnostic response headers
+	debug_prefixes: Vec<String>,
+	/// Uri prefixes crawlers are told not to index, from `noindex /prefix`
+	noindex_prefixes: Vec<String>,
+	/// Uri prefixes with an explicit etag kind, from `etag <strong|weak> /prefix`
+	etag_prefixes: Vec<(String, ETagKind)>,
+	/// Route patterns and what to apply to the files behind them, from `transform /route => ...`
+	transform_rules: Vec<(String, Arc<dyn Transform>)>,
+	/// Transforms for each mapped file, resolved from `transform_rules` once all routes are known
+	transforms: HashMap<PathBuf, Vec<Arc<dyn Transform>>>,
+	/// Shared by every `transform /route => includes`, so it only needs to be told about routes once
+	includes: Arc<Includes>,
+	/// Extension => content type, checked before the builtin table
+	mime_overrides: HashMap<String, String>,
+	/// Appended to text content types that don't specify a charset
+	charset: Option<String>,
+	/// Mounted directories that get checksum and signature files generated next to each file,
+	/// from `artifacts /route => dir`
+	artifact_sets: Vec<ArtifactSet>,
+	artifact_digests: Arc<DigestCache>,
+	/// Artifact route => its sidecars, generated at load
+	prepared_artifacts: HashMap<String, PreparedSet>,
+	/// Directory to generate listings from, for directory uris without an index
+	autoindex_root: Option<PathBuf>,
+	autoindex_hide_dotfiles: bool,
 	file_cache: HashMap<PathBuf, Arc<PreprocessedAsset>>,
-	caching_enabled: bool,
+	lazy_cache: HashMap<PathBuf, Arc<LazyAsset>>,
+	/// Hashes of files served straight from disk, taken at load so they can be verified later
+	recorded_hashes: HashMap<PathBuf, String>,
+	/// Every file routes resolved to at load, the only ones served straight from disk
+	disk_paths: HashSet<PathBuf>,
+	cache_mode: CacheMode,
+	/// What expiries and previews are checked against
+	clock: Arc<dyn Clock>,
 }
 
 impl Mappings {
-	pub fn new(caching_enabled: bool) -> Self {
+	pub fn new(cache_mode: CacheMode) -> Self {
 		Mappings {
 			mappings: HashMap::new(),
+			wildcard_mappings: Vec::new(),
+			wildcard_files: Vec::new(),
+			host_routes: Vec::new(),
+			preview_host: None,
+			previews: HashMap::new(),
+			unknown_host: None,
 			imported_mappings: Vec::new(),
+			mappings_files: Vec::new(),
+			import_depth: 0,
+			redirects: HashMap::new(),
+			expiries: Vec::new(),
+			block_rules: Vec::new(),
+			limits: Vec::new(),
+			auth_rules: Vec::new(),
+			ip_rules: Vec::new(),
+			global_headers: Vec::new(),
+			deadlines: Vec::new(),
+			fallbacks: Vec::new(),
+			debug_prefixes: Vec::new(),
+			noindex_prefixes: Vec::new(),
+			etag_prefixes: Vec::new(),
+			transform_rules: Vec::new(),
+			transforms: HashMap::new(),
+			includes: Arc::new(Includes::new()),
+			mime_overrides: HashMap::new(),
+			charset: None,
+			artifact_sets: Vec::new(),
+			artifact_digests: Arc::new(DigestCache::default()),
+			prepared_artifacts: HashMap::new(),
+			autoindex_root: None,
+			autoindex_hide_dotfiles: false,
 			file_cache: HashMap::new(),
-			caching_enabled,
+			lazy_cache: HashMap::new(),
+			recorded_hashes: HashMap::new(),
+			disk_paths: HashSet::new(),
+			cache_mode,
+			clock: clock::system(),
 		}
 	}
 
-	pub fn from_file(path: &str, caching_enabled: bool) -> crate::SBResult<Mappings> {
+	/// Loads a mappings file. Paths in it are relative to the directory it's in
+	pub fn from_file<P: AsRef<Path>>(path: P, cache_mode: CacheMode) -> crate::SBResult<Mappings> {
+		Self::from_file_reusing(path, cache_mode, None)
+	}
+
+	/// Like `from_file`, but takes cache entries from `previous` where nothing they were built from
+	/// has changed, so editing mappings doesn't mean recompressing everything
+	pub fn from_file_reusing<P: AsRef<Path>>(path: P, cache_mode: CacheMode, previous: Option<&Mappings>)
+		-> crate::SBResult<Mappings> {
+
+		let path = path.as_ref();
+
 		let mut file = fs::File::open(path)?;
 		let mut contents = String::new();
 		file.read_to_string(&mut contents)?;
 
-		let mut mps = Mappings::new(caching_enabled);
-		mps.load_from(&contents, Path::new(""))?;
-		if caching_enabled {
-			mps.process_mapped_assets()?;
+		let mut mps = Mappings::parse(&contents, path.parent().unwrap_or(Path::new("")), cache_mode)?;
+		mps.mappings_files.extend(fs::canonicalize(path));
+		mps.load_previews()?;
+
+		if STRICT_TYPES.load(Ordering::Relaxed) {
+			mps.check_declared_types()?;
+		}
+
+		mps.resolve_content_types();
+		mps.resolve_transforms();
+
+		if let Some(previous) = previous {
+			mps.reuse_cache(previous);
+			mps.reuse_limits(previous);
+			mps.artifact_digests = previous.artifact_digests.clone();
 		}
 
+		mps.prepare_artifacts()?;
+		mps.prepare_cache()?;
+
+		Ok(mps)
+	}
+
+	/// Just the routes and directives in `contents`, with paths relative to `dir`. Nothing is cached yet
+	pub fn parse(contents: &str, dir: &Path, cache_mode: CacheMode) -> SBResult<Mappings> {
+		let mut mps = Mappings::new(cache_mode);
+		mps.load_from(contents, dir)?;
+		Ok(mps)
+	}
+
+	pub fn from_dir(path: &str, cache_mode: CacheMode) -> crate::SBResult<Mappings> {
+		let mut mps = Mappings::new(cache_mode);
+		mps.mount_directory("", Path::new(path), &[])?;
+		mps.resolve_content_types();
+		mps.prepare_cache()?;
+
 		Ok(mps)
 	}
 
-	pub fn from_dir(path: &str, caching_enabled: bool) -> crate::SBResult<Mappings> {
-		let mut mps = Mappings::new(caching_enabled);
-		mps.walk_directory(Path::new(path))?;
+	fn load_previews(&mut self) -> SBResult<()> {
+		let preview_host = match &self.preview_host {
+			Some(host) => host.clone(),
+			None => return Ok(()),
+		};
+
+		for (name, preview) in preview::load()? {
+			if preview.expired(&*self.clock) { continue }
+
+			debug!("Adding preview {}{} => {:?}", name, &preview_host[1..], preview.dir);
+
+			if let Err(e) = collect_files(&preview.dir, &mut self.wildcard_files) {
+				warn!("Failed to load preview {}: {}", name, e);
+				continue
+			}
 
-		if caching_enabled {
-			mps.process_mapped_assets()?;
+			self.previews.insert(name, preview);
 		}
 
-		Ok(mps)
+		Ok(())
+	}
+
+	/// Copies over entries for files that haven't changed since `previous` cached them. Transformed
+	/// files are always rebuilt, since the transforms themselves might be what changed
+	fn reuse_cache(&mut self, previous: &Mappings) {
+		if self.cache_mode != previous.cache_mode { return }
+
+		let paths: Vec<PathBuf> = self.asset_paths()
+			.filter(|path| self.transforms_for(path).is_empty() && previous.transforms_for(path).is_empty())
+			.map(Into::into)
+			.collect();
+
+		for path in paths {
+			if let Some(asset) = previous.file_cache.get(&path) {
+				let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+				if asset.modified.is_some() && modified == asset.modified {
+					self.file_cache.insert(path.clone(), asset.clone());
+				}
+			}
+
+			// Lazy entries check for changes themselves
+			if let Some(asset) = previous.lazy_cache.get(&path) {
+				self.lazy_cache.insert(path, asset.clone());
+			}
+		}
+
+		info!("Reusing {} cached files", self.file_cache.len() + self.lazy_cache.len());
+	}
+
+	/// Limits on the same pattern keep counting requests admitted under the old mappings
+	fn reuse_limits(&mut self, previous: &Mappings) {
+		for limit in self.limits.iter_mut() {
+			if let Some(old) = previous.limits.iter().find(|old| old.pattern == limit.pattern) {
+				limit.in_flight = old.in_flight.clone();
+			}
+		}
+	}
+
+	/// Digests every artifact and signs SHA256SUMS up front, so requests for sidecars never wait on a big
+	/// file being read, and a key that can't sign them fails the load. Files changed after this are
+	/// picked up by the next reload
+	fn prepare_artifacts(&mut self) -> SBResult<()> {
+		let mut prepared = HashMap::new();
+
+		for set in self.artifact_sets.iter() {
+			let key = set.key.as_deref();
+			let mut files: Vec<_> = self.artifact_files(set).collect();
+			files.sort();
+
+			let mut digests = HashMap::new();
+			let mut sums = String::new();
+
+			for (name, path) in files {
+				let digest = self.artifact_digests.get(path, key)
+					.map_err(|e| failure::format_err!("Failed to hash artifact {:?}: {}", path, e))?;
+
+				sums.push_str(&artifacts::checksum_line(&digest.sha256, name));
+				digests.insert(path.to_owned(), digest);
+			}
+
+			let sums = sums.into_bytes();
+			let sums_signature = match key {
+				Some(key) => Some(key.sign(&sums)?),
+				None => None,
+			};
+
+			prepared.insert(set.prefix.clone(), PreparedSet { sums, sums_signature, digests });
+		}
+
+		self.artifact_digests.retain(|path| prepared.values().any(|set: &PreparedSet| set.digests.contains_key(path)));
+		self.prepared_artifacts = prepared;
+		Ok(())
+	}
+
+	fn prepare_cache(&mut self) -> SBResult<()> {
+		if self.cache_mode == CacheMode::Disabled {
+			self.disk_paths = self.asset_paths().map(Into::into).collect();
+		}
+
+		match self.cache_mode {
+			CacheMode::Eager => self.process_mapped_assets(),
+
+			CacheMode::Lazy => {
+				let paths: Vec<PathBuf> = self.asset_paths().map(Into::into).collect();
+
+				for path in paths {
+					if self.lazy_cache.contains_key(&path) { continue }
+
+					let transforms = self.transforms_for(&path).to_vec();
+					let asset = LazyAsset { file_path: path.clone(), transforms, state: Default::default() };
+					self.lazy_cache.insert(path, Arc::new(asset));
+				}
+
+				Ok(())
+			}
+
+			CacheMode::Disabled if VERIFY_INTEGRITY.load(Ordering::Relaxed) => {
+				let paths: Vec<PathBuf> = self.asset_paths()
+					.filter(|path| self.transforms_for(path).is_empty())
+					.map(Into::into)
+					.collect();
+
+				for path in paths {
+					if self.recorded_hashes.contains_key(&path) { continue }
+
+					// Missing files are reported when they're requested
+					if let Ok(hash) = hash_file(&path) {
+						self.recorded_hashes.insert(path, hash);
+					}
+				}
+
+				Ok(())
+			}
+
+			CacheMode::Disabled => Ok(()),
+		}
 	}
 
 	pub fn insert_data_mapping<T>(&mut self, key: &str, data: T) -> crate::SBResult<()>
@@ -88,32 +645,27 @@ impl Mappings {
 		let content_type = None;
 
 		self.file_cache.insert(key.into(), Arc::new(asset));
-		self.mappings.insert(key.into(), Mapping{ path: key.into(), content_type });
+		self.mappings.insert(key.into(), Mapping{ path: key.into(), content_type, ..Default::default() });
 
 		Ok(())
 	}
 
-	fn walk_directory(&mut self, path: &Path) -> SBResult<()> {
-		for entry in fs::read_dir(path)? {
-			let path = entry?.path();
-
-			if path.is_dir() {
-				self.walk_directory(&path)?;
+	/// Maps every file under `root` to `uri_prefix` + its path relative to `root`
+	fn mount_directory(&mut self, uri_prefix: &str, root: &Path, headers: &[(String, String)]) -> SBResult<()> {
+		let mut files = Vec::new();
+		collect_files(root, &mut files)?;
 
-			} else {
-				let mut path_str = path
-					.strip_prefix("./")
-					.unwrap_or(&path)
-					.to_str()
-					.ok_or_else(|| failure::format_err!("Failed to walk directory"))?
-					.to_owned();
-
-				if path_str.contains(".spiderbutter") { continue }
+		let uri_prefix = uri_prefix.trim_end_matches('/');
 
-				path_str.insert(0, '/');
+		for path in files {
+			let relative = path
+				.strip_prefix(root)
+				.unwrap_or(&path)
+				.to_str()
+				.ok_or_else(|| failure::format_err!("Failed to walk directory"))?;
 
-				self.mappings.insert(path_str, Mapping{ path: path.into(), content_type: None });
-			}
+			let key = format!("{}/{}", uri_prefix, relative);
+			self.mappings.insert(key, Mapping{ path, headers: headers.to_vec(), ..Default::default() });
 		}
 
 		Ok(())
@@ -125,12 +677,76 @@ impl Mappings {
 			.filter(|s| !s.is_empty() && !s.starts_with('#'));
 
 		let mut imports = Vec::new();
+		let mut save_data_variants = Vec::new();
+
+		// Set by `[host pattern]` lines, `[host *]` goes back to routes for any host
+		let mut current_host: Option<String> = None;
+
+		// Lines after `[headers]` are `Name: value` until the next section
+		let mut in_headers = false;
 
 		for mapping in iter {
+			if mapping == "[headers]" {
+				in_headers = true;
+				continue
+			}
+
+			if in_headers && !mapping.starts_with('[') {
+				// Taken whole, since there's only ever one header per line
+				let mut parts = mapping.splitn(2, ':').map(str::trim);
+				match (parts.next(), parts.next()) {
+					(Some(key), Some(value)) if !key.is_empty() && !key.contains(char::is_whitespace) => {
+						self.global_headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(key));
+						self.global_headers.push((key.to_owned(), value.to_owned()));
+					}
+
+					_ => failure::bail!("Invalid header '{}'. Routes after [headers] need a [host *] line before them", mapping),
+				}
+
+				continue
+			}
+
+			in_headers = false;
+
 			let partition = mapping.find("=>");
 			if partition.is_none() {
 				if mapping.starts_with("import") {
 					imports.push(Path::new(mapping[6..].trim()));
+				} else if mapping.starts_with("charset") {
+					self.charset = Some(mapping[7..].trim().to_owned());
+				} else if mapping.starts_with("etag") {
+					let mut parts = mapping[4..].split_whitespace();
+					let kind = match parts.next() {
+						Some("strong") => ETagKind::Strong,
+						Some("weak") => ETagKind::Weak,
+						_ => failure::bail!("Invalid etag directive '{}'", mapping),
+					};
+
+					let prefix = parts.next().unwrap_or("/");
+					self.etag_prefixes.push((prefix.to_owned(), kind));
+				} else if mapping.starts_with("[host") && mapping.ends_with(']') {
+					let pattern = mapping[5..mapping.len()-1].trim().to_ascii_lowercase();
+					current_host = if pattern == "*" { None } else { Some(pattern) };
+				} else if mapping.starts_with("previews") {
+					let pattern = mapping[8..].trim().to_ascii_lowercase();
+					if !pattern.starts_with("*.") {
+						failure::bail!("Previews need a wildcard host, e.g. 'previews *.preview.example.com'");
+					}
+
+					self.preview_host = Some(pattern);
+				} else if mapping.starts_with("unknown-host") {
+					let value = mapping[12..].trim().to_ascii_lowercase();
+					self.unknown_host = match value.as_str() {
+						"sites" => Some(UnknownHost::Sites),
+						host if !host.is_empty() && host.split('.').all(is_host_label) => Some(UnknownHost::Default(value.clone())),
+						_ => failure::bail!("Invalid unknown-host directive '{}', expected 'sites' or a host name", mapping),
+					};
+				} else if mapping.starts_with("debug") {
+					debug!("Enabling diagnostic headers for {}", mapping[5..].trim());
+					self.debug_prefixes.push(mapping[5..].trim().to_owned());
+				} else if mapping.starts_with("noindex") {
+					debug!("Hiding {} from crawlers", mapping[7..].trim());
+					self.noindex_prefixes.push(mapping[7..].trim().to_owned());
 				}
 
 				continue
@@ -139,154 +755,2095 @@ impl Mappings {
 			let (key, value) = mapping.split_at(partition.unwrap());
 			let (key, value) = (key.trim_end(), value[2..].trim_start());
 
-			// extract content type
-			let (value, content_type) = if let Some(pos) = value.find('[') {
-				let (value, type_start) = value.split_at(pos);
-				let content_type = type_start[1..].split(']').next().unwrap();
-				(value.trim(), Some(content_type.trim().into()))
+			// extract custom headers
+			let (value, headers) = if let Some(pos) = value.find('{') {
+				let (value, headers_start) = value.split_at(pos);
+				let headers = headers_start[1..].split('}').next().unwrap();
+				let value = [value.trim_end(), headers_start.splitn(2, '}').nth(1).unwrap_or("")].concat();
+				(value, parse_headers(headers)?)
 			} else {
-				(value, None)
+				(value.to_owned(), Vec::new())
 			};
 
-			// TODO: exclude cert directory
-			let path = [prefix, Path::new(value)].iter().collect();
+			let value = value.trim();
 
-			if let Some(content_type) = &content_type {
-				println!("Adding mapping {} => {:?} [{}]", key, path, content_type);
-			} else {
-				println!("Adding mapping {} => {:?}", key, path);
+			// directives look like `<directive> /route => value`
+			let directive = key.split_whitespace().next().unwrap_or("");
+			let directive_route = key[directive.len()..].trim();
+
+			if current_host.is_some() && !directive_route.is_empty() {
+				failure::bail!("Only routes are allowed in host sections, found '{}'", mapping);
 			}
-			self.mappings.insert(key.to_owned(), Mapping{ path, content_type });
-		}
 
-		self.imported_mappings.extend(imports.iter().map(From::from));
+			// mount /prefix => path/to/dir
+			if directive == "mount" {
+				let dir: PathBuf = [prefix, Path::new(value)].iter().collect();
+				debug!("Mounting {:?} at {}", dir, directive_route);
 
-		// TODO: Add inotify watches to imported mappings
-		for import in imports {
-			let path: PathBuf = [prefix, import, Path::new(MAPPINGS_FILENAME)].iter().collect();
-			let prefix = path.parent().unwrap_or(Path::new(""));
+				self.mount_directory(directive_route, &dir, &headers)?;
+				continue
+			}
 
-			println!("Importing {:?}", prefix);
+			// artifacts /releases => dist
+			// artifacts /releases => dist sign keys/release.pem
+			if directive == "artifacts" {
+				let mut parts = value.split_whitespace();
+				let dir: PathBuf = match parts.next() {
+					Some(dir) => [prefix, Path::new(dir)].iter().collect(),
+					None => failure::bail!("Artifacts for {} need a directory", directive_route),
+				};
+
+				let key = match (parts.next(), parts.next(), parts.next()) {
+					(None, _, _) => None,
+					(Some("sign"), Some(key), None) => {
+						let key = prefix.join(key);
+						if key.starts_with(&dir) {
+							failure::bail!("Signing key {:?} would be served from {}", key, directive_route);
+						}
+
+						Some(Arc::new(SigningKey::load(&key)?))
+					}
+					_ => failure::bail!("Invalid artifacts directive '{}', expected 'artifacts /route => dir [sign key.pem]'", mapping),
+				};
+
+				debug!("Serving artifacts {:?} at {}{}", dir, directive_route, if key.is_some() { ", signed" } else { "" });
+
+				self.mount_directory(directive_route, &dir, &headers)?;
+				self.artifact_sets.push(ArtifactSet{ prefix: directive_route.trim_end_matches('/').to_owned(), dir, key });
+				continue
+			}
 
-			let mut file = fs::File::open(&path)?;
-			let mut contents = String::new();
-			file.read_to_string(&mut contents)?;
+			// mime .ext => content/type
+			if directive == "mime" {
+				let extension = directive_route.trim_start_matches('.').to_ascii_lowercase();
+				self.mime_overrides.insert(extension, value.to_owned());
+				continue
+			}
 
-			self.load_from(&contents, &prefix)?;
-		}
+			// expire /route => 2030-01-01T00:00 /optional/redirect
+			if directive == "expire" {
+				let mut parts = value.split_whitespace();
+				let at = parts.next()
+					.and_then(crate::http::parse_iso_date)
+					.ok_or_else(|| failure::format_err!("Invalid expiry date for {}", directive_route))?;
 
-		Ok(())
-	}
+				let redirect = parts.next().map(str::to_owned);
 
-	// TODO: Add inotify watches to assets
-	fn process_mapped_assets(&mut self) -> SBResult<()> {
-		use std::collections::hash_map::Entry;
-		use std::time::Instant;
+				debug!("Expiring {} at {}", directive_route, crate::http::format_http_date(at));
+				self.expiries.push(Expiry{ route: directive_route.to_owned(), at, redirect });
+				continue
+			}
 
-		println!("Compressing mapped assets...");
-		let timer = Instant::now();
+			// block /wp-login.php => drop
+			// block-agent *sqlmap* => 403
+			if directive == "block" || directive == "block-agent" {
+				let action = match value {
+					"403" => BlockAction::Forbid,
+					"drop" => BlockAction::Drop,
+					_ => failure::bail!("Invalid block action '{}', expected 403 or drop", value),
+				};
 
-		for Mapping{path, ..} in self.mappings.values() {
-			let entry = self.file_cache.entry(path.clone());
+				let user_agent = directive == "block-agent";
+				let pattern = if user_agent { directive_route.to_ascii_lowercase() } else { directive_route.to_owned() };
 
-			if let Entry::Occupied(_) = entry { continue; }
+				debug!("Blocking {}{} => {}", if user_agent { "user agent " } else { "" }, pattern, value);
+				self.block_rules.push(BlockRule{ pattern, user_agent, action });
+				continue
+			}
 
-			println!("Compressing {:?}...", path);
+			// limit /archives/* => 4
+			// limit /big.iso => 2 queue
+			if directive == "limit" {
+				let mut parts = value.split_whitespace();
+				let max = parts.next()
+					.and_then(|max| max.parse::<usize>().ok())
+					.filter(|&max| max > 0)
+					.ok_or_else(|| failure::format_err!("Invalid limit for {}, expected a number of requests", directive_route))?;
+
+				let queue = match parts.next() {
+					None => false,
+					Some("queue") => true,
+					Some(other) => failure::bail!("Invalid limit option '{}', expected queue", other),
+				};
+
+				debug!("Limiting {} to {} requests at once{}", directive_route, max, if queue { ", queueing the rest" } else { "" });
+				self.limits.push(ConcurrencyLimit{ pattern: directive_route.to_owned(), max, queue, in_flight: Arc::new(AtomicUsize::new(0)) });
+				continue
+			}
 
-			let mut uncompressed_data = Vec::new();
+			// allow /internal => 10.0.0.0/8 192.168.0.0/16
+			// deny /internal => 10.0.9.0/24
+			if directive == "allow" || directive == "deny" {
+				let ranges = value.split_whitespace()
+					.map(Cidr::parse)
+					.collect::<SBResult<Vec<_>>>()?;
 
-			match fs::File::open(path) {
-				Ok(mut file) => {
-					file.read_to_end(&mut uncompressed_data)?;
+				if ranges.is_empty() {
+					failure::bail!("No address ranges given to {} {}", directive, directive_route);
 				}
 
-				Err(_) => {
-					println!("Failed to load file {:?}, skipping...", path);
-					continue
+				debug!("{} {} for {}", if directive == "allow" { "Allowing" } else { "Denying" }, value, directive_route);
+
+				let index = match self.ip_rules.iter().position(|(prefix, _)| prefix == directive_route) {
+					Some(index) => index,
+					None => {
+						self.ip_rules.push((directive_route.to_owned(), IpFilter::default()));
+						self.ip_rules.len() - 1
+					}
+				};
+
+				let filter = &mut self.ip_rules[index].1;
+				if directive == "allow" {
+					filter.allow.extend(ranges);
+				} else {
+					filter.deny.extend(ranges);
 				}
+
+				continue
 			}
 
-			entry.or_insert(Arc::new(PreprocessedAsset::process(uncompressed_data)?));
-		}
+			// deadline /downloads => 600
+			if directive == "deadline" {
+				let secs = value.parse::<u64>().ok()
+					.filter(|&secs| secs > 0)
+					.ok_or_else(|| failure::format_err!("Invalid deadline for {}, expected a number of seconds", directive_route))?;
 
-		println!("Compression finished in {}s {:.2}ms",
-			timer.elapsed().as_secs(),
-			timer.elapsed().subsec_nanos() as f64/1000_000.0);
+				debug!("Giving requests for {} {} seconds", directive_route, secs);
+				self.deadlines.push((directive_route.to_owned(), Duration::from_secs(secs)));
+				continue
+			}
 
-		Ok(())
-	}
+			// auth /staging => alice:pbkdf2-sha256:100000:9f3c51e2...:2bd806c9...
+			if directive == "auth" {
+				let mut parts = value.splitn(2, ':');
+				let user = parts.next().unwrap_or("").trim();
+				let hash = parts.next().unwrap_or("").trim();
 
-	pub fn get_route(&self, key: &str) -> Option<&Mapping> {
-		self.mappings.get(key)
-	}
+				let hash = PasswordHash::parse(hash)
+					.ok_or_else(|| failure::format_err!("Invalid auth for {}, expected user:pbkdf2-sha256:<iterations, at least {}>:<hex salt>:<hex hash>",
+						directive_route, MIN_PBKDF2_ITERATIONS))?;
 
-	pub fn get_asset(&self, route: &PathBuf) -> Option<Arc<dyn MappedAsset>> {
-		if self.caching_enabled {
-			self.file_cache.get(route)
-				.cloned()
-				.map(|a| a as Arc<dyn MappedAsset>)
+				if user.is_empty() {
+					failure::bail!("Invalid auth for {}, missing a user name", directive_route);
+				}
 
-		} else {
-			Some(Arc::new(UnprocessedAsset {file_path: route.clone()}) as Arc<dyn MappedAsset>)
-		}
-	}
-}
+				debug!("Requiring a login as {} for {}", user, directive_route);
 
+				match self.auth_rules.iter_mut().find(|rule| rule.prefix == directive_route) {
+					Some(rule) => rule.users.push((user.to_owned(), hash)),
+					None => self.auth_rules.push(AuthRule{ prefix: directive_route.to_owned(), users: vec![(user.to_owned(), hash)] }),
+				}
 
-impl PreprocessedAsset {
-	fn process(uncompressed_data: Vec<u8>) -> SBResult<PreprocessedAsset> {
-		let compression = Compression::best();
+				continue
+			}
 
-		let mut enc = GzEncoder::new(Vec::new(), compression);
-		enc.write_all(&uncompressed_data)?;
-		let gzipped_data = enc.finish()?;
+			// transform /route => substitute %BUILD_SHA% $BUILD_SHA
+			// transform /blog/* => banner banners/beta.html
+			// transform /*.html => includes
+			if directive == "transform" {
+				debug!("Transforming {} => {}", directive_route, value);
 
-		let mut enc = DeflateEncoder::new(Vec::new(), compression);
-		enc.write_all(&uncompressed_data)?;
-		let deflated_data = enc.finish()?;
+				let transform: Arc<dyn Transform> = match value {
+					"includes" => self.includes.clone(),
+					_ => transform::parse(value, prefix)?.into(),
+				};
 
-		Ok(PreprocessedAsset {
-			uncompressed_data,
-			deflated_data,
-			gzipped_data
-		})
-	}
-}
+				self.transform_rules.push((directive_route.to_owned(), transform));
+				continue
+			}
 
+			// save-data /route => path/to/smaller/variant
+			if directive == "save-data" {
+				let path: PathBuf = [prefix, Path::new(value)].iter().collect();
+				save_data_variants.push((directive_route.to_owned(), path));
+				continue
+			}
 
-impl MappedAsset for PreprocessedAsset {
-	fn get_encoding(&self, encoding: Encoding) -> SBResult<Vec<u8>> {
-		match encoding {
-			Encoding::Uncompressed => Ok(self.uncompressed_data.clone()),
-			Encoding::Deflate => Ok(self.deflated_data.clone()),
-			Encoding::Gzip => Ok(self.gzipped_data.clone()),
-		}
-	}
-}
+			// extract status and content type, either of which can be left out
+			let (value, status, content_type) = if let Some(pos) = value.find('[') {
+				let (value, type_start) = value.split_at(pos);
+				let bracketed = type_start[1..].split(']').next().unwrap_or("").trim();
 
-impl MappedAsset for UnprocessedAsset {
-	fn get_encoding(&self, encoding: Encoding) -> SBResult<Vec<u8>> {
-		let mut uncompressed_data = Vec::new();
+				let first = bracketed.split_whitespace().next().unwrap_or("");
+				let (status, content_type) = match first.parse::<u16>() {
+					Ok(status) => (Some(status), bracketed[first.len()..].trim()),
+					Err(_) => (None, bracketed),
+				};
 
-		println!("Processing {:?}", &self.file_path.as_path());
+				let content_type = Some(content_type.to_owned()).filter(|ct| !ct.is_empty());
+				(value.trim(), status, content_type)
+			} else {
+				(value, None, None)
+			};
 
-		fs::File::open(&self.file_path)?
-			.read_to_end(&mut uncompressed_data)?;
+			// redirect /old => /new [301]
+			if directive == "redirect" {
+				let status = match (status, &content_type) {
+					(None, None) => 301,
+					(Some(status), None) if [301, 302, 303, 307, 308].contains(&status) => status,
+					_ => failure::bail!("Invalid redirect status for {}, expected 301, 302, 303, 307 or 308", directive_route),
+				};
 
-		match encoding {
-			Encoding::Uncompressed => Ok(uncompressed_data),
+				debug!("Adding redirect {} => {} [{}]", directive_route, value, status);
+				self.redirects.insert(directive_route.to_owned(), Redirect{ location: value.to_owned(), status });
+				continue
+			}
 
-			Encoding::Deflate => {
-				let mut enc = DeflateEncoder::new(Vec::new(), Compression::fast());
-				enc.write_all(&uncompressed_data)?;
-				Ok(enc.finish()?)
+			// fallback /app/* => app/index.html
+			// fallback /app/* => app/index.html [404]
+			if directive == "fallback" {
+				let status = match status {
+					None => 200,
+					Some(status @ 200) | Some(status @ 404) => status,
+					Some(status) => failure::bail!("Invalid fallback status {} for {}, expected 200 or 404", status, directive_route),
+				};
+
+				let path: PathBuf = [prefix, Path::new(value)].iter().collect();
+				debug!("Adding fallback {} => {:?} [{}]", directive_route, path, status);
+
+				let mapping = Mapping{ path, content_type, headers, status: Some(status), ..Default::default() };
+				self.mappings.insert(format!("@fallback {}", directive_route), mapping);
+				self.fallbacks.push(directive_route.to_owned());
+				continue
 			}
 
-			Encoding::Gzip => {
-				let mut enc = GzEncoder::new(Vec::new(), Compression::fast());
-				enc.write_all(&uncompressed_data)?;
-				Ok(enc.finish()?)
+			// Only statuses that carry a body make sense for content, redirects have their own directive
+			match status {
+				None | Some(200..=203) | Some(400..=599) => {}
+				Some(status) => failure::bail!("Can't serve content with status {} for '{}'", status, key),
 			}
-		}
+
+			// [host *.example.com]
+			// /prefix/* => dir/{subdomain}/*
+			if let Some(host) = &current_host {
+				if key.ends_with('*') != value.ends_with('*') {
+					failure::bail!("Host route '{}' must be a wildcard on both sides, or neither", mapping);
+				}
+
+				let path: PathBuf = [prefix, Path::new(value.trim_end_matches('*'))].iter().collect();
+				debug!("Adding mapping {} => {:?} for host {}", key, path, host);
+
+				// Every possible subdomain lives under the part of the path before the first variable
+				let fixed_path: PathBuf = path.components()
+					.take_while(|c| !c.as_os_str().to_string_lossy().contains('{'))
+					.collect();
+
+				if fixed_path.as_os_str().is_empty() {
+					failure::bail!("Host route '{}' needs a fixed directory before any variables", mapping);
+				} else if fixed_path.is_dir() {
+					collect_files(&fixed_path, &mut self.wildcard_files)?;
+				} else if fixed_path.is_file() {
+					self.wildcard_files.push(fixed_path);
+				}
+
+				let mapping = Mapping{ path, content_type, headers, status, ..Default::default() };
+				self.host_routes.push(HostRoute{ host: host.clone(), route: key.to_owned(), mapping });
+				continue
+			}
+
+			// /prefix/* => dir/*
+			if key.ends_with('*') && value.ends_with('*') {
+				let dir: PathBuf = [prefix, Path::new(&value[..value.len()-1])].iter().collect();
+				debug!("Adding wildcard mapping {} => {:?}", key, dir);
+
+				collect_files(&dir, &mut self.wildcard_files)?;
+				self.wildcard_mappings.push((key.to_owned(), Mapping{ path: dir, content_type, headers, status, ..Default::default() }));
+				continue
+			}
+
+			// TODO: exclude cert directory
+			let path: PathBuf = [prefix, Path::new(value)].iter().collect();
+
+			match (status, &content_type) {
+				(Some(status), Some(content_type)) => debug!("Adding mapping {} => {:?} [{} {}]", key, path, status, content_type),
+				(Some(status), None) => debug!("Adding mapping {} => {:?} [{}]", key, path, status),
+				(None, Some(content_type)) => debug!("Adding mapping {} => {:?} [{}]", key, path, content_type),
+				(None, None) => debug!("Adding mapping {} => {:?}", key, path),
+			}
+			self.mappings.insert(key.to_owned(), Mapping{ path, content_type, headers, status, ..Default::default() });
+		}
+
+		self.imported_mappings.extend(imports.iter().map(From::from));
+
+		// TODO: Add inotify watches to imported mappings
+		for import in imports {
+			let path: PathBuf = [prefix, import, Path::new(MAPPINGS_FILENAME)].iter().collect();
+			let prefix = path.parent().unwrap_or(Path::new(""));
+
+			debug!("Importing {:?}", prefix);
+
+			if self.import_depth >= MAX_IMPORT_DEPTH {
+				failure::bail!("Imports nested too deeply at {:?}, is there an import cycle?", path);
+			}
+
+			let mut file = fs::File::open(&path)?;
+			let mut contents = String::new();
+			file.read_to_string(&mut contents)?;
+
+			self.mappings_files.extend(fs::canonicalize(&path));
+			self.import_depth += 1;
+			let result = self.load_from(&contents, &prefix);
+			self.import_depth -= 1;
+			result?;
+		}
+
+		// Variants are attached last so they can refer to routes from imports
+		for (route, path) in save_data_variants {
+			match self.mappings.get_mut(&route) {
+				Some(mapping) => {
+					debug!("Adding save-data variant {} => {:?}", route, path);
+					mapping.save_data_path = Some(path);
+				}
+
+				None => warn!("No mapping for save-data variant {}, skipping...", route),
+			}
+		}
+
+		Ok(())
+	}
+
+	fn process_mapped_assets(&mut self) -> SBResult<()> {
+		use std::time::Instant;
+
+		info!("Compressing mapped assets...");
+		let timer = Instant::now();
+
+		let mut paths: Vec<PathBuf> = self.asset_paths()
+			.filter(|path| !self.file_cache.contains_key(*path))
+			.map(Into::into)
+			.collect();
+
+		// Several routes can share a file
+		paths.sort();
+		paths.dedup();
+
+		let work: Vec<_> = paths.into_iter()
+			.map(|path| {
+				let transforms = self.transforms.get(&path).cloned().unwrap_or_else(Vec::new);
+				(path, transforms)
+			})
+			.collect();
+
+		let threads = compression_threads().min(work.len());
+		let work = Arc::new(Mutex::new(work.into_iter()));
+		let (result_tx, result_rx) = mpsc::channel();
+
+		// Each thread takes the next file as soon as it's done with the last, so one huge file doesn't hold up the rest
+		for _ in 0..threads {
+			let work = work.clone();
+			let result_tx = result_tx.clone();
+
+			thread::spawn(move || loop {
+				let next = work.lock().unwrap_or_else(|e| e.into_inner()).next();
+				let (path, transforms) = match next {
+					Some(next) => next,
+					None => break,
+				};
+
+				debug!("Compressing {:?}...", path);
+
+				let asset = PreprocessedAsset::load_transformed(&path, &transforms);
+				if result_tx.send((path, asset)).is_err() { break }
+			});
+		}
+
+		drop(result_tx);
+
+		for (path, asset) in result_rx {
+			match asset {
+				Ok(asset) => { self.file_cache.entry(path).or_insert(Arc::new(asset)); }
+				Err(_) => warn!("Failed to load file {:?}, skipping...", path),
+			}
+		}
+
+		info!("Compression finished in {}s {:.2}ms",
+			timer.elapsed().as_secs(),
+			timer.elapsed().subsec_nanos() as f64/1000_000.0);
+
+		Ok(())
+	}
+
+	pub fn cache_mode(&self) -> CacheMode {
+		self.cache_mode
+	}
+
+	pub fn routes(&self) -> impl Iterator<Item=(&str, &Mapping)> {
+		self.mappings.iter()
+			.chain(self.wildcard_mappings.iter().map(|(k, m)| (k, m)))
+			.map(|(k, m)| (k.as_str(), m))
+	}
+
+	pub fn cache_entries(&self) -> Vec<CacheEntryInfo> {
+		self.file_cache.iter()
+			.map(|(path, asset)| CacheEntryInfo {
+				path: path.clone(),
+				content_hash: asset.content_hash.clone(),
+				uncompressed_size: asset.uncompressed_data.len() as usize,
+				gzipped_size: asset.gzipped_data.as_ref().map(|data| data.len() as usize),
+				deflated_size: asset.deflated_data.as_ref().map(|data| data.len() as usize),
+				brotli_size: asset.brotli_data.as_ref().map(|data| data.len() as usize),
+			})
+			.collect()
+	}
+
+	/// Fails if any route declares a content type that its file doesn't look like, going by the file's
+	/// contents or else its extension. Has to happen before content types are resolved, while declared
+	/// types can still be told apart from guessed ones
+	fn check_declared_types(&self) -> SBResult<()> {
+		let mut checks: Vec<(&str, PathBuf, &str)> = Vec::new();
+
+		for (route, mapping) in self.mappings.iter() {
+			if let Some(content_type) = mapping.content_type.as_ref() {
+				checks.push((route, mapping.path.clone(), content_type));
+			}
+		}
+
+		// Wildcards are checked against whatever is in their directory right now
+		for (route, mapping) in self.wildcard_mappings.iter() {
+			if let Some(content_type) = mapping.content_type.as_ref() {
+				let mut files = Vec::new();
+				if mapping.path.is_dir() {
+					collect_files(&mapping.path, &mut files)?;
+				}
+
+				checks.extend(files.into_iter().map(|file| (route.as_str(), file, content_type.as_str())));
+			}
+		}
+
+		let mut conflicts = Vec::new();
+
+		for (route, path, declared) in checks {
+			let mut head = Vec::new();
+			if let Ok(file) = fs::File::open(&path) {
+				let _ = file.take(SNIFF_BYTES).read_to_end(&mut head);
+			}
+
+			let detected = mime::sniff(&head).map(Into::into)
+				.or_else(|| resolve_content_type(&self.mime_overrides, None, &path, None));
+
+			match detected {
+				Some(detected) if !mime::same_type(&detected, declared) => {
+					conflicts.push(format!("{} => {:?} is declared {} but looks like {}", route, path, declared, detected));
+				}
+
+				_ => {}
+			}
+		}
+
+		if !conflicts.is_empty() {
+			conflicts.sort();
+			failure::bail!("Declared content types don't match their files:\n  {}", conflicts.join("\n  "));
+		}
+
+		Ok(())
+	}
+
+	/// Fills in missing content types from extensions, and applies the configured charset.
+	/// Has to happen after parsing since `mime` and `charset` can appear anywhere
+	fn resolve_content_types(&mut self) {
+		let (overrides, charset) = (&self.mime_overrides, self.charset.as_ref());
+
+		for mapping in self.mappings.values_mut() {
+			let explicit = mapping.content_type.take();
+			mapping.content_type = resolve_content_type(overrides, charset, &mapping.path, explicit);
+		}
+	}
+
+	/// Works out which files `transform` rules apply to. Rules match the routes a file is served
+	/// under, but the cache is keyed by file, so a file reachable from several routes gets the
+	/// transforms from all of them
+	fn resolve_transforms(&mut self) {
+		if self.transform_rules.is_empty() { return }
+
+		let mut routes: Vec<(String, &Path)> = self.mappings.iter()
+			.map(|(route, mapping)| (route.clone(), mapping.path.as_path()))
+			.collect();
+
+		let include_wildcards = self.wildcard_mappings.iter()
+			.map(|(prefix, wildcard)| (prefix[..prefix.len()-1].to_owned(), wildcard.path.clone()))
+			.collect();
+
+		self.includes.set_routes(routes.iter().map(|(r, p)| (r.clone(), p.to_path_buf())).collect(), include_wildcards);
+
+		for (prefix, wildcard) in self.wildcard_mappings.iter() {
+			let prefix = &prefix[..prefix.len()-1];
+
+			for file in self.wildcard_files.iter() {
+				if let Ok(relative) = file.strip_prefix(&wildcard.path) {
+					let relative = relative.to_string_lossy();
+					routes.push((format!("{}{}", prefix, relative), file.as_path()));
+				}
+			}
+		}
+
+		let mut transforms: HashMap<PathBuf, Vec<Arc<dyn Transform>>> = HashMap::new();
+
+		for (pattern, transform) in self.transform_rules.iter() {
+			for (route, path) in routes.iter() {
+				if !glob_match(pattern, route) { continue }
+
+				let entry = transforms.entry(path.to_path_buf()).or_default();
+				if !entry.iter().any(|t| Arc::ptr_eq(t, transform)) {
+					entry.push(transform.clone());
+				}
+			}
+		}
+
+		self.transforms = transforms;
+	}
+
+	fn transforms_for(&self, path: &Path) -> &[Arc<dyn Transform>] {
+		self.transforms.get(path).map_or(&[], Vec::as_slice)
+	}
+
+	/// Paths of all files on disk that mappings point to
+	pub fn asset_paths(&self) -> impl Iterator<Item=&Path> {
+		self.mappings.values()
+			.flat_map(|m| std::iter::once(&m.path).chain(m.save_data_path.as_ref()))
+			.chain(self.wildcard_files.iter())
+			.map(PathBuf::as_path)
+	}
+
+	/// Files that cached entries were built from other than their own, like banners and included fragments
+	pub fn dependency_paths(&self) -> Vec<PathBuf> {
+		let mut paths: Vec<PathBuf> = self.transforms.iter()
+			.flat_map(|(path, transforms)| transforms.iter().flat_map(move |t| t.dependencies(path)))
+			.collect();
+
+		paths.sort();
+		paths.dedup();
+		paths
+	}
+
+	/// Cached files that were built from `source`, not counting `source` itself
+	fn dependents(&self, source: &Path) -> Vec<PathBuf> {
+		self.transforms.iter()
+			.filter(|(path, transforms)| transforms.iter().any(|t| t.dependencies(path).iter().any(|d| d == source)))
+			.map(|(path, _)| path.clone())
+			.collect()
+	}
+
+	/// Reprocesses a single file and replaces its cache entry, along with only the entries built from it,
+	/// like pages that include it. A file that's been deleted has its entry dropped instead, and a `.gz` or `.br`
+	/// sidecar reloads the file it was compressed from. Returns the entries that changed, which is none if caching isn't eager or nothing depends on `path`
+	pub fn reload_asset(&mut self, path: &Path) -> SBResult<Vec<PathBuf>> {
+		if self.cache_mode != CacheMode::Eager {
+			return Ok(Vec::new())
+		}
+
+		// A sidecar that's been written or removed changes how the file it was compressed from is served
+		let mut changed = match sidecar_source(path) {
+			Some(source) if self.transforms_for(&source).is_empty() && self.asset_paths().any(|p| p == source) => {
+				self.reload_file(&source)?
+			}
+			_ => Vec::new(),
+		};
+
+		changed.extend(self.reload_file(path)?);
+		Ok(changed)
+	}
+
+	fn reload_file(&mut self, path: &Path) -> SBResult<Vec<PathBuf>> {
+		let mapped = self.asset_paths().any(|p| p == path);
+		let mut changed = self.dependents(path);
+
+		if mapped && !path.exists() {
+			info!("{:?} was deleted, dropping it from the cache", path);
+			self.file_cache.remove(path);
+			changed.insert(0, path.to_owned());
+		} else if mapped {
+			info!("Recompressing {:?}...", path);
+
+			let asset = PreprocessedAsset::load_transformed(path, self.transforms_for(path))?;
+			self.file_cache.insert(path.to_owned(), Arc::new(asset));
+			changed.insert(0, path.to_owned());
+		}
+
+		for dependent in changed.iter().filter(|&dependent| dependent != path) {
+			info!("Recompressing {:?}, which depends on {:?}...", dependent, path);
+
+			let asset = PreprocessedAsset::load_transformed(dependent, self.transforms_for(dependent))?;
+			self.file_cache.insert(dependent.clone(), Arc::new(asset));
+		}
+
+		Ok(changed)
+	}
+
+	/// The current cache entries for `paths`, to bring another copy of these mappings up to date
+	pub fn delta(&self, paths: &[PathBuf]) -> MappingsDelta {
+		let mut delta = MappingsDelta { changed: Vec::new(), removed: Vec::new() };
+
+		for path in paths {
+			match self.file_cache.get(path) {
+				Some(asset) => delta.changed.push((path.clone(), asset.clone())),
+				None => delta.removed.push(path.clone()),
+			}
+		}
+
+		delta
+	}
+
+	pub fn apply_delta(&mut self, delta: MappingsDelta) {
+		for path in delta.removed {
+			self.file_cache.remove(&path);
+		}
+
+		self.file_cache.extend(delta.changed);
+	}
+
+	/// Rechecks cached files against the filesystem, for when file watching can't be trusted.
+	/// Lazy entries that have been requested before are refreshed in place, and eager entries
+	/// that have changed are returned so they can be passed to `reload_asset`
+	pub fn revalidate(&self) -> Vec<PathBuf> {
+		match self.cache_mode {
+			CacheMode::Eager => self.file_cache.iter()
+				.filter(|(path, asset)| {
+					// Data mappings have no file behind them
+					let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+					asset.modified.is_some() && modified != asset.modified
+				})
+				.map(|(path, _)| path.clone())
+				.collect(),
+
+			CacheMode::Lazy => {
+				for asset in self.lazy_cache.values().filter(|a| a.is_cached()) {
+					if let Err(err) = asset.refresh() {
+						warn!("Failed to revalidate {:?}: {:?}", asset.file_path, err);
+					}
+				}
+
+				Vec::new()
+			}
+
+			CacheMode::Disabled => Vec::new(),
+		}
+	}
+
+	/// Looks up the mapping for a uri, falling back to `<uri>/index.html` for directory style uris
+	pub fn get_route(&self, host: Option<&str>, key: &str) -> Option<Cow<'_, Mapping>> {
+		if let (Some(UnknownHost::Sites), true) = (&self.unknown_host, is_bare_host(host)) {
+			return None
+		}
+
+		let host = self.site_name(host);
+		let host = host.as_ref().map(String::as_str);
+
+		if let Some(mapping) = self.lookup_route(host, key) {
+			return Some(mapping)
+		}
+
+		let index_key = if key.ends_with('/') {
+			format!("{}index.html", key)
+		} else {
+			format!("{}/index.html", key)
+		};
+
+		self.lookup_route(host, &index_key)
+	}
+
+	/// The host routes are looked up under, after `unknown-host` has had its say
+	pub fn site_name(&self, host: Option<&str>) -> Option<String> {
+		let host = match (&self.unknown_host, is_bare_host(host)) {
+			(Some(UnknownHost::Default(default)), true) => Some(default.as_str()),
+			_ => host,
+		};
+
+		// Hosts are case insensitive, and may have a port or a trailing dot
+		host.map(|host| host.split(':').next().unwrap_or("").trim_end_matches('.').to_ascii_lowercase())
+	}
+
+	fn lookup_route(&self, host: Option<&str>, key: &str) -> Option<Cow<'_, Mapping>> {
+		if let Some(mapping) = host.and_then(|host| self.lookup_preview(host, key)) {
+			return Some(Cow::Owned(mapping))
+		}
+
+		if let Some(mapping) = host.and_then(|host| self.lookup_host_route(host, key)) {
+			return Some(Cow::Owned(mapping))
+		}
+
+		if let Some(mapping) = self.mappings.get(key) {
+			return Some(Cow::Borrowed(mapping))
+		}
+
+		// Longest matching wildcard prefix wins
+		let (pattern, wildcard) = self.wildcard_mappings.iter()
+			.filter(|(pattern, _)| key.starts_with(&pattern[..pattern.len()-1]))
+			.max_by_key(|(pattern, _)| pattern.len())?;
+
+		self.wildcard_target(&wildcard.path, &key[pattern.len()-1..], wildcard)
+			.map(Cow::Owned)
+	}
+
+	/// Previews stop being served as soon as they expire, even before the next reload
+	fn lookup_preview(&self, host: &str, key: &str) -> Option<Mapping> {
+		let name = match_host(self.preview_host.as_ref()?, host)?;
+		let preview = self.previews.get(name).filter(|p| !p.expired(&*self.clock))?;
+
+		self.wildcard_target(&preview.dir, key.trim_start_matches('/'), &Mapping::default())
+	}
+
+	fn lookup_host_route(&self, host: &str, key: &str) -> Option<Mapping> {
+		// Exact routes beat wildcards, then the longest wildcard prefix wins
+		let (host_route, subdomain) = self.host_routes.iter()
+			.filter_map(|r| Some((r, match_host(&r.host, host)?)))
+			.filter(|(r, _)| match r.route.ends_with('*') {
+				true => key.starts_with(&r.route[..r.route.len()-1]),
+				false => key == r.route,
+			})
+			.max_by_key(|(r, _)| (!r.route.ends_with('*'), r.route.len()))?;
+
+		let path = PathBuf::from(host_route.mapping.path.to_string_lossy().replace("{subdomain}", subdomain));
+
+		if host_route.route.ends_with('*') {
+			self.wildcard_target(&path, &key[host_route.route.len()-1..], &host_route.mapping)
+		} else if path.is_file() {
+			let content_type = resolve_content_type(&self.mime_overrides, self.charset.as_ref(), &path, host_route.mapping.content_type.clone());
+			let headers = host_route.mapping.headers.clone();
+			Some(Mapping{ path, content_type, headers, status: host_route.mapping.status, ..Default::default() })
+		} else {
+			None
+		}
+	}
+
+	/// Resolves the file `relative` to a wildcard directory, inheriting the wildcard's settings
+	fn wildcard_target(&self, dir: &Path, relative: &str, wildcard: &Mapping) -> Option<Mapping> {
+		let relative = Path::new(relative);
+
+		// Never let a wildcard reach outside of its directory
+		let escapes = relative.components()
+			.any(|c| !matches!(c, Component::Normal(_)));
+
+		if escapes || is_server_state(relative) { return None }
+
+		let path = dir.join(relative);
+		if !path.is_file() || self.is_mappings_file(&path) { return None }
+
+		let content_type = resolve_content_type(&self.mime_overrides, self.charset.as_ref(), &path, wildcard.content_type.clone());
+
+		let headers = wildcard.headers.clone();
+		Some(Mapping{ path, content_type, headers, status: wildcard.status, ..Default::default() })
+	}
+
+	/// Whether `path` is the mappings file being served, or one of its imports
+	fn is_mappings_file(&self, path: &Path) -> bool {
+		let named_like_one = self.mappings_files.iter()
+			.any(|file| file.file_name() == path.file_name());
+
+		// Only worth resolving symlinks for files that could be one
+		named_like_one && fs::canonicalize(path).map_or(false, |path| self.mappings_files.contains(&path))
+	}
+
+	pub fn get_redirect(&self, uri: &str) -> Option<&Redirect> {
+		self.redirects.get(uri)
+	}
+
+	/// The first block rule matching the request, if any
+	pub fn get_block(&self, uri: &str, user_agent: Option<&str>) -> Option<BlockAction> {
+		let user_agent = user_agent.map(str::to_ascii_lowercase);
+
+		self.block_rules.iter()
+			.find(|rule| match (rule.user_agent, &user_agent) {
+				(false, _) => glob_match(&rule.pattern, uri),
+				(true, Some(user_agent)) => glob_match(&rule.pattern, user_agent),
+				(true, None) => false,
+			})
+			.map(|rule| rule.action)
+	}
+
+	/// Takes a slot under the first limit matching `uri`, if there's one free
+	pub fn admit(&self, uri: &str) -> Admission {
+		let limit = match self.limits.iter().find(|limit| glob_match(&limit.pattern, uri)) {
+			Some(limit) => limit,
+			None => return Admission::Unlimited,
+		};
+
+		let admitted = limit.in_flight.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |in_flight| {
+			if in_flight < limit.max { Some(in_flight + 1) } else { None }
+		});
+
+		match admitted {
+			Ok(_) => Admission::Admitted(LimitSlot { in_flight: limit.in_flight.clone() }),
+			Err(_) => Admission::Full { queue: limit.queue },
+		}
+	}
+
+	/// Whether `peer` can request `uri`, going by the longest `allow` or `deny` prefix covering it
+	pub fn permits_ip(&self, uri: &str, peer: IpAddr) -> bool {
+		self.ip_rules.iter()
+			.filter(|(prefix, _)| covers_path(prefix, uri))
+			.max_by_key(|(prefix, _)| prefix.len())
+			.map_or(true, |(_, filter)| filter.permits(peer))
+	}
+
+	/// How long a request for `uri` gets from start to finish, going by the longest `deadline` prefix covering it
+	pub fn get_deadline(&self, uri: &str) -> Option<Duration> {
+		self.deadlines.iter()
+			.filter(|(prefix, _)| covers_path(prefix, uri))
+			.max_by_key(|(prefix, _)| prefix.len())
+			.map(|&(_, deadline)| deadline)
+	}
+
+	/// Whether `authorization` holds Basic credentials that the longest `auth` prefix covering `uri`
+	/// accepts. Returns the prefix to name as the realm if they aren't
+	pub fn check_auth(&self, uri: &str, authorization: Option<&str>) -> Result<(), &str> {
+		let rule = match self.auth_rules.iter().filter(|rule| covers_path(&rule.prefix, uri)).max_by_key(|rule| rule.prefix.len()) {
+			Some(rule) => rule,
+			None => return Ok(()),
+		};
+
+		let credentials = authorization
+			.and_then(|value| {
+				let mut parts = value.split_whitespace();
+				match (parts.next(), parts.next()) {
+					(Some(scheme), Some(encoded)) if scheme.eq_ignore_ascii_case("Basic") => base64::decode_block(encoded).ok(),
+					_ => None,
+				}
+			})
+			.and_then(|decoded| String::from_utf8(decoded).ok());
+
+		let (user, password) = match credentials.as_ref().and_then(|c| c.find(':').map(|colon| c.split_at(colon))) {
+			Some((user, password)) => (user, &password[1..]),
+			None => return Err(&rule.prefix),
+		};
+
+		let accepted = rule.users.iter()
+			.any(|(name, expected)| name == user && expected.matches(password));
+
+		if accepted { Ok(()) } else { Err(&rule.prefix) }
+	}
+
+	/// Scheduled expiry for a uri, whether or not it's passed yet. Exact routes beat the longest prefix
+	pub fn get_expiry(&self, uri: &str) -> Option<&Expiry> {
+		self.expiries.iter()
+			.filter(|e| match e.route.ends_with('*') {
+				true => uri.starts_with(&e.route[..e.route.len()-1]),
+				false => uri == e.route,
+			})
+			.max_by_key(|e| (!e.route.ends_with('*'), e.route.len()))
+	}
+
+	pub fn has_expired(&self, expiry: &Expiry) -> bool {
+		expiry.at <= self.clock.now()
+	}
+
+	pub fn clock(&self) -> &dyn Clock {
+		&*self.clock
+	}
+
+	/// Explicitly configured etag kind for a uri. The longest matching prefix wins
+	pub fn etag_kind(&self, uri: &str) -> Option<ETagKind> {
+		self.etag_prefixes.iter()
+			.filter(|(prefix, _)| uri.starts_with(prefix.as_str()))
+			.max_by_key(|(prefix, _)| prefix.len())
+			.map(|&(_, kind)| kind)
+	}
+
+	pub fn global_headers(&self) -> &[(String, String)] {
+		&self.global_headers
+	}
+
+	/// Whether crawlers should be told to leave a uri out of their index, and out of any sitemap
+	pub fn is_noindex(&self, uri: &str) -> bool {
+		self.noindex_prefixes.iter().any(|prefix| uri.starts_with(prefix.as_str()))
+	}
+
+	pub fn debug_enabled(&self, uri: &str) -> bool {
+		self.debug_prefixes.iter().any(|prefix| uri.starts_with(prefix.as_str()))
+	}
+
+	pub fn enable_autoindex(&mut self, root: PathBuf, hide_dotfiles: bool) {
+		self.autoindex_root = Some(root);
+		self.autoindex_hide_dotfiles = hide_dotfiles;
+	}
+
+	/// Generates a listing for a directory uri, if autoindex is enabled and the directory exists
+	pub fn get_directory_listing(&self, uri: &str, listing: &autoindex::Listing) -> Option<Arc<dyn MappedAsset>> {
+		let root = self.autoindex_root.as_ref()?;
+		if !uri.ends_with('/') { return None }
+
+		let relative = Path::new(uri.trim_start_matches('/'));
+		let escapes = relative.components()
+			.any(|c| !matches!(c, Component::Normal(_)));
+
+		if escapes || is_server_state(relative) { return None }
+
+		let dir = root.join(relative);
+		if !dir.is_dir() { return None }
+
+		match autoindex::render(&dir, uri, listing, self.autoindex_hide_dotfiles) {
+			Ok(html) => Some(Arc::new(GeneratedAsset { data: html.into_bytes() })),
+			Err(e) => {
+				warn!("Failed to generate listing for {:?}: {}", dir, e);
+				None
+			}
+		}
+	}
+
+	/// Generates a page linking to every site with a `[host]` section, for `/` on a bare IP address
+	/// if `unknown-host sites` is set
+	pub fn get_site_index(&self, host: Option<&str>, uri: &str) -> Option<Arc<dyn MappedAsset>> {
+		match self.unknown_host {
+			Some(UnknownHost::Sites) if uri == "/" && is_bare_host(host) => {}
+			_ => return None,
+		}
+
+		// Wildcard hosts have no one address to link to
+		let mut hosts: Vec<&str> = self.host_routes.iter()
+			.map(|r| r.host.as_str())
+			.filter(|host| !host.starts_with("*."))
+			.collect();
+
+		hosts.sort();
+		hosts.dedup();
+
+		match autoindex::render_sites(&hosts) {
+			Ok(html) => Some(Arc::new(GeneratedAsset { data: html.into_bytes() })),
+			Err(e) => {
+				warn!("Failed to generate site index: {}", e);
+				None
+			}
+		}
+	}
+
+	/// Checksum and signature files for artifacts as they were when the mappings loaded, and their content type.
+	/// Files that really exist under the same name are routes, so they're served instead
+	pub fn get_artifact_sidecar(&self, uri: &str) -> Option<(Arc<dyn MappedAsset>, String)> {
+		let set = self.artifact_sets.iter()
+			.filter(|set| uri.starts_with(&set.prefix) && uri[set.prefix.len()..].starts_with('/'))
+			.max_by_key(|set| set.prefix.len())?;
+
+		let prepared = self.prepared_artifacts.get(&set.prefix)?;
+		let sidecar = Sidecar::parse(&uri[set.prefix.len()+1..])?;
+
+		let data = match sidecar {
+			Sidecar::Sums => prepared.sums.clone(),
+			Sidecar::SumsSignature => prepared.sums_signature.clone()?,
+
+			Sidecar::Checksum(name) | Sidecar::Signature(name) => {
+				let path = self.artifact_files(set).find(|&(relative, _)| relative == name)?.1;
+				let digest = prepared.digests.get(path)?;
+
+				match sidecar {
+					Sidecar::Signature(_) => digest.signature.clone()?,
+					_ => artifacts::checksum_line(&digest.sha256, Path::new(name).file_name()?.to_str()?).into_bytes(),
+				}
+			}
+		};
+
+		Some((Arc::new(GeneratedAsset { data }), sidecar.content_type().to_owned()))
+	}
+
+	/// Every file of an artifact set that's still routed from its directory, relative to its route
+	fn artifact_files<'a>(&'a self, set: &'a ArtifactSet) -> impl Iterator<Item=(&'a str, &'a Path)> + 'a {
+		self.mappings.iter()
+			.filter(move |(route, mapping)| route.starts_with(&set.prefix) && route[set.prefix.len()..].starts_with('/')
+				&& mapping.path.starts_with(&set.dir))
+			.map(move |(route, mapping)| (&route[set.prefix.len()+1..], mapping.path.as_path()))
+	}
+
+	/// Page for a uri nothing else matched, from the first `fallback` pattern covering it
+	pub fn get_fallback(&self, uri: &str) -> Option<&Mapping> {
+		let pattern = self.fallbacks.iter().find(|pattern| glob_match(pattern, uri))?;
+		self.mappings.get(&format!("@fallback {}", pattern))
+	}
+
+	/// Page to serve alongside an error status, as declared by e.g. `@404 => errors/404.html`
+	pub fn get_status_route(&self, status: u16) -> Option<&Mapping> {
+		self.mappings.get(&format!("@{}", status))
+	}
+
+	pub fn get_asset(&self, route: &PathBuf) -> Option<Arc<dyn MappedAsset>> {
+		// Never read a path from disk just because a request led to it
+		if self.cache_mode == CacheMode::Disabled && !self.disk_paths.contains(route) {
+			return None
+		}
+
+		match self.cache_mode {
+			CacheMode::Eager => self.file_cache.get(route)
+				.cloned()
+				.map(|a| a as Arc<dyn MappedAsset>),
+
+			// Data mappings are always preprocessed, so check the file cache first
+			CacheMode::Lazy => self.file_cache.get(route)
+				.cloned()
+				.map(|a| a as Arc<dyn MappedAsset>)
+				.or_else(|| self.lazy_cache.get(route).cloned().map(|a| a as Arc<dyn MappedAsset>)),
+
+			// Transformed files can't be streamed straight from disk
+			CacheMode::Disabled if !self.transforms_for(route).is_empty() => {
+				match transform_file(route, self.transforms_for(route)) {
+					Ok(data) => Some(Arc::new(GeneratedAsset { data }) as Arc<dyn MappedAsset>),
+					Err(e) => {
+						warn!("Failed to transform {:?}: {}", route, e);
+						None
+					}
+				}
+			}
+
+			CacheMode::Disabled => {
+				let expected_hash = self.recorded_hashes.get(route).cloned();
+				Some(Arc::new(UnprocessedAsset {file_path: route.clone(), expected_hash}) as Arc<dyn MappedAsset>)
+			}
+		}
+	}
+}
+
+
+/// Matches a host against a `[host]` pattern, returning the subdomain matched by a leading `*.`
+fn match_host<'h>(pattern: &str, host: &'h str) -> Option<&'h str> {
+	if !pattern.starts_with("*.") {
+		return if pattern == host { Some("") } else { None }
+	}
+
+	let suffix = &pattern[1..];
+	if !host.ends_with(suffix) { return None }
+
+	// Only a single label, so it's always safe to use as a path component
+	let subdomain = &host[..host.len() - suffix.len()];
+	if is_host_label(subdomain) { Some(subdomain) } else { None }
+}
+
+/// Matches `text` against a pattern where `*` matches any run of characters, including none
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+	let mut parts = pattern.split('*');
+	let first = parts.next().unwrap_or("");
+	if !text.starts_with(first) { return false }
+
+	let mut rest = &text[first.len()..];
+	let mut parts: Vec<&str> = parts.collect();
+
+	// No stars, so it had to be an exact match
+	let last = match parts.pop() {
+		Some(last) => last,
+		None => return rest.is_empty(),
+	};
+
+	for part in parts {
+		match rest.find(part) {
+			Some(pos) => rest = &rest[pos + part.len()..],
+			None => return false,
+		}
+	}
+
+	rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Whether `prefix` covers `uri` a whole path segment at a time, so `/admin` covers `/admin` and `/admin/x`
+/// but not `/administrator`. A trailing slash doesn't matter, so `/admin/` covers `/admin` too
+fn covers_path(prefix: &str, uri: &str) -> bool {
+	let trimmed = prefix.trim_end_matches('/');
+
+	uri == trimmed || uri.starts_with(trimmed) && uri[trimmed.len()..].starts_with('/')
+}
+
+/// Whether a Host header is missing or just an IP address, as when someone types in the server's address
+fn is_bare_host(host: Option<&str>) -> bool {
+	let host = match host.map(str::trim) {
+		Some(host) if !host.is_empty() => host,
+		_ => return true,
+	};
+
+	// [::1]:8000, [::1], 127.0.0.1:8000 or 127.0.0.1
+	let address = match host.strip_prefix('[') {
+		Some(rest) => rest.split(']').next().unwrap_or(""),
+		None => host.split(':').next().unwrap_or(""),
+	};
+
+	address.parse::<std::net::IpAddr>().is_ok()
+}
+
+pub fn is_host_label(s: &str) -> bool {
+	!s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Parses `Key: value; Key: value`
+/// Headers from `{Key: value; Key: value}`. Only a `;` followed by `Name:` starts a new header,
+/// so values that are full of them, like CSPs, can be given whole
+fn parse_headers(headers: &str) -> SBResult<Vec<(String, String)>> {
+	let mut parsed: Vec<(String, String)> = Vec::new();
+
+	for part in headers.split(';') {
+		let starts_header = part.find(':')
+			.map_or(false, |colon| is_header_name(part[..colon].trim()));
+
+		match (starts_header, parsed.last_mut()) {
+			(true, _) => {
+				let colon = part.find(':').unwrap();
+				parsed.push((part[..colon].trim().to_owned(), part[colon+1..].trim().to_owned()));
+			}
+
+			(false, Some((_, value))) => {
+				value.push(';');
+				value.push_str(part.trim_end());
+			}
+
+			(false, None) if part.trim().is_empty() => {}
+			(false, None) => failure::bail!("Invalid header '{}'", part.trim()),
+		}
+	}
+
+	// A trailing `;` leaves nothing after it
+	for (_, value) in parsed.iter_mut() {
+		let trimmed = value.trim_end_matches(|c: char| c == ';' || c.is_whitespace()).len();
+		value.truncate(trimmed);
+	}
+
+	Ok(parsed)
+}
+
+/// The characters RFC 7230 allows in a field name
+fn is_header_name(name: &str) -> bool {
+	!name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+fn resolve_content_type(overrides: &HashMap<String, String>, charset: Option<&String>, path: &Path, explicit: Option<String>)
+	-> Option<String> {
+
+	let content_type = explicit.or_else(|| {
+		let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+		overrides.get(&extension).cloned()
+	});
+
+	let content_type = content_type
+		.or_else(|| mime::from_extension(path).map(Into::into))?;
+
+	Some(mime::with_charset(content_type, charset.map(String::as_str)))
+}
+
+/// Whether a path goes through the directory certs, keys and published generations are kept in
+pub fn is_server_state(path: &Path) -> bool {
+	path.components().any(|c| c.as_os_str() == publish::STATE_DIR)
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> SBResult<()> {
+	for entry in fs::read_dir(dir)? {
+		let path = entry?.path();
+
+		// Never walk into certs and published generations, though a generation itself can be walked
+		if path.file_name().map_or(false, |name| name == publish::STATE_DIR) {
+			continue
+		}
+
+		if path.is_dir() {
+			collect_files(&path, files)?;
+		} else {
+			files.push(path);
+		}
+	}
+
+	Ok(())
+}
+
+/// Check files served from disk against the hash taken when mappings were loaded, before every response
+pub fn set_verify_integrity(verify: bool) {
+	VERIFY_INTEGRITY.store(verify, Ordering::Relaxed);
+}
+
+pub fn verify_integrity() -> bool {
+	VERIFY_INTEGRITY.load(Ordering::Relaxed)
+}
+
+/// Refuse to load mappings where a route's declared `[content-type]` doesn't match its file
+pub fn set_strict_types(strict: bool) {
+	STRICT_TYPES.store(strict, Ordering::Relaxed);
+}
+
+/// How many threads compress assets when mappings are loaded, instead of one per CPU
+pub fn set_compression_threads(threads: usize) {
+	COMPRESSION_THREADS.store(threads, Ordering::Relaxed);
+}
+
+pub fn compression_threads() -> usize {
+	match COMPRESSION_THREADS.load(Ordering::Relaxed) {
+		0 => (unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) }).max(1) as usize,
+		threads => threads,
+	}
+}
+
+/// Path of a precompressed sidecar for `path`, e.g. `foo.js` => `foo.js.gz`
+fn sidecar_path(path: &Path, encoding: Encoding) -> Option<PathBuf> {
+	let extension = match encoding {
+		Encoding::Gzip => ".gz",
+		Encoding::Brotli => ".br",
+		_ => return None,
+	};
+
+	let mut sidecar = path.as_os_str().to_owned();
+	sidecar.push(extension);
+	Some(sidecar.into())
+}
+
+/// The sidecar for `path`, unless there isn't one or it's older than `path`, as when the source was
+/// edited and the sidecar wasn't rebuilt. Serving it then would serve the old content
+fn fresh_sidecar(path: &Path, encoding: Encoding) -> Option<PathBuf> {
+	let sidecar = sidecar_path(path, encoding)?;
+	let metadata = fs::metadata(&sidecar).ok().filter(|m| m.is_file())?;
+
+	let source_modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+	if metadata.modified().ok()? < source_modified {
+		debug!("Ignoring {:?}, since it's older than {:?}", sidecar, path);
+		return None
+	}
+
+	Some(sidecar)
+}
+
+/// The file a `.gz` or `.br` sidecar was compressed from
+fn sidecar_source(sidecar: &Path) -> Option<PathBuf> {
+	let name = sidecar.to_str()?;
+	let source = name.strip_suffix(".gz").or_else(|| name.strip_suffix(".br"))?;
+	Some(source.into())
+}
+
+/// Reads a file and runs it through `transforms` in order
+fn transform_file(path: &Path, transforms: &[Arc<dyn Transform>]) -> SBResult<Vec<u8>> {
+	let mut data = fs::read(path)?;
+	for transform in transforms {
+		data = transform.apply(path, data)?;
+	}
+
+	Ok(data)
+}
+
+/// Sidecars big enough to be are held open like the files they're compressed from
+fn read_sidecar(path: &Path, encoding: Encoding) -> Option<AssetData> {
+	let sidecar = fresh_sidecar(path, encoding)?;
+	let data = if fs::metadata(&sidecar).ok()?.len() >= OPEN_FILE_THRESHOLD_BYTES {
+		AssetData::File(OpenFile::open(&sidecar).ok()?)
+	} else {
+		AssetData::Buffered(fs::read(&sidecar).ok()?)
+	};
+
+	debug!("Using precompressed {:?}", sidecar);
+	Some(data)
+}
+
+
+impl PreprocessedAsset {
+	fn process(uncompressed_data: Vec<u8>) -> SBResult<PreprocessedAsset> {
+		Self::process_with_sidecars(AssetData::Buffered(uncompressed_data), None, None, true)
+	}
+
+	fn variant(&self, encoding: Encoding) -> Option<&AssetData> {
+		match encoding {
+			Encoding::Uncompressed => Some(&self.uncompressed_data),
+			Encoding::Deflate => self.deflated_data.as_ref(),
+			Encoding::Gzip => self.gzipped_data.as_ref(),
+			Encoding::Brotli => self.brotli_data.as_ref(),
+		}
+	}
+
+	fn load(path: &Path) -> SBResult<PreprocessedAsset> {
+		// Let the page cache hold large files rather than the heap
+		let uncompressed_data = if fs::metadata(path)?.len() >= OPEN_FILE_THRESHOLD_BYTES {
+			AssetData::File(OpenFile::open(path)?)
+		} else {
+			AssetData::Buffered(fs::read(path)?)
+		};
+
+		let gzipped_data = read_sidecar(path, Encoding::Gzip);
+		let brotli_data = read_sidecar(path, Encoding::Brotli);
+
+		let mut asset = Self::process_with_sidecars(uncompressed_data, gzipped_data, brotli_data, true)?;
+		asset.modified = fs::metadata(path)?.modified().ok();
+		Ok(asset)
+	}
+
+	/// Like `load`, but runs the file through `transforms` first. Sidecars are ignored when there are
+	/// any, since they'd be compressed from the untransformed file
+	fn load_transformed(path: &Path, transforms: &[Arc<dyn Transform>]) -> SBResult<PreprocessedAsset> {
+		if transforms.is_empty() {
+			return Self::load(path)
+		}
+
+		let mut asset = Self::process(transform_file(path, transforms)?)?;
+		asset.modified = fs::metadata(path)?.modified().ok();
+		Ok(asset)
+	}
+
+	/// Like `load_transformed`, but with no compressed copies, to serve while they're made elsewhere
+	fn load_uncompressed(path: &Path, transforms: &[Arc<dyn Transform>]) -> SBResult<PreprocessedAsset> {
+		let uncompressed_data = if !transforms.is_empty() {
+			AssetData::Buffered(transform_file(path, transforms)?)
+		} else if fs::metadata(path)?.len() >= OPEN_FILE_THRESHOLD_BYTES {
+			AssetData::File(OpenFile::open(path)?)
+		} else {
+			AssetData::Buffered(fs::read(path)?)
+		};
+
+		let mut asset = Self::process_with_sidecars(uncompressed_data, None, None, false)?;
+		asset.modified = fs::metadata(path)?.modified().ok();
+		Ok(asset)
+	}
+
+	/// Compressed copies are only made if `compress` is set, and never of files held open. Those are held
+	/// open to keep them out of memory, which compressed copies would undo, so they only have their sidecars
+	fn process_with_sidecars(uncompressed_data: AssetData, gzipped_data: Option<AssetData>, brotli_data: Option<AssetData>,
+		compress: bool) -> SBResult<PreprocessedAsset> {
+
+		let compression = Compression::best();
+
+		let compress = compress && match uncompressed_data {
+			AssetData::Buffered(_) => true,
+			AssetData::File(_) => false,
+		};
+
+		let mut gzip = match gzipped_data {
+			None if compress => Some(GzEncoder::new(Vec::new(), compression)),
+			_ => None,
+		};
+
+		let mut deflate = match compress {
+			true => Some(DeflateEncoder::new(Vec::new(), compression)),
+			false => None,
+		};
+
+		let mut hasher = Sha256::new();
+
+		// Read a piece at a time, so files held open are hashed without reading them into memory
+		{
+			let mut reader = uncompressed_data.reader(0, uncompressed_data.len());
+			let mut buf = vec![0u8; 64<<10];
+
+			loop {
+				let chunk = match reader.read(&mut buf)? {
+					0 => break,
+					size => &buf[..size],
+				};
+
+				if let Some(gzip) = gzip.as_mut() {
+					gzip.write_all(chunk)?;
+				}
+
+				if let Some(deflate) = deflate.as_mut() {
+					deflate.write_all(chunk)?;
+				}
+
+				hasher.update(chunk);
+			}
+		}
+
+		let gzipped_data = match (gzipped_data, gzip) {
+			(Some(data), _) => Some(data),
+			(None, Some(gzip)) => Some(AssetData::Buffered(gzip.finish()?)),
+			(None, None) => None,
+		};
+
+		let deflated_data = match deflate {
+			Some(deflate) => Some(AssetData::Buffered(deflate.finish()?)),
+			None => None,
+		};
+		let content_hash = hex(&hasher.finish());
+
+		Ok(PreprocessedAsset {
+			uncompressed_data,
+			deflated_data,
+			gzipped_data,
+			brotli_data,
+			content_hash,
+			modified: None,
+		})
+	}
+}
+
+
+pub fn hex(hash: &[u8]) -> String {
+	hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+	if hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+		return None
+	}
+
+	(0..hex.len()).step_by(2)
+		.map(|i| u8::from_str_radix(&hex[i..i+2], 16).ok())
+		.collect()
+}
+
+/// Hashes a file the same way `content_hash` does, without holding all of it in memory
+fn hash_file(path: &Path) -> SBResult<String> {
+	let mut file = fs::File::open(path)?;
+	let mut hasher = Sha256::new();
+	let mut buf = vec![0u8; 64<<10];
+
+	loop {
+		match file.read(&mut buf)? {
+			0 => break,
+			size => hasher.update(&buf[..size]),
+		}
+	}
+
+	Ok(hex(&hasher.finish()))
+}
+
+fn integrity_error(path: &Path) -> failure::Error {
+	failure::format_err!("{:?} has changed on disk since mappings were loaded", path)
+}
+
+impl AssetData {
+	fn len(&self) -> u64 {
+		match self {
+			AssetData::Buffered(data) => data.len() as u64,
+			AssetData::File(file) => file.len(),
+		}
+	}
+
+	/// All of it, failing if it's a file that's been written to since it was opened
+	fn read_all(&self) -> SBResult<Vec<u8>> {
+		match self {
+			AssetData::Buffered(data) => Ok(data.clone()),
+			AssetData::File(file) => {
+				file.check_unchanged()?;
+
+				let mut data = Vec::with_capacity(file.len() as usize);
+				file.reader(0, file.len()).read_to_end(&mut data)?;
+				Ok(data)
+			}
+		}
+	}
+
+	fn reader(&self, start: u64, len: u64) -> Box<dyn Read + '_> {
+		match self {
+			AssetData::Buffered(data) => {
+				let start = (start as usize).min(data.len());
+				let end = start.saturating_add(len as usize).min(data.len());
+				Box::new(&data[start..end])
+			}
+
+			AssetData::File(file) => Box::new(file.reader(start, len)),
+		}
+	}
+}
+
+
+impl MappedAsset for PreprocessedAsset {
+	fn get_encoding(&self, encoding: Encoding) -> SBResult<Vec<u8>> {
+		self.variant(encoding)
+			.ok_or_else(|| failure::format_err!("No {} variant available", encoding.content_coding()))?
+			.read_all()
+	}
+
+	fn get_stream(&self, encoding: Encoding) -> Option<SBResult<Box<dyn Read>>> {
+		match self.variant(encoding)? {
+			AssetData::File(file) => Some(file.check_unchanged().map(|_| Box::new(file.reader(0, file.len())) as Box<dyn Read>)),
+			AssetData::Buffered(_) => None,
+		}
+	}
+
+	fn open_range(&self, start: u64, len: u64) -> SBResult<Box<dyn Read>> {
+		match &self.uncompressed_data {
+			// Checked up front, so a file that's been written to since it was loaded isn't served
+			// with the old one's headers, and one that's been truncated doesn't fail halfway through
+			AssetData::File(file) => {
+				file.check_unchanged()?;
+				Ok(Box::new(file.reader(start, len)))
+			}
+
+			AssetData::Buffered(data) => {
+				let start = (start as usize).min(data.len());
+				let end = start.saturating_add(len as usize).min(data.len());
+				Ok(Box::new(Cursor::new(data[start..end].to_vec())))
+			}
+		}
+	}
+
+	fn has_encoding(&self, encoding: Encoding) -> bool {
+		self.variant(encoding).is_some()
+	}
+
+	fn encoded_len(&self, encoding: Encoding) -> Option<usize> {
+		self.variant(encoding).map(|data| data.len() as usize)
+	}
+
+	fn cache_status(&self) -> &'static str {
+		"hit"
+	}
+
+	fn content_hash(&self) -> Option<String> {
+		Some(self.content_hash.clone())
+	}
+
+	fn last_modified(&self) -> Option<SystemTime> {
+		self.modified
+	}
+
+	// Open files are read through to disk, so they can change under us
+	fn verify(&self) -> SBResult<()> {
+		let file = match &self.uncompressed_data {
+			AssetData::File(file) => file,
+			AssetData::Buffered(_) => return Ok(()),
+		};
+
+		file.check_unchanged()?;
+
+		let mut hasher = Sha256::new();
+		let mut reader = file.reader(0, file.len());
+		let mut buf = vec![0u8; 64<<10];
+
+		loop {
+			match reader.read(&mut buf)? {
+				0 => break,
+				size => hasher.update(&buf[..size]),
+			}
+		}
+
+		if hex(&hasher.finish()) != self.content_hash {
+			failure::bail!("File has changed on disk since mappings were loaded")
+		}
+
+		Ok(())
+	}
+}
+
+impl UnprocessedAsset {
+	fn open_stream(&self, encoding: Encoding) -> SBResult<Box<dyn Read>> {
+		if let Some(sidecar) = fresh_sidecar(&self.file_path, encoding) {
+			debug!("Using precompressed {:?}", sidecar);
+			return Ok(Box::new(fs::File::open(sidecar)?))
+		}
+
+		debug!("Processing {:?}", &self.file_path.as_path());
+
+		let file = fs::File::open(&self.file_path)?;
+
+		match encoding {
+			Encoding::Uncompressed => Ok(Box::new(file)),
+			Encoding::Deflate => Ok(Box::new(read::DeflateEncoder::new(file, Compression::fast()))),
+			Encoding::Gzip => Ok(Box::new(read::GzEncoder::new(file, Compression::fast()))),
+			Encoding::Brotli => failure::bail!("No brotli sidecar for {:?}", self.file_path),
+		}
+	}
+}
+
+impl MappedAsset for UnprocessedAsset {
+	fn get_encoding(&self, encoding: Encoding) -> SBResult<Vec<u8>> {
+		let mut data = Vec::new();
+		self.open_stream(encoding)?.read_to_end(&mut data)?;
+		Ok(data)
+	}
+
+	fn get_stream(&self, encoding: Encoding) -> Option<SBResult<Box<dyn Read>>> {
+		Some(self.open_stream(encoding))
+	}
+
+	fn has_encoding(&self, encoding: Encoding) -> bool {
+		match encoding {
+			Encoding::Brotli => fresh_sidecar(&self.file_path, encoding).is_some(),
+			_ => true,
+		}
+	}
+
+	fn open_range(&self, start: u64, len: u64) -> SBResult<Box<dyn Read>> {
+		let mut file = fs::File::open(&self.file_path)?;
+		file.seek(SeekFrom::Start(start))?;
+		Ok(Box::new(file.take(len)))
+	}
+
+	// Only known when the bytes come straight off disk
+	fn encoded_len(&self, encoding: Encoding) -> Option<usize> {
+		let sidecar = fresh_sidecar(&self.file_path, encoding)
+			.and_then(|p| fs::metadata(p).ok());
+
+		match (encoding, sidecar) {
+			(_, Some(metadata)) => Some(metadata.len() as usize),
+			(Encoding::Uncompressed, None) => fs::metadata(&self.file_path).ok().map(|m| m.len() as usize),
+			_ => None,
+		}
+	}
+
+	fn last_modified(&self) -> Option<SystemTime> {
+		fs::metadata(&self.file_path).and_then(|m| m.modified()).ok()
+	}
+
+	fn verify(&self) -> SBResult<()> {
+		match self.expected_hash.as_ref() {
+			Some(expected) if hash_file(&self.file_path)? != *expected => Err(integrity_error(&self.file_path)),
+			_ => Ok(()),
+		}
+	}
+}
+
+
+impl LazyAsset {
+	fn is_cached(&self) -> bool {
+		self.state.lock().map_or(false, |state| state.cached.is_some())
+	}
+
+	/// Modification time of the newest of the file, its sidecars, and anything it was built from
+	fn modified(&self) -> SBResult<SystemTime> {
+		let mut modified = fs::metadata(&self.file_path)?.modified()?;
+
+		if self.transforms.is_empty() {
+			let sidecars = [Encoding::Gzip, Encoding::Brotli].iter()
+				.filter_map(|&encoding| sidecar_path(&self.file_path, encoding))
+				.filter_map(|sidecar| fs::metadata(sidecar).and_then(|m| m.modified()).ok());
+
+			for sidecar_modified in sidecars {
+				modified = modified.max(sidecar_modified);
+			}
+		}
+
+		for dependency in self.transforms.iter().flat_map(|t| t.dependencies(&self.file_path)) {
+			// A fragment that's gone missing counts as changed
+			let dependency_modified = fs::metadata(&dependency).and_then(|m| m.modified()).unwrap_or_else(|_| SystemTime::now());
+			modified = modified.max(dependency_modified);
+		}
+
+		Ok(modified)
+	}
+
+	/// Returns the cached asset if nothing it was built from has been modified since. Otherwise starts
+	/// reprocessing it on another thread, unless that's already underway, and returns None
+	fn refresh(&self) -> SBResult<Option<Arc<PreprocessedAsset>>> {
+		let modified = self.modified()?;
+
+		let mut state = self.state.lock()
+			.map_err(|_| failure::format_err!("Lazy cache lock poisoned"))?;
+
+		if let Some((cached_modified, asset)) = state.cached.as_ref() {
+			if *cached_modified == modified {
+				return Ok(Some(asset.clone()))
+			}
+		}
+
+		if state.compressing == Some(modified) {
+			return Ok(None)
+		}
+
+		state.compressing = Some(modified);
+
+		let file_path = self.file_path.clone();
+		let transforms = self.transforms.clone();
+		let shared_state = self.state.clone();
+
+		thread::spawn(move || {
+			debug!("Compressing {:?}...", file_path);
+			let result = PreprocessedAsset::load_transformed(&file_path, &transforms);
+
+			let mut state = shared_state.lock().unwrap_or_else(|e| e.into_inner());
+
+			match result {
+				// Another version may have been modified and compressed in the meantime
+				Ok(asset) => if state.cached.as_ref().map_or(true, |(cached_modified, _)| *cached_modified < modified) {
+					state.cached = Some((modified, Arc::new(asset)));
+				}
+
+				Err(err) => warn!("Failed to compress {:?}: {:?}", file_path, err),
+			}
+		});
+
+		Ok(None)
+	}
+
+	/// Returns the cached asset, or while it's being reprocessed, the file as it is. Compressing it here
+	/// would hold up every other connection on the worker asking for it
+	fn current(&self) -> SBResult<Arc<PreprocessedAsset>> {
+		match self.refresh()? {
+			Some(asset) => Ok(asset),
+			None => Ok(Arc::new(PreprocessedAsset::load_uncompressed(&self.file_path, &self.transforms)?)),
+		}
+	}
+}
+
+impl MappedAsset for LazyAsset {
+	fn get_encoding(&self, encoding: Encoding) -> SBResult<Vec<u8>> {
+		self.current()?.get_encoding(encoding)
+	}
+
+	fn get_stream(&self, encoding: Encoding) -> Option<SBResult<Box<dyn Read>>> {
+		match self.current() {
+			Ok(asset) => asset.get_stream(encoding),
+			Err(e) => Some(Err(e)),
+		}
+	}
+
+	fn has_encoding(&self, encoding: Encoding) -> bool {
+		self.current()
+			.map(|asset| asset.has_encoding(encoding))
+			.unwrap_or(false)
+	}
+
+	fn open_range(&self, start: u64, len: u64) -> SBResult<Box<dyn Read>> {
+		self.current()?.open_range(start, len)
+	}
+
+	fn encoded_len(&self, encoding: Encoding) -> Option<usize> {
+		self.current().ok()?.encoded_len(encoding)
+	}
+
+	fn cache_status(&self) -> &'static str {
+		let modified = fs::metadata(&self.file_path).and_then(|m| m.modified()).ok();
+		let cached = self.state.lock().ok()
+			.and_then(|state| state.cached.as_ref().map(|(cached_modified, _)| *cached_modified));
+
+		match (modified, cached) {
+			(Some(modified), Some(cached)) if modified == cached => "hit",
+			_ => "miss",
+		}
+	}
+
+	fn content_hash(&self) -> Option<String> {
+		self.current().ok()?.content_hash()
+	}
+
+	fn last_modified(&self) -> Option<SystemTime> {
+		self.current().ok()?.last_modified()
+	}
+
+	fn verify(&self) -> SBResult<()> {
+		self.current()?.verify()
+			.map_err(|_| integrity_error(&self.file_path))
+	}
+}
+
+impl MappedAsset for GeneratedAsset {
+	fn get_encoding(&self, encoding: Encoding) -> SBResult<Vec<u8>> {
+		match encoding {
+			Encoding::Uncompressed => Ok(self.data.clone()),
+
+			Encoding::Deflate => {
+				let mut enc = DeflateEncoder::new(Vec::new(), Compression::fast());
+				enc.write_all(&self.data)?;
+				Ok(enc.finish()?)
+			}
+
+			Encoding::Gzip => {
+				let mut enc = GzEncoder::new(Vec::new(), Compression::fast());
+				enc.write_all(&self.data)?;
+				Ok(enc.finish()?)
+			}
+
+			Encoding::Brotli => failure::bail!("Brotli not supported for generated content"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_util::XorShift;
+
+	/// Tries every way `*` could split the text, which glob_match has to agree with
+	fn reference_glob(pattern: &[u8], text: &[u8]) -> bool {
+		match pattern.split_first() {
+			None => text.is_empty(),
+			Some((b'*', rest)) => (0..=text.len()).any(|skip| reference_glob(rest, &text[skip..])),
+			Some((c, rest)) => text.first() == Some(c) && reference_glob(rest, &text[1..]),
+		}
+	}
+
+	#[test]
+	fn glob_match_agrees_with_backtracking() {
+		let mut rng = XorShift::new(7);
+		let alphabet = ["a", "b", "/", "*", "**", "ab"];
+
+		for _ in 0..50_000 {
+			let pattern: String = (0..rng.below(7)).map(|_| *rng.pick(&alphabet)).collect();
+			let text: String = (0..rng.below(9)).map(|_| *rng.pick(&alphabet[..3])).collect();
+
+			assert_eq!(glob_match(&pattern, &text), reference_glob(pattern.as_bytes(), text.as_bytes()),
+				"{:?} against {:?}", pattern, text);
+		}
+	}
+
+	#[test]
+	fn glob_match_accepts_text_with_pieces_starred_out() {
+		let mut rng = XorShift::new(11);
+
+		for _ in 0..10_000 {
+			let text: String = (0..rng.below(16)).map(|_| *rng.pick(&["/", "a", "b", ".", "-"])).collect();
+
+			// Any substring replaced by a star still matches the text it came from
+			let mut pattern = text.clone();
+			for _ in 0..rng.below(3) {
+				let start = rng.below(pattern.len() as u64 + 1) as usize;
+				let end = start + rng.below((pattern.len() - start) as u64 + 1) as usize;
+				pattern.replace_range(start..end, "*");
+			}
+
+			assert!(glob_match(&pattern, &text), "{:?} against {:?}", pattern, text);
+			assert!(glob_match("*", &text));
+			assert!(glob_match(&text, &text));
+		}
+	}
+
+	struct TempDir(PathBuf);
+
+	impl TempDir {
+		fn new(name: &str) -> TempDir {
+			let dir = std::env::temp_dir().join(format!("spiderbutter-test-{}-{}", std::process::id(), name));
+			let _ = fs::remove_dir_all(&dir);
+			fs::create_dir_all(&dir).unwrap();
+			TempDir(dir)
+		}
+	}
+
+	impl Drop for TempDir {
+		fn drop(&mut self) {
+			let _ = fs::remove_dir_all(&self.0);
+		}
+	}
+
+	#[test]
+	fn get_route_picks_the_longest_wildcard_prefix() {
+		let mut rng = XorShift::new(3);
+
+		for round in 0..40 {
+			let tmp = TempDir::new(&format!("routes-{}", round));
+
+			// Nested prefixes, so most uris are under more than one wildcard
+			let mut prefixes: Vec<String> = (0..1 + rng.below(6))
+				.map(|_| {
+					let segments: Vec<&str> = (0..rng.below(4)).map(|_| *rng.pick(&["a", "b", "ab"])).collect();
+					format!("/{}", segments.iter().map(|s| format!("{}/", s)).collect::<String>())
+				})
+				.collect();
+
+			prefixes.sort();
+			prefixes.dedup();
+
+			let uris: Vec<String> = (0..20)
+				.map(|_| {
+					let segments: Vec<&str> = (0..1 + rng.below(4)).map(|_| *rng.pick(&["a", "b", "ab", "f"])).collect();
+					format!("/{}", segments.join("/"))
+				})
+				.collect();
+
+			// Each wildcard's directory holds every file a uri under it could ask for
+			let mut contents = String::new();
+			for (i, prefix) in prefixes.iter().enumerate() {
+				let dir = tmp.0.join(format!("d{}", i));
+				fs::create_dir_all(&dir).unwrap();
+
+				for uri in uris.iter().filter(|uri| uri.starts_with(prefix.as_str())) {
+					let file = dir.join(&uri[prefix.len()..]);
+					if file.is_dir() || fs::create_dir_all(file.parent().unwrap()).is_err() { continue }
+					let _ = fs::write(&file, uri);
+				}
+
+				contents.push_str(&format!("{}* => d{}/*\n", prefix, i));
+			}
+
+			let mappings = Mappings::parse(&contents, &tmp.0, CacheMode::Disabled).unwrap();
+
+			for uri in uris.iter() {
+				let longest = prefixes.iter().enumerate()
+					.filter(|(_, prefix)| uri.starts_with(prefix.as_str()))
+					.max_by_key(|(_, prefix)| prefix.len());
+
+				let expected = longest
+					.map(|(i, prefix)| tmp.0.join(format!("d{}", i)).join(&uri[prefix.len()..]))
+					.filter(|path| path.is_file());
+
+				let route = mappings.lookup_route(None, uri).map(|mapping| mapping.path.clone());
+				assert_eq!(route, expected, "{} under {:?}", uri, prefixes);
+			}
+		}
+	}
+
+	#[test]
+	fn exact_routes_win_over_wildcards() {
+		let tmp = TempDir::new("exact");
+		fs::create_dir_all(tmp.0.join("all/docs")).unwrap();
+		fs::write(tmp.0.join("all/docs/page"), "wildcard").unwrap();
+		fs::write(tmp.0.join("page"), "exact").unwrap();
+
+		let mappings = Mappings::parse("/* => all/*\n/docs/page => page\n", &tmp.0, CacheMode::Disabled).unwrap();
+		let route = mappings.get_route(None, "/docs/page").unwrap();
+		assert_eq!(route.path, tmp.0.join("page"));
+	}
+
+	#[test]
+	fn routes_expire_on_the_mappings_clock() {
+		use crate::test_util::FakeClock;
+
+		let tmp = TempDir::new("expire");
+		let mut mappings = Mappings::parse("expire /sale/* => 2030-01-01T00:00 /\n", &tmp.0, CacheMode::Disabled).unwrap();
+		let at = crate::http::parse_iso_date("2030-01-01T00:00").unwrap();
+
+		let clock = Arc::new(FakeClock::at(at - Duration::from_secs(1)));
+		mappings.clock = clock.clone();
+
+		let expiry = mappings.get_expiry("/sale/shoes").unwrap().clone();
+		assert_eq!(expiry.redirect.as_deref(), Some("/"));
+		assert!(!mappings.has_expired(&expiry));
+
+		clock.advance(Duration::from_secs(1));
+		assert!(mappings.has_expired(&expiry));
+		assert!(mappings.get_expiry("/other").is_none());
+	}
+
+	#[test]
+	fn route_headers_keep_semicolons_in_values() {
+		let headers = parse_headers("Cache-Control: no-cache; Content-Security-Policy: default-src 'self'; img-src https://cdn.example; X-A: b;").unwrap();
+		assert_eq!(headers, vec![
+			("Cache-Control".to_owned(), "no-cache".to_owned()),
+			("Content-Security-Policy".to_owned(), "default-src 'self'; img-src https://cdn.example".to_owned()),
+			("X-A".to_owned(), "b".to_owned()),
+		]);
+
+		assert!(parse_headers("not a header; X-A: b").is_err());
+	}
+
+	#[test]
+	fn redirects_only_take_redirect_statuses() {
+		let tmp = TempDir::new("redirect");
+
+		for status in &[301, 302, 303, 307, 308] {
+			let mappings = Mappings::parse(&format!("redirect /old => /new [{}]\n", status), &tmp.0, CacheMode::Disabled).unwrap();
+			assert_eq!(mappings.get_redirect("/old").unwrap().status, *status);
+		}
+
+		for status in &[300, 304, 305, 306] {
+			assert!(Mappings::parse(&format!("redirect /old => /new [{}]\n", status), &tmp.0, CacheMode::Disabled).is_err());
+		}
+	}
+
+	#[test]
+	fn prefixes_cover_whole_path_segments() {
+		assert!(covers_path("/admin", "/admin"));
+		assert!(covers_path("/admin", "/admin/"));
+		assert!(covers_path("/admin", "/admin/users"));
+		assert!(covers_path("/admin/", "/admin"));
+		assert!(covers_path("/admin/", "/admin/users"));
+		assert!(covers_path("/", "/anything"));
+		assert!(!covers_path("/admin", "/administrator"));
+		assert!(!covers_path("/admin/", "/admin-public"));
+	}
+
+	#[test]
+	fn auth_prefixes_stop_at_segment_boundaries() {
+		let tmp = TempDir::new("auth-boundary");
+		let rules = "auth /admin => alice:pbkdf2-sha256:100000:9f3c51e2a07b4d18c6e5f2a1b3d4e5f6:22b5f295e0958f333e7ac286ff7e43ccc874cc11bc8ec44ff06e28746e5ec602\n\
+			auth /admin-public/ => bob:pbkdf2-sha256:100000:9f3c51e2a07b4d18c6e5f2a1b3d4e5f6:22b5f295e0958f333e7ac286ff7e43ccc874cc11bc8ec44ff06e28746e5ec602\n";
+		let mappings = Mappings::parse(rules, &tmp.0, CacheMode::Disabled).unwrap();
+
+		assert_eq!(mappings.check_auth("/admin", None), Err("/admin"));
+		assert_eq!(mappings.check_auth("/admin/users", None), Err("/admin"));
+		assert!(mappings.check_auth("/administrator", None).is_ok());
+		assert_eq!(mappings.check_auth("/admin-public", None), Err("/admin-public/"));
+	}
+
+	#[test]
+	fn ip_rules_stop_at_segment_boundaries() {
+		let tmp = TempDir::new("ip-boundary");
+		let mappings = Mappings::parse("deny /internal => 0.0.0.0/0\n", &tmp.0, CacheMode::Disabled).unwrap();
+		let peer = IpAddr::from([203, 0, 113, 7]);
+
+		assert!(!mappings.permits_ip("/internal", peer));
+		assert!(!mappings.permits_ip("/internal/report", peer));
+		assert!(mappings.permits_ip("/internal-docs", peer));
+	}
+
+	#[test]
+	fn deadlines_stop_at_segment_boundaries() {
+		let tmp = TempDir::new("deadline-boundary");
+		let mappings = Mappings::parse("deadline /api => 5\ndeadline /api/export => 600\n", &tmp.0, CacheMode::Disabled).unwrap();
+
+		assert_eq!(mappings.get_deadline("/api"), Some(Duration::from_secs(5)));
+		assert_eq!(mappings.get_deadline("/api/users"), Some(Duration::from_secs(5)));
+		assert_eq!(mappings.get_deadline("/api/export/all"), Some(Duration::from_secs(600)));
+		assert_eq!(mappings.get_deadline("/apidocs"), None);
+	}
+
+	#[test]
+	fn auth_takes_salted_pbkdf2_hashes() {
+		let tmp = TempDir::new("auth");
+		let rule = "auth /private => alice:pbkdf2-sha256:100000:9f3c51e2a07b4d18c6e5f2a1b3d4e5f6:22b5f295e0958f333e7ac286ff7e43ccc874cc11bc8ec44ff06e28746e5ec602\n";
+		let mappings = Mappings::parse(rule, &tmp.0, CacheMode::Disabled).unwrap();
+
+		assert!(mappings.check_auth("/private/a", Some("Basic YWxpY2U6aHVudGVyMg==")).is_ok());
+		assert_eq!(mappings.check_auth("/private/a", Some("Basic YWxpY2U6aHVudGVyMw==")), Err("/private"));
+		assert_eq!(mappings.check_auth("/private/a", None), Err("/private"));
+		assert!(mappings.check_auth("/public", None).is_ok());
+
+		// Unsalted and too cheap hashes are refused
+		assert!(Mappings::parse("auth /private => alice:sha256:2bd806c97f0e00af1a1fc3328fa763a9269723c8db8fac4f93af71db186d6e90\n", &tmp.0, CacheMode::Disabled).is_err());
+		assert!(Mappings::parse("auth /private => alice:pbkdf2-sha256:1000:9f3c51e2a07b4d18:22b5f295e0958f333e7ac286ff7e43cc\n", &tmp.0, CacheMode::Disabled).is_err());
+	}
+
+	#[test]
+	fn sidecars_older_than_their_source_are_ignored() {
+		let tmp = TempDir::new("stale-sidecar");
+		let path = tmp.0.join("page.html");
+		let sidecar = tmp.0.join("page.html.gz");
+
+		fs::write(&sidecar, b"old sidecar").unwrap();
+		std::thread::sleep(Duration::from_millis(20));
+		fs::write(&path, b"new page").unwrap();
+
+		let asset = PreprocessedAsset::load(&path).unwrap();
+		assert_ne!(asset.get_encoding(Encoding::Gzip).unwrap(), b"old sidecar");
+
+		fs::write(&sidecar, b"new sidecar").unwrap();
+		let asset = PreprocessedAsset::load(&path).unwrap();
+		assert_eq!(asset.get_encoding(Encoding::Gzip).unwrap(), b"new sidecar");
+	}
+
+	#[test]
+	fn large_files_that_shrink_are_refused_rather_than_served() {
+		let tmp = TempDir::new("shrink");
+		let path = tmp.0.join("big.bin");
+		fs::write(&path, vec![b'x'; OPEN_FILE_THRESHOLD_BYTES as usize]).unwrap();
+
+		let asset = PreprocessedAsset::load(&path).unwrap();
+		assert!(asset.get_stream(Encoding::Uncompressed).unwrap().is_ok());
+		assert!(asset.verify().is_ok());
+
+		fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(10).unwrap();
+
+		assert!(asset.get_stream(Encoding::Uncompressed).unwrap().is_err());
+		assert!(asset.open_range(0, 100).is_err());
+		assert!(asset.verify().is_err());
+
+		// No compressed copies were kept in memory to fall back on
+		assert_eq!(asset.encoded_len(Encoding::Uncompressed), Some(OPEN_FILE_THRESHOLD_BYTES as usize));
+		assert!(asset.gzipped_data.is_none() && asset.deflated_data.is_none());
+		assert!(!asset.has_encoding(Encoding::Gzip) && !asset.has_encoding(Encoding::Deflate));
+	}
+
+	#[test]
+	fn lazy_assets_are_compressed_off_the_requesting_thread() {
+		let tmp = TempDir::new("lazy");
+		let path = tmp.0.join("page.html");
+		fs::write(&path, vec![b'a'; 4096]).unwrap();
+
+		let asset = LazyAsset { file_path: path, transforms: Vec::new(), state: Default::default() };
+
+		// Served as it is until it's been compressed
+		let first = asset.current().unwrap();
+		assert!(!first.has_encoding(Encoding::Gzip));
+		assert_eq!(first.get_encoding(Encoding::Uncompressed).unwrap(), vec![b'a'; 4096]);
+
+		let deadline = std::time::Instant::now() + Duration::from_secs(10);
+		while !asset.is_cached() {
+			assert!(std::time::Instant::now() < deadline, "Compression never finished");
+			std::thread::sleep(Duration::from_millis(10));
+		}
+
+		assert!(asset.has_encoding(Encoding::Gzip));
+		assert_eq!(asset.content_hash(), first.content_hash());
+	}
+
+	#[test]
+	fn large_files_are_only_compressed_by_their_sidecars() {
+		let tmp = TempDir::new("large-sidecar");
+		let path = tmp.0.join("big.bin");
+		fs::write(&path, vec![b'x'; OPEN_FILE_THRESHOLD_BYTES as usize]).unwrap();
+		fs::write(tmp.0.join("big.bin.gz"), vec![b'z'; OPEN_FILE_THRESHOLD_BYTES as usize]).unwrap();
+
+		let asset = PreprocessedAsset::load(&path).unwrap();
+		assert!(asset.deflated_data.is_none());
+
+		// A sidecar that big is held open too, and streamed when served
+		match asset.gzipped_data {
+			Some(AssetData::File(_)) => {}
+			_ => panic!("Expected the gzip sidecar to be held open"),
+		}
+
+		let mut body = Vec::new();
+		asset.get_stream(Encoding::Gzip).unwrap().unwrap().read_to_end(&mut body).unwrap();
+		assert_eq!(body.len(), OPEN_FILE_THRESHOLD_BYTES as usize);
 	}
 }
@@ -0,0 +1,118 @@
+use std::path::Path;
+
+/// Guesses a content type from a file extension, for mappings that don't specify one
+pub fn from_extension(path: &Path) -> Option<&'static str> {
+	let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+
+	let content_type = match extension.as_str() {
+		"html" | "htm" => "text/html",
+		"css" => "text/css",
+		"js" | "mjs" => "text/javascript",
+		"json" | "map" => "application/json",
+		"webmanifest" => "application/manifest+json",
+		"xml" => "application/xml",
+		"txt" => "text/plain",
+		"md" => "text/markdown",
+		"csv" => "text/csv",
+
+		"wasm" => "application/wasm",
+		"pdf" => "application/pdf",
+		"zip" => "application/zip",
+		"gz" => "application/gzip",
+
+		"png" => "image/png",
+		"jpg" | "jpeg" => "image/jpeg",
+		"gif" => "image/gif",
+		"svg" => "image/svg+xml",
+		"webp" => "image/webp",
+		"avif" => "image/avif",
+		"ico" => "image/x-icon",
+
+		"woff" => "font/woff",
+		"woff2" => "font/woff2",
+		"ttf" => "font/ttf",
+		"otf" => "font/otf",
+
+		"mp3" => "audio/mpeg",
+		"ogg" => "audio/ogg",
+		"wav" => "audio/wav",
+		"mp4" => "video/mp4",
+		"webm" => "video/webm",
+
+		_ => return None
+	};
+
+	Some(content_type)
+}
+
+/// Whether a content type is textual and so should carry a charset
+pub fn is_text(content_type: &str) -> bool {
+	let essence = content_type.split(';').next().unwrap_or("").trim();
+
+	essence.starts_with("text/")
+		|| essence.ends_with("+json")
+		|| essence.ends_with("+xml")
+		|| essence == "application/json"
+		|| essence == "application/javascript"
+		|| essence == "application/xml"
+}
+
+/// Appends `; charset=<charset>` to textual content types that don't already specify one
+pub fn with_charset(content_type: String, charset: Option<&str>) -> String {
+	match charset {
+		Some(charset) if is_text(&content_type) && !content_type.contains("charset=") => {
+			format!("{}; charset={}", content_type, charset)
+		}
+
+		_ => content_type
+	}
+}
+
+/// Works out a content type from the first few hundred bytes of a file, for formats that say what they are.
+/// Only used to catch mappings that declare the wrong type, never to pick one
+pub fn sniff(data: &[u8]) -> Option<&'static str> {
+	const SIGNATURES: &[(&[u8], &str)] = &[
+		(b"\x89PNG\r\n\x1a\n", "image/png"),
+		(b"\xff\xd8\xff", "image/jpeg"),
+		(b"GIF87a", "image/gif"),
+		(b"GIF89a", "image/gif"),
+		(b"%PDF-", "application/pdf"),
+		(b"\x1f\x8b", "application/gzip"),
+		(b"\0asm", "application/wasm"),
+		(b"wOFF", "font/woff"),
+		(b"wOF2", "font/woff2"),
+	];
+
+	if let Some((_, content_type)) = SIGNATURES.iter().find(|(signature, _)| data.starts_with(signature)) {
+		return Some(content_type)
+	}
+
+	if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+		return Some("image/webp")
+	}
+
+	let start = data.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(data.len());
+	let text = String::from_utf8_lossy(&data[start..data.len().min(start + 16)]).to_ascii_lowercase();
+
+	if text.starts_with("<!doctype html") || text.starts_with("<html") {
+		return Some("text/html")
+	}
+
+	None
+}
+
+/// Whether two content types name the same thing, ignoring parameters and old aliases
+pub fn same_type(a: &str, b: &str) -> bool {
+	fn canonical(content_type: &str) -> String {
+		let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+
+		match essence.as_str() {
+			"application/javascript" | "application/x-javascript" => "text/javascript".to_owned(),
+			"text/xml" => "application/xml".to_owned(),
+			"image/vnd.microsoft.icon" => "image/x-icon".to_owned(),
+			_ => essence,
+		}
+	}
+
+	canonical(a) == canonical(b)
+}
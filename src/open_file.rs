@@ -0,0 +1,144 @@
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+use std::fs;
+use std::io::{self, Read};
+
+use crate::SBResult;
+
+struct Inner {
+	file: fs::File,
+	len: u64,
+	modified: Option<SystemTime>,
+}
+
+/// A file held open so it can be served from disk, with the size it had when opened.
+/// Reads go through pread, so a file that's truncated while open gives a read error
+/// rather than the SIGBUS a memory map would
+#[derive(Clone)]
+pub struct OpenFile(Arc<Inner>);
+
+impl OpenFile {
+	pub fn open(path: &Path) -> SBResult<OpenFile> {
+		let file = fs::File::open(path)?;
+		let metadata = file.metadata()?;
+
+		Ok(OpenFile(Arc::new(Inner {
+			len: metadata.len(),
+			modified: metadata.modified().ok(),
+			file,
+		})))
+	}
+
+	pub fn len(&self) -> u64 {
+		self.0.len
+	}
+
+	/// Fails if the file's been written to since it was opened, going by its size and modification time.
+	/// Files replaced by a rename are fine, since the open one is still the old one
+	pub fn check_unchanged(&self) -> SBResult<()> {
+		let metadata = self.0.file.metadata()?;
+
+		if metadata.len() != self.0.len || metadata.modified().ok() != self.0.modified {
+			failure::bail!("File has changed on disk since it was loaded")
+		}
+
+		Ok(())
+	}
+
+	/// Reads `len` bytes starting at `start`, clamped to the size the file was opened with
+	pub fn reader(&self, start: u64, len: u64) -> FileRange {
+		let start = start.min(self.0.len);
+		let end = start.saturating_add(len).min(self.0.len);
+		FileRange { file: self.clone(), pos: start, end }
+	}
+}
+
+/// Part of an `OpenFile`. Each has its own position, so any number can read the same file at once
+pub struct FileRange {
+	file: OpenFile,
+	pos: u64,
+	end: u64,
+}
+
+impl Read for FileRange {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let remaining = self.end - self.pos;
+		if remaining == 0 || buf.is_empty() {
+			return Ok(0)
+		}
+
+		let size = (buf.len() as u64).min(remaining) as usize;
+
+		match self.file.0.file.read_at(&mut buf[..size], self.pos)? {
+			0 => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File got shorter while being read")),
+			read => {
+				self.pos += read as u64;
+				Ok(read)
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn temp_file(name: &str, data: &[u8]) -> std::path::PathBuf {
+		let path = std::env::temp_dir().join(format!("spiderbutter-test-{}-{}", std::process::id(), name));
+		fs::write(&path, data).unwrap();
+		path
+	}
+
+	#[test]
+	fn ranges_are_clamped_to_the_opened_size() {
+		let path = temp_file("ranges", b"0123456789");
+		let file = OpenFile::open(&path).unwrap();
+
+		let mut data = String::new();
+		file.reader(3, 4).read_to_string(&mut data).unwrap();
+		assert_eq!(data, "3456");
+
+		data.clear();
+		file.reader(8, 100).read_to_string(&mut data).unwrap();
+		assert_eq!(data, "89");
+
+		data.clear();
+		file.reader(100, 100).read_to_string(&mut data).unwrap();
+		assert_eq!(data, "");
+
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn truncated_files_fail_reads_instead_of_faulting() {
+		let path = temp_file("truncated", &vec![7u8; 64<<10]);
+		let file = OpenFile::open(&path).unwrap();
+		assert!(file.check_unchanged().is_ok());
+
+		fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(100).unwrap();
+		assert!(file.check_unchanged().is_err());
+
+		let err = file.reader(0, file.len()).read_to_end(&mut Vec::new()).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn replaced_files_keep_serving_the_old_contents() {
+		let path = temp_file("replaced", b"old contents");
+		let file = OpenFile::open(&path).unwrap();
+
+		let replacement = temp_file("replacement", b"new");
+		fs::rename(&replacement, &path).unwrap();
+
+		let mut data = String::new();
+		file.reader(0, file.len()).read_to_string(&mut data).unwrap();
+		assert_eq!(data, "old contents");
+		assert!(file.check_unchanged().is_ok());
+
+		fs::remove_file(&path).unwrap();
+	}
+}
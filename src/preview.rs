@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH, Duration};
+use std::fmt::Write;
+use std::fs;
+
+use crate::SBResult;
+use crate::clock::Clock;
+use crate::mappings::is_host_label;
+
+pub const PREVIEWS_FILENAME: &'static str = "previews.sb";
+
+/// A named content directory, served at `<name>.<preview host>` until it expires
+#[derive(Debug, Clone)]
+pub struct Preview {
+	pub dir: PathBuf,
+	pub expires: Option<SystemTime>,
+}
+
+impl Preview {
+	pub fn expired(&self, clock: &dyn Clock) -> bool {
+		self.expires.map_or(false, |expires| expires <= clock.now())
+	}
+}
+
+/// Reads the registered previews, or nothing if none have been registered yet
+pub fn load() -> SBResult<HashMap<String, Preview>> {
+	if !Path::new(PREVIEWS_FILENAME).exists() {
+		return Ok(HashMap::new())
+	}
+
+	Ok(parse(&fs::read_to_string(PREVIEWS_FILENAME)?))
+}
+
+/// Parses lines of `name => path/to/dir`, with `until <unix seconds>` after the name if it expires.
+/// The directory is the rest of the line, so it can have spaces in it. Lines that don't parse are
+/// skipped, so one bad entry doesn't take every other preview down with it
+pub fn parse(data: &str) -> HashMap<String, Preview> {
+	let mut previews = HashMap::new();
+
+	let iter = data.lines()
+		.map(str::trim)
+		.filter(|s| !s.is_empty() && !s.starts_with('#'));
+
+	for line in iter {
+		match parse_line(line) {
+			Ok((name, preview)) => { previews.insert(name.to_owned(), preview); }
+			Err(e) => warn!("Skipping preview '{}': {}", line, e),
+		}
+	}
+
+	previews
+}
+
+fn parse_line(line: &str) -> SBResult<(&str, Preview)> {
+	let mut parts = line.splitn(2, "=>").map(str::trim);
+	let (head, dir) = match (parts.next(), parts.next()) {
+		(Some(head), Some(dir)) if !dir.is_empty() => (head, dir),
+		_ => failure::bail!("expected 'name [until <unix seconds>] => dir'"),
+	};
+
+	let mut head = head.split_whitespace();
+	let name = head.next().filter(|name| is_host_label(name))
+		.ok_or_else(|| failure::format_err!("name must be a single dns label"))?;
+
+	let expires = match (head.next(), head.next(), head.next()) {
+		(None, _, _) => None,
+		(Some("until"), Some(secs), None) => Some(UNIX_EPOCH + Duration::from_secs(secs.parse()?)),
+		_ => failure::bail!("invalid expiry"),
+	};
+
+	Ok((name, Preview { dir: dir.into(), expires }))
+}
+
+/// Adds or replaces a preview, and drops any that have expired.
+/// A running server picks up the change the same way it does for the mappings file
+pub fn register(name: &str, dir: &Path, ttl: Option<Duration>, clock: &dyn Clock) -> SBResult<()> {
+	if !is_host_label(name) {
+		failure::bail!("Preview name '{}' must be a single dns label", name);
+	}
+
+	if !dir.is_dir() {
+		failure::bail!("{:?} is not a directory", dir);
+	}
+
+	// Entries are a line each, with the directory running to the end of it
+	let dir_str = dir.to_str().ok_or_else(|| failure::format_err!("{:?} isn't valid unicode", dir))?;
+	if dir_str.contains(|c| c == '\n' || c == '\r') || dir_str.trim() != dir_str {
+		failure::bail!("{:?} can't be a preview, since it has line breaks or whitespace at either end", dir);
+	}
+
+	let mut previews = load()?;
+	previews.retain(|_, preview| !preview.expired(clock));
+	previews.insert(name.to_owned(), Preview {
+		dir: dir.to_owned(),
+		expires: ttl.map(|ttl| clock.now() + ttl),
+	});
+
+	let mut names: Vec<_> = previews.keys().collect();
+	names.sort();
+
+	let mut contents = String::new();
+	for name in names {
+		let preview = &previews[name];
+		contents.push_str(name);
+
+		if let Some(expires) = preview.expires {
+			write!(contents, " until {}", expires.duration_since(UNIX_EPOCH)?.as_secs())?;
+		}
+
+		write!(contents, " => {}\n", preview.dir.display())?;
+	}
+
+	fs::write(PREVIEWS_FILENAME, contents)?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_util::FakeClock;
+
+	#[test]
+	fn previews_expire_on_the_clock() {
+		let clock = FakeClock::at(UNIX_EPOCH + Duration::from_secs(1_000_000));
+		let preview = Preview { dir: PathBuf::from("site"), expires: Some(clock.now() + Duration::from_secs(60)) };
+		let forever = Preview { expires: None, ..preview.clone() };
+
+		assert!(!preview.expired(&clock));
+		clock.advance(Duration::from_secs(60));
+		assert!(preview.expired(&clock));
+		assert!(!forever.expired(&clock));
+	}
+
+	#[test]
+	fn previews_can_be_in_directories_with_spaces() {
+		let previews = parse("a until 1000 => builds/pr 12\nb => my site\nbad name => x\nc until soon => x\n");
+
+		assert_eq!(previews.len(), 2);
+		assert_eq!(previews["a"].dir, PathBuf::from("builds/pr 12"));
+		assert_eq!(previews["a"].expires, Some(UNIX_EPOCH + Duration::from_secs(1000)));
+		assert_eq!(previews["b"].dir, PathBuf::from("my site"));
+		assert_eq!(previews["b"].expires, None);
+	}
+}
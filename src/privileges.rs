@@ -0,0 +1,92 @@
+use std::ffi::{CStr, CString};
+
+use crate::SBResult;
+
+/// Switches to `user`, and `group` if given, both as names or numeric ids, for good. Without `group`,
+/// uses the user's primary group. Has to happen after binding ports below 1024, and before anything
+/// else starts threads, since looking up users isn't thread safe
+pub fn drop_to(user: Option<&str>, group: Option<&str>) -> SBResult<()> {
+	let user = match (user, group) {
+		(Some(user), _) => user,
+		(None, None) => return Ok(()),
+		// Changing only the group would leave everything else running as root
+		(None, Some(_)) => failure::bail!("--group needs --user too"),
+	};
+
+	if unsafe { libc::geteuid() } != 0 {
+		failure::bail!("--user and --group need to start as root");
+	}
+
+	let user = lookup_user(user)?;
+
+	let gid = match group {
+		Some(group) => lookup_group(group)?,
+		None => user.gid,
+	};
+
+	unsafe {
+		// Supplementary groups first, since root's would otherwise come along
+		if libc::initgroups(user.name.as_ptr(), gid) != 0 {
+			failure::bail!("Failed to set supplementary groups: {}", std::io::Error::last_os_error());
+		}
+
+		if libc::setgid(gid) != 0 {
+			failure::bail!("Failed to switch to group {}: {}", gid, std::io::Error::last_os_error());
+		}
+
+		if libc::setuid(user.uid) != 0 {
+			failure::bail!("Failed to switch to user {}: {}", user.uid, std::io::Error::last_os_error());
+		}
+
+		// Paranoia, in case it somehow only changed the effective id
+		if user.uid != 0 && libc::setuid(0) == 0 {
+			failure::bail!("Still able to get root back after switching user");
+		}
+	}
+
+	info!("Running as uid {} gid {}", unsafe { libc::getuid() }, unsafe { libc::getgid() });
+	Ok(())
+}
+
+struct User {
+	name: CString,
+	uid: libc::uid_t,
+	gid: libc::gid_t,
+}
+
+fn lookup_user(user: &str) -> SBResult<User> {
+	let name = CString::new(user)?;
+
+	let passwd = match user.parse::<libc::uid_t>() {
+		Ok(uid) => unsafe { libc::getpwuid(uid) },
+		Err(_) => unsafe { libc::getpwnam(name.as_ptr()) },
+	};
+
+	if passwd.is_null() {
+		failure::bail!("No such user '{}'", user);
+	}
+
+	unsafe {
+		Ok(User {
+			name: CStr::from_ptr((*passwd).pw_name).to_owned(),
+			uid: (*passwd).pw_uid,
+			gid: (*passwd).pw_gid,
+		})
+	}
+}
+
+fn lookup_group(group: &str) -> SBResult<libc::gid_t> {
+	// Numeric groups don't need to exist in /etc/group
+	if let Ok(gid) = group.parse::<libc::gid_t>() {
+		return Ok(gid)
+	}
+
+	let name = CString::new(group)?;
+	let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+
+	if entry.is_null() {
+		failure::bail!("No such group '{}'", group);
+	}
+
+	Ok(unsafe { (*entry).gr_gid })
+}
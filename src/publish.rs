@@ -0,0 +1,295 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::fs;
+
+use crate::SBResult;
+use crate::clock::Clock;
+use crate::mappings::{Mappings, CacheMode, MAPPINGS_FILENAME};
+
+pub const STATE_DIR: &'static str = ".spiderbutter";
+const GENERATIONS_DIR: &'static str = ".spiderbutter/generations";
+
+/// Older generations are deleted on publish, so there's only so far back a rollback can go
+const KEEP_GENERATIONS: usize = 5;
+
+/// Symlink to the generation being served, relative to `STATE_DIR`
+pub const CURRENT_LINK_NAME: &'static str = "current";
+
+/// In a cluster's shared directory, says which generation every node should serve and when to switch to it
+const CLUSTER_TARGET_FILENAME: &'static str = "target";
+
+/// The generation whose mappings are loaded, or 0 if they came from the working directory
+static SERVING_GENERATION: AtomicU32 = AtomicU32::new(0);
+
+/// The mappings file for a generation, or the one in the working directory if nothing's been published
+pub fn mappings_path(generation: Option<u32>) -> PathBuf {
+	match generation {
+		Some(generation) => generation_dir(generation).join(MAPPINGS_FILENAME),
+		None => MAPPINGS_FILENAME.into(),
+	}
+}
+
+pub fn current_generation() -> Option<u32> {
+	let target = fs::read_link(Path::new(STATE_DIR).join(CURRENT_LINK_NAME)).ok()?;
+	target.file_name()?.to_str()?.parse().ok()
+}
+
+/// Called once a generation's mappings have been loaded and handed to the servers
+pub fn set_serving_generation(generation: Option<u32>) {
+	SERVING_GENERATION.store(generation.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// The generation actually being served, which lags behind `current_generation` while it loads
+pub fn serving_generation() -> Option<u32> {
+	match SERVING_GENERATION.load(Ordering::Relaxed) {
+		0 => None,
+		generation => Some(generation),
+	}
+}
+
+pub fn generations() -> SBResult<Vec<u32>> {
+	generations_in(Path::new(GENERATIONS_DIR))
+}
+
+fn generations_in(dir: &Path) -> SBResult<Vec<u32>> {
+	if !dir.exists() {
+		return Ok(Vec::new())
+	}
+
+	let mut generations = Vec::new();
+	for entry in fs::read_dir(dir)? {
+		if let Some(generation) = entry?.file_name().to_str().and_then(|n| n.parse().ok()) {
+			generations.push(generation);
+		}
+	}
+
+	generations.sort();
+	Ok(generations)
+}
+
+fn generation_dir(generation: u32) -> PathBuf {
+	Path::new(GENERATIONS_DIR).join(generation.to_string())
+}
+
+/// Copies `source` into a new generation, makes sure its mappings load and every mapped file exists,
+/// and only then switches to serving it. A running server notices the switch and reloads
+pub fn publish(source: &Path) -> SBResult<u32> {
+	if !source.join(MAPPINGS_FILENAME).is_file() {
+		failure::bail!("{:?} doesn't contain a {}", source, MAPPINGS_FILENAME);
+	}
+
+	let generation = generations()?.last().map_or(1, |g| g + 1);
+	stage(source, Path::new(GENERATIONS_DIR), generation)?;
+	switch_to(generation)?;
+	prune()?;
+
+	Ok(generation)
+}
+
+/// Copies and validates `source` as `generations_dir/<generation>`, so that it only appears once it's complete
+fn stage(source: &Path, generations_dir: &Path, generation: u32) -> SBResult<()> {
+	let staging = generations_dir.join(format!(".staging-{}", generation));
+
+	if staging.exists() {
+		fs::remove_dir_all(&staging)?;
+	}
+
+	copy_dir(source, &staging)?;
+
+	if let Err(e) = validate(&staging) {
+		fs::remove_dir_all(&staging)?;
+		return Err(e)
+	}
+
+	fs::rename(&staging, generations_dir.join(generation.to_string()))?;
+	Ok(())
+}
+
+/// Goes back `steps` generations from the one currently being served
+pub fn rollback(steps: usize) -> SBResult<u32> {
+	let current = current_generation()
+		.ok_or_else(|| failure::format_err!("Nothing has been published yet"))?;
+
+	let generations = generations()?;
+	let index = generations.iter().position(|&g| g == current)
+		.ok_or_else(|| failure::format_err!("Current generation {} is missing", current))?;
+
+	if steps > index {
+		failure::bail!("Can only roll back {} generation(s), {:?} are available", index, generations);
+	}
+
+	let generation = generations[index - steps];
+	switch_to(generation)?;
+
+	Ok(generation)
+}
+
+fn prune() -> SBResult<()> {
+	prune_in(Path::new(GENERATIONS_DIR), current_generation())
+}
+
+fn prune_in(dir: &Path, current: Option<u32>) -> SBResult<()> {
+	let generations = generations_in(dir)?;
+
+	let excess = generations.len().saturating_sub(KEEP_GENERATIONS);
+	for &generation in generations[..excess].iter().filter(|&&g| Some(g) != current) {
+		fs::remove_dir_all(dir.join(generation.to_string()))?;
+	}
+
+	Ok(())
+}
+
+/// Atomically points `current` at a generation
+pub fn switch_to(generation: u32) -> SBResult<()> {
+	if !generation_dir(generation).is_dir() {
+		failure::bail!("Generation {} doesn't exist", generation);
+	}
+
+	let target = Path::new("generations").join(generation.to_string());
+	let link = Path::new(STATE_DIR).join(CURRENT_LINK_NAME);
+	let temp_link = Path::new(STATE_DIR).join(".current-next");
+
+	let _ = fs::remove_file(&temp_link);
+	std::os::unix::fs::symlink(&target, &temp_link)?;
+	fs::rename(&temp_link, &link)?;
+
+	Ok(())
+}
+
+
+fn cluster_generations_dir(shared: &Path) -> PathBuf {
+	shared.join("generations")
+}
+
+/// A generation every node in a cluster should switch to at the same moment
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClusterTarget {
+	pub generation: u32,
+	pub activate_at: SystemTime,
+}
+
+/// Reads `<shared>/target`, which looks like `<generation> <unix seconds to switch at>`
+pub fn cluster_target(shared: &Path) -> SBResult<Option<ClusterTarget>> {
+	let path = shared.join(CLUSTER_TARGET_FILENAME);
+	if !path.exists() {
+		return Ok(None)
+	}
+
+	let contents = fs::read_to_string(&path)?;
+	let mut parts = contents.split_whitespace();
+
+	let generation = parts.next().and_then(|g| g.parse::<u32>().ok()).filter(|&g| g > 0);
+	let activate_at = parts.next().and_then(|secs| secs.parse().ok())
+		.and_then(|secs| UNIX_EPOCH.checked_add(Duration::from_secs(secs)));
+
+	match (generation, activate_at) {
+		(Some(generation), Some(activate_at)) => Ok(Some(ClusterTarget { generation, activate_at })),
+		_ => failure::bail!("{:?} should contain a generation and a unix time to switch at", path),
+	}
+}
+
+fn set_cluster_target(shared: &Path, target: ClusterTarget) -> SBResult<()> {
+	let secs = target.activate_at.duration_since(UNIX_EPOCH)?.as_secs();
+
+	// Written aside and renamed so nodes never read half a file
+	let temp_path = shared.join(format!(".{}-next", CLUSTER_TARGET_FILENAME));
+	fs::write(&temp_path, format!("{} {}\n", target.generation, secs))?;
+	fs::rename(&temp_path, shared.join(CLUSTER_TARGET_FILENAME))?;
+
+	Ok(())
+}
+
+/// Like `publish`, but into a cluster's shared directory. Every node switches to the new generation
+/// `delay` from now, which should be long enough for all of them to have copied it
+pub fn publish_to_cluster(source: &Path, shared: &Path, delay: Duration, clock: &dyn Clock) -> SBResult<ClusterTarget> {
+	if !source.join(MAPPINGS_FILENAME).is_file() {
+		failure::bail!("{:?} doesn't contain a {}", source, MAPPINGS_FILENAME);
+	}
+
+	let generations_dir = cluster_generations_dir(shared);
+	let generation = generations_in(&generations_dir)?.last().map_or(1, |g| g + 1);
+	stage(source, &generations_dir, generation)?;
+
+	let target = ClusterTarget { generation, activate_at: clock.now() + delay };
+	set_cluster_target(shared, target)?;
+	prune_in(&generations_dir, Some(generation))?;
+
+	Ok(target)
+}
+
+/// Goes back `steps` generations from the cluster's current target
+pub fn rollback_cluster(shared: &Path, steps: usize, delay: Duration, clock: &dyn Clock) -> SBResult<ClusterTarget> {
+	let current = cluster_target(shared)?
+		.ok_or_else(|| failure::format_err!("Nothing has been published to {:?} yet", shared))?;
+
+	let generations = generations_in(&cluster_generations_dir(shared))?;
+	let index = generations.iter().position(|&g| g == current.generation)
+		.ok_or_else(|| failure::format_err!("Target generation {} is missing", current.generation))?;
+
+	if steps > index {
+		failure::bail!("Can only roll back {} generation(s), {:?} are available", index, generations);
+	}
+
+	let target = ClusterTarget { generation: generations[index - steps], activate_at: clock.now() + delay };
+	set_cluster_target(shared, target)?;
+
+	Ok(target)
+}
+
+/// Brings this node in line with the cluster's target. A new generation gets copied as soon as it's
+/// seen, but only switched to once its time comes. Returns the target if it's just been switched to
+pub fn sync_with_cluster(shared: &Path, clock: &dyn Clock) -> SBResult<Option<ClusterTarget>> {
+	let target = match cluster_target(shared)? {
+		Some(target) => target,
+		None => return Ok(None),
+	};
+
+	if current_generation() == Some(target.generation) {
+		return Ok(None)
+	}
+
+	// Generation numbers come from the cluster, so one that's already here is the same content
+	if !generation_dir(target.generation).is_dir() {
+		let source = cluster_generations_dir(shared).join(target.generation.to_string());
+		stage(&source, Path::new(GENERATIONS_DIR), target.generation)?;
+	}
+
+	if clock.now() < target.activate_at {
+		return Ok(None)
+	}
+
+	switch_to(target.generation)?;
+	prune()?;
+
+	Ok(Some(target))
+}
+
+fn validate(dir: &Path) -> SBResult<()> {
+	let mappings = Mappings::from_file(dir.join(MAPPINGS_FILENAME), CacheMode::Disabled)?;
+
+	if let Some(missing) = mappings.asset_paths().find(|path| !path.is_file()) {
+		failure::bail!("{:?} is mapped but doesn't exist", missing);
+	}
+
+	Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> SBResult<()> {
+	fs::create_dir_all(to)?;
+
+	for entry in fs::read_dir(from)? {
+		let entry = entry?;
+		if entry.file_name() == STATE_DIR { continue }
+
+		let (from, to) = (entry.path(), to.join(entry.file_name()));
+
+		if from.is_dir() {
+			copy_dir(&from, &to)?;
+		} else {
+			fs::copy(&from, &to)?;
+		}
+	}
+
+	Ok(())
+}
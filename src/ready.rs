@@ -0,0 +1,54 @@
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::Mutex;
+
+/// Tells a supervisor when we're serving real content, once everything being waited on is done.
+/// Readiness is a line of JSON written to an inherited file descriptor, which is then closed,
+/// and `READY=1` for systemd
+pub struct ReadyNotifier {
+	state: Mutex<ReadyState>,
+}
+
+struct ReadyState {
+	fd: Option<RawFd>,
+	waiting_on: Vec<&'static str>,
+	summary: String,
+}
+
+impl ReadyNotifier {
+	pub fn new(fd: Option<RawFd>, waiting_on: Vec<&'static str>, summary: String) -> ReadyNotifier {
+		ReadyNotifier {
+			state: Mutex::new(ReadyState { fd, waiting_on, summary }),
+		}
+	}
+
+	/// Whether everything being waited on is done
+	pub fn is_ready(&self) -> bool {
+		self.state.lock().unwrap_or_else(|e| e.into_inner()).waiting_on.is_empty()
+	}
+
+	pub fn done(&self, what: &'static str) {
+		let mut state = match self.state.lock() {
+			Ok(state) => state,
+			Err(_) => return,
+		};
+
+		if state.waiting_on.is_empty() { return }
+
+		state.waiting_on.retain(|&w| w != what);
+		if !state.waiting_on.is_empty() { return }
+
+		info!("Ready.");
+		crate::systemd::notify("READY=1\nSTATUS=Serving");
+
+		if let Some(fd) = state.fd.take() {
+			// Whoever passed --ready_fd handed ownership of it to us
+			let mut file = unsafe { File::from_raw_fd(fd) };
+
+			if let Err(err) = writeln!(file, "{}", state.summary) {
+				warn!("Failed to write to ready fd {}: {:?}", fd, err);
+			}
+		}
+	}
+}
@@ -0,0 +1,149 @@
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::fileserver::OPEN_CONNECTIONS;
+use crate::cert::PENDING_CERT_WRITES;
+
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Ends of a pipe that gets a byte written to it for every SIGHUP, so reloads can wake up a poll
+static RELOAD_READ_FD: AtomicI32 = AtomicI32::new(-1);
+static RELOAD_WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+
+/// Set once SIGINT or SIGTERM has arrived. Accept loops stop taking new connections once they see it
+pub fn requested() -> bool {
+	REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Stops SIGINT, SIGTERM and SIGHUP from killing the process outright, so `start` can deal with them instead.
+/// Has to be called before any other threads are spawned, since they inherit the signal mask
+pub fn block_signals() {
+	unsafe {
+		let mut set = shutdown_signals();
+		libc::sigaddset(&mut set, libc::SIGHUP);
+		libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+
+		let mut fds = [-1; 2];
+		if libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) == 0 {
+			RELOAD_READ_FD.store(fds[0], Ordering::SeqCst);
+			RELOAD_WRITE_FD.store(fds[1], Ordering::SeqCst);
+		} else {
+			warn!("Failed to create reload pipe, SIGHUP will be ignored");
+		}
+	}
+}
+
+/// Blocks until `fd` is readable, a SIGHUP arrives, or `timeout` passes.
+/// Returns true if there were any SIGHUPs, which are consumed
+pub fn wait_for_reload(fd: Option<RawFd>, timeout: Option<Duration>) -> bool {
+	let reload_fd = RELOAD_READ_FD.load(Ordering::SeqCst);
+
+	let mut fds = [
+		libc::pollfd { fd: reload_fd, events: libc::POLLIN, revents: 0 },
+		libc::pollfd { fd: fd.unwrap_or(-1), events: libc::POLLIN, revents: 0 },
+	];
+
+	let timeout = timeout.map_or(-1, |t| t.as_millis() as libc::c_int);
+	unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout); }
+
+	if fds[0].revents & libc::POLLIN == 0 {
+		return false
+	}
+
+	// Several SIGHUPs close together only need one reload
+	let mut buffer = [0u8; 64];
+	while unsafe { libc::read(reload_fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) } > 0 {}
+
+	true
+}
+
+/// Wakes up `wait_for_reload` the same way a SIGHUP does
+pub fn request_reload() {
+	let reload_fd = RELOAD_WRITE_FD.load(Ordering::SeqCst);
+	unsafe { libc::write(reload_fd, [1u8].as_ptr() as *const libc::c_void, 1); }
+}
+
+/// Waits for SIGINT or SIGTERM, then stops `listeners` accepting and gives open connections and
+/// certificate writes up to `drain_timeout` to finish before exiting. A second signal exits straight away.
+/// SIGHUPs in the meantime wake up `wait_for_reload`
+pub fn start(listeners: Vec<RawFd>, drain_timeout: Duration) {
+	thread::spawn(move || {
+		let signal = loop {
+			let signal = unsafe {
+				let mut set = shutdown_signals();
+				libc::sigaddset(&mut set, libc::SIGHUP);
+
+				let mut signal = 0;
+				libc::sigwait(&set, &mut signal);
+				signal
+			};
+
+			if signal != libc::SIGHUP {
+				break signal
+			}
+
+			info!("Received SIGHUP, reloading mappings...");
+			request_reload();
+		};
+
+		info!("Received signal {}, finishing open connections...", signal);
+		REQUESTED.store(true, Ordering::SeqCst);
+		crate::systemd::notify("STOPPING=1");
+
+		// Wakes up accept loops blocked on these, which then notice the shutdown and stop
+		for fd in listeners {
+			unsafe { libc::shutdown(fd, libc::SHUT_RDWR); }
+		}
+
+		let deadline = Instant::now() + drain_timeout;
+
+		loop {
+			let open = OPEN_CONNECTIONS.load(Ordering::SeqCst);
+			let writes = PENDING_CERT_WRITES.load(Ordering::SeqCst);
+
+			if open == 0 && writes == 0 {
+				info!("All connections finished");
+				break
+			}
+
+			if Instant::now() >= deadline {
+				warn!("Gave up waiting on {} connections", open);
+				break
+			}
+
+			if wait_for_signal(DRAIN_POLL_INTERVAL) {
+				warn!("Received another signal, exiting now");
+				std::process::exit(1);
+			}
+		}
+
+		std::process::exit(0);
+	});
+}
+
+fn shutdown_signals() -> libc::sigset_t {
+	unsafe {
+		let mut set = std::mem::zeroed();
+		libc::sigemptyset(&mut set);
+		libc::sigaddset(&mut set, libc::SIGINT);
+		libc::sigaddset(&mut set, libc::SIGTERM);
+		set
+	}
+}
+
+/// Doubles as a sleep while draining
+fn wait_for_signal(timeout: Duration) -> bool {
+	let timeout = libc::timespec {
+		tv_sec: timeout.as_secs() as libc::time_t,
+		tv_nsec: timeout.subsec_nanos() as libc::c_long,
+	};
+
+	unsafe {
+		let set = shutdown_signals();
+		libc::sigtimedwait(&set, std::ptr::null_mut(), &timeout) > 0
+	}
+}
@@ -0,0 +1,159 @@
+use std::net::TcpListener;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::thread;
+use std::time::Duration;
+
+use crate::SBResult;
+use crate::heartbeat::Heartbeats;
+
+/// First file descriptor passed by socket activation, after stdin, stdout and stderr
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Listening sockets passed in by systemd socket activation, so privileged ports can be used without root
+#[derive(Default)]
+pub struct ActivatedListeners {
+	pub http: Vec<TcpListener>,
+	pub https: Vec<TcpListener>,
+}
+
+/// Takes sockets passed by socket activation, following sd_listen_fds(3). Sockets named `http` or `https`
+/// with FileDescriptorName= go where they say, and there can be several of each.
+/// Without names, the first is plain HTTP and the second is TLS
+pub fn take_listeners() -> SBResult<ActivatedListeners> {
+	let pid = std::env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+	let count = std::env::var("LISTEN_FDS").ok().and_then(|count| count.parse::<RawFd>().ok());
+	let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+
+	// Children shouldn't think these are meant for them
+	std::env::remove_var("LISTEN_PID");
+	std::env::remove_var("LISTEN_FDS");
+	std::env::remove_var("LISTEN_FDNAMES");
+
+	let count = match (pid, count) {
+		(Some(pid), Some(count)) if pid == std::process::id() && count > 0 => count,
+		_ => return Ok(ActivatedListeners::default()),
+	};
+
+	let names: Vec<&str> = names.split(':').collect();
+	let named = names.iter().any(|&name| name == "http" || name == "https");
+
+	let mut listeners = ActivatedListeners::default();
+
+	for (index, fd) in (LISTEN_FDS_START .. LISTEN_FDS_START + count).enumerate() {
+		unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC); }
+
+		let name = match (named, names.get(index).cloned()) {
+			(true, name) => name.unwrap_or(""),
+			(false, _) if index == 0 => "http",
+			(false, _) if index == 1 => "https",
+			(false, _) => "",
+		};
+
+		let listeners = match name {
+			"http" => &mut listeners.http,
+			"https" => &mut listeners.https,
+			_ => {
+				warn!("Ignoring unexpected activated socket {} ({:?})", fd, name);
+				continue
+			}
+		};
+
+		let listener = unsafe { TcpListener::from_raw_fd(fd) };
+		info!("Using activated socket {} for {} on {}", fd, name, listener.local_addr()?);
+		listeners.push(listener);
+	}
+
+	Ok(listeners)
+}
+
+/// Sends a state change like `READY=1` to systemd, see sd_notify(3). Does nothing if we weren't started
+/// by systemd with Type=notify
+pub fn notify(state: &str) {
+	let socket_path = match std::env::var_os("NOTIFY_SOCKET") {
+		Some(path) => path,
+		None => return,
+	};
+
+	let socket_path = socket_path.as_bytes();
+	if socket_path.is_empty() { return }
+
+	unsafe {
+		let mut addr: libc::sockaddr_un = std::mem::zeroed();
+		addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+		if socket_path.len() >= addr.sun_path.len() {
+			warn!("NOTIFY_SOCKET is too long, not notifying systemd");
+			return
+		}
+
+		for (dst, &src) in addr.sun_path.iter_mut().zip(socket_path) {
+			*dst = src as libc::c_char;
+		}
+
+		// A leading @ means an abstract socket, which starts with a nul and has no terminator
+		let abstract_socket = socket_path[0] == b'@';
+		if abstract_socket {
+			addr.sun_path[0] = 0;
+		}
+
+		let addr_len = std::mem::size_of::<libc::sa_family_t>() + socket_path.len() + if abstract_socket { 0 } else { 1 };
+
+		let fd = libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+		if fd < 0 {
+			warn!("Failed to create socket to notify systemd");
+			return
+		}
+
+		let sent = libc::sendto(fd, state.as_ptr() as *const libc::c_void, state.len(), libc::MSG_NOSIGNAL,
+			&addr as *const libc::sockaddr_un as *const libc::sockaddr, addr_len as libc::socklen_t);
+
+		if sent < 0 {
+			warn!("Failed to notify systemd of {}", state);
+		}
+
+		libc::close(fd);
+	}
+}
+
+/// How long a worker can go without a heartbeat before it's thought to be stuck. Generous, since a connection
+/// can hold its worker up for a while on a slow disk. Work that can legitimately take longer, like compressing
+/// lazily cached assets, is done on threads of its own rather than in a worker
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Feeds systemd's watchdog from its own thread, so reloading or compressing a big site doesn't count as hanging,
+/// but only while every worker still has a heartbeat. Does nothing if there's no watchdog
+pub fn start_watchdog(heartbeats: Heartbeats) {
+	let interval = match watchdog_interval() {
+		Some(interval) => interval,
+		None => return,
+	};
+
+	thread::spawn(move || {
+		let mut was_stalled = false;
+
+		loop {
+			let stalled = heartbeats.stalled(STALL_TIMEOUT);
+
+			match (stalled, was_stalled) {
+				(false, _) => notify("WATCHDOG=1"),
+				(true, false) => error!("A worker has stopped responding, no longer feeding the watchdog"),
+				(true, true) => {}
+			}
+
+			was_stalled = stalled;
+			thread::sleep(interval);
+		}
+	});
+}
+
+/// How often to send `WATCHDOG=1`, if systemd wants it. Half the configured WatchdogSec=, as sd_watchdog_enabled(3) suggests
+pub fn watchdog_interval() -> Option<Duration> {
+	let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+
+	if let Some(pid) = std::env::var("WATCHDOG_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) {
+		if pid != std::process::id() { return None }
+	}
+
+	Some(Duration::from_micros(usec / 2))
+}
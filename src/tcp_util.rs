@@ -3,8 +3,49 @@ use std::os::unix::io::AsRawFd;
 use acme_client::openssl::ssl::SslStream;
 use crate::SBResult;
 
-use std::ops::Generator;
-use std::io::Write;
+use std::cell::RefCell;
+use std::ops::{Deref, Generator};
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+const COPY_CHUNK_SIZE: usize = 64<<10;
+
+/// Max bytes written per resume before yielding to other connections on the same thread,
+/// so small responses aren't stuck behind large downloads
+const WRITE_SLICE_SIZE: usize = 256<<10;
+
+/// Request read buffers start out this big, and grow by as much again whenever they fill up
+const READ_BUFFER_SIZE: usize = 8<<10;
+
+/// Buffers that grew past this for a big request are freed rather than pooled, so they don't pin memory
+const MAX_POOLED_BUFFER_SIZE: usize = 64<<10;
+
+/// Most buffers each worker thread keeps for reuse, enough for as many connections as it'll take on at once
+const MAX_POOLED_BUFFERS: usize = 128;
+
+thread_local! {
+	/// Buffers of connections that have finished on this thread, emptied and ready for the next ones
+	static READ_BUFFERS: RefCell<Vec<Vec<u8>>> = RefCell::new(Vec::new());
+}
+
+/// A client that accepts no data for this many seconds is assumed to have gone away
+static WRITE_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(30);
+
+/// Responses up to this size are built into one buffer and sent with a single write
+pub const COALESCE_MAX_SIZE: usize = 16<<10;
+
+/// Bodies up to this size are sent along with the head in a single writev.
+/// Anything larger is written in slices so it can't hog the worker thread
+pub const VECTORED_MAX_SIZE: usize = WRITE_SLICE_SIZE;
+
+pub fn set_write_timeout(timeout: Duration) {
+	WRITE_TIMEOUT_SECS.store(timeout.as_secs(), Ordering::Relaxed);
+}
+
+fn write_timeout() -> Duration {
+	Duration::from_secs(WRITE_TIMEOUT_SECS.load(Ordering::Relaxed))
+}
 
 pub trait TcpStreamExt {
 	fn has_pending_writes(&self) -> bool;
@@ -19,9 +60,8 @@ impl TcpStreamExt for TcpStream {
 
 			let mut pending = 0i32;
 			let ret = libc::ioctl(fd, libc::TIOCOUTQ, &mut pending as *mut i32);
-			assert!(ret >= 0);
-
-			pending > 0
+			// A failing ioctl just means there's nothing useful to wait on
+			ret >= 0 && pending > 0
 		}
 	}
 
@@ -31,9 +71,8 @@ impl TcpStreamExt for TcpStream {
 
 			let mut pending = 0i32;
 			let ret = libc::ioctl(fd, libc::TIOCINQ, &mut pending as *mut i32);
-			assert!(ret >= 0);
-
-			pending > 0
+			// A failing ioctl just means there's nothing useful to wait on
+			ret >= 0 && pending > 0
 		}
 	}
 
@@ -50,9 +89,8 @@ impl TcpStreamExt for SslStream<TcpStream> {
 
 			let mut pending = 0i32;
 			let ret = libc::ioctl(fd, libc::TIOCOUTQ, &mut pending as *mut i32);
-			assert!(ret >= 0);
-
-			pending > 0
+			// A failing ioctl just means there's nothing useful to wait on
+			ret >= 0 && pending > 0
 		}
 	}
 
@@ -62,9 +100,8 @@ impl TcpStreamExt for SslStream<TcpStream> {
 
 			let mut pending = 0i32;
 			let ret = libc::ioctl(fd, libc::TIOCINQ, &mut pending as *mut i32);
-			assert!(ret >= 0);
-
-			pending > 0
+			// A failing ioctl just means there's nothing useful to wait on
+			ret >= 0 && pending > 0
 		}
 	}
 
@@ -74,6 +111,234 @@ impl TcpStreamExt for SslStream<TcpStream> {
 	}
 }
 
+/// A TLS connection that says it's done with close_notify when dropped. Without it, clients can't
+/// tell a whole response from a cut off one, and openssl forgets the session so it can't be resumed
+pub struct TlsStream(pub SslStream<TcpStream>);
+
+impl Read for TlsStream {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		self.0.read(buf)
+	}
+}
+
+impl Write for TlsStream {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.0.write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.0.flush()
+	}
+}
+
+impl TcpStreamExt for TlsStream {
+	fn has_pending_writes(&self) -> bool { self.0.has_pending_writes() }
+	fn has_pending_reads(&self) -> bool { self.0.has_pending_reads() }
+	fn set_nonblocking(&self, nonblock: bool) -> SBResult<()> { self.0.set_nonblocking(nonblock) }
+}
+
+impl Drop for TlsStream {
+	fn drop(&mut self) {
+		// Only one attempt, since the socket's nonblocking. Not waiting on the client's close_notify is fine
+		let _ = self.0.shutdown();
+	}
+}
+
+/// Holds a request as it's read in. Taken from the current thread's pool, and given back on drop,
+/// so a steady stream of connections doesn't mean a steady stream of allocations
+pub struct ReadBuffer {
+	data: Vec<u8>,
+}
+
+impl ReadBuffer {
+	pub fn new() -> Self {
+		let data = READ_BUFFERS.with(|pool| pool.borrow_mut().pop())
+			.unwrap_or_else(|| Vec::with_capacity(READ_BUFFER_SIZE));
+
+		ReadBuffer { data }
+	}
+
+	/// Reads whatever's available onto the end of the buffer, growing it if it's full
+	pub fn read_from<R: Read>(&mut self, reader: &mut R) -> io::Result<usize> {
+		let len = self.data.len();
+		if len == self.data.capacity() {
+			self.data.reserve(READ_BUFFER_SIZE);
+		}
+
+		self.data.resize(self.data.capacity(), 0);
+		let result = reader.read(&mut self.data[len..]);
+		self.data.truncate(len + *result.as_ref().unwrap_or(&0));
+
+		result
+	}
+
+	pub fn extend_from_slice(&mut self, data: &[u8]) {
+		self.data.extend_from_slice(data);
+	}
+}
+
+impl Deref for ReadBuffer {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		&self.data
+	}
+}
+
+impl Drop for ReadBuffer {
+	fn drop(&mut self) {
+		if self.data.capacity() > MAX_POOLED_BUFFER_SIZE { return }
+
+		let mut data = std::mem::take(&mut self.data);
+		data.clear();
+
+		// Can fail if the thread is on its way out, in which case the buffer's just freed
+		let _ = READ_BUFFERS.try_with(|pool| {
+			let mut pool = pool.borrow_mut();
+			if pool.len() < MAX_POOLED_BUFFERS {
+				pool.push(data);
+			}
+		});
+	}
+}
+
+
+/// Counts the bytes actually accepted by the underlying stream
+pub struct CountingStream<S> {
+	inner: S,
+	sent: u64,
+}
+
+impl<S> CountingStream<S> {
+	pub fn new(inner: S) -> Self {
+		CountingStream { inner, sent: 0 }
+	}
+
+	pub fn sent(&self) -> u64 { self.sent }
+}
+
+impl<S: Write> Write for CountingStream<S> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let size = self.inner.write(buf)?;
+		self.sent += size as u64;
+		Ok(size)
+	}
+
+	fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+		let size = self.inner.write_vectored(bufs)?;
+		self.sent += size as u64;
+		Ok(size)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+impl<S: TcpStreamExt> TcpStreamExt for CountingStream<S> {
+	fn has_pending_writes(&self) -> bool { self.inner.has_pending_writes() }
+	fn has_pending_reads(&self) -> bool { self.inner.has_pending_reads() }
+	fn set_nonblocking(&self, nonblock: bool) -> SBResult<()> { self.inner.set_nonblocking(nonblock) }
+}
+
+/// Fails reads and writes once a request's time is up, so however its time is being spent,
+/// the request ends at the next read or write
+pub struct DeadlineStream<S> {
+	inner: S,
+	deadline: Option<Instant>,
+}
+
+/// Carried by the io::Error a `DeadlineStream` fails with
+#[derive(Debug)]
+pub struct DeadlineExceeded;
+
+impl std::fmt::Display for DeadlineExceeded {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("request deadline exceeded")
+	}
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+impl<S> DeadlineStream<S> {
+	pub fn new(inner: S, deadline: Option<Instant>) -> Self {
+		DeadlineStream { inner, deadline }
+	}
+
+	pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+		self.deadline = deadline;
+	}
+
+	pub fn expired(&self) -> bool {
+		self.deadline.map_or(false, |deadline| Instant::now() >= deadline)
+	}
+
+	/// The stream without the deadline, for a last word once it's passed
+	pub fn get_mut(&mut self) -> &mut S {
+		&mut self.inner
+	}
+
+	fn check(&self) -> io::Result<()> {
+		if self.expired() {
+			return Err(io::Error::new(io::ErrorKind::TimedOut, DeadlineExceeded))
+		}
+
+		Ok(())
+	}
+}
+
+impl<S: Read> Read for DeadlineStream<S> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		self.check()?;
+		self.inner.read(buf)
+	}
+}
+
+impl<S: Write> Write for DeadlineStream<S> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.check()?;
+		self.inner.write(buf)
+	}
+
+	fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+		self.check()?;
+		self.inner.write_vectored(bufs)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+impl<S: TcpStreamExt> TcpStreamExt for DeadlineStream<S> {
+	fn has_pending_writes(&self) -> bool { self.inner.has_pending_writes() }
+	fn has_pending_reads(&self) -> bool { self.inner.has_pending_reads() }
+	fn set_nonblocking(&self, nonblock: bool) -> SBResult<()> { self.inner.set_nonblocking(nonblock) }
+}
+
+/// Whether an error came from a `DeadlineStream` running out of time, including with context added
+pub fn is_deadline_exceeded(error: &failure::Error) -> bool {
+	error.iter_chain()
+		.filter_map(|cause| cause.downcast_ref::<io::Error>())
+		.filter_map(|e| e.get_ref())
+		.any(|inner| inner.is::<DeadlineExceeded>())
+}
+
+/// Whether an error means the client went away, rather than something going wrong on our end
+pub fn is_disconnect(error: &failure::Error) -> bool {
+	use std::io::ErrorKind::*;
+
+	// Also a TimedOut, but the client didn't do anything wrong
+	if is_deadline_exceeded(error) {
+		return false
+	}
+
+	match error.downcast_ref::<io::Error>().map(io::Error::kind) {
+		Some(BrokenPipe) | Some(ConnectionReset) | Some(ConnectionAborted) | Some(TimedOut) => true,
+		_ => false,
+	}
+}
+
 
 #[must_use]
 pub fn write_async<'a, S>(stream: &'a mut S, bytes: &'a [u8]) -> impl Generator<Yield=(), Return=SBResult<()>> + 'a
@@ -83,19 +348,102 @@ pub fn write_async<'a, S>(stream: &'a mut S, bytes: &'a [u8]) -> impl Generator<
 
 	move || {
 		let mut cursor = 0;
+		let mut written_since_yield = 0;
+		let mut last_progress = Instant::now();
 
 		loop {
-			let result = stream.write(&bytes[cursor..]);
+			let slice_end = bytes.len().min(cursor + WRITE_SLICE_SIZE - written_since_yield);
+
+			let result = stream.write(&bytes[cursor..slice_end]);
 			match result {
-				Err(ref e) if e.kind() == WouldBlock => yield,
-				Err(ref e) if e.kind() == Interrupted => yield,
+				Err(ref e) if e.kind() == WouldBlock => {
+					if last_progress.elapsed() > write_timeout() {
+						return Err(stall_error())
+					}
+				},
+				Err(ref e) if e.kind() == Interrupted => {},
 				Err(e) => return Err(e.into()),
 				Ok(sz) => {
+					last_progress = Instant::now();
 					cursor += sz;
+					written_since_yield += sz;
 					if cursor >= bytes.len() { break }
-					continue
+					if written_since_yield < WRITE_SLICE_SIZE { continue }
 				},
 			};
+
+			written_since_yield = 0;
+			yield
+		}
+
+		Ok(())
+	}
+}
+
+/// Writes `head` and `body` together using writev, so small responses don't cost two round trips
+/// through the kernel. `body` should be at most `VECTORED_MAX_SIZE`
+#[must_use]
+pub fn write_vectored_async<'a, S>(stream: &'a mut S, head: &'a [u8], body: &'a [u8]) -> impl Generator<Yield=(), Return=SBResult<()>> + 'a
+	where S: TcpStreamExt + Write {
+
+	use std::io::IoSlice;
+	use std::io::ErrorKind::{WouldBlock, Interrupted};
+
+	static move || {
+		let mut written = 0;
+		let mut last_progress = Instant::now();
+
+		while written < head.len() {
+			let slices = [IoSlice::new(&head[written..]), IoSlice::new(body)];
+
+			match stream.write_vectored(&slices) {
+				Err(ref e) if e.kind() == WouldBlock => {
+					if last_progress.elapsed() > write_timeout() {
+						return Err(stall_error())
+					}
+
+					yield
+				}
+
+				Err(ref e) if e.kind() == Interrupted => {},
+				Err(e) => return Err(e.into()),
+				Ok(sz) => {
+					last_progress = Instant::now();
+					written += sz;
+				}
+			}
+		}
+
+		// Once the head is out, whatever is left of the body is a plain write
+		let body_written = written - head.len();
+		task_await!(write_async(stream, &body[body_written..]))
+	}
+}
+
+fn stall_error() -> failure::Error {
+	io::Error::new(io::ErrorKind::TimedOut, "client stopped reading").into()
+}
+
+/// Copies `reader` to `stream` in fixed size chunks, so large files never need to be fully buffered
+#[must_use]
+pub fn copy_async<'a, S, R>(stream: &'a mut S, mut reader: R) -> impl Generator<Yield=(), Return=SBResult<()>> + 'a
+	where S: TcpStreamExt + Write, R: Read + 'a {
+
+	use std::io::ErrorKind::Interrupted;
+
+	static move || {
+		let mut buf = vec![0u8; COPY_CHUNK_SIZE];
+
+		loop {
+			let size = match reader.read(&mut buf) {
+				Err(ref e) if e.kind() == Interrupted => continue,
+				Err(e) => return Err(e.into()),
+				Ok(0) => break,
+				Ok(sz) => sz,
+			};
+
+			task_await!(write_async(stream, &buf[..size]))?;
+			yield;
 		}
 
 		Ok(())
@@ -0,0 +1,51 @@
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::clock::Clock;
+
+/// Small deterministic generator for property tests, so failures can be reproduced from the seed
+pub struct XorShift(u64);
+
+impl XorShift {
+	pub fn new(seed: u64) -> XorShift {
+		XorShift(seed.max(1))
+	}
+
+	pub fn next(&mut self) -> u64 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 7;
+		self.0 ^= self.0 << 17;
+		self.0
+	}
+
+	pub fn below(&mut self, n: u64) -> u64 {
+		self.next() % n.max(1)
+	}
+
+	pub fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+		&items[self.below(items.len() as u64) as usize]
+	}
+}
+
+/// A clock that only moves when told to. Sleeping on it moves it forward instantly
+pub struct FakeClock(Mutex<SystemTime>);
+
+impl FakeClock {
+	pub fn at(time: SystemTime) -> FakeClock {
+		FakeClock(Mutex::new(time))
+	}
+
+	pub fn advance(&self, duration: Duration) {
+		*self.0.lock().unwrap() += duration;
+	}
+}
+
+impl Clock for FakeClock {
+	fn now(&self) -> SystemTime {
+		*self.0.lock().unwrap()
+	}
+
+	fn sleep(&self, duration: Duration) {
+		self.advance(duration)
+	}
+}
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf, Component};
+use std::sync::{Mutex, RwLock};
+use std::fs;
+
+use crate::SBResult;
+
+/// How deep includes can nest before giving up, which is also what stops include cycles
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+const INCLUDE_START: &[u8] = b"<!--#include";
+const INCLUDE_END: &[u8] = b"-->";
+
+/// Rewrites a file's contents before it's compressed and cached, so the output stays cacheable.
+/// Transforms run in the order they're declared, each seeing the output of the last
+pub trait Transform: Send + Sync {
+	fn apply(&self, path: &Path, data: Vec<u8>) -> SBResult<Vec<u8>>;
+
+	/// Other files the output for `path` was built from, as of the last `apply`.
+	/// The cached output is rebuilt when any of them change
+	fn dependencies(&self, _path: &Path) -> Vec<PathBuf> {
+		Vec::new()
+	}
+}
+
+/// Replaces every occurrence of `from` with `to`, from `transform /route => substitute %BUILD_SHA% $BUILD_SHA`
+pub struct Substitute {
+	pub from: Vec<u8>,
+	pub to: Vec<u8>,
+}
+
+/// Inserts some html just after the opening `<body>` tag, or at the start if there isn't one.
+/// From `transform /route => banner path/to/banner.html`
+pub struct Banner {
+	pub path: PathBuf,
+}
+
+/// Replaces `<!--#include "/fragments/header.html"-->` with the file behind that route, from
+/// `transform /route => includes`. Fragments can include other fragments
+pub struct Includes {
+	routes: RwLock<IncludeRoutes>,
+	/// Fragments each file included last time it was built
+	dependencies: Mutex<HashMap<PathBuf, Vec<PathBuf>>>,
+}
+
+#[derive(Default)]
+struct IncludeRoutes {
+	exact: HashMap<String, PathBuf>,
+	/// `/prefix/` and the directory it maps to
+	wildcards: Vec<(String, PathBuf)>,
+}
+
+/// Parses the value of a `transform` directive. Paths are relative to `prefix`
+pub fn parse(value: &str, prefix: &Path) -> SBResult<Box<dyn Transform>> {
+	let mut parts = value.split_whitespace();
+
+	match (parts.next(), parts.next(), parts.next(), parts.next()) {
+		(Some("substitute"), Some(from), Some(to), None) => {
+			// `$NAME` takes the replacement from the environment, for things only known at deploy time
+			let to = if to.starts_with('$') {
+				std::env::var(&to[1..])
+					.map_err(|_| failure::format_err!("Environment variable {} for substitution isn't set", to))?
+			} else {
+				to.to_owned()
+			};
+
+			Ok(Box::new(Substitute{ from: from.as_bytes().to_vec(), to: to.into_bytes() }))
+		}
+
+		(Some("banner"), Some(path), None, None) => {
+			// Read when it's applied so edits show up without reloading mappings, but fail early if it's missing
+			let path = prefix.join(path);
+			fs::metadata(&path)?;
+			Ok(Box::new(Banner{ path }))
+		}
+
+		_ => failure::bail!("Invalid transform '{}', expected 'substitute FROM TO', 'banner path' or 'includes'", value),
+	}
+}
+
+impl Transform for Substitute {
+	fn apply(&self, _: &Path, data: Vec<u8>) -> SBResult<Vec<u8>> {
+		if self.from.is_empty() { return Ok(data) }
+
+		let mut output = Vec::with_capacity(data.len());
+		let mut rest = &data[..];
+
+		while let Some(pos) = find(rest, &self.from) {
+			output.extend_from_slice(&rest[..pos]);
+			output.extend_from_slice(&self.to);
+			rest = &rest[pos + self.from.len()..];
+		}
+
+		output.extend_from_slice(rest);
+		Ok(output)
+	}
+}
+
+impl Transform for Banner {
+	fn apply(&self, _: &Path, mut data: Vec<u8>) -> SBResult<Vec<u8>> {
+		let html = fs::read(&self.path)?;
+		let lowercase = data.to_ascii_lowercase();
+
+		// `<body` followed by `>` or attributes, not e.g. `<bodyguard>`
+		let body_start = (0..lowercase.len())
+			.filter(|&i| lowercase[i..].starts_with(b"<body"))
+			.find(|&i| lowercase.get(i + 5).map_or(false, |c| *c == b'>' || c.is_ascii_whitespace()));
+
+		let insert_at = body_start
+			.and_then(|start| find(&lowercase[start..], b">").map(|end| start + end + 1))
+			.unwrap_or(0);
+
+		data.splice(insert_at..insert_at, html);
+		Ok(data)
+	}
+
+	fn dependencies(&self, _: &Path) -> Vec<PathBuf> {
+		vec![self.path.clone()]
+	}
+}
+
+impl Includes {
+	pub fn new() -> Self {
+		Includes {
+			routes: RwLock::new(IncludeRoutes::default()),
+			dependencies: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Tells includes where routes point. Has to be called once all mappings are loaded, and before
+	/// anything is built
+	pub fn set_routes(&self, exact: HashMap<String, PathBuf>, wildcards: Vec<(String, PathBuf)>) {
+		let mut routes = self.routes.write().unwrap_or_else(|e| e.into_inner());
+		*routes = IncludeRoutes { exact, wildcards };
+	}
+
+	fn expand(&self, data: &[u8], depth: usize, included: &mut Vec<PathBuf>) -> SBResult<Vec<u8>> {
+		if depth > MAX_INCLUDE_DEPTH {
+			failure::bail!("Includes nested too deeply, is there an include cycle?");
+		}
+
+		let mut output = Vec::with_capacity(data.len());
+		let mut rest = data;
+
+		while let Some(start) = find(rest, INCLUDE_START) {
+			let end = match find(&rest[start..], INCLUDE_END) {
+				Some(end) => start + end + INCLUDE_END.len(),
+				None => break,
+			};
+
+			output.extend_from_slice(&rest[..start]);
+
+			let directive = &rest[start + INCLUDE_START.len() .. end - INCLUDE_END.len()];
+			let route = String::from_utf8_lossy(directive);
+			let route = route.trim().trim_matches('"');
+
+			match self.resolve(route) {
+				Some(path) => {
+					let fragment = fs::read(&path)?;
+					included.push(path);
+					output.extend(self.expand(&fragment, depth + 1, included)?);
+				}
+
+				// Leave it in place so it shows up in the page source
+				None => {
+					warn!("Nothing to include for {}", route);
+					output.extend_from_slice(&rest[start..end]);
+				}
+			}
+
+			rest = &rest[end..];
+		}
+
+		output.extend_from_slice(rest);
+		Ok(output)
+	}
+
+	fn resolve(&self, route: &str) -> Option<PathBuf> {
+		let routes = self.routes.read().unwrap_or_else(|e| e.into_inner());
+
+		if let Some(path) = routes.exact.get(route) {
+			return Some(path.clone())
+		}
+
+		routes.wildcards.iter()
+			.filter(|(prefix, _)| route.starts_with(prefix.as_str()))
+			.max_by_key(|(prefix, _)| prefix.len())
+			.and_then(|(prefix, dir)| {
+				let relative = Path::new(&route[prefix.len()..]);
+
+				// Same as wildcard routes, never reach outside the directory
+				let escapes = relative.components()
+					.any(|c| !matches!(c, Component::Normal(_)));
+
+				if escapes { None } else { Some(dir.join(relative)) }
+			})
+			.filter(|path| path.is_file())
+	}
+}
+
+impl Transform for Includes {
+	fn apply(&self, path: &Path, data: Vec<u8>) -> SBResult<Vec<u8>> {
+		if find(&data, INCLUDE_START).is_none() {
+			return Ok(data)
+		}
+
+		let mut included = Vec::new();
+		let output = self.expand(&data, 0, &mut included)?;
+
+		let mut dependencies = self.dependencies.lock().unwrap_or_else(|e| e.into_inner());
+		dependencies.insert(path.to_owned(), included);
+
+		Ok(output)
+	}
+
+	fn dependencies(&self, path: &Path) -> Vec<PathBuf> {
+		let dependencies = self.dependencies.lock().unwrap_or_else(|e| e.into_inner());
+		dependencies.get(path).cloned().unwrap_or_default()
+	}
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+	haystack.windows(needle.len()).position(|window| window == needle)
+}
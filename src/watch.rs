@@ -0,0 +1,33 @@
+use std::sync::{Arc, RwLock};
+
+/// The latest version of some value, shared between whoever changes it and whoever reads it.
+/// Readers get a snapshot that stays the same for as long as they hold it, and changes are
+/// seen by the very next `get`
+pub struct Watch<T> {
+	value: Arc<RwLock<Arc<T>>>,
+}
+
+impl<T> Clone for Watch<T> {
+	fn clone(&self) -> Self {
+		Watch { value: self.value.clone() }
+	}
+}
+
+impl<T> Watch<T> {
+	pub fn new(value: T) -> Self {
+		Watch { value: Arc::new(RwLock::new(Arc::new(value))) }
+	}
+
+	pub fn get(&self) -> Arc<T> {
+		self.value.read().unwrap_or_else(|e| e.into_inner()).clone()
+	}
+}
+
+impl<T: Clone> Watch<T> {
+	/// Changes the value in place, copying it first only if someone still holds a snapshot.
+	/// Changes from different threads happen one after another, never interleaved
+	pub fn modify<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+		let mut value = self.value.write().unwrap_or_else(|e| e.into_inner());
+		f(Arc::make_mut(&mut value))
+	}
+}
@@ -0,0 +1,267 @@
+//! Runs the server against an HTTP/1.1 conformance checklist, in the style of h1spec and REDbot,
+//! so protocol features added later don't break the basics
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+const HEALTH_PATH: &str = "/.spiderbutter/health";
+
+/// Long enough to be worth compressing, so responses have encodings to vary on
+const PAGE: &str = "<!doctype html><title>conformance</title><p>Some text that compresses well, well, well, well, well.</p>\n";
+
+/// A server in `--local` mode, serving a scratch directory on its own port
+struct Server {
+	child: Child,
+	port: u16,
+	dir: PathBuf,
+}
+
+impl Server {
+	fn start(name: &str) -> Server {
+		let dir = std::env::temp_dir().join(format!("spiderbutter-conformance-{}-{}", std::process::id(), name));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		fs::write(dir.join("index.html"), PAGE).unwrap();
+		fs::write(dir.join("page.html"), PAGE).unwrap();
+
+		// Whatever port the OS hands out is free, and very likely to stay that way until the server takes it
+		let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+		let child = Command::new(env!("CARGO_BIN_EXE_spiderbutter"))
+			.args(&["--local", "--quiet", "--port", &port.to_string()])
+			.current_dir(&dir)
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.spawn()
+			.unwrap();
+
+		let server = Server { child, port, dir };
+
+		// Listening starts before content's loaded, and anything asked for before then is remembered as missing
+		let started = Instant::now();
+		while !server.is_ready() {
+			assert!(started.elapsed() < STARTUP_TIMEOUT, "server on {} didn't become ready", port);
+			thread::sleep(Duration::from_millis(50));
+		}
+
+		server
+	}
+
+	fn is_ready(&self) -> bool {
+		let mut stream = match TcpStream::connect(("127.0.0.1", self.port)) {
+			Ok(stream) => stream,
+			Err(_) => return false,
+		};
+
+		let mut data = Vec::new();
+		let _ = stream.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", HEALTH_PATH).as_bytes());
+		let _ = stream.read_to_end(&mut data);
+
+		Response::parse(&data).map_or(false, |response| response.status == 200)
+	}
+
+	fn send(&self, request: &str) -> Response {
+		let mut stream = TcpStream::connect(("127.0.0.1", self.port)).unwrap();
+		stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+		stream.write_all(request.as_bytes()).unwrap();
+
+		// Every response closes the connection, so reading to the end gets exactly one
+		let mut data = Vec::new();
+		let _ = stream.read_to_end(&mut data);
+
+		Response::parse(&data).unwrap_or_else(|| panic!("unparseable response to {:?}: {:?}", request, String::from_utf8_lossy(&data)))
+	}
+}
+
+impl Drop for Server {
+	fn drop(&mut self) {
+		let _ = self.child.kill();
+		let _ = self.child.wait();
+		let _ = fs::remove_dir_all(&self.dir);
+	}
+}
+
+struct Response {
+	status: u16,
+	fields: Vec<(String, String)>,
+	body: Vec<u8>,
+}
+
+impl Response {
+	fn parse(data: &[u8]) -> Option<Response> {
+		let head_end = data.windows(4).position(|window| window == b"\r\n\r\n")?;
+		let head = std::str::from_utf8(&data[..head_end]).ok()?;
+		let mut lines = head.split("\r\n");
+
+		let status = lines.next()?.split(' ').nth(1)?.parse().ok()?;
+		let fields = lines
+			.map(|line| {
+				let colon = line.find(':')?;
+				Some((line[..colon].to_owned(), line[colon+1..].trim().to_owned()))
+			})
+			.collect::<Option<_>>()?;
+
+		Some(Response { status, fields, body: data[head_end+4..].to_vec() })
+	}
+
+	fn get(&self, name: &str) -> Option<&str> {
+		self.fields.iter()
+			.find(|(key, _)| key.eq_ignore_ascii_case(name))
+			.map(|(_, value)| value.as_str())
+	}
+
+	fn count(&self, name: &str) -> usize {
+		self.fields.iter().filter(|(key, _)| key.eq_ignore_ascii_case(name)).count()
+	}
+}
+
+#[test]
+fn host_is_required_from_http_1_1_clients() {
+	let server = Server::start("host");
+
+	assert_eq!(server.send("GET /page.html HTTP/1.1\r\n\r\n").status, 400);
+	assert_eq!(server.send("GET /page.html HTTP/1.0\r\n\r\n").status, 200);
+	assert_eq!(server.send("GET /page.html HTTP/1.1\r\nhost: example.com\r\n\r\n").status, 200);
+}
+
+#[test]
+fn duplicate_host_fields_are_rejected() {
+	let server = Server::start("duplicate-host");
+
+	assert_eq!(server.send("GET /page.html HTTP/1.1\r\nHost: a.example\r\nHost: b.example\r\n\r\n").status, 400);
+	assert_eq!(server.send("GET /page.html HTTP/1.1\r\nHost: a.example\r\nhOST: a.example\r\n\r\n").status, 400);
+}
+
+#[test]
+fn absolute_form_targets_are_accepted() {
+	let server = Server::start("absolute-form");
+
+	let response = server.send("GET http://example.com/page.html HTTP/1.1\r\nHost: other.example\r\n\r\n");
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, PAGE.as_bytes());
+
+	// The target's authority stands in for a missing Host
+	assert_eq!(server.send("GET http://example.com/page.html HTTP/1.1\r\n\r\n").status, 200);
+
+	// No path means the root
+	let response = server.send("GET HTTPS://example.com HTTP/1.1\r\n\r\n");
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, PAGE.as_bytes());
+}
+
+#[test]
+fn obsolete_line_folding_is_rejected() {
+	let server = Server::start("folding");
+
+	assert_eq!(server.send("GET /page.html HTTP/1.1\r\nHost: example.com\r\nX-Long: one\r\n two\r\n\r\n").status, 400);
+	assert_eq!(server.send("GET /page.html HTTP/1.1\r\nHost: example.com\r\nX-Long: one\r\n\ttwo\r\n\r\n").status, 400);
+}
+
+#[test]
+fn whitespace_before_the_colon_is_rejected() {
+	let server = Server::start("field-whitespace");
+
+	assert_eq!(server.send("GET /page.html HTTP/1.1\r\nHost : example.com\r\n\r\n").status, 400);
+	assert_eq!(server.send("GET /page.html HTTP/1.1\r\nHost\t: example.com\r\n\r\n").status, 400);
+}
+
+#[test]
+fn methods_are_case_sensitive_and_unknown_ones_are_not_implemented() {
+	let server = Server::start("methods");
+
+	assert_eq!(server.send("get /page.html HTTP/1.1\r\nHost: example.com\r\n\r\n").status, 501);
+	assert_eq!(server.send("BREW /page.html HTTP/1.1\r\nHost: example.com\r\n\r\n").status, 501);
+}
+
+#[test]
+fn only_http_1_is_spoken() {
+	let server = Server::start("versions");
+
+	assert_eq!(server.send("GET /page.html HTTP/2.0\r\nHost: example.com\r\n\r\n").status, 505);
+	assert_eq!(server.send("GET /page.html HTTP/1.2\r\nHost: example.com\r\n\r\n").status, 200);
+	assert_eq!(server.send("GET /page.html HTTP/one\r\nHost: example.com\r\n\r\n").status, 400);
+}
+
+#[test]
+fn head_matches_get_without_a_body() {
+	let server = Server::start("head");
+
+	for encoding in &["identity", "gzip"] {
+		let get = server.send(&format!("GET /page.html HTTP/1.1\r\nHost: example.com\r\nAccept-Encoding: {}\r\n\r\n", encoding));
+		let head = server.send(&format!("HEAD /page.html HTTP/1.1\r\nHost: example.com\r\nAccept-Encoding: {}\r\n\r\n", encoding));
+
+		assert_eq!(head.status, 200);
+		assert!(head.body.is_empty());
+		assert_eq!(get.get("Content-Length"), Some(get.body.len().to_string().as_str()));
+		assert_eq!(head.get("Content-Length"), get.get("Content-Length"), "{}", encoding);
+		assert_eq!(head.get("Content-Encoding"), get.get("Content-Encoding"), "{}", encoding);
+		assert_eq!(head.get("ETag"), get.get("ETag"), "{}", encoding);
+	}
+}
+
+#[test]
+fn every_response_has_date_and_closes() {
+	let server = Server::start("date");
+
+	let requests = [
+		"GET /page.html HTTP/1.1\r\nHost: example.com\r\n\r\n",
+		"GET /missing HTTP/1.1\r\nHost: example.com\r\n\r\n",
+		"GET /page.html HTTP/1.1\r\n\r\n",
+		"BREW /page.html HTTP/1.1\r\nHost: example.com\r\n\r\n",
+	];
+
+	for request in requests.iter() {
+		let response = server.send(request);
+		assert_eq!(response.count("Date"), 1, "{:?}", request);
+		assert_eq!(response.get("Connection"), Some("close"), "{:?}", request);
+	}
+}
+
+#[test]
+fn compressed_responses_vary_on_accept_encoding() {
+	let server = Server::start("vary");
+
+	let response = server.send("GET /page.html HTTP/1.1\r\nHost: example.com\r\nAccept-Encoding: gzip\r\n\r\n");
+	assert_eq!(response.get("Content-Encoding"), Some("gzip"));
+	assert_eq!(response.count("Vary"), 1);
+	assert!(response.get("Vary").unwrap().to_ascii_lowercase().contains("accept-encoding"));
+}
+
+#[test]
+fn not_modified_keeps_caching_headers() {
+	let server = Server::start("not-modified");
+
+	let full = server.send("GET /page.html HTTP/1.1\r\nHost: example.com\r\n\r\n");
+	let etag = full.get("ETag").unwrap();
+
+	let response = server.send(&format!("GET /page.html HTTP/1.1\r\nHost: example.com\r\nIf-None-Match: {}\r\n\r\n", etag));
+	assert_eq!(response.status, 304);
+	assert!(response.body.is_empty());
+	assert_eq!(response.get("ETag"), Some(etag));
+	assert_eq!(response.get("Vary"), full.get("Vary"));
+	assert_eq!(response.get("Cache-Control"), full.get("Cache-Control"));
+}
+
+#[test]
+fn field_names_are_case_insensitive() {
+	let server = Server::start("field-case");
+
+	let response = server.send("GET /page.html HTTP/1.1\r\nhost: example.com\r\nrAnGe: bytes=0-3\r\n\r\n");
+	assert_eq!(response.status, 206);
+	assert_eq!(response.body, &PAGE.as_bytes()[..4]);
+}
+
+#[test]
+fn unsatisfiable_ranges_say_how_long_the_file_is() {
+	let server = Server::start("ranges");
+
+	let response = server.send("GET /page.html HTTP/1.1\r\nHost: example.com\r\nRange: bytes=999999-\r\n\r\n");
+	assert_eq!(response.status, 416);
+	assert_eq!(response.get("Content-Range"), Some(format!("bytes */{}", PAGE.len()).as_str()));
+}